@@ -0,0 +1,192 @@
+// --input-archive: assemble every .s file of a tar, tar.gz or zip archive in
+// one process. The results go into an output archive under the same names,
+// together with index.json holding the status of each file.
+use super::{assemble_source, file_status, Options};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::panic;
+
+enum Kind {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+fn archive_kind(path: &str) -> Kind {
+    if path.ends_with(".zip") {
+        Kind::Zip
+    } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        Kind::TarGz
+    } else if path.ends_with(".tar") {
+        Kind::Tar
+    } else {
+        panic!(
+            "unsupported archive {}, use .tar, .tar.gz, .tgz or .zip",
+            path
+        );
+    }
+}
+
+fn is_source(name: &str) -> bool {
+    name.ends_with(".s") || name.ends_with(".S")
+}
+
+fn read_tar<R: Read>(reader: R, wanted: fn(&str) -> bool) -> Vec<(String, String)> {
+    let mut sources = vec![];
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let name = entry.path().unwrap().to_string_lossy().into_owned();
+        if entry.header().entry_type().is_file() && wanted(&name) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            sources.push((name, content));
+        }
+    }
+    sources
+}
+
+fn read_zip(file: File, wanted: fn(&str) -> bool) -> Vec<(String, String)> {
+    let mut sources = vec![];
+    let mut archive = zip::ZipArchive::new(file).unwrap();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).unwrap();
+        let name = String::from(entry.name());
+        if entry.is_file() && wanted(&name) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            sources.push((name, content));
+        }
+    }
+    sources
+}
+
+// (name, content) of the wanted files, in archive order
+fn read_entries(path: &str, wanted: fn(&str) -> bool) -> Vec<(String, String)> {
+    let file = File::open(path).unwrap();
+    match archive_kind(path) {
+        Kind::Tar => read_tar(file, wanted),
+        Kind::TarGz => read_tar(GzDecoder::new(file), wanted),
+        Kind::Zip => read_zip(file, wanted),
+    }
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[(String, String)]) -> W {
+    let mut builder = tar::Builder::new(writer);
+    for (name, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, content.as_bytes())
+            .unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn write_zip(file: File, entries: &[(String, String)]) {
+    let mut writer = zip::ZipWriter::new(file);
+    for (name, content) in entries {
+        writer
+            .start_file(name.as_str(), zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+fn write_entries(path: &str, entries: &[(String, String)]) {
+    let file = File::create(path).unwrap();
+    match archive_kind(path) {
+        Kind::Tar => {
+            write_tar(file, entries);
+        }
+        Kind::TarGz => {
+            write_tar(GzEncoder::new(file, Compression::default()), entries)
+                .finish()
+                .unwrap();
+        }
+        Kind::Zip => write_zip(file, entries),
+    }
+}
+
+// Returns the number of files that failed to assemble.
+pub(super) fn assemble_archive(
+    input: &str,
+    output: &str,
+    options: &Options,
+    arch_check: bool,
+) -> usize {
+    let sources = read_entries(input, is_source);
+    let mut entries = vec![];
+    let mut index = vec![];
+    let mut failed = 0;
+    // errors are reported in index.json, not as panic messages on stderr
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    for (name, content) in sources.iter() {
+        let result = assemble_source(name, content, options, arch_check);
+        index.push(file_status(name, &result));
+        match result {
+            Ok(text) => entries.push((name.clone(), text)),
+            Err(_) => failed += 1,
+        }
+    }
+    panic::set_hook(hook);
+    let index = serde_json::to_string_pretty(&index).unwrap();
+    entries.push((String::from("index.json"), index));
+    write_entries(output, &entries);
+    eprintln!("{}: {} files, {} failed", input, sources.len(), failed);
+    failed
+}
+
+#[test]
+fn test_archive() {
+    let dir = std::env::temp_dir().join(format!("rna-archive-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let sources = vec![
+        (String::from("a.s"), String::from("andn zero, tp, s6\n")),
+        (
+            String::from("sub/b.S"),
+            String::from("fadd.s ft0, ft1, ft2, rxx\n"),
+        ),
+        (String::from("c.s"), String::from("movq %rax, %rbx\n")),
+        (String::from("readme.txt"), String::from("not assembly\n")),
+    ];
+    for ext in ["tar", "tar.gz", "zip"].iter() {
+        let input = dir.join(format!("in.{}", ext));
+        let output = dir.join(format!("out.{}", ext));
+        let input = input.to_str().unwrap();
+        let output = output.to_str().unwrap();
+        write_entries(input, &sources);
+        assert_eq!(
+            assemble_archive(input, output, &Options::default(), true),
+            2
+        );
+
+        let results = read_entries(output, |_| true);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "a.s");
+        assert_eq!(
+            results[0].1,
+            "# andn zero,tp,s6\n.byte 0x33,0x70,0x62,0x41\n\n"
+        );
+        assert_eq!(results[1].0, "index.json");
+        let index: serde_json::Value = serde_json::from_str(&results[1].1).unwrap();
+        assert_eq!(index[0]["status"], "ok");
+        assert_eq!(index[1]["file"], "sub/b.S");
+        assert_eq!(
+            index[1]["message"],
+            "invalid rounding mode rxx, expected one of rne, rtz, rdn, rup, rmm, dyn"
+        );
+        assert_eq!(
+            index[2]["message"],
+            "line 1: \"movq %rax, %rbx\" looks like x86 assembly, not RISC-V."
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
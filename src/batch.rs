@@ -0,0 +1,166 @@
+// rna batch --jobs N dir -o outdir: assemble every .s file below dir on N
+// threads. Outputs mirror the directory structure, outdir/summary.json holds
+// the status of each file.
+use super::{assemble_source, file_status, Options};
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// .s/.S files below dir, relative to dir and sorted
+fn find_sources(dir: &Path) -> Vec<PathBuf> {
+    let mut sources = vec![];
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel) = pending.pop() {
+        for entry in fs::read_dir(dir.join(&rel)).unwrap() {
+            let entry = entry.unwrap();
+            let path = rel.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|e| e == "s" || e == "S") {
+                sources.push(path);
+            }
+        }
+    }
+    sources.sort();
+    sources
+}
+
+fn assemble_file(
+    dir: &Path,
+    out_dir: &Path,
+    rel: &Path,
+    options: &Options,
+    arch_check: bool,
+) -> Result<(), String> {
+    let path = dir.join(rel);
+    let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    // the path, not just rel, for .include
+    let text = assemble_source(&path.to_string_lossy(), &content, options, arch_check)?;
+    let output = out_dir.join(rel);
+    fs::create_dir_all(output.parent().unwrap()).map_err(|e| e.to_string())?;
+    fs::write(&output, text).map_err(|e| e.to_string())
+}
+
+// Returns the number of files that failed to assemble.
+pub(super) fn assemble_dir(
+    dir: &str,
+    out_dir: &str,
+    jobs: usize,
+    options: &Options,
+    arch_check: bool,
+) -> usize {
+    let dir = Path::new(dir);
+    let out_dir = Path::new(out_dir);
+    let sources = find_sources(dir);
+    let results: Mutex<Vec<Option<Result<(), String>>>> =
+        Mutex::new(sources.iter().map(|_| None).collect());
+    let next = AtomicUsize::new(0);
+    // errors are reported in summary.json, not as panic messages on stderr
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= sources.len() {
+                    break;
+                }
+                let result = assemble_file(dir, out_dir, &sources[i], options, arch_check);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    panic::set_hook(hook);
+
+    let results = results.into_inner().unwrap();
+    let summary: Vec<serde_json::Value> = sources
+        .iter()
+        .zip(results.iter())
+        .map(|(rel, result)| file_status(&rel.to_string_lossy(), result.as_ref().unwrap()))
+        .collect();
+    let failed = results.iter().filter(|r| matches!(r, Some(Err(_)))).count();
+    fs::create_dir_all(out_dir).unwrap();
+    fs::write(
+        out_dir.join("summary.json"),
+        serde_json::to_string_pretty(&summary).unwrap(),
+    )
+    .unwrap();
+    eprintln!(
+        "{}: {} files, {} failed",
+        dir.display(),
+        sources.len(),
+        failed
+    );
+    failed
+}
+
+#[test]
+fn test_batch() {
+    let root = std::env::temp_dir().join(format!("rna-batch-{}", std::process::id()));
+    let dir = root.join("in");
+    let out_dir = root.join("out");
+    fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+    fs::write(dir.join("a.s"), "andn zero, tp, s6\n").unwrap();
+    fs::write(dir.join("sub/b.S"), "fadd.s ft0, ft1, ft2, rxx\n").unwrap();
+    fs::write(dir.join("sub/deeper/c.s"), "fence.i\n").unwrap();
+    fs::write(dir.join("sub/notes.txt"), "not assembly\n").unwrap();
+
+    let failed = assemble_dir(
+        dir.to_str().unwrap(),
+        out_dir.to_str().unwrap(),
+        4,
+        &Options::default(),
+        true,
+    );
+    assert_eq!(failed, 1);
+    assert_eq!(
+        fs::read_to_string(out_dir.join("a.s")).unwrap(),
+        "# andn zero,tp,s6\n.byte 0x33,0x70,0x62,0x41\n\n"
+    );
+    assert_eq!(
+        fs::read_to_string(out_dir.join("sub/deeper/c.s")).unwrap(),
+        "# fence.i\n.byte 0x0f,0x10,0x00,0x00\n\n"
+    );
+    assert!(!out_dir.join("sub/b.S").exists());
+    assert!(!out_dir.join("sub/notes.txt").exists());
+
+    let summary = fs::read_to_string(out_dir.join("summary.json")).unwrap();
+    let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+    assert_eq!(summary.as_array().unwrap().len(), 3);
+    assert_eq!(summary[0]["file"], "a.s");
+    assert_eq!(summary[0]["status"], "ok");
+    assert_eq!(summary[1]["file"], "sub/b.S");
+    assert_eq!(summary[1]["status"], "error");
+    assert_eq!(summary[2]["status"], "ok");
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_batch_include() {
+    let root = std::env::temp_dir().join(format!("rna-batch-include-{}", std::process::id()));
+    let dir = root.join("in");
+    let out_dir = root.join("out");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.s"), ".include \"x.inc\"\n").unwrap();
+    fs::write(dir.join("sub/x.inc"), "andn zero, tp, s6\n").unwrap();
+
+    let failed = assemble_dir(
+        dir.to_str().unwrap(),
+        out_dir.to_str().unwrap(),
+        1,
+        &Options::default(),
+        true,
+    );
+    assert_eq!(failed, 0);
+    assert_eq!(
+        fs::read_to_string(out_dir.join("sub/a.s")).unwrap(),
+        "# andn zero,tp,s6\n.byte 0x33,0x70,0x62,0x41\n\n\n"
+    );
+    let summary = fs::read_to_string(out_dir.join("summary.json")).unwrap();
+    let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+    assert_eq!(summary[0]["file"], "sub/a.s");
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,869 @@
+// C-extension: 16-bit compressed instructions, RV64C, Zcb, Zcmp and Zcmop
+use super::{
+    freg_name2value, parse_imm, reg_name2value, split_address, BinaryInstruction, TextInstruction,
+};
+
+// Only x8-x15 (f8-f15) are reachable from the 3-bit register fields
+fn creg(value: u8, name: &str) -> u32 {
+    if !(8..=15).contains(&value) {
+        panic!(
+            "register {} can't be used in this compressed instruction, only x8-x15/f8-f15",
+            name
+        );
+    }
+    (value - 8).into()
+}
+
+// check range and alignment, return the two's complement bits
+fn check_imm(imm: &str, min: i64, max: i64, align: i64) -> u32 {
+    let val = parse_imm(imm);
+    if val < min || val > max || val % align != 0 {
+        panic!(
+            "immediate {} out of range [{}, {}] or not a multiple of {}",
+            imm, min, max, align
+        );
+    }
+    val as u32
+}
+
+fn check_nonzero(imm: u32, name: &str) {
+    if imm == 0 {
+        panic!("immediate of {} can't be zero", name);
+    }
+}
+
+// the memory operand must be based on sp: "8(sp)"
+fn sp_offset(operand: &str) -> &str {
+    let (offset, reg) = split_address(operand);
+    if reg_name2value(reg) != 2 {
+        panic!("base register of {} must be sp", operand);
+    }
+    offset
+}
+
+// Zcmp register list "{ra, s0-s5}", split at the commas by parse_line.
+// Returns the rlist field: 4 is {ra}, 5 {ra, s0}, ... 14 {ra, s0-s9}, 15
+// {ra, s0-s11}.
+fn parse_rlist(operands: &[String]) -> u32 {
+    let list = operands.join(",");
+    let regs: Vec<&str> = list
+        .strip_prefix('{')
+        .and_then(|l| l.strip_suffix('}'))
+        .unwrap_or_else(|| panic!("invalid register list {}", list))
+        .split(',')
+        .map(|r| r.trim())
+        .collect();
+    let rlist = match regs.as_slice() {
+        ["ra"] => Some(4),
+        ["ra", "s0"] => Some(5),
+        ["ra", range] => range
+            .strip_prefix("s0-s")
+            .and_then(|n| n.parse::<u32>().ok())
+            .and_then(|n| match n {
+                1..=9 => Some(n + 5),
+                11 => Some(15),
+                _ => None,
+            }),
+        _ => None,
+    };
+    rlist.unwrap_or_else(|| panic!("invalid register list {}", list))
+}
+
+// s0-s7 in the 3-bit r1s'/r2s' fields of cm.mvsa01 and cm.mva01s
+fn sreg(name: &str) -> u32 {
+    match reg_name2value(name) {
+        8 => 0,
+        9 => 1,
+        v @ 18..=23 => (v - 16).into(),
+        _ => panic!(
+            "register {} can't be used in cm.mvsa01/cm.mva01s, only s0-s7",
+            name
+        ),
+    }
+}
+
+impl BinaryInstruction {
+    fn set_op(&mut self, op: u8) {
+        let bits = BinaryInstruction::bits_array(op.into(), 2);
+        self.set(0, 1, bits);
+    }
+    fn set_cfunct3(&mut self, funct3: u8) {
+        let bits = BinaryInstruction::bits_array(funct3.into(), 3);
+        self.set(13, 15, bits);
+    }
+    // full 5-bit integer register at bits [begin, begin + 4]
+    fn set_creg5(&mut self, begin: u8, name: &str) {
+        let bits = BinaryInstruction::bits_array(reg_name2value(name).into(), 5);
+        self.set(begin, begin + 4, bits);
+    }
+    // 3-bit register rd'/rs1'/rs2' at bits [begin, begin + 2]
+    fn set_creg3(&mut self, begin: u8, name: &str) {
+        let bits = BinaryInstruction::bits_array(creg(reg_name2value(name), name), 3);
+        self.set(begin, begin + 2, bits);
+    }
+    fn set_cfreg3(&mut self, begin: u8, name: &str) {
+        let bits = BinaryInstruction::bits_array(creg(freg_name2value(name), name), 3);
+        self.set(begin, begin + 2, bits);
+    }
+    // Immediate bits are listed from instruction bit `high` downwards, the
+    // same way as the spec writes them: offset[11|4|9:8|10|6|7|3:1|5]
+    fn set_scattered(&mut self, imm: u32, high: u8, order: &[u8]) {
+        for (i, bit) in order.iter().enumerate() {
+            let pos = high - i as u8;
+            self.set(pos, pos, vec![((imm >> bit) & 1) as u8]);
+        }
+    }
+}
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+fn is_creg(reg: u32) -> bool {
+    (8..=15).contains(&reg)
+}
+
+fn fits(val: i64, min: i64, max: i64, align: i64) -> bool {
+    val >= min && val <= max && val % align == 0
+}
+
+// Compressed equivalent of a 32-bit instruction, the same rewrite GNU as
+// does under .option rvc. The instruction is decoded from its bits, then
+// encoded again as the compressed TextInstruction.
+pub(super) fn compress(inst: &BinaryInstruction) -> Option<(TextInstruction, BinaryInstruction)> {
+    if inst.data.len() != 4 {
+        return None;
+    }
+    let word = inst
+        .data
+        .iter()
+        .rev()
+        .fold(0, |acc, b| (acc << 8) | u32::from(*b));
+    let opcode = word & 0x7f;
+    let rd = (word >> 7) & 0x1f;
+    let funct3 = (word >> 12) & 0x7;
+    let rs1 = (word >> 15) & 0x1f;
+    let rs2 = (word >> 20) & 0x1f;
+    let funct7 = word >> 25;
+    let imm_i = i64::from((word as i32) >> 20);
+    let imm_s = i64::from(((word as i32) >> 25) << 5) | i64::from(rd);
+    let imm_b = i64::from(((word as i32) >> 31) << 12)
+        | i64::from((word >> 7) & 1) << 11
+        | i64::from((word >> 25) & 0x3f) << 5
+        | i64::from((word >> 8) & 0xf) << 1;
+    let imm_j = i64::from(((word as i32) >> 31) << 20)
+        | i64::from((word >> 12) & 0xff) << 12
+        | i64::from((word >> 20) & 1) << 11
+        | i64::from((word >> 21) & 0x3ff) << 1;
+    let imm_u = i64::from((word as i32) >> 12);
+
+    let x = |reg: u32| String::from(ABI_NAMES[reg as usize]);
+    let f = |reg: u32| format!("f{}", reg);
+    let addr = |offset: i64, reg: u32| format!("{}({})", offset, ABI_NAMES[reg as usize]);
+    let (name, operands): (&str, Vec<String>) = match (opcode, funct3, funct7) {
+        // addi
+        (0b0010011, 0b000, _) => {
+            if rd == 0 && rs1 == 0 && imm_i == 0 {
+                ("c.nop", vec![])
+            } else if rd != 0 && rd == rs1 && imm_i != 0 && fits(imm_i, -32, 31, 1) {
+                ("c.addi", vec![x(rd), imm_i.to_string()])
+            } else if rd == 2 && rs1 == 2 && imm_i != 0 && fits(imm_i, -512, 496, 16) {
+                ("c.addi16sp", vec![x(rd), imm_i.to_string()])
+            } else if is_creg(rd) && rs1 == 2 && fits(imm_i, 4, 1020, 4) {
+                ("c.addi4spn", vec![x(rd), x(rs1), imm_i.to_string()])
+            } else if rd != 0 && rs1 == 0 && fits(imm_i, -32, 31, 1) {
+                ("c.li", vec![x(rd), imm_i.to_string()])
+            } else if rd != 0 && rs1 != 0 && imm_i == 0 {
+                ("c.mv", vec![x(rd), x(rs1)])
+            } else {
+                return None;
+            }
+        }
+        // addiw
+        (0b0011011, 0b000, _) if rd != 0 && rd == rs1 && fits(imm_i, -32, 31, 1) => {
+            ("c.addiw", vec![x(rd), imm_i.to_string()])
+        }
+        // lui
+        (0b0110111, _, _) if rd != 0 && rd != 2 && imm_u != 0 && fits(imm_u, -32, 31, 1) => {
+            ("c.lui", vec![x(rd), (imm_u & 0xfffff).to_string()])
+        }
+        // slli
+        (0b0010011, 0b001, _) if rd != 0 && rd == rs1 && funct7 >> 1 == 0 && imm_i != 0 => {
+            ("c.slli", vec![x(rd), imm_i.to_string()])
+        }
+        // srli, srai
+        (0b0010011, 0b101, _) if is_creg(rd) && rd == rs1 && (imm_i & 0x3f) != 0 => {
+            match funct7 >> 1 {
+                0b000000 => ("c.srli", vec![x(rd), imm_i.to_string()]),
+                0b010000 => ("c.srai", vec![x(rd), (imm_i & 0x3f).to_string()]),
+                _ => return None,
+            }
+        }
+        // andi
+        (0b0010011, 0b111, _) if is_creg(rd) && rd == rs1 && fits(imm_i, -32, 31, 1) => {
+            ("c.andi", vec![x(rd), imm_i.to_string()])
+        }
+        // add
+        (0b0110011, 0b000, 0b0000000) if rd != 0 && rs2 != 0 => {
+            if rd == rs1 {
+                ("c.add", vec![x(rd), x(rs2)])
+            } else if rs1 == 0 {
+                ("c.mv", vec![x(rd), x(rs2)])
+            } else {
+                return None;
+            }
+        }
+        // sub, xor, or, and, subw, addw
+        (0b0110011, _, _) | (0b0111011, _, _) if is_creg(rd) && rd == rs1 && is_creg(rs2) => {
+            let name = match (opcode, funct3, funct7) {
+                (0b0110011, 0b000, 0b0100000) => "c.sub",
+                (0b0110011, 0b100, 0b0000000) => "c.xor",
+                (0b0110011, 0b110, 0b0000000) => "c.or",
+                (0b0110011, 0b111, 0b0000000) => "c.and",
+                (0b0111011, 0b000, 0b0100000) => "c.subw",
+                (0b0111011, 0b000, 0b0000000) => "c.addw",
+                _ => return None,
+            };
+            (name, vec![x(rd), x(rs2)])
+        }
+        // lw, ld, fld
+        (0b0000011, 0b010, _) | (0b0000011, 0b011, _) | (0b0000111, 0b011, _) => {
+            let is_fp = opcode == 0b0000111;
+            let (align, max, max_sp) = if funct3 == 0b010 {
+                (4, 124, 252)
+            } else {
+                (8, 248, 504)
+            };
+            let rd_name = if is_fp { f(rd) } else { x(rd) };
+            let name = match (funct3, is_fp) {
+                (0b010, _) => "lw",
+                (_, false) => "ld",
+                _ => "fld",
+            };
+            if rs1 == 2 && (is_fp || rd != 0) && fits(imm_i, 0, max_sp, align) {
+                (
+                    match name {
+                        "lw" => "c.lwsp",
+                        "ld" => "c.ldsp",
+                        _ => "c.fldsp",
+                    },
+                    vec![rd_name, addr(imm_i, rs1)],
+                )
+            } else if is_creg(rd) && is_creg(rs1) && fits(imm_i, 0, max, align) {
+                (
+                    match name {
+                        "lw" => "c.lw",
+                        "ld" => "c.ld",
+                        _ => "c.fld",
+                    },
+                    vec![rd_name, addr(imm_i, rs1)],
+                )
+            } else {
+                return None;
+            }
+        }
+        // sw, sd, fsd
+        (0b0100011, 0b010, _) | (0b0100011, 0b011, _) | (0b0100111, 0b011, _) => {
+            let is_fp = opcode == 0b0100111;
+            let (align, max, max_sp) = if funct3 == 0b010 {
+                (4, 124, 252)
+            } else {
+                (8, 248, 504)
+            };
+            let rs2_name = if is_fp { f(rs2) } else { x(rs2) };
+            let name = match (funct3, is_fp) {
+                (0b010, _) => "sw",
+                (_, false) => "sd",
+                _ => "fsd",
+            };
+            if rs1 == 2 && fits(imm_s, 0, max_sp, align) {
+                (
+                    match name {
+                        "sw" => "c.swsp",
+                        "sd" => "c.sdsp",
+                        _ => "c.fsdsp",
+                    },
+                    vec![rs2_name, addr(imm_s, rs1)],
+                )
+            } else if is_creg(rs2) && is_creg(rs1) && fits(imm_s, 0, max, align) {
+                (
+                    match name {
+                        "sw" => "c.sw",
+                        "sd" => "c.sd",
+                        _ => "c.fsd",
+                    },
+                    vec![rs2_name, addr(imm_s, rs1)],
+                )
+            } else {
+                return None;
+            }
+        }
+        // jal zero, offset
+        (0b1101111, _, _) if rd == 0 && fits(imm_j, -2048, 2046, 2) => {
+            ("c.j", vec![imm_j.to_string()])
+        }
+        // jalr zero/ra, 0(rs1)
+        (0b1100111, 0b000, _) if rs1 != 0 && imm_i == 0 && (rd == 0 || rd == 1) => {
+            (if rd == 0 { "c.jr" } else { "c.jalr" }, vec![x(rs1)])
+        }
+        // beq/bne rs1, zero, offset
+        (0b1100011, 0b000, _) | (0b1100011, 0b001, _)
+            if is_creg(rs1) && rs2 == 0 && fits(imm_b, -256, 254, 2) =>
+        {
+            (
+                if funct3 == 0b000 { "c.beqz" } else { "c.bnez" },
+                vec![x(rs1), imm_b.to_string()],
+            )
+        }
+        // ebreak
+        _ if word == 0x00100073 => ("c.ebreak", vec![]),
+        _ => return None,
+    };
+    let text = TextInstruction {
+        opcode: String::from(name),
+        operands,
+        raw: None,
+    };
+    let bin = text.convert_compressed()?;
+    Some((text, bin))
+}
+
+impl TextInstruction {
+    // cm.push {ra, s0-s5}, -64
+    // cm.mvsa01 s0, s1
+    fn convert_zcmp(&self, name: &str) -> Option<BinaryInstruction> {
+        let ops = &self.operands;
+        let mut res = BinaryInstruction::new_compressed();
+        res.set_op(0b10);
+        res.set_cfunct3(0b101);
+        match name {
+            "push" | "pop" | "popret" | "popretz" => {
+                assert!(ops.len() >= 2);
+                let rlist = parse_rlist(&ops[..ops.len() - 1]);
+                // ra and s0-s11 are saved in 8 bytes each, rounded up to 16
+                let count = if rlist == 15 { 13 } else { rlist - 3 };
+                let base = ((count * 8).div_ceil(16) * 16) as i64;
+                // cm.push moves sp down, the others move it up
+                let sign = if name == "push" { -1 } else { 1 };
+                let adj = parse_imm(&ops[ops.len() - 1]) * sign;
+                let spimm = (adj - base) / 16;
+                if adj % 16 != 0 || !(0..=3).contains(&spimm) {
+                    panic!(
+                        "stack adjustment {} out of range [{}, {}] or not a multiple of 16",
+                        ops[ops.len() - 1],
+                        base * sign,
+                        (base + 48) * sign
+                    );
+                }
+                let funct5 = match name {
+                    "push" => 0b11000,
+                    "pop" => 0b11010,
+                    "popretz" => 0b11100,
+                    _ => 0b11110,
+                };
+                res.set(8, 12, BinaryInstruction::bits_array(funct5, 5));
+                res.set(4, 7, BinaryInstruction::bits_array(rlist, 4));
+                res.set(2, 3, BinaryInstruction::bits_array(spimm as u32, 2));
+            }
+            "mvsa01" | "mva01s" => {
+                assert_eq!(ops.len(), 2);
+                let r1s = sreg(&ops[0]);
+                let r2s = sreg(&ops[1]);
+                if name == "mvsa01" && r1s == r2s {
+                    panic!("cm.mvsa01 needs two different registers");
+                }
+                res.set(10, 12, vec![1, 1, 0]);
+                res.set(7, 9, BinaryInstruction::bits_array(r1s, 3));
+                let funct2 = if name == "mvsa01" { 0b01 } else { 0b11 };
+                res.set(5, 6, BinaryInstruction::bits_array(funct2, 2));
+                res.set(2, 4, BinaryInstruction::bits_array(r2s, 3));
+            }
+            _ => return None,
+        }
+        Some(res)
+    }
+
+    pub(super) fn convert_compressed(&self) -> Option<BinaryInstruction> {
+        if let Some(name) = self.opcode.strip_prefix("cm.") {
+            return self.convert_zcmp(name);
+        }
+        let name = self.opcode.strip_prefix("c.")?;
+        let ops = &self.operands;
+        let mut res = BinaryInstruction::new_compressed();
+        match name {
+            // quadrant 0
+            "addi4spn" => {
+                assert_eq!(ops.len(), 3);
+                if reg_name2value(&ops[1]) != 2 {
+                    panic!("c.addi4spn only adds to sp");
+                }
+                let imm = check_imm(&ops[2], 4, 1020, 4);
+                res.set_op(0b00);
+                res.set_cfunct3(0b000);
+                res.set_scattered(imm, 12, &[5, 4, 9, 8, 7, 6, 2, 3]);
+                res.set_creg3(2, &ops[0]);
+            }
+            // flw and fsw are RV32 only, they take the encodings of ld and sd
+            "lw" | "ld" | "flw" | "fld" | "sw" | "sd" | "fsw" | "fsd" => {
+                assert_eq!(ops.len(), 2);
+                let (offset, rs1) = split_address(&ops[1]);
+                let offset = if offset.is_empty() { "0" } else { offset };
+                res.set_op(0b00);
+                res.set_creg3(7, rs1);
+                if name.ends_with('w') {
+                    let imm = check_imm(offset, 0, 124, 4);
+                    res.set_scattered(imm, 12, &[5, 4, 3]);
+                    res.set_scattered(imm, 6, &[2, 6]);
+                } else {
+                    let imm = check_imm(offset, 0, 248, 8);
+                    res.set_scattered(imm, 12, &[5, 4, 3]);
+                    res.set_scattered(imm, 6, &[7, 6]);
+                }
+                let funct3 = match name {
+                    "fld" => 0b001,
+                    "lw" => 0b010,
+                    "ld" | "flw" => 0b011,
+                    "fsd" => 0b101,
+                    "sw" => 0b110,
+                    _ => 0b111,
+                };
+                res.set_cfunct3(funct3);
+                if name.starts_with('f') {
+                    res.set_cfreg3(2, &ops[0]);
+                } else {
+                    res.set_creg3(2, &ops[0]);
+                }
+            }
+            // Zcb byte/halfword loads and stores: funct6, rs1', uimm, rd'/rs2'
+            "lbu" | "lhu" | "lh" | "sb" | "sh" => {
+                assert_eq!(ops.len(), 2);
+                let (offset, rs1) = split_address(&ops[1]);
+                let offset = if offset.is_empty() { "0" } else { offset };
+                let funct6 = match name {
+                    "lbu" => 0b100000,
+                    "lhu" | "lh" => 0b100001,
+                    "sb" => 0b100010,
+                    _ => 0b100011,
+                };
+                res.set_op(0b00);
+                res.set(10, 15, BinaryInstruction::bits_array(funct6, 6));
+                res.set_creg3(7, rs1);
+                if name == "lbu" || name == "sb" {
+                    let imm = check_imm(offset, 0, 3, 1);
+                    res.set_scattered(imm, 6, &[0, 1]);
+                } else {
+                    let imm = check_imm(offset, 0, 2, 2);
+                    // bit 6 tells c.lh from c.lhu
+                    res.set(6, 6, vec![(name == "lh") as u8]);
+                    res.set_scattered(imm, 5, &[1]);
+                }
+                res.set_creg3(2, &ops[0]);
+            }
+            // quadrant 1
+            "nop" => {
+                assert_eq!(ops.len(), 0);
+                res.set_op(0b01);
+            }
+            "addi" | "addiw" | "li" => {
+                assert_eq!(ops.len(), 2);
+                if name == "addiw" && reg_name2value(&ops[0]) == 0 {
+                    panic!("c.addiw can't write to zero");
+                }
+                let imm = check_imm(&ops[1], -32, 31, 1);
+                res.set_op(0b01);
+                res.set_cfunct3(match name {
+                    "addi" => 0b000,
+                    "addiw" => 0b001,
+                    _ => 0b010,
+                });
+                res.set_scattered(imm, 12, &[5]);
+                res.set_creg5(7, &ops[0]);
+                res.set_scattered(imm, 6, &[4, 3, 2, 1, 0]);
+            }
+            "addi16sp" => {
+                assert_eq!(ops.len(), 2);
+                if reg_name2value(&ops[0]) != 2 {
+                    panic!("c.addi16sp only adds to sp");
+                }
+                let imm = check_imm(&ops[1], -512, 496, 16);
+                check_nonzero(imm, "c.addi16sp");
+                res.set_op(0b01);
+                res.set_cfunct3(0b011);
+                res.set_scattered(imm, 12, &[9]);
+                res.set_creg5(7, &ops[0]);
+                res.set_scattered(imm, 6, &[4, 6, 8, 7, 5]);
+            }
+            "lui" => {
+                assert_eq!(ops.len(), 2);
+                let rd = reg_name2value(&ops[0]);
+                if rd == 0 || rd == 2 {
+                    panic!("c.lui can't write to zero or sp");
+                }
+                // the upper 20 bits, like lui: 1..31 or 0xfffe0..0xfffff
+                let mut val = parse_imm(&ops[1]);
+                if (0xfffe0..=0xfffff).contains(&val) {
+                    val -= 0x100000;
+                }
+                let imm = check_imm(&val.to_string(), -32, 31, 1);
+                check_nonzero(imm, "c.lui");
+                res.set_op(0b01);
+                res.set_cfunct3(0b011);
+                res.set_scattered(imm, 12, &[5]);
+                res.set_creg5(7, &ops[0]);
+                res.set_scattered(imm, 6, &[4, 3, 2, 1, 0]);
+            }
+            "srli" | "srai" | "andi" => {
+                assert_eq!(ops.len(), 2);
+                let imm = if name == "andi" {
+                    check_imm(&ops[1], -32, 31, 1)
+                } else {
+                    check_imm(&ops[1], 1, 63, 1)
+                };
+                res.set_op(0b01);
+                res.set_cfunct3(0b100);
+                res.set_scattered(imm, 12, &[5]);
+                let funct2 = match name {
+                    "srli" => 0b00,
+                    "srai" => 0b01,
+                    _ => 0b10,
+                };
+                res.set(10, 11, BinaryInstruction::bits_array(funct2, 2));
+                res.set_creg3(7, &ops[0]);
+                res.set_scattered(imm, 6, &[4, 3, 2, 1, 0]);
+            }
+            "sub" | "xor" | "or" | "and" | "subw" | "addw" => {
+                assert_eq!(ops.len(), 2);
+                let (funct6, funct2) = match name {
+                    "sub" => (0b100011, 0b00),
+                    "xor" => (0b100011, 0b01),
+                    "or" => (0b100011, 0b10),
+                    "and" => (0b100011, 0b11),
+                    "subw" => (0b100111, 0b00),
+                    _ => (0b100111, 0b01),
+                };
+                res.set_op(0b01);
+                res.set(10, 15, BinaryInstruction::bits_array(funct6, 6));
+                res.set_creg3(7, &ops[0]);
+                res.set(5, 6, BinaryInstruction::bits_array(funct2, 2));
+                res.set_creg3(2, &ops[1]);
+            }
+            // Zcb unary operations on rd'
+            "zext.b" | "sext.b" | "zext.h" | "sext.h" | "zext.w" | "not" => {
+                assert_eq!(ops.len(), 1);
+                let funct = match name {
+                    "zext.b" => 0b000,
+                    "sext.b" => 0b001,
+                    "zext.h" => 0b010,
+                    "sext.h" => 0b011,
+                    "zext.w" => 0b100,
+                    _ => 0b101,
+                };
+                res.set_op(0b01);
+                res.set(10, 15, BinaryInstruction::bits_array(0b100111, 6));
+                res.set_creg3(7, &ops[0]);
+                res.set(5, 6, vec![1, 1]);
+                res.set(2, 4, BinaryInstruction::bits_array(funct, 3));
+            }
+            "mul" => {
+                assert_eq!(ops.len(), 2);
+                res.set_op(0b01);
+                res.set(10, 15, BinaryInstruction::bits_array(0b100111, 6));
+                res.set_creg3(7, &ops[0]);
+                res.set(5, 6, vec![0, 1]);
+                res.set_creg3(2, &ops[1]);
+            }
+            // Zcmop: c.lui xN, 0 with an odd N
+            _ if name.starts_with("mop.") => {
+                assert_eq!(ops.len(), 0);
+                let n = parse_imm(&name[4..]);
+                if !(1..=15).contains(&n) || n % 2 == 0 {
+                    panic!("c.{} doesn't exist, N must be odd and in [1, 15]", name);
+                }
+                res.set_op(0b01);
+                res.set_cfunct3(0b011);
+                res.set(7, 11, BinaryInstruction::bits_array(n as u32, 5));
+            }
+            "j" => {
+                assert_eq!(ops.len(), 1);
+                let imm = check_imm(&ops[0], -2048, 2046, 2);
+                res.set_op(0b01);
+                res.set_cfunct3(0b101);
+                res.set_scattered(imm, 12, &[11, 4, 9, 8, 10, 6, 7, 3, 2, 1, 5]);
+            }
+            "jal" => panic!("c.jal is only available on RV32, use --xlen 32"),
+            "beqz" | "bnez" => {
+                assert_eq!(ops.len(), 2);
+                let imm = check_imm(&ops[1], -256, 254, 2);
+                res.set_op(0b01);
+                res.set_cfunct3(if name == "beqz" { 0b110 } else { 0b111 });
+                res.set_scattered(imm, 12, &[8, 4, 3]);
+                res.set_creg3(7, &ops[0]);
+                res.set_scattered(imm, 6, &[7, 6, 2, 1, 5]);
+            }
+            // quadrant 2
+            "slli" => {
+                assert_eq!(ops.len(), 2);
+                let imm = check_imm(&ops[1], 1, 63, 1);
+                res.set_op(0b10);
+                res.set_cfunct3(0b000);
+                res.set_scattered(imm, 12, &[5]);
+                res.set_creg5(7, &ops[0]);
+                res.set_scattered(imm, 6, &[4, 3, 2, 1, 0]);
+            }
+            "lwsp" | "ldsp" | "flwsp" | "fldsp" => {
+                assert_eq!(ops.len(), 2);
+                let offset = sp_offset(&ops[1]);
+                let offset = if offset.is_empty() { "0" } else { offset };
+                res.set_op(0b10);
+                if name.starts_with('f') {
+                    let bits = BinaryInstruction::bits_array(freg_name2value(&ops[0]).into(), 5);
+                    res.set(7, 11, bits);
+                } else {
+                    if reg_name2value(&ops[0]) == 0 {
+                        panic!("c.{} can't load into zero", name);
+                    }
+                    res.set_creg5(7, &ops[0]);
+                }
+                if name.ends_with("wsp") {
+                    let imm = check_imm(offset, 0, 252, 4);
+                    res.set_cfunct3(if name == "lwsp" { 0b010 } else { 0b011 });
+                    res.set_scattered(imm, 12, &[5]);
+                    res.set_scattered(imm, 6, &[4, 3, 2, 7, 6]);
+                } else {
+                    let imm = check_imm(offset, 0, 504, 8);
+                    res.set_cfunct3(if name == "ldsp" { 0b011 } else { 0b001 });
+                    res.set_scattered(imm, 12, &[5]);
+                    res.set_scattered(imm, 6, &[4, 3, 8, 7, 6]);
+                }
+            }
+            "swsp" | "sdsp" | "fswsp" | "fsdsp" => {
+                assert_eq!(ops.len(), 2);
+                let offset = sp_offset(&ops[1]);
+                let offset = if offset.is_empty() { "0" } else { offset };
+                res.set_op(0b10);
+                if name.starts_with('f') {
+                    let bits = BinaryInstruction::bits_array(freg_name2value(&ops[0]).into(), 5);
+                    res.set(2, 6, bits);
+                } else {
+                    res.set_creg5(2, &ops[0]);
+                }
+                if name.ends_with("wsp") {
+                    let imm = check_imm(offset, 0, 252, 4);
+                    res.set_cfunct3(if name == "swsp" { 0b110 } else { 0b111 });
+                    res.set_scattered(imm, 12, &[5, 4, 3, 2, 7, 6]);
+                } else {
+                    let imm = check_imm(offset, 0, 504, 8);
+                    res.set_cfunct3(if name == "sdsp" { 0b111 } else { 0b101 });
+                    res.set_scattered(imm, 12, &[5, 4, 3, 8, 7, 6]);
+                }
+            }
+            "jr" | "jalr" => {
+                assert_eq!(ops.len(), 1);
+                if reg_name2value(&ops[0]) == 0 {
+                    panic!("c.{} can't jump to zero", name);
+                }
+                res.set_op(0b10);
+                res.set(12, 15, vec![0, 0, 0, 1]);
+                if name == "jalr" {
+                    res.set(12, 12, vec![1]);
+                }
+                res.set_creg5(7, &ops[0]);
+            }
+            "mv" | "add" => {
+                assert_eq!(ops.len(), 2);
+                if reg_name2value(&ops[1]) == 0 {
+                    panic!("c.{} can't use zero as source", name);
+                }
+                res.set_op(0b10);
+                res.set(12, 15, vec![0, 0, 0, 1]);
+                if name == "add" {
+                    res.set(12, 12, vec![1]);
+                }
+                res.set_creg5(7, &ops[0]);
+                res.set_creg5(2, &ops[1]);
+            }
+            "ebreak" => {
+                assert_eq!(ops.len(), 0);
+                res.set_op(0b10);
+                res.set(12, 15, vec![1, 0, 0, 1]);
+            }
+            _ => return None,
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::test;
+
+#[test]
+fn test_compressed_quadrant0() {
+    test("c.addi4spn a0, sp, 16", ".byte 0x08,0x08");
+    test("c.lw a0, 4(a1)", ".byte 0xc8,0x41");
+    test("c.ld s0, 248(a5)", ".byte 0xe0,0x7f");
+    test("c.fld fa0, 8(a1)", ".byte 0x88,0x25");
+    test("c.sw a2, 124(a3)", ".byte 0xf0,0xde");
+    test("c.sd s1, 0(s0)", ".byte 0x04,0xe0");
+    test("c.fsd fs0, 16(a0)", ".byte 0x00,0xa9");
+}
+
+#[test]
+fn test_compressed_quadrant1() {
+    test("c.nop", ".byte 0x01,0x00");
+    test("c.addi a0, -1", ".byte 0x7d,0x15");
+    test("c.addiw a1, 31", ".byte 0xfd,0x25");
+    test("c.li t0, -32", ".byte 0x81,0x52");
+    test("c.addi16sp sp, -64", ".byte 0x39,0x71");
+    test("c.lui a0, 1", ".byte 0x05,0x65");
+    test("c.lui a0, 1048575", ".byte 0x7d,0x75");
+    test("c.srli a0, 63", ".byte 0x7d,0x91");
+    test("c.srai s1, 1", ".byte 0x85,0x84");
+    test("c.andi a5, -16", ".byte 0xc1,0x9b");
+    test("c.sub a0, a1", ".byte 0x0d,0x8d");
+    test("c.and s0, s1", ".byte 0x65,0x8c");
+    test("c.addw a4, a5", ".byte 0x3d,0x9f");
+    test("c.j -2048", ".byte 0x01,0xb0");
+    test("c.beqz a0, 254", ".byte 0x7d,0xcd");
+    test("c.bnez s1, -256", ".byte 0x81,0xf0");
+}
+
+#[test]
+fn test_compressed_zcb() {
+    test("c.lbu a0, 3(a1)", ".byte 0xe8,0x81");
+    test("c.lbu a0, (a1)", ".byte 0x88,0x81");
+    test("c.lhu a0, 2(a1)", ".byte 0xa8,0x85");
+    test("c.lh a0, 2(a1)", ".byte 0xe8,0x85");
+    test("c.sb a0, 1(a1)", ".byte 0xc8,0x89");
+    test("c.sh a0, 2(a1)", ".byte 0xa8,0x8d");
+    test("c.zext.b a0", ".byte 0x61,0x9d");
+    test("c.sext.b a0", ".byte 0x65,0x9d");
+    test("c.zext.h a0", ".byte 0x69,0x9d");
+    test("c.sext.h a0", ".byte 0x6d,0x9d");
+    test("c.zext.w a0", ".byte 0x71,0x9d");
+    test("c.not a0", ".byte 0x75,0x9d");
+    test("c.mul a0, a1", ".byte 0x4d,0x9d");
+}
+
+#[test]
+fn test_zcmp() {
+    test("cm.push {ra}, -16", ".byte 0x42,0xb8");
+    test("cm.push {ra, s0-s5}, -64", ".byte 0xa2,0xb8");
+    test("cm.push {ra, s0-s11}, -160", ".byte 0xfe,0xb8");
+    test("cm.pop {ra, s0}, 32", ".byte 0x56,0xba");
+    test("cm.popretz {ra, s0-s1}, 32", ".byte 0x62,0xbc");
+    test("cm.popret {ra, s0-s11}, 112", ".byte 0xf2,0xbe");
+    test("cm.mvsa01 s1, s0", ".byte 0xa2,0xac");
+    test("cm.mva01s s7, s2", ".byte 0xea,0xaf");
+}
+
+#[test]
+#[should_panic(expected = "stack adjustment -16 out of range")]
+fn test_zcmp_stack_adj() {
+    test("cm.push {ra, s0-s2}, -16", "");
+}
+
+#[test]
+fn test_zcmop() {
+    test("c.mop.1", ".byte 0x81,0x60");
+    test("c.mop.7", ".byte 0x81,0x63");
+    test("c.mop.15", ".byte 0x81,0x67");
+}
+
+#[test]
+#[should_panic(expected = "c.mop.2 doesn't exist")]
+fn test_zcmop_even() {
+    test("c.mop.2", "");
+}
+
+#[test]
+fn test_compressed_quadrant2() {
+    test("c.slli t1, 12", ".byte 0x32,0x03");
+    test("c.lwsp ra, 252(sp)", ".byte 0xfe,0x50");
+    test("c.ldsp s0, 8(sp)", ".byte 0x22,0x64");
+    test("c.fldsp fa0, 504(sp)", ".byte 0x7e,0x35");
+    test("c.swsp a0, 4(sp)", ".byte 0x2a,0xc2");
+    test("c.sdsp ra, 504(sp)", ".byte 0x86,0xff");
+    test("c.jr ra", ".byte 0x82,0x80");
+    test("c.jalr t0", ".byte 0x82,0x92");
+    test("c.mv a0, a1", ".byte 0x2e,0x85");
+    test("c.add s0, t6", ".byte 0x7e,0x94");
+    test("c.ebreak", ".byte 0x02,0x90");
+}
+
+#[cfg(test)]
+fn compress_word(word: u32) -> Option<String> {
+    let mut inst = BinaryInstruction::new();
+    inst.data = word.to_le_bytes().to_vec();
+    compress(&inst).map(|(text, bin)| format!("{} {}", text, bin))
+}
+
+#[test]
+fn test_compress() {
+    // addi a0, a0, -1
+    assert_eq!(
+        compress_word(0xfff50513).unwrap(),
+        "c.addi a0,-1 .byte 0x7d,0x15"
+    );
+    // addi sp, sp, -64
+    assert_eq!(
+        compress_word(0xfc010113).unwrap(),
+        "c.addi16sp sp,-64 .byte 0x39,0x71"
+    );
+    // addi a0, sp, 16
+    assert_eq!(
+        compress_word(0x01010513).unwrap(),
+        "c.addi4spn a0,sp,16 .byte 0x08,0x08"
+    );
+    // addi a0, a1, 0
+    assert_eq!(
+        compress_word(0x00058513).unwrap(),
+        "c.mv a0,a1 .byte 0x2e,0x85"
+    );
+    // ld s0, 8(sp)
+    assert_eq!(
+        compress_word(0x00813403).unwrap(),
+        "c.ldsp s0,8(sp) .byte 0x22,0x64"
+    );
+    // fsd fs0, 16(a0)
+    assert_eq!(
+        compress_word(0x00853827).unwrap(),
+        "c.fsd f8,16(a0) .byte 0x00,0xa9"
+    );
+    // sub a0, a0, a1
+    assert_eq!(
+        compress_word(0x40b50533).unwrap(),
+        "c.sub a0,a1 .byte 0x0d,0x8d"
+    );
+    // jalr zero, 0(ra)
+    assert_eq!(
+        compress_word(0x00008067).unwrap(),
+        "c.jr ra .byte 0x82,0x80"
+    );
+    // beq a0, zero, 254
+    assert_eq!(
+        compress_word(0x0e050f63).unwrap(),
+        "c.beqz a0,254 .byte 0x7d,0xcd"
+    );
+    // lui a0, 0xfffff
+    assert_eq!(
+        compress_word(0xfffff537).unwrap(),
+        "c.lui a0,1048575 .byte 0x7d,0x75"
+    );
+    // ebreak
+    assert_eq!(
+        compress_word(0x00100073).unwrap(),
+        "c.ebreak .byte 0x02,0x90"
+    );
+    // addi a0, a1, 1: no compressed form
+    assert_eq!(compress_word(0x00158513), None);
+    // sh3add.uw a3, s5, gp
+    assert_eq!(compress_word(0x203ae6bb), None);
+}
+
+#[test]
+#[should_panic(expected = "only x8-x15")]
+fn test_compressed_register_subset() {
+    test("c.lw t0, 4(a1)", "");
+}
@@ -0,0 +1,176 @@
+// Scalar cryptography: Zkne/Zknd AES, Zknh SHA-256/SHA-512, Zksed SM4 and
+// Zksh SM3.
+use super::{parse_unsigned, BinaryInstruction, TextInstruction};
+
+impl BinaryInstruction {
+    // aes32esi/sm4ed rd, rs1, rs2, bs: bs selects the byte of rs2, funct7 bits 30-31
+    fn set_bs_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 4);
+        self.set_operands(&operands[..3]);
+        let bs = parse_unsigned(operands[3].as_ref());
+        if bs > 3 {
+            panic!("byte select {} out of range [0, 3]", operands[3]);
+        }
+        self.set(30, 31, BinaryInstruction::bits_array(bs as u32, 2));
+    }
+}
+
+impl TextInstruction {
+    pub(super) fn convert_crypto(&self) -> Option<BinaryInstruction> {
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b0110011);
+        res.set_funct3(0);
+        match self.opcode.as_str() {
+            "aes64es" | "aes64esm" | "aes64ds" | "aes64dsm" | "aes64ks2" => {
+                res.set_funct7(match self.opcode.as_str() {
+                    "aes64es" => 0b0011001,
+                    "aes64esm" => 0b0011011,
+                    "aes64ds" => 0b0011101,
+                    "aes64dsm" => 0b0011111,
+                    _ => 0b0111111,
+                });
+                res.set_operands(&self.operands);
+            }
+            "aes64im" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b001);
+                res.set_funct7(0b0011000);
+                res.set_2operands(&self.operands, 0);
+            }
+            // aes64ks1i rd, rs1, rnum
+            "aes64ks1i" => {
+                assert_eq!(self.operands.len(), 3);
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b001);
+                res.set_funct7(0b0011000);
+                res.set_rd(self.operands[0].as_ref());
+                res.set_rs1(self.operands[1].as_ref());
+                let rnum = parse_unsigned(self.operands[2].as_ref());
+                if rnum > 10 {
+                    panic!("round number {} out of range [0, 10]", self.operands[2]);
+                }
+                res.set(24, 24, vec![1]);
+                res.set(20, 23, BinaryInstruction::bits_array(rnum as u32, 4));
+            }
+            // RV32 only
+            "aes32esi" | "aes32esmi" | "aes32dsi" | "aes32dsmi" => {
+                let funct5 = match self.opcode.as_str() {
+                    "aes32esi" => 0b10001,
+                    "aes32esmi" => 0b10011,
+                    "aes32dsi" => 0b10101,
+                    _ => 0b10111,
+                };
+                res.set(25, 29, BinaryInstruction::bits_array(funct5, 5));
+                res.set_bs_operands(&self.operands);
+            }
+            "sm4ed" | "sm4ks" => {
+                let funct5 = if self.opcode == "sm4ed" {
+                    0b11000
+                } else {
+                    0b11010
+                };
+                res.set(25, 29, BinaryInstruction::bits_array(funct5, 5));
+                res.set_bs_operands(&self.operands);
+            }
+            // funct7 0001000, the rs2 field selects the function
+            "sha256sig0" | "sha256sig1" | "sha256sum0" | "sha256sum1" | "sha512sig0"
+            | "sha512sig1" | "sha512sum0" | "sha512sum1" | "sm3p0" | "sm3p1" => {
+                let rs2 = match self.opcode.as_str() {
+                    "sha256sum0" => 0b00000,
+                    "sha256sum1" => 0b00001,
+                    "sha256sig0" => 0b00010,
+                    "sha256sig1" => 0b00011,
+                    "sha512sum0" => 0b00100,
+                    "sha512sum1" => 0b00101,
+                    "sha512sig0" => 0b00110,
+                    "sha512sig1" => 0b00111,
+                    "sm3p0" => 0b01000,
+                    _ => 0b01001,
+                };
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b001);
+                res.set_funct7(0b0001000);
+                res.set_2operands(&self.operands, rs2);
+            }
+            // RV32 only, the 64-bit values are split over register pairs
+            "sha512sum0r" | "sha512sum1r" | "sha512sig0l" | "sha512sig1l" | "sha512sig0h"
+            | "sha512sig1h" => {
+                res.set_funct7(match self.opcode.as_str() {
+                    "sha512sum0r" => 0b0101000,
+                    "sha512sum1r" => 0b0101001,
+                    "sha512sig0l" => 0b0101010,
+                    "sha512sig1l" => 0b0101011,
+                    "sha512sig0h" => 0b0101110,
+                    _ => 0b0101111,
+                });
+                res.set_operands(&self.operands);
+            }
+            _ => return None,
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::{rv32::test_rv32, test};
+
+#[test]
+fn test_aes64() {
+    test("aes64es a0, a1, a2", ".byte 0x33,0x85,0xc5,0x32");
+    test("aes64esm a0, a1, a2", ".byte 0x33,0x85,0xc5,0x36");
+    test("aes64ds a0, a1, a2", ".byte 0x33,0x85,0xc5,0x3a");
+    test("aes64dsm a0, a1, a2", ".byte 0x33,0x85,0xc5,0x3e");
+    test("aes64im a0, a1", ".byte 0x13,0x95,0x05,0x30");
+    test("aes64ks1i a0, a1, 10", ".byte 0x13,0x95,0xa5,0x31");
+    test("aes64ks2 a0, a1, a2", ".byte 0x33,0x85,0xc5,0x7e");
+}
+
+#[test]
+fn test_aes32() {
+    test_rv32("aes32esi a0, a1, a2, 3", ".byte 0x33,0x85,0xc5,0xe2");
+    test_rv32("aes32esmi a0, a1, a2, 1", ".byte 0x33,0x85,0xc5,0x66");
+    test_rv32("aes32dsi a0, a1, a2, 2", ".byte 0x33,0x85,0xc5,0xaa");
+    test_rv32("aes32dsmi a0, a1, a2, 0", ".byte 0x33,0x85,0xc5,0x2e");
+}
+
+#[test]
+#[should_panic(expected = "round number 11 out of range")]
+fn test_aes64ks1i_range() {
+    test("aes64ks1i a0, a1, 11", "");
+}
+
+#[test]
+fn test_sha() {
+    test("sha256sig0 a0, a1", ".byte 0x13,0x95,0x25,0x10");
+    test("sha256sig1 a0, a1", ".byte 0x13,0x95,0x35,0x10");
+    test("sha256sum0 a0, a1", ".byte 0x13,0x95,0x05,0x10");
+    test("sha256sum1 a0, a1", ".byte 0x13,0x95,0x15,0x10");
+    test("sha512sig0 a0, a1", ".byte 0x13,0x95,0x65,0x10");
+    test("sha512sig1 a0, a1", ".byte 0x13,0x95,0x75,0x10");
+    test("sha512sum0 a0, a1", ".byte 0x13,0x95,0x45,0x10");
+    test("sha512sum1 a0, a1", ".byte 0x13,0x95,0x55,0x10");
+}
+
+#[test]
+fn test_sha512_rv32() {
+    test_rv32("sha512sig0h a0, a1, a2", ".byte 0x33,0x85,0xc5,0x5c");
+    test_rv32("sha512sig0l a0, a1, a2", ".byte 0x33,0x85,0xc5,0x54");
+    test_rv32("sha512sig1h a0, a1, a2", ".byte 0x33,0x85,0xc5,0x5e");
+    test_rv32("sha512sig1l a0, a1, a2", ".byte 0x33,0x85,0xc5,0x56");
+    test_rv32("sha512sum0r a0, a1, a2", ".byte 0x33,0x85,0xc5,0x50");
+    test_rv32("sha512sum1r a0, a1, a2", ".byte 0x33,0x85,0xc5,0x52");
+}
+
+#[test]
+fn test_sm4_sm3() {
+    test("sm4ed a0, a1, a2, 3", ".byte 0x33,0x85,0xc5,0xf0");
+    test("sm4ks a0, a1, a2, 1", ".byte 0x33,0x85,0xc5,0x74");
+    test("sm3p0 a0, a1", ".byte 0x13,0x95,0x85,0x10");
+    test("sm3p1 a0, a1", ".byte 0x13,0x95,0x95,0x10");
+}
+
+#[test]
+#[should_panic(expected = "byte select 4 out of range")]
+fn test_byte_select_range() {
+    test("sm4ed a0, a1, a2, 4", "");
+}
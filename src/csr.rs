@@ -0,0 +1,320 @@
+// Zicsr: csrrw/csrrs/csrrc and the immediate forms, with the standard CSR
+// names from the privileged spec.
+use super::{parse_unsigned, BinaryInstruction, TextInstruction};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+lazy_static! {
+    pub static ref CSR_MAP: HashMap<String, u16> = {
+        let mut map = HashMap::new();
+        // unprivileged floating-point, vector and entropy source
+        map.insert(String::from("fflags"), 0x001);
+        map.insert(String::from("frm"), 0x002);
+        map.insert(String::from("fcsr"), 0x003);
+        map.insert(String::from("vstart"), 0x008);
+        map.insert(String::from("vxsat"), 0x009);
+        map.insert(String::from("vxrm"), 0x00a);
+        map.insert(String::from("vcsr"), 0x00f);
+        map.insert(String::from("seed"), 0x015);
+        map.insert(String::from("vl"), 0xc20);
+        map.insert(String::from("vtype"), 0xc21);
+        map.insert(String::from("vlenb"), 0xc22);
+        // unprivileged counters/timers
+        map.insert(String::from("cycle"), 0xc00);
+        map.insert(String::from("time"), 0xc01);
+        map.insert(String::from("instret"), 0xc02);
+        map.insert(String::from("cycleh"), 0xc80);
+        map.insert(String::from("timeh"), 0xc81);
+        map.insert(String::from("instreth"), 0xc82);
+        for i in 3..32 {
+            map.insert(format!("hpmcounter{}", i), 0xc00 + i);
+            map.insert(format!("hpmcounter{}h", i), 0xc80 + i);
+        }
+        // supervisor
+        map.insert(String::from("sstatus"), 0x100);
+        map.insert(String::from("sie"), 0x104);
+        map.insert(String::from("stvec"), 0x105);
+        map.insert(String::from("scounteren"), 0x106);
+        map.insert(String::from("senvcfg"), 0x10a);
+        map.insert(String::from("sscratch"), 0x140);
+        map.insert(String::from("sepc"), 0x141);
+        map.insert(String::from("scause"), 0x142);
+        map.insert(String::from("stval"), 0x143);
+        map.insert(String::from("sip"), 0x144);
+        map.insert(String::from("satp"), 0x180);
+        map.insert(String::from("scontext"), 0x5a8);
+        // hypervisor and virtual supervisor
+        map.insert(String::from("hstatus"), 0x600);
+        map.insert(String::from("hedeleg"), 0x602);
+        map.insert(String::from("hideleg"), 0x603);
+        map.insert(String::from("hie"), 0x604);
+        map.insert(String::from("htimedelta"), 0x605);
+        map.insert(String::from("hcounteren"), 0x606);
+        map.insert(String::from("hgeie"), 0x607);
+        map.insert(String::from("henvcfg"), 0x60a);
+        map.insert(String::from("htval"), 0x643);
+        map.insert(String::from("hip"), 0x644);
+        map.insert(String::from("hvip"), 0x645);
+        map.insert(String::from("htinst"), 0x64a);
+        map.insert(String::from("hgatp"), 0x680);
+        map.insert(String::from("hgeip"), 0xe12);
+        map.insert(String::from("vsstatus"), 0x200);
+        map.insert(String::from("vsie"), 0x204);
+        map.insert(String::from("vstvec"), 0x205);
+        map.insert(String::from("vsscratch"), 0x240);
+        map.insert(String::from("vsepc"), 0x241);
+        map.insert(String::from("vscause"), 0x242);
+        map.insert(String::from("vstval"), 0x243);
+        map.insert(String::from("vsip"), 0x244);
+        map.insert(String::from("vsatp"), 0x280);
+        // machine information, trap setup and handling
+        map.insert(String::from("mvendorid"), 0xf11);
+        map.insert(String::from("marchid"), 0xf12);
+        map.insert(String::from("mimpid"), 0xf13);
+        map.insert(String::from("mhartid"), 0xf14);
+        map.insert(String::from("mconfigptr"), 0xf15);
+        map.insert(String::from("mstatus"), 0x300);
+        map.insert(String::from("misa"), 0x301);
+        map.insert(String::from("medeleg"), 0x302);
+        map.insert(String::from("mideleg"), 0x303);
+        map.insert(String::from("mie"), 0x304);
+        map.insert(String::from("mtvec"), 0x305);
+        map.insert(String::from("mcounteren"), 0x306);
+        map.insert(String::from("menvcfg"), 0x30a);
+        map.insert(String::from("mstatush"), 0x310);
+        map.insert(String::from("menvcfgh"), 0x31a);
+        map.insert(String::from("mcountinhibit"), 0x320);
+        map.insert(String::from("mscratch"), 0x340);
+        map.insert(String::from("mepc"), 0x341);
+        map.insert(String::from("mcause"), 0x342);
+        map.insert(String::from("mtval"), 0x343);
+        map.insert(String::from("mip"), 0x344);
+        map.insert(String::from("mtinst"), 0x34a);
+        map.insert(String::from("mtval2"), 0x34b);
+        // machine memory protection
+        for i in 0..16 {
+            map.insert(format!("pmpcfg{}", i), 0x3a0 + i);
+        }
+        for i in 0..64 {
+            map.insert(format!("pmpaddr{}", i), 0x3b0 + i);
+        }
+        // machine counters/timers
+        map.insert(String::from("mcycle"), 0xb00);
+        map.insert(String::from("minstret"), 0xb02);
+        map.insert(String::from("mcycleh"), 0xb80);
+        map.insert(String::from("minstreth"), 0xb82);
+        for i in 3..32 {
+            map.insert(format!("mhpmcounter{}", i), 0xb00 + i);
+            map.insert(format!("mhpmcounter{}h", i), 0xb80 + i);
+            map.insert(format!("mhpmevent{}", i), 0x320 + i);
+        }
+        // debug/trace
+        map.insert(String::from("tselect"), 0x7a0);
+        map.insert(String::from("tdata1"), 0x7a1);
+        map.insert(String::from("tdata2"), 0x7a2);
+        map.insert(String::from("tdata3"), 0x7a3);
+        map.insert(String::from("mcontext"), 0x7a8);
+        map.insert(String::from("dcsr"), 0x7b0);
+        map.insert(String::from("dpc"), 0x7b1);
+        map.insert(String::from("dscratch0"), 0x7b2);
+        map.insert(String::from("dscratch1"), 0x7b3);
+        map
+    };
+}
+
+lazy_static! {
+    // vendor CSRs from --csr-defs
+    static ref USER_CSR_MAP: RwLock<HashMap<String, u16>> = RwLock::new(HashMap::new());
+}
+
+// Load vendor CSR names from a TOML file with one "name = number" per line:
+//   mycounter = 0x7c0
+//   mydebug = 1984
+pub(super) fn load_csr_defs(content: &str) {
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(t)) => t,
+        Ok(_) => panic!("CSR definitions must be a TOML table"),
+        Err(e) => panic!("invalid CSR definitions: {}", e),
+    };
+    let mut map = HashMap::new();
+    for (name, value) in table {
+        let val = match value.as_integer() {
+            Some(v) if (0..=0xfff).contains(&v) => v as u16,
+            _ => panic!("CSR {} must be a 12-bit number, got {}", name, value),
+        };
+        let name = name.to_lowercase();
+        if let Some(std_val) = CSR_MAP.get(&name) {
+            if *std_val != val {
+                panic!("CSR {} redefines a standard CSR ({:#x})", name, std_val);
+            }
+        }
+        map.insert(name, val);
+    }
+    USER_CSR_MAP.write().unwrap().extend(map);
+}
+
+// the operand which is a CSR, for the instructions which have one
+pub(super) fn csr_operand(opcode: &str) -> Option<usize> {
+    match opcode {
+        "csrr" | "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => Some(1),
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => Some(0),
+        _ => None,
+    }
+}
+
+pub(super) fn is_csr_name(name: &str) -> bool {
+    CSR_MAP.contains_key(name) || USER_CSR_MAP.read().unwrap().contains_key(name)
+}
+
+// CSR name or a 12-bit number
+fn csr_name2value(name: &str) -> u32 {
+    if let Some(val) = CSR_MAP.get(name) {
+        return (*val).into();
+    }
+    if let Some(val) = USER_CSR_MAP.read().unwrap().get(name) {
+        return (*val).into();
+    }
+    if !name.starts_with(|c: char| c.is_ascii_digit()) {
+        panic!("can't find CSR name {}", name);
+    }
+    let val = parse_unsigned(name);
+    if val > 0xfff {
+        panic!("CSR number {} out of range", name);
+    }
+    val as u32
+}
+
+impl BinaryInstruction {
+    fn set_csr(&mut self, csr: &str) {
+        let bits = BinaryInstruction::bits_array(csr_name2value(csr), 12);
+        self.set(20, 31, bits);
+    }
+    // csrrwi rd, csr, uimm: the 5-bit immediate takes the place of rs1
+    fn set_uimm5(&mut self, uimm: &str) {
+        let val = parse_unsigned(uimm);
+        if val > 31 {
+            panic!("immediate {} out of range [0, 31]", uimm);
+        }
+        let bits = BinaryInstruction::bits_array(val as u32, 5);
+        self.set(15, 19, bits);
+    }
+}
+
+impl TextInstruction {
+    // csrr/csrw/csrs/csrc, their immediate forms and the counter reads as
+    // csrrs/csrrw/... operands: rd, csr, rs1 or uimm
+    fn csr_pseudo(&self) -> Option<(&'static str, Vec<String>)> {
+        let ops: Vec<&str> = self.operands.iter().map(|o| o.as_str()).collect();
+        let (name, operands) = match (self.opcode.as_str(), ops.len()) {
+            ("csrr", 2) => ("csrrs", [ops[0], ops[1], "zero"]),
+            ("csrw", 2) => ("csrrw", ["zero", ops[0], ops[1]]),
+            ("csrs", 2) => ("csrrs", ["zero", ops[0], ops[1]]),
+            ("csrc", 2) => ("csrrc", ["zero", ops[0], ops[1]]),
+            ("csrwi", 2) => ("csrrwi", ["zero", ops[0], ops[1]]),
+            ("csrsi", 2) => ("csrrsi", ["zero", ops[0], ops[1]]),
+            ("csrci", 2) => ("csrrci", ["zero", ops[0], ops[1]]),
+            // the h forms read the upper halves on RV32
+            (counter, 1) if counter.starts_with("rd") => {
+                let csr = match counter {
+                    "rdcycle" => "cycle",
+                    "rdtime" => "time",
+                    "rdinstret" => "instret",
+                    "rdcycleh" => "cycleh",
+                    "rdtimeh" => "timeh",
+                    "rdinstreth" => "instreth",
+                    _ => return None,
+                };
+                ("csrrs", [ops[0], csr, "zero"])
+            }
+            _ => return None,
+        };
+        Some((name, operands.iter().map(|o| String::from(*o)).collect()))
+    }
+    pub(super) fn convert_csr(&self) -> Option<BinaryInstruction> {
+        let pseudo = self.csr_pseudo();
+        let (name, operands) = match &pseudo {
+            Some((name, operands)) => (*name, operands),
+            None => (self.opcode.as_str(), &self.operands),
+        };
+        let funct3 = match name {
+            "csrrw" => 0b001,
+            "csrrs" => 0b010,
+            "csrrc" => 0b011,
+            "csrrwi" => 0b101,
+            "csrrsi" => 0b110,
+            "csrrci" => 0b111,
+            _ => return None,
+        };
+
+        assert_eq!(operands.len(), 3);
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1110011);
+        res.set_funct3(funct3);
+        res.set_rd(operands[0].as_ref());
+        res.set_csr(operands[1].as_ref());
+        if funct3 & 0b100 == 0 {
+            res.set_rs1(operands[2].as_ref());
+        } else {
+            res.set_uimm5(operands[2].as_ref());
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::{rv32::test_rv32, test};
+
+#[test]
+fn test_csr() {
+    test("csrrw t0, mstatus, t1", ".byte 0xf3,0x12,0x03,0x30");
+    test("csrrs a0, cycle, zero", ".byte 0x73,0x25,0x00,0xc0");
+    test("csrrc zero, mie, a1", ".byte 0x73,0xb0,0x45,0x30");
+    test("csrrwi a0, fcsr, 31", ".byte 0x73,0xd5,0x3f,0x00");
+    test("csrrsi zero, sstatus, 2", ".byte 0x73,0x60,0x01,0x10");
+    test("csrrci a1, 0x7c0, 1", ".byte 0xf3,0xf5,0x00,0x7c");
+    test("csrrs a0, pmpaddr63, zero", ".byte 0x73,0x25,0xf0,0x3e");
+    test("csrrs a0, 3072, zero", ".byte 0x73,0x25,0x00,0xc0");
+}
+
+#[test]
+fn test_csr_pseudos() {
+    test("csrr a0, mstatus", ".byte 0x73,0x25,0x00,0x30");
+    test("csrw mtvec, t0", ".byte 0x73,0x90,0x52,0x30");
+    test("csrs mie, a1", ".byte 0x73,0xa0,0x45,0x30");
+    test("csrc mie, a1", ".byte 0x73,0xb0,0x45,0x30");
+    test("csrwi fcsr, 7", ".byte 0x73,0xd0,0x33,0x00");
+    test("csrsi sstatus, 2", ".byte 0x73,0x60,0x01,0x10");
+    test("csrci sstatus, 2", ".byte 0x73,0x70,0x01,0x10");
+    test("rdcycle a0", ".byte 0x73,0x25,0x00,0xc0");
+    test("rdtime a0", ".byte 0x73,0x25,0x10,0xc0");
+    test("rdinstret a0", ".byte 0x73,0x25,0x20,0xc0");
+    // RV32 only
+    test_rv32("rdcycleh a0", ".byte 0x73,0x25,0x00,0xc8");
+    test_rv32("rdinstreth a0", ".byte 0x73,0x25,0x20,0xc8");
+}
+
+#[test]
+fn test_csr_defs() {
+    load_csr_defs("vendor_ctl = 0x7c1\nVendor_Status = 1986\nmstatus = 0x300\n");
+    test("csrrw t0, vendor_ctl, t1", ".byte 0xf3,0x12,0x13,0x7c");
+    test("csrrs a0, vendor_status, zero", ".byte 0x73,0x25,0x20,0x7c");
+}
+
+#[test]
+#[should_panic(expected = "redefines a standard CSR")]
+fn test_csr_defs_conflict() {
+    load_csr_defs("mstatus = 0x7c0");
+}
+
+#[test]
+#[should_panic(expected = "must be a 12-bit number")]
+fn test_csr_defs_range() {
+    load_csr_defs("too_big = 0x1000");
+}
+
+#[test]
+#[should_panic(expected = "can't find CSR name")]
+fn test_csr_unknown() {
+    test("csrrw t0, mstatuss, t1", "");
+}
@@ -0,0 +1,527 @@
+// Data directives: .byte, .half, .word, .dword and their aliases are emitted
+// as bytes, little-endian, so that their size is known to labels.rs. The
+// operands are numbers, constant expressions or label differences; anything
+// else, like the address of a label, is left to the real assembler.
+// The strings of .ascii, .asciz and .string, the floating-point values of
+// .float and .double, the files of .incbin, and the padding of alignments
+// when labels.rs knows the offset are emitted as bytes too.
+use super::{expr, labels::Location, parse_imm, TextInstruction};
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+const DATA: [(&str, usize); 10] = [
+    (".byte", 1),
+    (".half", 2),
+    (".short", 2),
+    (".2byte", 2),
+    (".word", 4),
+    (".long", 4),
+    (".4byte", 4),
+    (".dword", 8),
+    (".quad", 8),
+    (".8byte", 8),
+];
+
+// string directives and whether a NUL is appended to each string
+const STRINGS: [(&str, bool); 3] = [(".ascii", false), (".asciz", true), (".string", true)];
+
+// floating-point directives and the size of their IEEE-754 values
+const FLOATS: [(&str, usize); 3] = [(".float", 4), (".single", 4), (".double", 8)];
+
+// alignments, whether the operand is a power of 2 and the size of the fill
+// value, .align is .p2align on RISC-V
+const ALIGNS: [(&str, bool, u32); 7] = [
+    (".align", true, 1),
+    (".p2align", true, 1),
+    (".p2alignw", true, 2),
+    (".p2alignl", true, 4),
+    (".balign", false, 1),
+    (".balignw", false, 2),
+    (".balignl", false, 4),
+];
+
+// directives which reserve a number of bytes
+const SPACES: [&str; 3] = [".zero", ".skip", ".space"];
+
+// bytes per operand of a data directive
+pub(super) fn width(opcode: &str) -> Option<usize> {
+    DATA.iter().find(|d| d.0 == opcode).map(|d| d.1)
+}
+
+// Values of the labels for the expressions, twice with a different address
+// of each run of known sizes: a label difference in one run has the same
+// value both times, the address of a label doesn't.
+pub(super) fn label_values(labels: &HashMap<String, Location>) -> [HashMap<String, i64>; 2] {
+    let mut values = [HashMap::new(), HashMap::new()];
+    for (name, location) in labels {
+        insert_label(&mut values, name, location);
+    }
+    values
+}
+
+// the two values of a label, see label_values
+pub(super) fn insert_label(values: &mut [HashMap<String, i64>; 2], name: &str, at: &Location) {
+    let run = at.run as i64;
+    values[0].insert(String::from(name), run.wrapping_mul(1 << 32) + at.offset);
+    let base = run.wrapping_mul(0x5555_5555_5555) + 0x1234_5677;
+    values[1].insert(String::from(name), base + at.offset);
+}
+
+// the value of a number, constant expression or label difference
+pub(super) fn value(operand: &str, labels: &[HashMap<String, i64>; 2]) -> Option<i64> {
+    if expr::is_literal(operand) {
+        return Some(parse_imm(operand));
+    }
+    let value = expr::eval(operand, &labels[0])?;
+    if expr::eval(operand, &labels[1])? == value {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+// The bytes of a data directive, None if an operand isn't known here.
+pub(super) fn encode(
+    opcode: &str,
+    operands: &[String],
+    labels: &[HashMap<String, i64>; 2],
+) -> Option<Vec<u8>> {
+    let width = width(opcode).unwrap();
+    let mut bytes = vec![];
+    for operand in operands {
+        let val = value(operand, labels)?;
+        if width < 8 && !(-(1 << (width * 8 - 1))..(1 << (width * 8))).contains(&val) {
+            panic!("{} value {} out of range", opcode, operand);
+        }
+        bytes.extend_from_slice(&val.to_le_bytes()[..width]);
+    }
+    Some(bytes)
+}
+
+// the value of \x and octal escapes: `val` so far and at most `max` more
+// digits
+fn escape_digits(chars: &mut Peekable<Chars>, mut val: u32, radix: u32, max: usize) -> u32 {
+    for _ in 0..max {
+        match chars.peek().and_then(|c| c.to_digit(radix)) {
+            Some(d) => val = val * radix + d,
+            None => break,
+        }
+        chars.next();
+    }
+    val
+}
+
+// the bytes of "text" with the C escapes: \n, \t, \xNN, \0, octal \NNN, ...
+fn unescape(string: &str) -> Vec<u8> {
+    let text = match string.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(text) => text,
+        _ => panic!("expected a quoted string, not {}", string),
+    };
+    let mut bytes = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('a') => 0x07,
+            Some('b') => 0x08,
+            Some('f') => 0x0c,
+            Some('v') => 0x0b,
+            Some(c @ ('\\' | '\'' | '"')) => c as u8,
+            Some('x') if chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) => {
+                escape_digits(&mut chars, 0, 16, 2) as u8
+            }
+            Some(c @ '0'..='7') => escape_digits(&mut chars, c as u32 - '0' as u32, 8, 2) as u8,
+            Some(c) => panic!("unknown escape sequence \\{} in {}", c, string),
+            None => panic!("unterminated escape sequence in {}", string),
+        };
+        bytes.push(byte);
+    }
+    bytes
+}
+
+// The bytes of a string directive, None for other lines.
+fn string_bytes(inst: &TextInstruction) -> Option<Vec<u8>> {
+    let nul = STRINGS.iter().find(|s| s.0 == inst.opcode)?.1;
+    let mut bytes = vec![];
+    for operand in &inst.operands {
+        bytes.extend(unescape(operand));
+        if nul {
+            bytes.push(0);
+        }
+    }
+    Some(bytes)
+}
+
+// The bytes of .float/.single/.double, None for other lines. The values are
+// decimal, with an optional exponent, or inf and nan.
+fn float_bytes(inst: &TextInstruction) -> Option<Vec<u8>> {
+    let size = FLOATS.iter().find(|f| f.0 == inst.opcode)?.1;
+    let mut bytes = vec![];
+    for operand in &inst.operands {
+        let valid = if size == 4 {
+            operand
+                .parse::<f32>()
+                .map(|val| bytes.extend_from_slice(&val.to_le_bytes()))
+        } else {
+            operand
+                .parse::<f64>()
+                .map(|val| bytes.extend_from_slice(&val.to_le_bytes()))
+        };
+        if valid.is_err() {
+            panic!("invalid floating-point value {}", operand);
+        }
+    }
+    Some(bytes)
+}
+
+// .incbin "file"[, skip[, count]]: the file is relative to the current
+// directory, like in GNU as
+fn incbin(inst: &TextInstruction) -> Option<Vec<u8>> {
+    if inst.opcode != ".incbin" {
+        return None;
+    }
+    let ops = &inst.operands;
+    if ops.is_empty() || ops.len() > 3 {
+        panic!(".incbin expects \"file\"[, skip[, count]]");
+    }
+    let file = String::from_utf8_lossy(&unescape(&ops[0])).into_owned();
+    let bytes = match std::fs::read(&file) {
+        Ok(bytes) => bytes,
+        Err(e) => panic!("can't read {}: {}", file, e),
+    };
+    let skip = ops.get(1).map_or(0, |skip| parse_imm(skip));
+    if skip < 0 || skip as usize > bytes.len() {
+        panic!(
+            ".incbin skip {} out of range, {} is {} bytes",
+            ops[1],
+            file,
+            bytes.len()
+        );
+    }
+    let count = ops
+        .get(2)
+        .map_or(bytes.len() as i64 - skip, |count| parse_imm(count));
+    if count < 0 || (skip + count) as usize > bytes.len() {
+        panic!(
+            ".incbin count {} out of range, {} is {} bytes",
+            ops[2],
+            file,
+            bytes.len()
+        );
+    }
+    Some(bytes[skip as usize..(skip + count) as usize].to_vec())
+}
+
+// The bytes of string and floating-point directives and .incbin, None for
+// other lines.
+pub(super) fn bytes(inst: &TextInstruction) -> Option<Vec<u8>> {
+    string_bytes(inst)
+        .or_else(|| float_bytes(inst))
+        .or_else(|| incbin(inst))
+}
+
+// Alignment, fill byte and maximum padding of .align, .balign and .p2align,
+// None if an operand isn't a number.
+pub(super) fn alignment(inst: &TextInstruction) -> Option<(i64, Option<Vec<u8>>, Option<i64>)> {
+    let &(_, power, size) = ALIGNS.iter().find(|a| a.0 == inst.opcode)?;
+    let ops = &inst.operands;
+    if ops.is_empty() || ops.len() > 3 || !ops.iter().all(|o| expr::is_literal(o)) {
+        return None;
+    }
+    let val = parse_imm(&ops[0]);
+    let align = if power {
+        if !(0..32).contains(&val) {
+            panic!("{} {} out of range [0, 31]", inst.opcode, ops[0]);
+        }
+        1 << val
+    } else {
+        if val <= 0 || val & (val - 1) != 0 {
+            panic!("alignment {} is not a power of 2", ops[0]);
+        }
+        val
+    };
+    // the fill value in little-endian, 1, 2 or 4 bytes
+    let fill = ops.get(1).map(|fill| {
+        let val = parse_imm(fill);
+        if !(-(1 << (size * 8 - 1))..1 << (size * 8)).contains(&val) {
+            panic!("{} fill value {} out of range", inst.opcode, fill);
+        }
+        val.to_le_bytes()[..size as usize].to_vec()
+    });
+    Some((align, fill, ops.get(2).map(|max| parse_imm(max))))
+}
+
+// The padding of an alignment: the fill pattern, after zeros up to a multiple
+// of its size, or nops in code with the odd byte and c.nop first.
+pub(super) fn padding(size: usize, fill: Option<&[u8]>) -> Vec<Vec<u8>> {
+    match fill {
+        Some(_) if size == 0 => return vec![],
+        Some(pattern) => {
+            let mut bytes = vec![0; size % pattern.len()];
+            for _ in 0..size / pattern.len() {
+                bytes.extend(pattern);
+            }
+            return vec![bytes];
+        }
+        None => {}
+    }
+    let mut res = vec![];
+    if size % 2 == 1 {
+        res.push(vec![0]);
+    }
+    if size % 4 >= 2 {
+        res.push(vec![0x01, 0x00]);
+    }
+    for _ in 0..size / 4 {
+        res.push(vec![0x13, 0x00, 0x00, 0x00]);
+    }
+    res
+}
+
+// bytes reserved by .zero, .skip or .space, None if the size isn't a number
+pub(super) fn space_size(inst: &TextInstruction) -> Option<usize> {
+    let ops = &inst.operands;
+    if !SPACES.contains(&inst.opcode.as_str()) || ops.is_empty() || ops.len() > 2 {
+        return None;
+    }
+    if !expr::is_literal(&ops[0]) {
+        return None;
+    }
+    match parse_imm(&ops[0]) {
+        val if val >= 0 => Some(val as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_data() {
+    let lines = vec![
+        ".byte 1, -1, 'a'",
+        ".half 0x1234",
+        ".word 1 << 20",
+        ".dword -2",
+        ".quad 0x8000000000000000",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# .byte 1,-1,97",
+            ".byte 0x01,0xff,0x61",
+            "# .half 0x1234",
+            ".byte 0x34,0x12",
+            "# .word 1048576",
+            ".byte 0x00,0x00,0x10,0x00",
+            "# .dword -2",
+            ".byte 0xfe,0xff,0xff,0xff,0xff,0xff,0xff,0xff",
+            "# .quad 0x8000000000000000",
+            ".byte 0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x80",
+        ]
+    );
+}
+
+#[test]
+fn test_data_labels() {
+    let lines = vec![
+        "table:",
+        ".word end - table, (end - table) / 4",
+        ".word table",
+        "nop",
+        "end:",
+        "beqz a0, end",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "table:",
+            "# .word end - table,(end - table) / 4",
+            ".byte 0x10,0x00,0x00,0x00,0x04,0x00,0x00,0x00",
+            ".word table",
+            "# nop",
+            ".byte 0x13,0x00,0x00,0x00",
+            "end:",
+            "# beqz a0,end",
+            ".byte 0x63,0x00,0x05,0x00",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = ".byte value 256 out of range")]
+fn test_data_range() {
+    let lines = vec![String::from(".byte 256")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+fn test_strings() {
+    let lines = vec![
+        "msg:",
+        ".ascii \"Hi, RISC-V\"",
+        ".asciz \"a\\tb\\n\", \"\\x41\\101\\0\"",
+        ".string \"\\\"q\\\\\"",
+        ".ascii \"\"",
+        "end:",
+        ".byte end - msg",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "msg:",
+            "# .ascii \"Hi, RISC-V\"",
+            ".byte 0x48,0x69,0x2c,0x20,0x52,0x49,0x53,0x43,0x2d,0x56",
+            "# .asciz \"a\\tb\\n\",\"\\x41\\101\\0\"",
+            ".byte 0x61,0x09,0x62,0x0a,0x00,0x41,0x41,0x00,0x00",
+            "# .string \"\\\"q\\\\\"",
+            ".byte 0x22,0x71,0x5c,0x00",
+            ".ascii \"\"",
+            "end:",
+            "# .byte end - msg",
+            ".byte 0x17",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "unknown escape sequence \\q")]
+fn test_strings_escape() {
+    let lines = vec![String::from(".ascii \"\\q\"")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+fn test_align() {
+    let lines = vec![
+        ".text",
+        "start:",
+        ".byte 1",
+        ".p2align 2",
+        "nop",
+        ".balign 16, 0xff",
+        ".align 3",
+        ".zero 6",
+        ".skip 4, 0xaa",
+        ".balign 8",
+        ".space 3",
+        ".balign 8, 0, 4",
+        ".balign 4",
+        "j start",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            ".text",
+            "start:",
+            "# .byte 1",
+            ".byte 0x01",
+            "# .p2align 2",
+            ".byte 0x00",
+            ".byte 0x01,0x00",
+            "# nop",
+            ".byte 0x13,0x00,0x00,0x00",
+            "# .balign 16,0xff",
+            ".byte 0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff",
+            "# .align 3",
+            ".zero 6",
+            ".skip 4,0xaa",
+            "# .balign 8",
+            ".byte 0x01,0x00",
+            ".byte 0x13,0x00,0x00,0x00",
+            ".space 3",
+            "# .balign 8,0,4",
+            "# .balign 4",
+            ".byte 0x00",
+            "# j start",
+            ".byte 0x6f,0xf0,0xdf,0xfd",
+        ]
+    );
+}
+
+#[test]
+fn test_align_unknown_offset() {
+    // the size of .fill isn't known, and so isn't the offset after it
+    let lines = vec![".data", ".fill 3, 1, 0", ".balign 8", ".p2align sym"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![".data", ".fill 3,1,0", ".balign 8", ".p2align sym"]
+    );
+}
+
+#[test]
+#[should_panic(expected = "alignment 6 is not a power of 2")]
+fn test_align_power_of_2() {
+    let lines = vec![String::from(".balign 6")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+#[should_panic(expected = ".balignw fill value 0x10000 out of range")]
+fn test_align_fill_range() {
+    let lines = vec![String::from(".balignw 4, 0x10000")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+fn test_floats() {
+    let lines = vec![".float 1.5, -0.1", ".double 1e10, inf"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# .float 1.5,-0.1",
+            ".byte 0x00,0x00,0xc0,0x3f,0xcd,0xcc,0xcc,0xbd",
+            "# .double 1e10,inf",
+            ".byte 0x00,0x00,0x00,0x20,0x5f,0xa0,0x02,0x42,0x00,0x00,0x00,0x00,0x00,0x00,0xf0,0x7f",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "invalid floating-point value 1.5f")]
+fn test_floats_invalid() {
+    let lines = vec![String::from(".float 1.5f")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+fn test_incbin() {
+    let file = std::env::temp_dir().join(format!("rna-incbin-{}.bin", std::process::id()));
+    std::fs::write(&file, (0..20).collect::<Vec<u8>>()).unwrap();
+    let file = file.to_str().unwrap();
+    let lines = vec![
+        format!(".incbin \"{}\"", file),
+        format!(".incbin \"{}\", 18", file),
+        format!(".incbin \"{}\", 2, 3", file),
+    ];
+    let output = assemble("a.s", lines, &Options::default());
+    std::fs::remove_file(file).unwrap();
+    assert_eq!(
+        output,
+        vec![
+            format!("# .incbin \"{}\"", file),
+            String::from(
+                ".byte 0x00,0x01,0x02,0x03,0x04,0x05,0x06,0x07,0x08,0x09,0x0a,0x0b,0x0c,0x0d,0x0e,0x0f"
+            ),
+            String::from(".byte 0x10,0x11,0x12,0x13"),
+            format!("# .incbin \"{}\",18", file),
+            String::from(".byte 0x12,0x13"),
+            format!("# .incbin \"{}\",2,3", file),
+            String::from(".byte 0x02,0x03,0x04"),
+        ]
+    );
+}
@@ -0,0 +1,466 @@
+// Constant expressions in immediate operands, e.g. (1 << 12) - 4 or
+// BASE + 8, with the C operators + - * / % << >> & | ^ ~ and parentheses.
+// For .if there are also == != < <= > >= && || and !; as in GNU as, a true
+// comparison is -1.
+// Character literals like 'A' or '\n' are numbers too, and %hi/%lo of a
+// constant are evaluated.
+// Operands which aren't constant, like registers, symbols or %lo(sym), are
+// left as they are.
+use super::{parse_literal, TextInstruction};
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// the longer ones first
+const OPERATORS: [&str; 23] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "<>", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "&",
+    "|", "^", "~", "!", "(", ")",
+];
+
+// 'A', '\n', '\0', ...: the value and the length including the quotes
+fn char_literal(literal: &str) -> Option<(i64, usize)> {
+    let mut chars = literal[1..].chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            'a' => '\x07',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            c @ ('\\' | '\'' | '"') => c,
+            c => panic!("unknown escape sequence \\{} in {}", c, literal),
+        },
+        '\'' => return None,
+        c => c,
+    };
+    if chars.next()? != '\'' {
+        return None;
+    }
+    Some((c as i64, literal.len() - chars.as_str().len()))
+}
+
+fn tokenize(expr: &str) -> Option<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let len = if c == '\'' {
+            let (val, len) = char_literal(rest)?;
+            tokens.push(Token::Num(val));
+            len
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if c.is_ascii_digit() {
+                tokens.push(Token::Num(parse_literal(word)? as i64));
+            } else {
+                tokens.push(Token::Name(String::from(word)));
+            }
+            len
+        } else {
+            let op = OPERATORS.iter().find(|op| rest.starts_with(*op))?;
+            tokens.push(Token::Op(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+// binary operators by precedence, lowest first
+const LEVELS: [&[&str]; 9] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!=", "<>"],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a HashMap<String, i64>,
+    expr: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+    // a || b, a && b, ... down to the multiplicative operators
+    fn binary(&mut self, level: usize) -> Option<i64> {
+        if level == LEVELS.len() {
+            return self.term();
+        }
+        let mut val = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            let compare = |cond: bool| if cond { -1 } else { 0 };
+            val = match op {
+                "||" => (val != 0 || rhs != 0) as i64,
+                "&&" => (val != 0 && rhs != 0) as i64,
+                "==" => compare(val == rhs),
+                "!=" | "<>" => compare(val != rhs),
+                "<" => compare(val < rhs),
+                "<=" => compare(val <= rhs),
+                ">" => compare(val > rhs),
+                ">=" => compare(val >= rhs),
+                "|" => val | rhs,
+                "^" => val ^ rhs,
+                "&" => val & rhs,
+                "<<" => val.wrapping_shl(rhs as u32),
+                ">>" => val.wrapping_shr(rhs as u32),
+                "+" => val.wrapping_add(rhs),
+                _ => val.wrapping_sub(rhs),
+            };
+        }
+        Some(val)
+    }
+    fn term(&mut self) -> Option<i64> {
+        let mut val = self.unary()?;
+        while let Some(op) = self.peek_op().filter(|op| ["*", "/", "%"].contains(op)) {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            if op != "*" && rhs == 0 {
+                panic!("division by zero in {}", self.expr);
+            }
+            val = match op {
+                "*" => val.wrapping_mul(rhs),
+                "/" => val.wrapping_div(rhs),
+                _ => val.wrapping_rem(rhs),
+            };
+        }
+        Some(val)
+    }
+    fn unary(&mut self) -> Option<i64> {
+        let token = self.tokens.get(self.pos)?.clone();
+        self.pos += 1;
+        match token {
+            Token::Num(val) => Some(val),
+            Token::Name(name) => self.symbols.get(&name).copied(),
+            Token::Op("-") => Some(self.unary()?.wrapping_neg()),
+            Token::Op("+") => self.unary(),
+            Token::Op("~") => Some(!self.unary()?),
+            Token::Op("!") => Some((self.unary()? == 0) as i64),
+            // %hi(value) and %lo(value), the parts of lui + addi
+            Token::Op("%") => {
+                let function = match self.tokens.get(self.pos) {
+                    Some(Token::Name(name)) => name.clone(),
+                    _ => return None,
+                };
+                self.pos += 1;
+                if self.peek_op() != Some("(") {
+                    return None;
+                }
+                let val = self.unary()?;
+                let hi = val.wrapping_add(0x800) >> 12;
+                match function.as_str() {
+                    "hi" => Some(hi & 0xfffff),
+                    "lo" => Some(val - (hi << 12)),
+                    _ => None,
+                }
+            }
+            Token::Op("(") => {
+                let val = self.binary(0)?;
+                if self.peek_op() != Some(")") {
+                    return None;
+                }
+                self.pos += 1;
+                Some(val)
+            }
+            _ => None,
+        }
+    }
+}
+
+// the value of a constant expression, None if it isn't one
+pub(super) fn eval(expr: &str, symbols: &HashMap<String, i64>) -> Option<i64> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        symbols,
+        expr,
+    };
+    let val = parser.binary(0)?;
+    if parser.pos != parser.tokens.len() {
+        return None;
+    }
+    Some(val)
+}
+
+// a plain number, which is kept as written
+pub(super) fn is_literal(operand: &str) -> bool {
+    parse_literal(operand.strip_prefix('-').unwrap_or(operand)).is_some()
+}
+
+// the value of the expression, or of the offset of expr(reg)
+fn fold_operand(operand: &str, symbols: &HashMap<String, i64>) -> Option<String> {
+    if is_literal(operand) {
+        return None;
+    }
+    if let Some(val) = eval(operand, symbols) {
+        return Some(val.to_string());
+    }
+    // the '(' which matches the last ')'
+    let inner = operand.strip_suffix(')')?;
+    let mut depth = 0;
+    let begin = inner.rfind(|c| {
+        match c {
+            ')' => depth += 1,
+            '(' if depth == 0 => return true,
+            '(' => depth -= 1,
+            _ => {}
+        }
+        false
+    })?;
+    let offset = operand[..begin].trim();
+    if offset.is_empty() || is_literal(offset) {
+        return None;
+    }
+    let val = eval(offset, symbols)?;
+    Some(format!("{}{}", val, &operand[begin..]))
+}
+
+// .equ/.set/.equiv name, value, and name = value
+pub(super) fn definition(inst: &TextInstruction) -> Option<(&str, &str)> {
+    match (inst.opcode.as_str(), inst.operands.as_slice()) {
+        (".equ", [name, value]) | (".set", [name, value]) | (".equiv", [name, value]) => {
+            Some((name, value))
+        }
+        (name, [value]) => Some((name, value.strip_prefix('=')?.trim())),
+        _ => None,
+    }
+}
+
+// Named constants like GNU as: .equ and .set may redefine a name, .equiv
+// may not. A value which isn't constant, e.g. a label, is left to the real
+// assembler and makes the name unknown here.
+pub(super) fn define_constant(inst: &TextInstruction, symbols: &mut HashMap<String, i64>) {
+    let (name, value) = match definition(inst) {
+        Some(d) => d,
+        None => return,
+    };
+    if inst.opcode == ".equiv" && symbols.contains_key(name) {
+        panic!("symbol {} is already defined", name);
+    }
+    match eval(value, symbols) {
+        Some(val) => symbols.insert(String::from(name), val),
+        None => symbols.remove(name),
+    };
+}
+
+// --defsym NAME=VALUE, the value may be an expression
+pub(super) fn parse_defsym(arg: &str) -> (String, i64) {
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => (name.trim(), value),
+        _ => panic!("invalid --defsym {}, expected NAME=VALUE", arg),
+    };
+    match eval(value, &HashMap::new()) {
+        Some(val) => (String::from(name), val),
+        None => panic!("invalid --defsym {}: {} is not a constant", arg, value),
+    }
+}
+
+// replace constant expressions in the operands by their decimal value, except
+// the name of .equ and the like
+pub(super) fn fold_constants(inst: &mut TextInstruction, symbols: &HashMap<String, i64>) {
+    let skip = match inst.opcode.as_str() {
+        ".equ" | ".set" | ".equiv" => 1,
+        _ => 0,
+    };
+    for operand in inst.operands.iter_mut().skip(skip) {
+        if let Some(folded) = fold_operand(operand, symbols) {
+            *operand = folded;
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_eval() {
+    let symbols: HashMap<String, i64> =
+        [(String::from("BASE"), 0x1000), (String::from("SIZE"), 12)]
+            .iter()
+            .cloned()
+            .collect();
+    assert_eq!(eval("(1 << 12) - 4", &symbols), Some(4092));
+    assert_eq!(eval("BASE + 8", &symbols), Some(0x1008));
+    assert_eq!(eval("SIZE * 3", &symbols), Some(36));
+    assert_eq!(eval("1 + 2 * 3", &symbols), Some(7));
+    assert_eq!(eval("-7 / 2", &symbols), Some(-3));
+    assert_eq!(eval("-7 % 2", &symbols), Some(-1));
+    assert_eq!(eval("0xff & ~0xf | 1 ^ 3", &symbols), Some(0xf2));
+    assert_eq!(eval("-16 >> 2", &symbols), Some(-4));
+    assert_eq!(eval("a0", &symbols), None);
+    assert_eq!(eval("%lo(sym)", &symbols), None);
+    assert_eq!(eval("(1 + 2", &symbols), None);
+    assert_eq!(eval("SIZE == 12", &symbols), Some(-1));
+    assert_eq!(eval("SIZE <> 12", &symbols), Some(0));
+    assert_eq!(eval("1 << 2 > 3 && BASE >= 0x1000", &symbols), Some(1));
+    assert_eq!(eval("SIZE < 8 || !BASE", &symbols), Some(0));
+}
+
+#[test]
+fn test_char_literals() {
+    let symbols = HashMap::new();
+    assert_eq!(eval("'A'", &symbols), Some(65));
+    assert_eq!(eval("'\\n'", &symbols), Some(10));
+    assert_eq!(eval("'\\0'", &symbols), Some(0));
+    assert_eq!(eval("'\\''", &symbols), Some(39));
+    assert_eq!(eval("'a' - 'A'", &symbols), Some(32));
+    assert_eq!(eval("'ab'", &symbols), None);
+    let lines = vec!["li a0, 'A'", "li a1, ','", ".byte 'h', 'i', '\\n'"];
+    let lines = lines.into_iter().map(String::from).collect();
+    let output = assemble("a.s", lines, &Options::default());
+    assert_eq!(
+        output,
+        vec![
+            "# li a0,65",
+            ".byte 0x13,0x05,0x10,0x04",
+            "# li a1,44",
+            ".byte 0x93,0x05,0xc0,0x02",
+            "# .byte 104,105,10",
+            ".byte 0x68,0x69,0x0a",
+        ]
+    );
+}
+
+#[test]
+fn test_equ() {
+    let lines = vec![
+        ".equ buf_size, 256",
+        ".set count, 3",
+        "addi a0, a0, buf_size - 1",
+        ".set count, count + 1",
+        "li a1, count * 8",
+        "half = buf_size / 2",
+        ".word half, buf_size",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let output = assemble("a.s", lines, &Options::default());
+    assert_eq!(
+        output,
+        vec![
+            ".equ buf_size,256",
+            ".set count,3",
+            "# addi a0,a0,255",
+            ".byte 0x13,0x05,0xf5,0x0f",
+            ".set count,4",
+            "# li a1,32",
+            ".byte 0x93,0x05,0x00,0x02",
+            "half = buf_size / 2",
+            "# .word 128,256",
+            ".byte 0x80,0x00,0x00,0x00,0x00,0x01,0x00,0x00",
+        ]
+    );
+}
+
+#[test]
+fn test_defsym() {
+    assert_eq!(parse_defsym("DEBUG=1"), (String::from("DEBUG"), 1));
+    assert_eq!(
+        parse_defsym("base=0x1000 + 16"),
+        (String::from("base"), 0x1010)
+    );
+    let options = Options {
+        defsyms: vec![parse_defsym("offset=16")],
+        ..Default::default()
+    };
+    let lines = vec![String::from("sd a0, offset * 2(sp)")];
+    assert_eq!(
+        assemble("a.s", lines, &options),
+        vec![
+            ".set offset,16",
+            "# sd a0,32(sp)",
+            ".byte 0x23,0x30,0xa1,0x02"
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "invalid --defsym DEBUG, expected NAME=VALUE")]
+fn test_defsym_invalid() {
+    parse_defsym("DEBUG");
+}
+
+#[test]
+#[should_panic(expected = "symbol size is already defined")]
+fn test_equiv_redefinition() {
+    let lines = vec![".equiv size, 1", ".equiv size, 2"];
+    assemble(
+        "a.s",
+        lines.into_iter().map(String::from).collect(),
+        &Options::default(),
+    );
+}
+
+#[test]
+fn test_hi_lo() {
+    let symbols = HashMap::new();
+    assert_eq!(eval("%hi(0x12345678)", &symbols), Some(0x12345));
+    assert_eq!(eval("%lo(0x12345678)", &symbols), Some(0x678));
+    assert_eq!(eval("%hi(0x12345fff)", &symbols), Some(0x12346));
+    assert_eq!(eval("%lo(0x12345fff)", &symbols), Some(-1));
+    assert_eq!(eval("%hi(sym)", &symbols), None);
+    let lines = vec!["lui a0, %hi(0x12345fff)", "addi a0, a0, %lo(0x12345fff)"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# lui a0,74566",
+            ".byte 0x37,0x65,0x34,0x12",
+            "# addi a0,a0,-1",
+            ".byte 0x13,0x05,0xf5,0xff",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "division by zero in 4 / (2 - 2)")]
+fn test_eval_division_by_zero() {
+    eval("4 / (2 - 2)", &HashMap::new());
+}
+
+#[test]
+fn test_fold_constants() {
+    let lines = vec![
+        "addi a0, a1, (1 << 11) - 1",
+        "lw a0, (2 * 4)(sp)",
+        "rori a0, a0, 64 / 2",
+        "addi a0, a0, %lo(sym)",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let output = assemble("a.s", lines, &Options::default());
+    assert_eq!(
+        output,
+        vec![
+            "# addi a0,a1,2047",
+            ".byte 0x13,0x85,0xf5,0x7f",
+            "# lw a0,8(sp)",
+            ".byte 0x03,0x25,0x81,0x00",
+            "# rori a0,a0,32",
+            ".byte 0x13,0x55,0x05,0x62",
+            "addi a0,a0,%lo(sym)",
+        ]
+    );
+}
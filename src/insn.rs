@@ -0,0 +1,253 @@
+// .insn <format> opcode, ...: GNU-style structural encodings. The opcode is
+// a number or a major opcode name (op, op_imm, load, ...). Branch and jump
+// targets are numeric offsets.
+use super::{parse_imm, split_address, BinaryInstruction, FP_REG_MAP, REG_MAP};
+
+const FORMATS: [&str; 11] = ["r", "r4", "i", "s", "b", "sb", "u", "j", "uj", "cr", "ci"];
+
+// major opcode names, without the '_' separators
+const OPCODE_NAMES: [(&str, u8); 26] = [
+    ("load", 0b0000011),
+    ("loadfp", 0b0000111),
+    ("custom0", 0b0001011),
+    ("miscmem", 0b0001111),
+    ("opimm", 0b0010011),
+    ("auipc", 0b0010111),
+    ("opimm32", 0b0011011),
+    ("store", 0b0100011),
+    ("storefp", 0b0100111),
+    ("custom1", 0b0101011),
+    ("amo", 0b0101111),
+    ("op", 0b0110011),
+    ("lui", 0b0110111),
+    ("op32", 0b0111011),
+    ("madd", 0b1000011),
+    ("msub", 0b1000111),
+    ("nmsub", 0b1001011),
+    ("nmadd", 0b1001111),
+    ("opfp", 0b1010011),
+    ("opv", 0b1010111),
+    ("custom2", 0b1011011),
+    ("branch", 0b1100011),
+    ("jalr", 0b1100111),
+    ("jal", 0b1101111),
+    ("system", 0b1110011),
+    ("custom3", 0b1111011),
+];
+
+pub(super) fn is_format(name: &str) -> bool {
+    FORMATS.contains(&name)
+}
+
+// check range and alignment, return the two's complement bits
+fn check_range(value: &str, min: i64, max: i64, align: i64) -> u32 {
+    let val = parse_imm(value);
+    if val < min || val > max || val % align != 0 {
+        panic!(
+            ".insn operand {} out of range [{}, {}] or not a multiple of {}",
+            value, min, max, align
+        );
+    }
+    val as u32
+}
+
+// integer or floating-point register, e.g. for r4 with fa0
+fn any_reg(name: &str) -> u32 {
+    match REG_MAP.get(name).or_else(|| FP_REG_MAP.get(name)) {
+        Some(reg) => (*reg).into(),
+        None => panic!("can't find register name {}", name),
+    }
+}
+
+fn opcode(value: &str, bits: u32) -> u32 {
+    let name = value.replace('_', "");
+    match OPCODE_NAMES.iter().find(|o| o.0 == name) {
+        Some((_, opcode)) if bits == 7 => (*opcode).into(),
+        _ => check_range(value, 0, (1 << bits) - 1, 1),
+    }
+}
+
+fn funct(value: &str, bits: u32) -> u32 {
+    check_range(value, 0, (1 << bits) - 1, 1)
+}
+
+impl BinaryInstruction {
+    fn set_field(&mut self, begin: u8, end: u8, value: u32) {
+        let bits = BinaryInstruction::bits_array(value, (end - begin + 1).into());
+        self.set(begin, end, bits);
+    }
+    fn set_insn_reg(&mut self, begin: u8, name: &str) {
+        self.set_field(begin, begin + 4, any_reg(name));
+    }
+    // bits of imm from `high` downwards: the same scattering as compressed.rs
+    fn set_imm_bits(&mut self, imm: u32, high: u8, order: &[u8]) {
+        for (i, bit) in order.iter().enumerate() {
+            let pos = high - i as u8;
+            self.set(pos, pos, vec![((imm >> bit) & 1) as u8]);
+        }
+    }
+}
+
+pub(super) fn insn_format(format: &str, ops: &[String]) -> BinaryInstruction {
+    let compressed = format.starts_with('c');
+    let mut res = if compressed {
+        BinaryInstruction::new_compressed()
+    } else {
+        BinaryInstruction::new()
+    };
+    let expect = |count: usize, syntax: &str| {
+        if ops.len() != count {
+            panic!(".insn {} expects {}", format, syntax);
+        }
+    };
+    match format {
+        // .insn r opcode, funct3, funct7, rd, rs1, rs2
+        // .insn r opcode, funct3, funct2, rd, rs1, rs2, rs3 (same as r4)
+        "r" | "r4" if ops.len() == 7 => {
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_field(12, 14, funct(&ops[1], 3));
+            res.set_field(25, 26, funct(&ops[2], 2));
+            res.set_insn_reg(7, &ops[3]);
+            res.set_insn_reg(15, &ops[4]);
+            res.set_insn_reg(20, &ops[5]);
+            res.set_insn_reg(27, &ops[6]);
+        }
+        "r" => {
+            expect(6, "opcode, funct3, funct7, rd, rs1, rs2");
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_field(12, 14, funct(&ops[1], 3));
+            res.set_field(25, 31, funct(&ops[2], 7));
+            res.set_insn_reg(7, &ops[3]);
+            res.set_insn_reg(15, &ops[4]);
+            res.set_insn_reg(20, &ops[5]);
+        }
+        "r4" => expect(7, "opcode, funct3, funct2, rd, rs1, rs2, rs3"),
+        // .insn i opcode, funct3, rd, rs1, simm12
+        // .insn i opcode, funct3, rd, simm12(rs1)
+        "i" => {
+            let (rs1, imm) = match ops.len() {
+                5 => (ops[3].as_str(), ops[4].as_str()),
+                4 => {
+                    let (offset, rs1) = split_address(&ops[3]);
+                    (rs1, if offset.is_empty() { "0" } else { offset })
+                }
+                _ => panic!(".insn i expects opcode, funct3, rd, rs1, simm12"),
+            };
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_field(12, 14, funct(&ops[1], 3));
+            res.set_insn_reg(7, &ops[2]);
+            res.set_insn_reg(15, rs1);
+            res.set_field(20, 31, check_range(imm, -2048, 2047, 1) & 0xfff);
+        }
+        // .insn s opcode, funct3, rs2, simm12(rs1)
+        "s" => {
+            expect(4, "opcode, funct3, rs2, simm12(rs1)");
+            let (offset, rs1) = split_address(&ops[3]);
+            let offset = if offset.is_empty() { "0" } else { offset };
+            let imm = check_range(offset, -2048, 2047, 1);
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_field(12, 14, funct(&ops[1], 3));
+            res.set_insn_reg(20, &ops[2]);
+            res.set_insn_reg(15, rs1);
+            res.set_field(7, 11, imm & 0x1f);
+            res.set_field(25, 31, (imm >> 5) & 0x7f);
+        }
+        // .insn b opcode, funct3, rs1, rs2, offset
+        "b" | "sb" => {
+            expect(5, "opcode, funct3, rs1, rs2, offset");
+            let imm = check_range(&ops[4], -4096, 4094, 2);
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_field(12, 14, funct(&ops[1], 3));
+            res.set_insn_reg(15, &ops[2]);
+            res.set_insn_reg(20, &ops[3]);
+            res.set_imm_bits(imm, 31, &[12, 10, 9, 8, 7, 6, 5]);
+            res.set_imm_bits(imm, 11, &[4, 3, 2, 1, 11]);
+        }
+        // .insn u opcode, rd, imm20
+        "u" => {
+            expect(3, "opcode, rd, imm20");
+            let imm = check_range(&ops[2], -0x80000, 0xfffff, 1);
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_insn_reg(7, &ops[1]);
+            res.set_field(12, 31, imm & 0xfffff);
+        }
+        // .insn j opcode, rd, offset
+        "j" | "uj" => {
+            expect(3, "opcode, rd, offset");
+            let imm = check_range(&ops[2], -0x100000, 0xffffe, 2);
+            res.set_field(0, 6, opcode(&ops[0], 7));
+            res.set_insn_reg(7, &ops[1]);
+            res.set_imm_bits(imm, 31, &[20, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 11]);
+            res.set_imm_bits(imm, 19, &[19, 18, 17, 16, 15, 14, 13, 12]);
+        }
+        // .insn cr opcode2, funct4, rd, rs2
+        "cr" => {
+            expect(4, "opcode2, funct4, rd, rs2");
+            res.set_field(0, 1, opcode(&ops[0], 2));
+            res.set_field(12, 15, funct(&ops[1], 4));
+            res.set_insn_reg(7, &ops[2]);
+            res.set_insn_reg(2, &ops[3]);
+        }
+        // .insn ci opcode2, funct3, rd, simm6
+        "ci" => {
+            expect(4, "opcode2, funct3, rd, simm6");
+            let imm = check_range(&ops[3], -32, 31, 1);
+            res.set_field(0, 1, opcode(&ops[0], 2));
+            res.set_field(13, 15, funct(&ops[1], 3));
+            res.set_insn_reg(7, &ops[2]);
+            res.set_imm_bits(imm, 12, &[5]);
+            res.set_imm_bits(imm, 6, &[4, 3, 2, 1, 0]);
+        }
+        _ => unreachable!(),
+    }
+    if !compressed && res.data[0] & 0b11 != 0b11 {
+        panic!(".insn {}: opcode {} is not a 32-bit opcode", format, ops[0]);
+    }
+    res
+}
+
+#[cfg(test)]
+use super::test;
+
+#[test]
+fn test_insn_formats() {
+    test(
+        ".insn r 0x33, 0, 0, a0, a1, a2",
+        ".byte 0x33,0x85,0xc5,0x00",
+    );
+    test(".insn r op, 0, 0, a0, a1, a2", ".byte 0x33,0x85,0xc5,0x00");
+    test(
+        ".insn r4 0x43, 0, 0, fa0, fa1, fa2, fa3",
+        ".byte 0x43,0x85,0xc5,0x68",
+    );
+    test(
+        ".insn r 0x43, 0, 0, fa0, fa1, fa2, fa3",
+        ".byte 0x43,0x85,0xc5,0x68",
+    );
+    test(".insn i 0x13, 0, a0, a1, -1", ".byte 0x13,0x85,0xf5,0xff");
+    test(".insn i 0x3, 2, a0, 8(a1)", ".byte 0x03,0xa5,0x85,0x00");
+    test(".insn i load, 2, a0, 8(a1)", ".byte 0x03,0xa5,0x85,0x00");
+    test(".insn s 0x23, 2, a0, -8(a1)", ".byte 0x23,0xac,0xa5,0xfe");
+    test(".insn b 0x63, 0, a0, a1, 16", ".byte 0x63,0x08,0xb5,0x00");
+    test(
+        ".insn sb 0x63, 1, a0, a1, -4096",
+        ".byte 0x63,0x10,0xb5,0x80",
+    );
+    test(".insn u 0x37, a0, 0xfffff", ".byte 0x37,0xf5,0xff,0xff");
+    // same as jal ra, 2046 and jal ra, -8
+    test(".insn j 0x6f, ra, 2046", ".byte 0xef,0x00,0xe0,0x7f");
+    test(".insn uj jal, ra, -8", ".byte 0xef,0xf0,0x9f,0xff");
+}
+
+#[test]
+fn test_insn_compressed_formats() {
+    // c.add a0, a1 and c.addi a0, -1
+    test(".insn cr 2, 9, a0, a1", ".byte 0x2e,0x95");
+    test(".insn ci 1, 0, a0, -1", ".byte 0x7d,0x15");
+}
+
+#[test]
+#[should_panic(expected = ".insn operand 3 out of range")]
+fn test_insn_branch_alignment() {
+    test(".insn b 0x63, 0, a0, a1, 3", "");
+}
@@ -0,0 +1,586 @@
+// Labels and the branches, jumps and %pcrel_hi/%pcrel_lo pairs which refer to
+// them, in two passes. Only the sizes of encoded instructions are known here:
+// text which is dumped, like unknown instructions or directives, may be of any
+// size in the real assembler, e.g. compressed by .option rvc. So a label is
+// resolved when everything between it and the reference is encoded, otherwise
+// it's left to the real assembler, which emits the relocations.
+use super::{is_number, parse_imm, pseudo, TextInstruction};
+use std::collections::HashMap;
+
+// what the first pass knows about a line
+pub(super) enum Slot {
+    Label(String),
+    // bytes, None if unknown
+    Size(Option<usize>),
+    // instruction which refers to a label, 4 bytes if it's resolved
+    Ref(Reloc),
+    // alignment and the maximum padding, resolved from the start of the
+    // section only, where the offset in it is known
+    Align(i64, Option<i64>),
+    // switch to a section of sections.rs, each has its own location
+    Section(usize),
+}
+
+pub(super) enum Reloc {
+    // branch or jump to a label, the range of its offset: [-range, range),
+    // and the alignment of the target, 2 bytes with rvc and 4 without
+    Offset(String, i64, i64),
+    // auipc rd, %pcrel_hi(label)
+    PcrelHi(String),
+    // %pcrel_lo(label of the auipc) of addi, jalr, loads and stores
+    PcrelLo(String),
+}
+
+// where a slot is: a run of known sizes and the offset in it, and the offset
+// in the section too if the run starts at the start of the section
+#[derive(Clone, Copy)]
+pub(super) struct Location {
+    pub(super) run: usize,
+    pub(super) offset: i64,
+    pub(super) section: usize,
+    pub(super) from_start: bool,
+}
+
+// what the second pass needs from resolve
+pub(super) struct Layout {
+    // value of each resolved slot
+    pub(super) values: HashMap<usize, i64>,
+    pub(super) locations: Vec<Location>,
+    // locations of the named labels
+    pub(super) labels: HashMap<String, Location>,
+}
+
+const BRANCHES: [&str; 16] = [
+    "beq", "bne", "blt", "bge", "bltu", "bgeu", "beqz", "bnez", "blez", "bgez", "bltz", "bgtz",
+    "bgt", "ble", "bgtu", "bleu",
+];
+
+// directives which don't emit any bytes
+const NO_BYTES: [&str; 13] = [
+    ".equ", ".set", ".equiv", ".globl", ".global", ".local", ".weak", ".type", ".size", ".file",
+    ".ident", ".loc", ".option",
+];
+
+fn is_numeric_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+// 1f and 1b: the next and the previous definition of the local label 1
+fn local_ref(operand: &str) -> Option<(&str, char)> {
+    let direction = operand.chars().last()?;
+    let name = &operand[..operand.len() - 1];
+    if (direction == 'f' || direction == 'b') && is_numeric_label(name) {
+        Some((name, direction))
+    } else {
+        None
+    }
+}
+
+// the name of a label definition, name: or a local label like 1:
+pub(super) fn label_name(raw: &str) -> Option<&str> {
+    let name = raw.strip_suffix(':')?;
+    let symbol_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+    if is_numeric_label(name) {
+        return Some(name);
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(symbol_char) {
+        return None;
+    }
+    Some(name)
+}
+
+// %pcrel_lo(label) in an operand like %pcrel_lo(label)(a0)
+fn pcrel_lo(operand: &str) -> Option<&str> {
+    let rest = operand.strip_prefix("%pcrel_lo(")?;
+    Some(&rest[..rest.find(')')?])
+}
+
+// the offset operand of a branch or jump and the range of the offset:
+// [-range, range)
+fn offset_operand(inst: &TextInstruction) -> Option<(usize, i64)> {
+    let ops = &inst.operands;
+    match inst.opcode.as_str() {
+        "j" if ops.len() == 1 => Some((0, 1 << 20)),
+        "jal" if ops.len() == 1 || ops.len() == 2 => Some((ops.len() - 1, 1 << 20)),
+        name if BRANCHES.contains(&name) && !ops.is_empty() => Some((ops.len() - 1, 1 << 12)),
+        _ => None,
+    }
+}
+
+// What's wrong with the numeric offset of a branch or jump, if anything. An
+// offset to a label is checked when it's resolved.
+pub(super) fn offset_error(inst: &TextInstruction) -> Option<String> {
+    let (pos, range) = offset_operand(inst)?;
+    let offset = &inst.operands[pos];
+    if !is_number(offset) || local_ref(offset).is_some() {
+        return None;
+    }
+    let value = parse_imm(offset);
+    if (-range..range).contains(&value) && value % 2 == 0 {
+        return None;
+    }
+    Some(format!(
+        "{} offset {} out of range [{}, {}] or not a multiple of 2",
+        inst.opcode,
+        offset,
+        -range,
+        range - 2
+    ))
+}
+
+// The operand which refers to a label, the text which is replaced by the
+// value once it's resolved, and what the value is. `compress` tells whether
+// rvc is on, which allows branch targets at 2-byte offsets.
+pub(super) fn label_operand(
+    inst: &TextInstruction,
+    compress: bool,
+) -> Option<(usize, String, Reloc)> {
+    let ops = &inst.operands;
+    let (pos, range) = match (offset_operand(inst), inst.opcode.as_str()) {
+        (Some(operand), _) => operand,
+        (None, "auipc") if ops.len() == 2 => {
+            let label = ops[1].strip_prefix("%pcrel_hi(")?.strip_suffix(')')?;
+            let reloc = Reloc::PcrelHi(String::from(label));
+            return Some((1, ops[1].clone(), reloc));
+        }
+        (None, "addi") | (None, "jalr") if ops.len() == 3 || ops.len() == 2 => {
+            let label = pcrel_lo(ops.last().unwrap())?;
+            let pattern = format!("%pcrel_lo({})", label);
+            return Some((ops.len() - 1, pattern, Reloc::PcrelLo(String::from(label))));
+        }
+        (None, name) if pseudo::LOADS.contains(&name) || pseudo::STORES.contains(&name) => {
+            let label = pcrel_lo(ops.get(1)?)?;
+            let pattern = format!("%pcrel_lo({})", label);
+            return Some((1, pattern, Reloc::PcrelLo(String::from(label))));
+        }
+        _ => return None,
+    };
+    if is_number(&ops[pos]) && local_ref(&ops[pos]).is_none() {
+        return None;
+    }
+    let align = if compress { 2 } else { 4 };
+    let reloc = Reloc::Offset(ops[pos].clone(), range, align);
+    Some((pos, ops[pos].clone(), reloc))
+}
+
+// directives without bytes, like .globl or .equ, and name = value
+pub(super) fn is_zero_sized(inst: &TextInstruction) -> bool {
+    NO_BYTES.contains(&inst.opcode.as_str())
+        || inst.opcode.starts_with(".cfi_")
+        || (inst.operands.len() == 1 && inst.operands[0].starts_with('='))
+}
+
+// the upper 20 bits of a pc-relative offset, rounded for the sign-extended
+// lower 12 bits
+fn hi20(offset: i64) -> i64 {
+    offset.wrapping_add(0x800) >> 12
+}
+
+// The values of the resolved references, by slot: offsets of branches and
+// jumps, and the hi20/lo12 parts of %pcrel_hi/%pcrel_lo. The instructions
+// between a label and a reference to it must be of known size. A reference
+// which isn't resolved is of unknown size itself, so resolving is repeated
+// until nothing changes. The values of alignments are their padding.
+pub(super) fn resolve(slots: &[Slot]) -> Layout {
+    let mut unresolved = vec![false; slots.len()];
+    loop {
+        // location of each slot: a run of known sizes and the offset in it
+        let mut run = 0;
+        let mut offset = 0;
+        let mut runs = 1;
+        // whether the run starts at the start of the section, where the
+        // offsets are the ones in the section
+        let mut from_start = true;
+        // run, offset and from_start of the other sections
+        let mut sections = HashMap::new();
+        let mut section = 0;
+        let mut locations = vec![];
+        // slot and location of each label, local labels may be defined
+        // several times
+        let mut labels = HashMap::new();
+        let mut local_labels = vec![];
+        let mut paddings = HashMap::new();
+        for (i, slot) in slots.iter().enumerate() {
+            locations.push(Location {
+                run,
+                offset,
+                section,
+                from_start,
+            });
+            match slot {
+                Slot::Label(name) if is_numeric_label(name) => {
+                    local_labels.push((name.as_str(), i));
+                }
+                Slot::Label(name) => {
+                    if labels.insert(name.as_str(), i).is_some() {
+                        panic!("label {} is already defined", name);
+                    }
+                }
+                Slot::Size(Some(size)) => offset += *size as i64,
+                Slot::Ref(..) if !unresolved[i] => offset += 4,
+                Slot::Align(align, max) if from_start => {
+                    let padding = (align - offset % align) % align;
+                    let padding = match max {
+                        Some(max) if padding > *max => 0,
+                        _ => padding,
+                    };
+                    paddings.insert(i, padding);
+                    offset += padding;
+                }
+                Slot::Section(next) => {
+                    sections.insert(section, (run, offset, from_start));
+                    section = *next;
+                    (run, offset, from_start) = match sections.get(next) {
+                        Some(location) => *location,
+                        None => {
+                            runs += 1;
+                            (runs - 1, 0, true)
+                        }
+                    };
+                }
+                _ => {
+                    run = runs;
+                    runs += 1;
+                    offset = 0;
+                    from_start = false;
+                }
+            }
+        }
+        // the slot of a label, seen from slot i
+        let find_label = |name: &str, i: usize| match local_ref(name) {
+            Some((local, 'b')) => local_labels
+                .iter()
+                .rev()
+                .find(|l| l.1 < i && l.0 == local)
+                .map(|l| l.1),
+            Some((local, _)) => local_labels
+                .iter()
+                .find(|l| l.1 > i && l.0 == local)
+                .map(|l| l.1),
+            None => labels.get(name).copied(),
+        };
+        // offset from slot i to a label in the same run
+        let label_offset = |name: &str, i: usize| {
+            let location = locations[i];
+            let label = locations[find_label(name, i)?];
+            if label.run == location.run {
+                Some(label.offset - location.offset)
+            } else {
+                None
+            }
+        };
+        let mut resolved = HashMap::new();
+        let mut changed = false;
+        // pc-relative offsets of the resolved %pcrel_hi, by slot
+        let mut pcrel_hi = HashMap::new();
+        for (i, slot) in slots.iter().enumerate() {
+            let value = match slot {
+                Slot::Ref(_) if unresolved[i] => continue,
+                Slot::Ref(Reloc::Offset(name, range, _)) => {
+                    label_offset(name, i).filter(|o| (-range..*range).contains(o))
+                }
+                Slot::Ref(Reloc::PcrelHi(name)) => label_offset(name, i)
+                    .filter(|o| (-(1 << 19)..(1 << 19)).contains(&hi20(*o)))
+                    .map(|o| {
+                        pcrel_hi.insert(i, o);
+                        hi20(o) & 0xfffff
+                    }),
+                _ => continue,
+            };
+            match value {
+                Some(value) => {
+                    resolved.insert(i, value);
+                }
+                None => {
+                    unresolved[i] = true;
+                    changed = true;
+                }
+            }
+        }
+        // %pcrel_lo(label): the label is on the auipc, possibly with other
+        // labels or empty lines in between
+        for (i, slot) in slots.iter().enumerate() {
+            if let Slot::Ref(Reloc::PcrelLo(name)) = slot {
+                if unresolved[i] {
+                    continue;
+                }
+                let auipc = find_label(name, i).and_then(|label| {
+                    slots[label..]
+                        .iter()
+                        .position(|s| !matches!(s, Slot::Label(_) | Slot::Size(Some(0))))
+                        .map(|p| label + p)
+                });
+                match auipc.and_then(|a| pcrel_hi.get(&a)) {
+                    Some(offset) => {
+                        resolved.insert(i, offset - (hi20(*offset) << 12));
+                    }
+                    None => {
+                        unresolved[i] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            resolved.extend(paddings);
+            let labels = labels
+                .into_iter()
+                .map(|(name, i)| (String::from(name), locations[i]))
+                .collect();
+            return Layout {
+                values: resolved,
+                locations,
+                labels,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_labels() {
+    let lines = vec![
+        "loop:",
+        "addi a0, a0, -1",
+        "bnez a0, loop",
+        "beq a0, a1, done",
+        "j loop",
+        "done:",
+        "ret",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "loop:",
+            "# addi a0,a0,-1",
+            ".byte 0x13,0x05,0xf5,0xff",
+            "# bnez a0,loop",
+            ".byte 0xe3,0x1e,0x05,0xfe",
+            "# beq a0,a1,done",
+            ".byte 0x63,0x04,0xb5,0x00",
+            "# j loop",
+            ".byte 0x6f,0xf0,0x5f,0xff",
+            "done:",
+            "# ret",
+            ".byte 0x67,0x80,0x00,0x00",
+        ]
+    );
+}
+
+#[test]
+fn test_labels_compress() {
+    let options = Options {
+        compress: true,
+        ..Default::default()
+    };
+    let lines = vec!["loop:", "addi a0, a0, -1", "bnez a0, loop"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &options),
+        vec![
+            "loop:",
+            "# addi a0,a0,-1",
+            ".byte 0x7d,0x15",
+            "# bnez a0,loop",
+            ".byte 0xe3,0x1f,0x05,0xfe",
+        ]
+    );
+}
+
+#[test]
+fn test_labels_unknown_size() {
+    // add is dumped as text, and so is the branch over it
+    let lines = vec![
+        "loop:",
+        "add a0, a0, a1",
+        "bnez a0, loop",
+        "j external",
+        "j loop",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "loop:",
+            "add a0,a0,a1",
+            "bnez a0,loop",
+            "j external",
+            "j loop",
+        ]
+    );
+}
+
+#[test]
+fn test_local_labels() {
+    let lines = vec![
+        "1:",
+        "addi a0, a0, -1",
+        "bnez a0, 1b",
+        "beqz a1, 1f",
+        "addi a1, a1, 1",
+        "1:",
+        "j 1b",
+        "j 2f",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "1:",
+            "# addi a0,a0,-1",
+            ".byte 0x13,0x05,0xf5,0xff",
+            "# bnez a0,1b",
+            ".byte 0xe3,0x1e,0x05,0xfe",
+            "# beqz a1,1f",
+            ".byte 0x63,0x84,0x05,0x00",
+            "# addi a1,a1,1",
+            ".byte 0x93,0x85,0x15,0x00",
+            "1:",
+            "# j 1b",
+            ".byte 0x6f,0x00,0x00,0x00",
+            "j 2f",
+        ]
+    );
+}
+
+#[test]
+fn test_pcrel_labels() {
+    let lines = vec!["lla a1, data", "call func", "func:", "ret", "data:"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# lla a1,data",
+            ".Lpcrel_lla0:",
+            "# auipc a1,%pcrel_hi(data)",
+            ".byte 0x97,0x05,0x00,0x00",
+            "# addi a1,a1,%pcrel_lo(.Lpcrel_lla0)",
+            ".byte 0x93,0x85,0x45,0x01",
+            "# call func",
+            ".Lpcrel_call1:",
+            "# auipc ra,%pcrel_hi(func)",
+            ".byte 0x97,0x00,0x00,0x00",
+            "# jalr ra,%pcrel_lo(.Lpcrel_call1)(ra)",
+            ".byte 0xe7,0x80,0x80,0x00",
+            "func:",
+            "# ret",
+            ".byte 0x67,0x80,0x00,0x00",
+            "data:",
+        ]
+    );
+}
+
+#[test]
+fn test_pcrel_rounding() {
+    // 0x800 bytes away: hi20 is rounded up, lo12 is -2048
+    let slots = vec![
+        Slot::Label(String::from("l")),
+        Slot::Ref(Reloc::PcrelHi(String::from("data"))),
+        Slot::Ref(Reloc::PcrelLo(String::from("l"))),
+        Slot::Size(Some(0x7f8)),
+        Slot::Label(String::from("data")),
+    ];
+    let values = resolve(&slots).values;
+    assert_eq!(values.get(&1), Some(&1));
+    assert_eq!(values.get(&2), Some(&-2048));
+}
+
+#[test]
+fn test_pcrel_unresolved() {
+    // msg is in another section, the real assembler emits the relocations
+    let lines = vec!["la a0, msg", ".data", "msg:"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# la a0,msg",
+            ".Lpcrel_la0:",
+            "auipc a0,%pcrel_hi(msg)",
+            "addi a0,a0,%pcrel_lo(.Lpcrel_la0)",
+            ".data",
+            "msg:",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "label loop is already defined")]
+fn test_labels_duplicate() {
+    let lines = vec!["loop:", "loop:"];
+    assemble(
+        "a.s",
+        lines.into_iter().map(String::from).collect(),
+        &Options::default(),
+    );
+}
+
+#[test]
+fn test_labels_before_statements() {
+    let lines = vec![
+        "start: addi a0, a0, 1",
+        "bnez a0, start",
+        "1: nop; j 1b",
+        "msg: .asciz \"Hi\"",
+        "end: done: ret",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "start:",
+            "# addi a0,a0,1",
+            ".byte 0x13,0x05,0x15,0x00",
+            "# bnez a0,start",
+            ".byte 0xe3,0x1e,0x05,0xfe",
+            "1:",
+            "# nop",
+            ".byte 0x13,0x00,0x00,0x00",
+            "# j 1b",
+            ".byte 0x6f,0xf0,0xdf,0xff",
+            "msg:",
+            "# .asciz \"Hi\"",
+            ".byte 0x48,0x69,0x00",
+            "end:",
+            "done:",
+            "# ret",
+            ".byte 0x67,0x80,0x00,0x00",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "a.s:1: branch target odd is not aligned to 2 bytes")]
+fn test_labels_misaligned() {
+    let options = Options {
+        compress: true,
+        ..Default::default()
+    };
+    let lines = vec!["j odd", ".byte 1", "odd:", "nop"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assemble("a.s", lines, &options);
+}
+
+#[test]
+fn test_labels_rvc_alignment() {
+    let options = Options {
+        compress: true,
+        ..Default::default()
+    };
+    let lines = vec!["beqz a0, half", ".2byte 0", "half:"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &options)[1],
+        ".byte 0x63,0x03,0x05,0x00"
+    );
+}
+
+#[test]
+#[should_panic(expected = "a.s:1: branch target half is not aligned to 4 bytes")]
+fn test_labels_norvc_alignment() {
+    let lines = vec!["beqz a0, half", ".2byte 0", "half:"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assemble("a.s", lines, &Options::default());
+}
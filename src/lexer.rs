@@ -0,0 +1,196 @@
+// The tokens of a line, for parse_line: the mnemonic, registers and other
+// keywords, numbers, symbols, strings and punctuation. Whitespace of any
+// kind and length only separates tokens, so `addi\ta0, a0,1` and
+// `lw a0, 8 ( sp )` are read like `addi a0,a0,1` and `lw a0,8(sp)`.
+use super::is_keyword;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(super) enum Kind {
+    Mnemonic,
+    // registers, CSR names, rounding modes and the like
+    Register,
+    // including character literals
+    Number,
+    // labels, constants and relocation functions
+    Symbol,
+    Str,
+    Punct,
+}
+
+#[derive(PartialEq, Debug)]
+pub(super) struct Token {
+    pub(super) kind: Kind,
+    // As written, but mnemonics, keywords, numbers like 0X1F and relocation
+    // functions like %HI are lowercase. Symbols are case-sensitive.
+    pub(super) text: String,
+    // whitespace before it
+    pub(super) space: bool,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+// the length of the string or character literal at the start of text, up to
+// the end if it isn't closed
+fn quoted_len(text: &str) -> usize {
+    let quote = text.chars().next().unwrap();
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        if !escaped && c == quote {
+            return i + 1;
+        }
+        escaped = !escaped && c == '\\';
+    }
+    text.len()
+}
+
+pub(super) fn tokenize(line: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut mnemonic = String::new();
+    // the operand the tokens are in, from 0
+    let mut operand = 0;
+    let mut space = false;
+    let mut pos = 0;
+    while let Some(c) = line[pos..].chars().next() {
+        if c.is_whitespace() {
+            space = true;
+            pos += c.len_utf8();
+            continue;
+        }
+        let rest = &line[pos..];
+        let len = if c == '"' || c == '\'' {
+            quoted_len(rest)
+        } else if is_word_char(c) {
+            rest.find(|c| !is_word_char(c)).unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        let text = &rest[..len];
+        let lower = text.to_lowercase();
+        let relocation = !space && tokens.last().is_some_and(|t| t.text == "%");
+        let (kind, text) = match c {
+            '"' => (Kind::Str, String::from(text)),
+            '\'' => (Kind::Number, String::from(text)),
+            // labels and the name of name = value
+            _ if tokens.is_empty()
+                && is_word_char(c)
+                && rest[len..].trim_start().starts_with([':', '=']) =>
+            {
+                (Kind::Symbol, String::from(text))
+            }
+            _ if tokens.is_empty() && is_word_char(c) => {
+                mnemonic = lower.clone();
+                (Kind::Mnemonic, lower)
+            }
+            _ if c.is_ascii_digit() => (Kind::Number, lower),
+            _ if is_word_char(c) && relocation => (Kind::Symbol, lower),
+            _ if is_word_char(c) && is_keyword(&mnemonic, operand, &lower) => {
+                (Kind::Register, lower)
+            }
+            _ if is_word_char(c) => (Kind::Symbol, String::from(text)),
+            _ => (Kind::Punct, String::from(text)),
+        };
+        if kind == Kind::Punct && text == "," {
+            operand += 1;
+        }
+        tokens.push(Token { kind, text, space });
+        space = false;
+        pos += len;
+    }
+    tokens
+}
+
+// The text of tokens, with a single space where there was whitespace, but
+// none inside parentheses or between an offset and its (base).
+pub(super) fn join(tokens: &[Token]) -> String {
+    let mut res = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && token.space {
+            let prev = &tokens[i - 1];
+            let tight = prev.text == "("
+                || token.text == ")"
+                || (token.text == "(" && prev.kind != Kind::Punct);
+            if !tight {
+                res.push(' ');
+            }
+        }
+        res.push_str(&token.text);
+    }
+    res
+}
+
+#[cfg(test)]
+use super::parse_line;
+
+#[test]
+fn test_tokenize() {
+    let kinds = |line: &str| {
+        tokenize(line)
+            .into_iter()
+            .map(|t| (t.kind, t.text))
+            .collect::<Vec<_>>()
+    };
+    let token = |kind, text: &str| (kind, String::from(text));
+    assert_eq!(
+        kinds("LW\tA0, %LO(Sym) ( SP )"),
+        vec![
+            token(Kind::Mnemonic, "lw"),
+            token(Kind::Register, "a0"),
+            token(Kind::Punct, ","),
+            token(Kind::Punct, "%"),
+            token(Kind::Symbol, "lo"),
+            token(Kind::Punct, "("),
+            token(Kind::Symbol, "Sym"),
+            token(Kind::Punct, ")"),
+            token(Kind::Punct, "("),
+            token(Kind::Register, "sp"),
+            token(Kind::Punct, ")"),
+        ]
+    );
+    assert_eq!(
+        kinds(".ascii \"a, b\" , 'X', 0X1F"),
+        vec![
+            token(Kind::Mnemonic, ".ascii"),
+            token(Kind::Str, "\"a, b\""),
+            token(Kind::Punct, ","),
+            token(Kind::Number, "'X'"),
+            token(Kind::Punct, ","),
+            token(Kind::Number, "0x1f"),
+        ]
+    );
+    assert_eq!(
+        kinds("Loop:"),
+        vec![token(Kind::Symbol, "Loop"), token(Kind::Punct, ":")]
+    );
+    // keywords only where they are operands
+    assert_eq!(kinds("csrr a0, Cycle")[3], token(Kind::Register, "cycle"));
+    assert_eq!(
+        kinds("csrw Mstatus, a0")[1],
+        token(Kind::Register, "mstatus")
+    );
+    assert_eq!(kinds("csrr Cycle, a0")[1], token(Kind::Symbol, "Cycle"));
+    assert_eq!(kinds("la a0, Cycle")[3], token(Kind::Symbol, "Cycle"));
+    assert_eq!(kinds(".equ Time, 3")[1], token(Kind::Symbol, "Time"));
+    assert_eq!(
+        kinds("fcvt.w.s a0, fa0, RTZ")[5],
+        token(Kind::Register, "rtz")
+    );
+    assert_eq!(kinds("la a0, RTZ")[3], token(Kind::Symbol, "RTZ"));
+    assert_eq!(kinds("vsetvli a0, a1, E8")[5], token(Kind::Register, "e8"));
+    assert_eq!(kinds("la a0, Ta")[3], token(Kind::Symbol, "Ta"));
+}
+
+#[test]
+fn test_parse_line() {
+    let parse = |line: &str| parse_line(line).to_string();
+    assert_eq!(parse("addi\ta0,\t a1,   1"), "addi a0,a1,1");
+    assert_eq!(parse("lw a0, 8 ( sp )"), "lw a0,8(sp)");
+    assert_eq!(parse("sd a0, -8(sp),"), "sd a0,-8(sp)");
+    assert_eq!(
+        parse(".word end  -  msg, ( 1 << 2 ) + 1"),
+        ".word end - msg,(1 << 2) + 1"
+    );
+    assert_eq!(parse("ret\t"), "ret");
+    assert_eq!(parse("Size = 4"), "Size = 4");
+}
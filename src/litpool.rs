@@ -0,0 +1,112 @@
+// --litpool: li constants which need a long lui/addi/slli chain are loaded
+// from a .rodata pool instead, auipc + ld: 8 bytes of code and 8 bytes of
+// data. The pool is flushed at labels, .ltorg and the end of the file. RV64
+// only, every RV32 constant fits in lui + addi.
+use super::{parse_imm, pseudo, TextInstruction};
+
+// li chains longer than this are loaded from the pool
+const MAX_LI_STEPS: usize = 4;
+
+#[derive(Default)]
+pub(super) struct LitPool {
+    // label and value of the constants since the last flush
+    pending: Vec<(String, i64)>,
+    count: usize,
+}
+
+impl LitPool {
+    // auipc + ld of an li constant, None if the li chain is short enough.
+    // The %pcrel_lo part refers to the label of the auipc, .Lpcrel_li<index>.
+    pub(super) fn load(&mut self, inst: &TextInstruction, index: usize) -> Option<Vec<String>> {
+        if inst.opcode != "li" || inst.operands.len() != 2 {
+            return None;
+        }
+        let val = parse_imm(&inst.operands[1]);
+        if pseudo::li_sequence(val, false).len() <= MAX_LI_STEPS {
+            return None;
+        }
+        let entry = match self.pending.iter().find(|e| e.1 == val) {
+            Some(e) => e.0.clone(),
+            None => {
+                let entry = format!(".Llitpool{}", self.count);
+                self.count += 1;
+                self.pending.push((entry.clone(), val));
+                entry
+            }
+        };
+        let rd = &inst.operands[0];
+        let label = format!(".Lpcrel_li{}", index);
+        Some(vec![
+            format!("{}:", label),
+            format!("auipc {},%pcrel_hi({})", rd, entry),
+            format!("ld {},%pcrel_lo({})({})", rd, label, rd),
+        ])
+    }
+    // the pending constants, in .rodata so that they are never executed
+    pub(super) fn flush(&mut self) -> Vec<String> {
+        if self.pending.is_empty() {
+            return vec![];
+        }
+        let mut lines = vec![
+            String::from(".pushsection .rodata"),
+            String::from(".balign 8"),
+        ];
+        for (entry, val) in self.pending.drain(..) {
+            lines.push(format!("{}:", entry));
+            lines.push(format!(".dword {:#x}", val as u64));
+        }
+        lines.push(String::from(".popsection"));
+        lines
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_litpool() {
+    let options = Options {
+        litpool: true,
+        ..Default::default()
+    };
+    let lines = vec![
+        "li a0, 0x123456789abcdef0",
+        "li a1, 5",
+        "li a2, 0x123456789abcdef0",
+        "loop:",
+        "li a3, -0x123456789abcdef0",
+        ".ltorg",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let output = assemble("a.s", lines, &options);
+    assert_eq!(
+        output,
+        vec![
+            "# li a0,0x123456789abcdef0",
+            ".Lpcrel_li0:",
+            "auipc a0,%pcrel_hi(.Llitpool0)",
+            "ld a0,%pcrel_lo(.Lpcrel_li0)(a0)",
+            "# li a1,5",
+            ".byte 0x93,0x05,0x50,0x00",
+            "# li a2,0x123456789abcdef0",
+            ".Lpcrel_li1:",
+            "auipc a2,%pcrel_hi(.Llitpool0)",
+            "ld a2,%pcrel_lo(.Lpcrel_li1)(a2)",
+            ".pushsection .rodata",
+            ".balign 8",
+            ".Llitpool0:",
+            ".dword 0x123456789abcdef0",
+            ".popsection",
+            "loop:",
+            "# li a3,-0x123456789abcdef0",
+            ".Lpcrel_li2:",
+            "auipc a3,%pcrel_hi(.Llitpool1)",
+            "ld a3,%pcrel_lo(.Lpcrel_li2)(a3)",
+            ".pushsection .rodata",
+            ".balign 8",
+            ".Llitpool1:",
+            ".dword 0xedcba98765432110",
+            ".popsection",
+        ]
+    );
+}
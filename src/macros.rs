@@ -0,0 +1,395 @@
+// .macro name arg1, arg2=default, ... / .endm: GNU-style macros, expanded
+// before the lines are parsed. In the body \arg is replaced by the argument,
+// \() by nothing, to join an argument with the text after it, and \@ by the
+// number of expansions so far. The arguments of a call are positional or
+// name=value; :req ones must be given, a :vararg one takes the rest.
+use super::split_operands;
+use std::collections::HashMap;
+
+// Calls within calls, beyond this it's probably a recursion without end.
+// The default of --max-macro-depth.
+pub(super) const MAX_DEPTH: usize = 100;
+
+struct Param {
+    name: String,
+    default: Option<String>,
+    required: bool,
+    vararg: bool,
+}
+
+struct Macro {
+    params: Vec<Param>,
+    // with the index of the line it's written on, see source.rs
+    body: Vec<(usize, String)>,
+    // the index of the .macro line
+    at: usize,
+}
+
+// a macro call, for the expansion and the backtrace of errors in it
+pub(super) struct Call {
+    pub(super) name: String,
+    // the value of each parameter: reg=a0, by=1
+    pub(super) args: String,
+    // the index of the .macro line
+    pub(super) defined: usize,
+    pub(super) body: Vec<(usize, String)>,
+}
+
+pub(super) struct Macros {
+    macros: HashMap<String, Macro>,
+    // the macro being defined and the .macro lines nested in its body
+    defining: Option<(String, Macro, usize)>,
+    count: usize,
+    max_depth: usize,
+}
+
+// the first word of a line and the rest
+fn split_first(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], line[pos..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+fn parse_params(params: &str) -> Vec<Param> {
+    params
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (p, default) = match p.find('=') {
+                Some(pos) => (&p[..pos], Some(String::from(&p[pos + 1..]))),
+                None => (p, None),
+            };
+            let (name, qualifier) = match p.find(':') {
+                Some(pos) => (&p[..pos], &p[pos + 1..]),
+                None => (p, ""),
+            };
+            if !["", "req", "vararg"].contains(&qualifier) {
+                panic!("unknown macro parameter qualifier {}", p);
+            }
+            Param {
+                name: String::from(name),
+                default,
+                required: qualifier == "req",
+                vararg: qualifier == "vararg",
+            }
+        })
+        .collect()
+}
+
+// replace \name, \() and \@ in a line of the body
+fn substitute(line: &str, args: &HashMap<&str, String>, count: usize) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(pos) = rest.find('\\') {
+        res.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix("()") {
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('@') {
+            res.push_str(&count.to_string());
+            rest = after;
+            continue;
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        match args.get(&rest[..len]) {
+            Some(value) => {
+                res.push_str(value);
+                rest = &rest[len..];
+            }
+            None => res.push('\\'),
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+// \name of `.rept count, name` in a line of the block, on repetition `i`
+pub(super) fn substitute_counter(line: &str, name: &str, i: usize) -> String {
+    let args = [(name, i.to_string())].iter().cloned().collect();
+    substitute(line, &args, i)
+}
+
+impl Macros {
+    pub(super) fn new(max_depth: usize) -> Macros {
+        Macros {
+            macros: HashMap::new(),
+            defining: None,
+            count: 0,
+            max_depth,
+        }
+    }
+    pub(super) fn is_defining(&self) -> bool {
+        self.defining.is_some()
+    }
+    // .macro, .endm and .purgem and the lines of a definition, true if the
+    // line is one of them. `at` is the index of the line.
+    pub(super) fn define(&mut self, line: &str, at: usize) -> bool {
+        let (first, rest) = split_first(line);
+        let first = first.to_lowercase();
+        if let Some((_, mac, nested)) = &mut self.defining {
+            match first.as_str() {
+                ".endm" | ".endmacro" if *nested == 0 => {
+                    let (name, mac, _) = self.defining.take().unwrap();
+                    self.macros.insert(name, mac);
+                }
+                ".endm" | ".endmacro" => {
+                    *nested -= 1;
+                    mac.body.push((at, String::from(line)));
+                }
+                ".macro" => {
+                    *nested += 1;
+                    mac.body.push((at, String::from(line)));
+                }
+                _ => mac.body.push((at, String::from(line))),
+            }
+            return true;
+        }
+        match first.as_str() {
+            ".macro" => {
+                let (name, params) = split_first(rest);
+                let name = name.trim_end_matches(',');
+                if name.is_empty() {
+                    panic!(".macro without a name");
+                }
+                let mac = Macro {
+                    params: parse_params(params),
+                    body: vec![],
+                    at,
+                };
+                self.defining = Some((name.to_lowercase(), mac, 0));
+            }
+            ".endm" | ".endmacro" => panic!("{} without .macro", first),
+            ".purgem" => {
+                if self.macros.remove(&rest.to_lowercase()).is_none() {
+                    panic!("macro {} is not defined", rest);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+    // a call of a defined macro
+    pub(super) fn is_call(&self, line: &str) -> bool {
+        self.macros
+            .contains_key(&split_first(line).0.to_lowercase())
+    }
+    // A macro call, `depth` calls deep, or what's wrong with it. The caller
+    // expands the lines of the body in turn, up to .exitm.
+    pub(super) fn call(&mut self, line: &str, depth: usize) -> Result<Call, String> {
+        let (first, rest) = split_first(line);
+        let first = first.to_lowercase();
+        let mac = &self.macros[&first];
+        if depth >= self.max_depth {
+            return Err(format!("macro {} nested too deeply", first));
+        }
+        let (args, body) = self.body(mac, &first, rest)?;
+        let defined = mac.at;
+        self.count += 1;
+        Ok(Call {
+            name: first,
+            args,
+            defined,
+            body,
+        })
+    }
+    // at the end of the file
+    pub(super) fn finish(&self) {
+        if let Some((name, _, _)) = &self.defining {
+            panic!(".macro {} without .endm", name);
+        }
+    }
+    // the values of the parameters and the body of a call with the arguments
+    fn body(
+        &self,
+        mac: &Macro,
+        name: &str,
+        args: &str,
+    ) -> Result<(String, Vec<(usize, String)>), String> {
+        let mut args: Vec<&str> = if args.is_empty() {
+            vec![]
+        } else {
+            split_operands(args).into_iter().map(|a| a.trim()).collect()
+        };
+        // a b c instead of a, b, c
+        if args.len() == 1 && mac.params.len() > 1 {
+            args = args[0].split_whitespace().collect();
+        }
+        let mut values: HashMap<&str, String> = HashMap::new();
+        let mut position = 0;
+        for (i, arg) in args.iter().enumerate() {
+            let keyword = arg.find('=').and_then(|pos| {
+                let param = mac.params.iter().find(|p| p.name == arg[..pos].trim())?;
+                Some((param, arg[pos + 1..].trim()))
+            });
+            if let Some((param, value)) = keyword {
+                values.insert(&param.name, String::from(value));
+                continue;
+            }
+            let param = match mac.params.get(position) {
+                Some(param) => param,
+                None => return Err(format!("too many arguments for macro {}", name)),
+            };
+            position += 1;
+            if param.vararg {
+                values.insert(&param.name, args[i..].join(","));
+                break;
+            }
+            values.insert(&param.name, String::from(*arg));
+        }
+        for param in &mac.params {
+            if !values.contains_key(param.name.as_str()) {
+                if param.required {
+                    return Err(format!("missing argument {} of macro {}", param.name, name));
+                }
+                let default = param.default.clone().unwrap_or_default();
+                values.insert(&param.name, default);
+            }
+        }
+        let args = mac
+            .params
+            .iter()
+            .map(|p| format!("{}={}", p.name, values[p.name.as_str()]))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let body = mac
+            .body
+            .iter()
+            .map(|(at, line)| (*at, substitute(line, &values, self.count)))
+            .collect();
+        Ok((args, body))
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_macros() {
+    let lines = vec![
+        ".macro inc reg, by=1",
+        "addi \\reg, \\reg, \\by",
+        ".endm",
+        ".macro twice op:req, args:vararg",
+        "\\op \\args",
+        "\\op \\args",
+        ".endm",
+        "INC a0",
+        "inc a1, 2",
+        "inc by=3, reg=a2",
+        "twice inc, a3",
+        "inc a4 4",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# addi a0,a0,1",
+            ".byte 0x13,0x05,0x15,0x00",
+            "# addi a1,a1,2",
+            ".byte 0x93,0x85,0x25,0x00",
+            "# addi a2,a2,3",
+            ".byte 0x13,0x06,0x36,0x00",
+            "# addi a3,a3,1",
+            ".byte 0x93,0x86,0x16,0x00",
+            "# addi a3,a3,1",
+            ".byte 0x93,0x86,0x16,0x00",
+            "# addi a4,a4,4",
+            ".byte 0x13,0x07,0x47,0x00",
+        ]
+    );
+}
+
+#[test]
+fn test_macro_labels() {
+    let lines = vec![
+        ".macro wait reg",
+        "\\reg\\()_loop\\@:",
+        "addi \\reg, \\reg, -1",
+        "bnez \\reg, \\reg\\()_loop\\@",
+        ".exitm",
+        "nop",
+        ".endm",
+        "wait a0",
+        "wait a1",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "a0_loop0:",
+            "# addi a0,a0,-1",
+            ".byte 0x13,0x05,0xf5,0xff",
+            "# bnez a0,a0_loop0",
+            ".byte 0xe3,0x1e,0x05,0xfe",
+            "a1_loop1:",
+            "# addi a1,a1,-1",
+            ".byte 0x93,0x85,0xf5,0xff",
+            "# bnez a1,a1_loop1",
+            ".byte 0xe3,0x9e,0x05,0xfe",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "missing argument op of macro twice")]
+fn test_macro_required() {
+    let lines = vec![".macro twice op:req", "\\op", "\\op", ".endm", "twice"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+#[should_panic(expected = "macro loop nested too deeply")]
+fn test_macro_recursion() {
+    let lines = vec![".macro loop", "loop", ".endm", "loop"];
+    let lines = lines.into_iter().map(String::from).collect();
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+#[should_panic(expected = "a.s:5: macro deep nested too deeply\n  \
+                           in macro deep(n=3 - 1 - 1), defined at a.s:1, called at a.s:5\n  \
+                           in macro deep(n=3 - 1), defined at a.s:1, called at a.s:5\n  \
+                           in macro deep(n=3), defined at a.s:1, called at a.s:9")]
+fn test_macro_max_depth() {
+    let lines = vec![
+        ".macro deep n",
+        ".if \\n == 0",
+        "nop",
+        ".else",
+        "deep \\n - 1",
+        ".endif",
+        ".endm",
+        "deep 2",
+        "deep 3",
+    ];
+    let lines: Vec<String> = lines.into_iter().map(String::from).collect();
+    let options = Options {
+        max_macro_depth: Some(3),
+        ..Default::default()
+    };
+    assemble("a.s", lines, &options);
+}
+
+#[test]
+#[should_panic(expected = "a.s:5: too many arguments for macro inc\n  \
+                           in macro twice(reg=a0), defined at a.s:4, called at a.s:8")]
+fn test_macro_calls() {
+    let lines = vec![
+        ".macro inc reg",
+        "addi \\reg, \\reg, 1",
+        ".endm",
+        ".macro twice reg",
+        "inc \\reg, a1",
+        "inc \\reg",
+        ".endm",
+        "twice a0",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assemble("a.s", lines, &Options::default());
+}
@@ -1,10 +1,36 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::Read;
 use std::{fmt, io};
 
+mod archive;
+mod batch;
+mod compressed;
+mod crypto;
+mod csr;
+mod data;
+mod expr;
+mod insn;
+mod labels;
+mod lexer;
+mod litpool;
+mod macros;
+mod opcodes;
+mod packed;
+mod peephole;
+mod privileged;
+mod pseudo;
+#[cfg(all(test, feature = "riscv-opcodes"))]
+mod riscv_opcodes;
+mod rv32;
+mod sections;
+mod source;
+mod symbols;
+mod thead;
+mod vector;
+
 lazy_static! {
     pub static ref REG_MAP: HashMap<String, u8> = {
         let mut map = HashMap::new();
@@ -41,6 +67,29 @@ lazy_static! {
         map.insert(String::from("t4"), 29);
         map.insert(String::from("t5"), 30);
         map.insert(String::from("t6"), 31);
+        // numeric names, as in disassembler output
+        for i in 0..32 {
+            map.insert(format!("x{}", i), i);
+        }
+        map
+    };
+    pub static ref FP_REG_MAP: HashMap<String, u8> = {
+        let mut map = HashMap::new();
+        for i in 0..32 {
+            map.insert(format!("f{}", i), i);
+        }
+        for i in 0..8 {
+            map.insert(format!("ft{}", i), i); // ft0-ft7 == f0-f7
+            map.insert(format!("fa{}", i), i + 10); // fa0-fa7 == f10-f17
+        }
+        map.insert(String::from("fs0"), 8);
+        map.insert(String::from("fs1"), 9);
+        for i in 2..12 {
+            map.insert(format!("fs{}", i), i + 16); // fs2-fs11 == f18-f27
+        }
+        for i in 8..12 {
+            map.insert(format!("ft{}", i), i + 20); // ft8-ft11 == f28-f31
+        }
         map
     };
 }
@@ -48,13 +97,272 @@ lazy_static! {
 fn reg_name2value(name: &str) -> u8 {
     let res = REG_MAP.get(name);
     if res.is_none() {
+        if FP_REG_MAP.contains_key(name) {
+            panic!(
+                "{} is a floating-point register, expected an integer register",
+                name
+            );
+        }
         panic!("can't find register name {}", name);
     }
-    res.unwrap().clone()
+    *res.unwrap()
+}
+
+fn freg_name2value(name: &str) -> u8 {
+    let res = FP_REG_MAP.get(name);
+    if res.is_none() {
+        if REG_MAP.contains_key(name) {
+            panic!(
+                "{} is an integer register, expected a floating-point register",
+                name
+            );
+        }
+        panic!("can't find floating-point register name {}", name);
+    }
+    *res.unwrap()
+}
+
+// dynamic rounding mode, used when no rounding mode is given
+const RM_DYN: u8 = 0b111;
+
+// rounding mode operand of floating-point instructions, 0b101 and 0b110
+// are reserved
+fn parse_rm(name: &str) -> u8 {
+    match name {
+        "rne" => 0b000,
+        "rtz" => 0b001,
+        "rdn" => 0b010,
+        "rup" => 0b011,
+        "rmm" => 0b100,
+        "dyn" => RM_DYN,
+        _ => panic!(
+            "invalid rounding mode {}, expected one of rne, rtz, rdn, rup, rmm, dyn",
+            name
+        ),
+    }
+}
+
+// fmt field of floating-point instructions
+fn fp_fmt(suffix: &str) -> Option<u8> {
+    match suffix {
+        "s" => Some(0b00),
+        "d" => Some(0b01),
+        // Zfh
+        "h" => Some(0b10),
+        "q" => Some(0b11),
+        _ => None,
+    }
+}
+
+// Zfa fli immediates by rs1 index. The minimum normal value (1) depends on
+// the format and is only accepted as "min", nan (31) only by name.
+const FLI_VALUES: [f64; 32] = [
+    -1.0,
+    f64::NAN,
+    1.0 / 65536.0,
+    1.0 / 32768.0,
+    1.0 / 256.0,
+    1.0 / 128.0,
+    0.0625,
+    0.125,
+    0.25,
+    0.3125,
+    0.375,
+    0.4375,
+    0.5,
+    0.625,
+    0.75,
+    0.875,
+    1.0,
+    1.25,
+    1.5,
+    1.75,
+    2.0,
+    2.5,
+    3.0,
+    4.0,
+    8.0,
+    16.0,
+    128.0,
+    256.0,
+    32768.0,
+    65536.0,
+    f64::INFINITY,
+    f64::NAN,
+];
+
+fn fli_index(imm: &str) -> u32 {
+    match imm {
+        "min" => 1,
+        "nan" => 31,
+        _ => imm
+            .parse::<f64>()
+            .ok()
+            .and_then(|v| FLI_VALUES.iter().position(|c| *c == v))
+            .unwrap_or_else(|| panic!("{} is not a valid fli constant", imm)) as u32,
+    }
+}
+
+// significand bits of a floating-point format
+fn fp_precision(fmt: u8) -> u32 {
+    match fmt {
+        0b00 => 24,
+        0b01 => 53,
+        0b10 => 11,
+        0b11 => 113,
+        _ => unreachable!(),
+    }
+}
+
+// integer operand type of fcvt, encoded in rs2
+fn int_type(suffix: &str) -> Option<u8> {
+    match suffix {
+        "w" => Some(0b00),
+        "wu" => Some(0b01),
+        "l" => Some(0b10),
+        "lu" => Some(0b11),
+        _ => None,
+    }
+}
+
+fn int_width(int_type: u8) -> u32 {
+    if int_type < 0b10 {
+        32
+    } else {
+        64
+    }
+}
+
+// memory operand: "8(a0)" -> ("8", "a0")
+fn split_address(operand: &str) -> (&str, &str) {
+    let begin = operand.find('(');
+    let end = operand.rfind(')');
+    if begin.is_none() || end.is_none() {
+        panic!("invalid address operand {}", operand);
+    }
+    let begin = begin.unwrap();
+    (
+        operand[..begin].trim(),
+        operand[begin + 1..end.unwrap()].trim(),
+    )
+}
+
+// memory operand of A-extension: "(a0)" or "0(a0)"
+fn address_reg_name(operand: &str) -> &str {
+    let (offset, reg) = split_address(operand);
+    if !offset.is_empty() && offset != "0" {
+        panic!("offset must be zero in address operand {}", operand);
+    }
+    reg
+}
+
+// predecessor/successor set of fence: letters selected in order from "iorw"
+fn parse_fence_set(set: &str) -> u32 {
+    let mut res = 0;
+    let mut rest = set;
+    for (letter, bit) in [('i', 8), ('o', 4), ('r', 2), ('w', 1)].iter() {
+        if let Some(r) = rest.strip_prefix(*letter) {
+            res |= bit;
+            rest = r;
+        }
+    }
+    if set.is_empty() || !rest.is_empty() {
+        panic!(
+            "invalid fence operand {}, must be letters in order from iorw",
+            set
+        );
+    }
+    res
+}
+
+// 0x hexadecimal, 0b binary, 0o octal or decimal, with optional _ separators
+fn parse_literal(value: &str) -> Option<u64> {
+    let value = value.replace('_', "");
+    let (digits, radix) = if let Some(hex) = value.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(bin) = value.strip_prefix("0b") {
+        (bin, 2)
+    } else if let Some(oct) = value.strip_prefix("0o") {
+        (oct, 8)
+    } else {
+        (value.as_str(), 10)
+    };
+    u64::from_str_radix(digits, radix).ok()
+}
+
+// signed immediate, any literal of parse_literal. Values above i64::MAX
+// wrap, e.g. 0xffffffffffffffff is -1.
+fn parse_imm(imm: &str) -> i64 {
+    let (negative, literal) = match imm.strip_prefix('-') {
+        Some(literal) => (true, literal),
+        None => (false, imm),
+    };
+    match parse_literal(literal) {
+        Some(val) if negative => (val as i64).wrapping_neg(),
+        Some(val) => val as i64,
+        None => panic!("invalid immediate {}", imm),
+    }
+}
+
+// plain numbers, not symbols or %lo(symbol)
+fn is_number(value: &str) -> bool {
+    value
+        .trim_start_matches('-')
+        .starts_with(|c: char| c.is_ascii_digit())
+}
+
+// 12-bit signed offset of load/store, empty means 0
+fn parse_offset(offset: &str) -> u32 {
+    if offset.is_empty() {
+        return 0;
+    }
+    let val = parse_imm(offset);
+    if !(-2048..=2047).contains(&val) {
+        panic!("offset {} out of range", offset);
+    }
+    (val as u32) & 0xfff
+}
+
+// Instruction length in bytes, encoded in the lowest bits of the first
+// 16-bit parcel. None for the reserved 192-bit and longer encodings.
+fn instruction_length(parcel: u16) -> Option<usize> {
+    if parcel & 0b11 != 0b11 {
+        Some(2)
+    } else if parcel & 0b11100 != 0b11100 {
+        Some(4)
+    } else if parcel & 0b100000 == 0 {
+        Some(6)
+    } else if parcel & 0b1000000 == 0 {
+        Some(8)
+    } else {
+        let nnn = ((parcel >> 12) & 0b111) as usize;
+        if nnn == 0b111 {
+            None
+        } else {
+            Some(10 + 2 * nnn)
+        }
+    }
+}
+
+// unsigned value, any literal of parse_literal: raw .insn values, CSR numbers
+fn parse_unsigned(value: &str) -> u64 {
+    match parse_literal(value) {
+        Some(val) => val,
+        None => panic!("invalid value {}", value),
+    }
+}
+
+fn parse_shamt(value: &str, max: u8) -> u8 {
+    match parse_imm(value) {
+        val if (0..=max.into()).contains(&val) => val as u8,
+        _ => panic!("shift amount {} out of range [0, {}]", value, max),
+    }
 }
 
 struct BinaryInstruction {
-    pub data: [u8; 4],
+    // 4 bytes, 2 bytes for compressed instructions, see instruction_length
+    // for others
+    pub data: Vec<u8>,
     flag_shamt: bool,
     flag_funct6: bool,
 }
@@ -71,44 +379,111 @@ impl fmt::Display for BinaryInstruction {
             }
         }
 
-        write!(
-            f,
-            ".byte 0x{:02x},0x{:02x},0x{:02x},0x{:02x}",
-            self.data[0], self.data[1], self.data[2], self.data[3]
-        )
+        let bytes: Vec<String> = self.data.iter().map(|b| format!("0x{:02x}", b)).collect();
+        write!(f, ".byte {}", bytes.join(","))
     }
 }
 
 impl BinaryInstruction {
     fn new() -> BinaryInstruction {
+        BinaryInstruction::with_len(4)
+    }
+    fn new_compressed() -> BinaryInstruction {
+        BinaryInstruction::with_len(2)
+    }
+    fn with_len(len: usize) -> BinaryInstruction {
+        BinaryInstruction::from_bytes(vec![0; len])
+    }
+    // bytes of data directives, see data.rs
+    fn from_bytes(data: Vec<u8>) -> BinaryInstruction {
         BinaryInstruction {
-            data: [0; 4],
+            data,
             flag_shamt: false,
             flag_funct6: false,
         }
     }
+    // R-type view of the instruction: name, begin, end
+    const FIELDS: [(&'static str, u8, u8); 6] = [
+        ("funct7", 25, 31),
+        ("rs2", 20, 24),
+        ("rs1", 15, 19),
+        ("funct3", 12, 14),
+        ("rd", 7, 11),
+        ("opcode", 0, 6),
+    ];
+    // CR-type view of compressed instructions
+    const COMPRESSED_FIELDS: [(&'static str, u8, u8); 4] = [
+        ("funct4", 12, 15),
+        ("rd/rs1", 7, 11),
+        ("rs2", 2, 6),
+        ("op", 0, 1),
+    ];
+    fn fields(&self) -> &'static [(&'static str, u8, u8)] {
+        if self.data.len() == 2 {
+            &BinaryInstruction::COMPRESSED_FIELDS
+        } else {
+            &BinaryInstruction::FIELDS
+        }
+    }
+    fn dump(bits: Vec<u8>) -> String {
+        let str: Vec<String> = bits.into_iter().map(|i| format!("{}", i)).rev().collect();
+        str.join("")
+    }
     fn to_bits_string(&self) -> String {
-        fn dump(bits: Vec<u8>) -> String {
-            let str: Vec<String> = bits.into_iter().map(|i| format!("{}", i)).rev().collect();
-            str.join("")
-        }
-        let opcode = self.get(0, 6);
-        let rd = self.get(7, 11);
-        let funct3 = self.get(12, 14);
-        let rs1 = self.get(15, 19);
-        let rs2 = self.get(20, 24);
-        let funct7 = self.get(25, 31);
-        format!(
-            "funct7: {} rs2: {} rs1: {} funct3: {} rd: {} opcode: {}",
-            dump(funct7),
-            dump(rs2),
-            dump(rs1),
-            dump(funct3),
-            dump(rd),
-            dump(opcode)
-        )
+        let fields: Vec<String> = self
+            .fields()
+            .iter()
+            .map(|(name, begin, end)| {
+                format!(
+                    "{}: {}",
+                    name,
+                    BinaryInstruction::dump(self.get(*begin, *end))
+                )
+            })
+            .collect();
+        fields.join(" ")
+    }
+    // fields which are different from the expected instruction
+    #[cfg(test)]
+    fn diff_fields(&self, expected: &BinaryInstruction) -> Vec<String> {
+        if self.data.len() != expected.data.len() {
+            return vec![format!(
+                "length: expected {} bytes, got {} bytes",
+                expected.data.len(),
+                self.data.len()
+            )];
+        }
+        self.fields()
+            .iter()
+            .filter(|(_, begin, end)| self.get(*begin, *end) != expected.get(*begin, *end))
+            .map(|(name, begin, end)| {
+                format!(
+                    "{}: expected {}, got {}",
+                    name,
+                    BinaryInstruction::dump(expected.get(*begin, *end)),
+                    BinaryInstruction::dump(self.get(*begin, *end))
+                )
+            })
+            .collect()
+    }
+    // ".byte 0x3b,0x86,0x5d,0x09", the reverse of Display
+    #[cfg(test)]
+    fn from_byte_line(line: &str) -> Option<BinaryInstruction> {
+        let bytes: Vec<u8> = line
+            .strip_prefix(".byte ")?
+            .split(',')
+            .map(|b| u8::from_str_radix(b.trim().trim_start_matches("0x"), 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if instruction_length(u16::from(bytes[0]) | (u16::from(*bytes.get(1)?) << 8))
+            != Some(bytes.len())
+        {
+            return None;
+        }
+        let mut res = BinaryInstruction::new();
+        res.data = bytes;
+        Some(res)
     }
-    fn bits_array(val: u8, count: usize) -> Vec<u8> {
+    fn bits_array(val: u32, count: usize) -> Vec<u8> {
         assert_eq!(val >> count, 0);
         let mut res = Vec::<u8>::new();
         for index in 0..count {
@@ -127,7 +502,7 @@ impl BinaryInstruction {
         let begin = begin as usize;
         let end = end as usize;
 
-        assert_eq!((end - begin + 1) as usize, bits.len());
+        assert_eq!(end - begin + 1, bits.len());
         for index in begin..=end {
             let byte_index = index / 8;
             let bit_index = index % 8;
@@ -155,74 +530,228 @@ impl BinaryInstruction {
                 res.push(0);
             }
         }
-        assert_eq!((end - begin + 1) as usize, res.len());
+        assert_eq!(end - begin + 1, res.len());
         res
     }
     fn set_opcode(&mut self, opcode: u8) {
-        let bits = BinaryInstruction::bits_array(opcode, 7);
+        let bits = BinaryInstruction::bits_array(opcode.into(), 7);
         self.set(0, 6, bits);
     }
     fn set_rd(&mut self, rd: &str) {
         let rd = reg_name2value(rd);
-        let bits = BinaryInstruction::bits_array(rd, 5);
+        let bits = BinaryInstruction::bits_array(rd.into(), 5);
         self.set(7, 11, bits);
     }
     fn set_funct3(&mut self, funct3: u8) {
-        let bits = BinaryInstruction::bits_array(funct3, 3);
+        let bits = BinaryInstruction::bits_array(funct3.into(), 3);
         self.set(12, 14, bits);
     }
     fn set_rs1(&mut self, rs1: &str) {
         let rs1 = reg_name2value(rs1);
-        let bits = BinaryInstruction::bits_array(rs1, 5);
+        let bits = BinaryInstruction::bits_array(rs1.into(), 5);
         self.set(15, 19, bits);
     }
     fn set_rs2(&mut self, rs2: &str) {
         let rs2 = reg_name2value(rs2);
-        let bits = BinaryInstruction::bits_array(rs2, 5);
+        let bits = BinaryInstruction::bits_array(rs2.into(), 5);
         self.set(20, 24, bits);
     }
 
     fn set_shamt(&mut self, shamt: u8) {
-        let bits = BinaryInstruction::bits_array(shamt, 6);
+        let bits = BinaryInstruction::bits_array(shamt.into(), 6);
         self.set(20, 25, bits);
         self.flag_shamt = true;
     }
     // funct6 <-> shamt
     fn set_funct6(&mut self, funct6: u8) {
-        let bits = BinaryInstruction::bits_array(funct6, 6);
+        let bits = BinaryInstruction::bits_array(funct6.into(), 6);
         self.set(26, 31, bits);
         self.flag_funct6 = true;
     }
 
     fn set_funct7(&mut self, funct7: u8) {
-        let bits = BinaryInstruction::bits_array(funct7, 7);
+        let bits = BinaryInstruction::bits_array(funct7.into(), 7);
         self.set(25, 31, bits);
     }
-    fn set_operands(&mut self, operands: &Vec<String>) {
+    // A-extension: funct5 + aq + rl <-> funct7
+    fn set_funct5(&mut self, funct5: u8) {
+        let bits = BinaryInstruction::bits_array(funct5.into(), 5);
+        self.set(27, 31, bits);
+    }
+    // fmt of floating-point instructions, lower 2 bits of funct7
+    fn set_fmt(&mut self, fmt: u8) {
+        let bits = BinaryInstruction::bits_array(fmt.into(), 2);
+        self.set(25, 26, bits);
+    }
+    fn set_frd(&mut self, rd: &str) {
+        let rd = freg_name2value(rd);
+        let bits = BinaryInstruction::bits_array(rd.into(), 5);
+        self.set(7, 11, bits);
+    }
+    fn set_frs1(&mut self, rs1: &str) {
+        let rs1 = freg_name2value(rs1);
+        let bits = BinaryInstruction::bits_array(rs1.into(), 5);
+        self.set(15, 19, bits);
+    }
+    fn set_frs2(&mut self, rs2: &str) {
+        let rs2 = freg_name2value(rs2);
+        let bits = BinaryInstruction::bits_array(rs2.into(), 5);
+        self.set(20, 24, bits);
+    }
+    fn set_frs3(&mut self, rs3: &str) {
+        let rs3 = freg_name2value(rs3);
+        let bits = BinaryInstruction::bits_array(rs3.into(), 5);
+        self.set(27, 31, bits);
+    }
+    fn set_aq_rl(&mut self, aq: u8, rl: u8) {
+        self.set(26, 26, vec![aq]);
+        self.set(25, 25, vec![rl]);
+    }
+    fn set_operands(&mut self, operands: &[String]) {
         assert_eq!(operands.len(), 3);
 
         self.set_rd(operands[0].as_ref());
         self.set_rs1(operands[1].as_ref());
         self.set_rs2(operands[2].as_ref());
     }
-    fn set_2operands(&mut self, operands: &Vec<String>, rs2: u8) {
+    fn set_2operands(&mut self, operands: &[String], rs2: u8) {
         assert_eq!(operands.len(), 2);
 
         self.set_rd(operands[0].as_ref());
         self.set_rs1(operands[1].as_ref());
 
-        let bits = BinaryInstruction::bits_array(rs2, 5);
+        let bits = BinaryInstruction::bits_array(rs2.into(), 5);
+        self.set(20, 24, bits);
+    }
+
+    // lr.w rd, (rs1)
+    fn set_lr_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 2);
+
+        self.set_rd(operands[0].as_ref());
+        self.set_rs1(address_reg_name(operands[1].as_ref()));
+
+        let bits = BinaryInstruction::bits_array(0, 5);
+        self.set(20, 24, bits);
+    }
+    // sc.w rd, rs2, (rs1)
+    fn set_atomic_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 3);
+
+        self.set_rd(operands[0].as_ref());
+        self.set_rs2(operands[1].as_ref());
+        self.set_rs1(address_reg_name(operands[2].as_ref()));
+    }
+
+    // fadd.s rd, rs1, rs2
+    fn set_fp_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 3);
+
+        self.set_frd(operands[0].as_ref());
+        self.set_frs1(operands[1].as_ref());
+        self.set_frs2(operands[2].as_ref());
+    }
+    // fsqrt.s rd, rs1
+    fn set_fp_2operands(&mut self, operands: &[String], rs2: u8) {
+        assert_eq!(operands.len(), 2);
+
+        self.set_frd(operands[0].as_ref());
+        self.set_frs1(operands[1].as_ref());
+
+        let bits = BinaryInstruction::bits_array(rs2.into(), 5);
         self.set(20, 24, bits);
     }
+    // fmadd.s rd, rs1, rs2, rs3
+    fn set_fp_r4_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 4);
+
+        self.set_frd(operands[0].as_ref());
+        self.set_frs1(operands[1].as_ref());
+        self.set_frs2(operands[2].as_ref());
+        self.set_frs3(operands[3].as_ref());
+    }
+    // flw rd, offset(rs1)
+    fn set_fp_load_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 2);
+
+        self.set_frd(operands[0].as_ref());
+        let (offset, rs1) = split_address(operands[1].as_ref());
+        self.set_rs1(rs1);
+        let bits = BinaryInstruction::bits_array(parse_offset(offset), 12);
+        self.set(20, 31, bits);
+    }
+    // fsw rs2, offset(rs1)
+    fn set_fp_store_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 2);
+
+        self.set_frs2(operands[0].as_ref());
+        let (offset, rs1) = split_address(operands[1].as_ref());
+        self.set_rs1(rs1);
+        let imm = parse_offset(offset);
+        let bits = BinaryInstruction::bits_array(imm & 0x1f, 5);
+        self.set(7, 11, bits);
+        let bits = BinaryInstruction::bits_array(imm >> 5, 7);
+        self.set(25, 31, bits);
+    }
 
-    fn set_immediate(&mut self, operands: &Vec<String>) {
+    // Instruction in a vendor opcode space:
+    //   .insn custom0 funct3, funct7, rd, rs1, rs2    (R-type)
+    //   .insn custom0 funct3, rd, rs1, imm            (I-type)
+    fn custom(space: &str, operands: &[String]) -> BinaryInstruction {
+        let opcode = match space.replace(&['-', '_'][..], "").as_str() {
+            "custom0" => 0b0001011,
+            "custom1" => 0b0101011,
+            "custom2" => 0b1011011,
+            "custom3" => 0b1111011,
+            _ => panic!(
+                "unknown opcode space {}, expected custom0, custom1, custom2 or custom3",
+                space
+            ),
+        };
+        let funct = |operand: &str, bits: u32| -> u8 {
+            let val = parse_imm(operand);
+            if val < 0 || val >= 1 << bits {
+                panic!(
+                    "{} {} doesn't fit into {} bits of the {} opcode space",
+                    if bits == 3 { "funct3" } else { "funct7" },
+                    operand,
+                    bits,
+                    space
+                );
+            }
+            val as u8
+        };
+
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(opcode);
+        res.set_funct3(funct(&operands[0], 3));
+        match operands.len() {
+            5 => {
+                res.set_funct7(funct(&operands[1], 7));
+                res.set_operands(&operands[2..]);
+            }
+            4 => {
+                res.set_rd(operands[1].as_ref());
+                res.set_rs1(operands[2].as_ref());
+                let bits = BinaryInstruction::bits_array(parse_offset(&operands[3]), 12);
+                res.set(20, 31, bits);
+            }
+            _ => panic!(
+                ".insn {} expects funct3, funct7, rd, rs1, rs2 or funct3, rd, rs1, imm",
+                space
+            ),
+        }
+        res
+    }
+
+    fn set_immediate(&mut self, operands: &[String]) {
         self.set_rd(operands[0].as_ref());
         self.set_rs1(operands[1].as_ref());
-        let shamt = operands[2].parse::<u8>().unwrap();
-        self.set_shamt(shamt);
+        self.set_shamt(parse_shamt(&operands[2], 63));
     }
 }
 
+#[derive(Clone)]
 struct TextInstruction {
     pub opcode: String,
     pub operands: Vec<String>,
@@ -231,7 +760,11 @@ struct TextInstruction {
 
 impl fmt::Display for TextInstruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} {}", self.opcode, self.operands.join(","))
+        if self.operands.is_empty() {
+            write!(f, "{}", self.opcode)
+        } else {
+            write!(f, "{} {}", self.opcode, self.operands.join(","))
+        }
     }
 }
 
@@ -244,6 +777,10 @@ impl TextInstruction {
         }
     }
     fn convert(&self) -> Option<BinaryInstruction> {
+        // custom instructions from --opcodes take precedence
+        if let Some(res) = self.convert_user() {
+            return Some(res);
+        }
         let mut res = BinaryInstruction::new();
         match self.opcode.as_ref() {
             "add.uw" => {
@@ -316,6 +853,33 @@ impl TextInstruction {
                 res.set_immediate(&self.operands);
                 Some(res)
             }
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "beqz" | "bnez" | "blez" | "bgez"
+            | "bltz" | "bgtz" | "bgt" | "ble" | "bgtu" | "bleu" => self.convert_branch(),
+            "addi" | "addiw" | "slti" | "sltiu" | "xori" | "ori" | "andi" | "lb" | "lh" | "lw"
+            | "ld" | "lbu" | "lhu" | "lwu" | "sb" | "sh" | "sw" | "sd" => self.convert_imm12(),
+            "brev8" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b101);
+                res.set_funct7(0b0110100);
+                res.set_2operands(&self.operands, 0b00111);
+                Some(res)
+            }
+            // Zicbom/Zicboz: cbo.clean (rs1), the imm field selects the operation
+            "cbo.clean" | "cbo.flush" | "cbo.inval" | "cbo.zero" => {
+                assert_eq!(self.operands.len(), 1);
+                let funct12 = match self.opcode.as_str() {
+                    "cbo.inval" => 0,
+                    "cbo.clean" => 1,
+                    "cbo.flush" => 2,
+                    _ => 4,
+                };
+                res.set_opcode(0b0001111);
+                res.set_funct3(0b010);
+                res.set_rd("zero");
+                res.set_rs1(address_reg_name(self.operands[0].as_ref()));
+                res.set(20, 31, BinaryInstruction::bits_array(funct12, 12));
+                Some(res)
+            }
             "clmul" => {
                 res.set_opcode(0b0110011);
                 res.set_funct3(0b001);
@@ -379,6 +943,149 @@ impl TextInstruction {
                 res.set_2operands(&self.operands, 0b00001);
                 Some(res)
             }
+            // Zicond
+            "czero.eqz" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b101);
+                res.set_funct7(0b0000111);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            "czero.nez" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b111);
+                res.set_funct7(0b0000111);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            "fence" | "fence.tso" => {
+                let (fm, pred, succ) = if self.opcode == "fence.tso" {
+                    assert!(self.operands.is_empty());
+                    (0b1000, 0b0011, 0b0011)
+                } else if self.operands.is_empty() {
+                    (0, 0b1111, 0b1111)
+                } else {
+                    assert_eq!(self.operands.len(), 2);
+                    (
+                        0,
+                        parse_fence_set(&self.operands[0]),
+                        parse_fence_set(&self.operands[1]),
+                    )
+                };
+                res.set_opcode(0b0001111);
+                res.set_funct3(0);
+                res.set(28, 31, BinaryInstruction::bits_array(fm, 4));
+                res.set(24, 27, BinaryInstruction::bits_array(pred, 4));
+                res.set(20, 23, BinaryInstruction::bits_array(succ, 4));
+                Some(res)
+            }
+            "fence.i" => {
+                // Zifencei: all fields are zero
+                assert!(self.operands.is_empty());
+                res.set_opcode(0b0001111);
+                res.set_funct3(0b001);
+                Some(res)
+            }
+            "flw" => {
+                res.set_opcode(0b0000111);
+                res.set_funct3(0b010);
+                res.set_fp_load_operands(&self.operands);
+                Some(res)
+            }
+            "fsw" => {
+                res.set_opcode(0b0100111);
+                res.set_funct3(0b010);
+                res.set_fp_store_operands(&self.operands);
+                Some(res)
+            }
+            "fld" => {
+                res.set_opcode(0b0000111);
+                res.set_funct3(0b011);
+                res.set_fp_load_operands(&self.operands);
+                Some(res)
+            }
+            "fsd" => {
+                res.set_opcode(0b0100111);
+                res.set_funct3(0b011);
+                res.set_fp_store_operands(&self.operands);
+                Some(res)
+            }
+            "flq" => {
+                res.set_opcode(0b0000111);
+                res.set_funct3(0b100);
+                res.set_fp_load_operands(&self.operands);
+                Some(res)
+            }
+            "fsq" => {
+                res.set_opcode(0b0100111);
+                res.set_funct3(0b100);
+                res.set_fp_store_operands(&self.operands);
+                Some(res)
+            }
+            // j offset, jal [rd,] offset: only numeric offsets, symbols are left
+            // to the real assembler
+            "j" | "jal" => {
+                let ops = &self.operands;
+                let (rd, offset) = match (self.opcode.as_str(), ops.len()) {
+                    ("j", 1) => ("zero", ops[0].as_str()),
+                    ("jal", 1) => ("ra", ops[0].as_str()),
+                    ("jal", 2) => (ops[0].as_str(), ops[1].as_str()),
+                    _ => panic!("wrong number of operands for {}", self.opcode),
+                };
+                if !is_number(offset) {
+                    return None;
+                }
+                if let Some(e) = labels::offset_error(self) {
+                    panic!("{}", e);
+                }
+                let ops: Vec<String> = vec!["jal", rd, offset]
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                Some(insn::insn_format("j", &ops))
+            }
+            // lui/auipc rd, imm20: only numeric immediates, %hi(sym) and the
+            // like are left to the real assembler
+            "lui" | "auipc" => {
+                assert_eq!(self.operands.len(), 2);
+                let imm = self.operands[1].as_str();
+                if !is_number(imm) {
+                    return None;
+                }
+                if !(0..=0xfffff).contains(&parse_imm(imm)) {
+                    panic!("immediate {} out of range [0, 1048575]", imm);
+                }
+                let ops: Vec<String> = vec![self.opcode.as_str(), &self.operands[0], imm]
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                Some(insn::insn_format("u", &ops))
+            }
+            // jr rs, jalr rs (rd is ra), jalr rd, offset(rs) and jalr rd, rs, offset
+            "jalr" | "jr" => {
+                let ops = &self.operands;
+                let (rd, rs1, offset) = match (self.opcode.as_str(), ops.len()) {
+                    ("jr", 1) => ("zero", ops[0].as_str(), "0"),
+                    ("jalr", 1) => ("ra", ops[0].as_str(), "0"),
+                    ("jalr", 2) if ops[1].contains('(') => {
+                        let (offset, rs1) = split_address(&ops[1]);
+                        let offset = if offset.is_empty() { "0" } else { offset };
+                        (ops[0].as_str(), rs1, offset)
+                    }
+                    ("jalr", 2) => (ops[0].as_str(), ops[1].as_str(), "0"),
+                    ("jalr", 3) => (ops[0].as_str(), ops[1].as_str(), ops[2].as_str()),
+                    _ => panic!("wrong number of operands for {}", self.opcode),
+                };
+                // %pcrel_lo(label) and the like
+                if !is_number(offset) {
+                    return None;
+                }
+                let ops: Vec<String> = vec!["jalr", "0", rd, rs1, offset]
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                Some(insn::insn_format("i", &ops))
+            }
             "max" => {
                 res.set_opcode(0b0110011);
                 res.set_funct3(0b110);
@@ -407,6 +1114,61 @@ impl TextInstruction {
                 res.set_operands(&self.operands);
                 Some(res)
             }
+            // addi rd, rs, 0
+            "mv" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0);
+                res.set_2operands(&self.operands, 0);
+                Some(res)
+            }
+            // sub(w) rd, zero, rs
+            "neg" | "negw" => {
+                assert_eq!(self.operands.len(), 2);
+                let opcode = if self.opcode == "neg" {
+                    0b0110011
+                } else {
+                    0b0111011
+                };
+                res.set_opcode(opcode);
+                res.set_funct3(0);
+                res.set_funct7(0b0100000);
+                res.set_rd(&self.operands[0]);
+                res.set_rs1("zero");
+                res.set_rs2(&self.operands[1]);
+                Some(res)
+            }
+            // addi zero, zero, 0
+            "nop" => {
+                assert!(self.operands.is_empty());
+                res.set_opcode(0b0010011);
+                res.set_funct3(0);
+                Some(res)
+            }
+            // xori rd, rs, -1
+            "not" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b100);
+                res.set_funct7(0b1111111);
+                res.set_2operands(&self.operands, 0b11111);
+                Some(res)
+            }
+            // Zihintntl: add zero, zero, rs2 with rs2 in x2-x5
+            "ntl.p1" | "ntl.pall" | "ntl.s1" | "ntl.all" => {
+                assert!(self.operands.is_empty());
+                let rs2 = match self.opcode.as_str() {
+                    "ntl.p1" => "sp",
+                    "ntl.pall" => "gp",
+                    "ntl.s1" => "tp",
+                    _ => "t0",
+                };
+                res.set_opcode(0b0110011);
+                res.set_funct3(0);
+                res.set_funct7(0);
+                res.set_rd("zero");
+                res.set_rs1("zero");
+                res.set_rs2(rs2);
+                Some(res)
+            }
             "orc.b" => {
                 res.set_opcode(0b0010011);
                 res.set_funct3(0b101);
@@ -421,6 +1183,44 @@ impl TextInstruction {
                 res.set_operands(&self.operands);
                 Some(res)
             }
+            "pack" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b100);
+                res.set_funct7(0b0000100);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            "packh" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b111);
+                res.set_funct7(0b0000100);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            "packw" => {
+                res.set_opcode(0b0111011);
+                res.set_funct3(0b100);
+                res.set_funct7(0b0000100);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            // Zihintpause: fence w, 0
+            "pause" => {
+                assert!(self.operands.is_empty());
+                res.set_opcode(0b0001111);
+                res.set_funct3(0);
+                res.set(24, 27, BinaryInstruction::bits_array(0b0001, 4));
+                Some(res)
+            }
+            // jalr zero, 0(ra)
+            "ret" => {
+                assert!(self.operands.is_empty());
+                res.set_opcode(0b1100111);
+                res.set_funct3(0);
+                res.set_rd("zero");
+                res.set_rs1("ra");
+                Some(res)
+            }
             "rev8" => {
                 res.set_opcode(0b0010011);
                 res.set_funct3(0b101);
@@ -461,7 +1261,7 @@ impl TextInstruction {
                 res.set_funct3(0b101);
                 res.set_funct7(0b0110000);
 
-                let shamt = self.operands[2].parse::<u8>().unwrap();
+                let shamt = parse_shamt(&self.operands[2], 31);
                 let mut operands = self.operands.clone();
                 operands.pop();
                 res.set_2operands(&operands, shamt);
@@ -474,6 +1274,13 @@ impl TextInstruction {
                 res.set_operands(&self.operands);
                 Some(res)
             }
+            // sltiu rd, rs, 1
+            "seqz" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b011);
+                res.set_2operands(&self.operands, 1);
+                Some(res)
+            }
             "sext.b" => {
                 res.set_opcode(0b0010011);
                 res.set_funct3(0b001);
@@ -488,6 +1295,23 @@ impl TextInstruction {
                 res.set_2operands(&self.operands, 0b00101);
                 Some(res)
             }
+            // addiw rd, rs, 0
+            "sext.w" => {
+                res.set_opcode(0b0011011);
+                res.set_funct3(0);
+                res.set_2operands(&self.operands, 0);
+                Some(res)
+            }
+            // slt rd, zero, rs
+            "sgtz" => {
+                assert_eq!(self.operands.len(), 2);
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b010);
+                res.set_rd(&self.operands[0]);
+                res.set_rs1("zero");
+                res.set_rs2(&self.operands[1]);
+                Some(res)
+            }
             "sh1add" => {
                 res.set_opcode(0b0110011);
                 res.set_funct3(0b010);
@@ -537,72 +1361,1480 @@ impl TextInstruction {
                 res.set_immediate(&self.operands);
                 Some(res)
             }
-            "xnor" => {
+            // slt rd, rs, zero
+            "sltz" => {
                 res.set_opcode(0b0110011);
-                res.set_funct3(0b100);
-                res.set_funct7(0b0100000);
-                res.set_operands(&self.operands);
+                res.set_funct3(0b010);
+                res.set_2operands(&self.operands, 0);
                 Some(res)
             }
-            "zext.h" => {
+            // sltu rd, zero, rs
+            "snez" => {
+                assert_eq!(self.operands.len(), 2);
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b011);
+                res.set_rd(&self.operands[0]);
+                res.set_rs1("zero");
+                res.set_rs2(&self.operands[1]);
+                Some(res)
+            }
+            // zip and unzip are RV32-only
+            "unzip" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b101);
+                res.set_funct7(0b0000100);
+                res.set_2operands(&self.operands, 0b01111);
+                Some(res)
+            }
+            // Zawrs, the funct12 field selects the timeout
+            "wrs.nto" | "wrs.sto" => {
+                assert!(self.operands.is_empty());
+                let funct12 = if self.opcode == "wrs.nto" { 0xd } else { 0x1d };
+                res.set_opcode(0b1110011);
+                res.set_funct3(0);
+                res.set(20, 31, BinaryInstruction::bits_array(funct12, 12));
+                Some(res)
+            }
+            "xnor" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b100);
+                res.set_funct7(0b0100000);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            "xperm4" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b010);
+                res.set_funct7(0b0010100);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            "xperm8" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b100);
+                res.set_funct7(0b0010100);
+                res.set_operands(&self.operands);
+                Some(res)
+            }
+            // andi rd, rs, 0xff
+            "zext.b" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b111);
+                res.set_2operands(&self.operands, 0);
+                res.set(20, 31, BinaryInstruction::bits_array(0xff, 12));
+                Some(res)
+            }
+            "zext.h" => {
                 res.set_opcode(0b0111011);
                 res.set_funct3(0b100);
                 res.set_funct7(0b0000100);
                 res.set_2operands(&self.operands, 0b00000);
                 Some(res)
             }
-            _ => None,
+            // add.uw rd, rs, zero
+            "zext.w" => {
+                res.set_opcode(0b0111011);
+                res.set_funct3(0);
+                res.set_funct7(0b0000100);
+                res.set_2operands(&self.operands, 0);
+                Some(res)
+            }
+            "zip" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b001);
+                res.set_funct7(0b0000100);
+                res.set_2operands(&self.operands, 0b01111);
+                Some(res)
+            }
+            ".insn" => self.convert_insn(),
+            _ => self
+                .convert_atomic()
+                .or_else(|| self.convert_float())
+                .or_else(|| self.convert_compressed())
+                .or_else(|| self.convert_csr())
+                .or_else(|| self.convert_crypto())
+                .or_else(|| self.convert_mop())
+                .or_else(|| self.convert_privileged())
+                .or_else(|| self.convert_vector()),
+        }
+    }
+    // F/D/Q-extension, the fmt field comes from the mnemonic suffix
+    fn convert_float(&self) -> Option<BinaryInstruction> {
+        if let Some(types) = self.opcode.strip_prefix("fcvt.") {
+            return self.convert_fcvt(types);
+        }
+        // fmv.x.w and the like, fmv.s is a pseudo-instruction below
+        if let Some(types) = self.opcode.strip_prefix("fmv.").filter(|t| t.contains('x')) {
+            return self.convert_fmv(types);
+        }
+        let index = self.opcode.rfind('.')?;
+        let fmt = fp_fmt(&self.opcode[index + 1..])?;
+
+        let mut res = BinaryInstruction::new();
+        match &self.opcode[..index] {
+            "fmadd" | "fmsub" | "fnmsub" | "fnmadd" => {
+                let opcode = match &self.opcode[..index] {
+                    "fmadd" => 0b1000011,
+                    "fmsub" => 0b1000111,
+                    "fnmsub" => 0b1001011,
+                    _ => 0b1001111,
+                };
+                let (operands, rm) = self.split_rm(4, RM_DYN);
+                res.set_opcode(opcode);
+                res.set_funct3(rm);
+                res.set_fmt(fmt);
+                res.set_fp_r4_operands(operands);
+            }
+            "fadd" | "fsub" | "fmul" | "fdiv" => {
+                let funct5 = match &self.opcode[..index] {
+                    "fadd" => 0b00000,
+                    "fsub" => 0b00001,
+                    "fmul" => 0b00010,
+                    _ => 0b00011,
+                };
+                let (operands, rm) = self.split_rm(3, RM_DYN);
+                res.set_opcode(0b1010011);
+                res.set_funct3(rm);
+                res.set_funct5(funct5);
+                res.set_fmt(fmt);
+                res.set_fp_operands(operands);
+            }
+            "fsqrt" => {
+                let (operands, rm) = self.split_rm(2, RM_DYN);
+                res.set_opcode(0b1010011);
+                res.set_funct3(rm);
+                res.set_funct5(0b01011);
+                res.set_fmt(fmt);
+                res.set_fp_2operands(operands, 0);
+            }
+            "fsgnj" | "fsgnjn" | "fsgnjx" => {
+                let funct3 = match &self.opcode[..index] {
+                    "fsgnj" => 0b000,
+                    "fsgnjn" => 0b001,
+                    _ => 0b010,
+                };
+                res.set_opcode(0b1010011);
+                res.set_funct3(funct3);
+                res.set_funct5(0b00100);
+                res.set_fmt(fmt);
+                res.set_fp_operands(&self.operands);
+            }
+            // fsgnj/fsgnjx/fsgnjn rd, rs, rs
+            "fmv" | "fabs" | "fneg" => {
+                let funct3 = match &self.opcode[..index] {
+                    "fmv" => 0b000,
+                    "fneg" => 0b001,
+                    _ => 0b010,
+                };
+                assert_eq!(self.operands.len(), 2);
+                let operands = vec![
+                    self.operands[0].clone(),
+                    self.operands[1].clone(),
+                    self.operands[1].clone(),
+                ];
+                res.set_opcode(0b1010011);
+                res.set_funct3(funct3);
+                res.set_funct5(0b00100);
+                res.set_fmt(fmt);
+                res.set_fp_operands(&operands);
+            }
+            "feq" | "flt" | "fle" => {
+                let funct3 = match &self.opcode[..index] {
+                    "feq" => 0b010,
+                    "flt" => 0b001,
+                    _ => 0b000,
+                };
+                assert_eq!(self.operands.len(), 3);
+                res.set_opcode(0b1010011);
+                res.set_funct3(funct3);
+                res.set_funct5(0b10100);
+                res.set_fmt(fmt);
+                res.set_rd(self.operands[0].as_ref());
+                res.set_frs1(self.operands[1].as_ref());
+                res.set_frs2(self.operands[2].as_ref());
+            }
+            "fclass" => {
+                assert_eq!(self.operands.len(), 2);
+                res.set_opcode(0b1010011);
+                res.set_funct3(0b001);
+                res.set_funct5(0b11100);
+                res.set_fmt(fmt);
+                res.set_rd(self.operands[0].as_ref());
+                res.set_frs1(self.operands[1].as_ref());
+                res.set(20, 24, BinaryInstruction::bits_array(0, 5));
+            }
+            // Zfa
+            "fli" => {
+                assert_eq!(self.operands.len(), 2);
+                res.set_opcode(0b1010011);
+                res.set_funct3(0);
+                res.set_funct5(0b11110);
+                res.set_fmt(fmt);
+                res.set_frd(self.operands[0].as_ref());
+                let index = fli_index(self.operands[1].as_ref());
+                res.set(15, 19, BinaryInstruction::bits_array(index, 5));
+                res.set(20, 24, BinaryInstruction::bits_array(1, 5));
+            }
+            "fminm" | "fmaxm" => {
+                let funct3 = if &self.opcode[..index] == "fminm" {
+                    0b010
+                } else {
+                    0b011
+                };
+                res.set_opcode(0b1010011);
+                res.set_funct3(funct3);
+                res.set_funct5(0b00101);
+                res.set_fmt(fmt);
+                res.set_fp_operands(&self.operands);
+            }
+            "fround" | "froundnx" => {
+                let rs2 = if &self.opcode[..index] == "fround" {
+                    0b00100
+                } else {
+                    0b00101
+                };
+                let (operands, rm) = self.split_rm(2, RM_DYN);
+                res.set_opcode(0b1010011);
+                res.set_funct3(rm);
+                res.set_funct5(0b01000);
+                res.set_fmt(fmt);
+                res.set_fp_2operands(operands, rs2);
+            }
+            "fleq" | "fltq" => {
+                let funct3 = if &self.opcode[..index] == "fleq" {
+                    0b100
+                } else {
+                    0b101
+                };
+                assert_eq!(self.operands.len(), 3);
+                res.set_opcode(0b1010011);
+                res.set_funct3(funct3);
+                res.set_funct5(0b10100);
+                res.set_fmt(fmt);
+                res.set_rd(self.operands[0].as_ref());
+                res.set_frs1(self.operands[1].as_ref());
+                res.set_frs2(self.operands[2].as_ref());
+            }
+            // fcvtmod.w.d rd, rs1, rtz: the rounding mode must be rtz
+            "fcvtmod.w" if fmt == 0b01 => {
+                assert_eq!(self.operands.len(), 3);
+                if parse_rm(&self.operands[2]) != 0b001 {
+                    panic!("fcvtmod.w.d only supports the rtz rounding mode");
+                }
+                res.set_opcode(0b1010011);
+                res.set_funct3(0b001);
+                res.set_funct5(0b11000);
+                res.set_fmt(fmt);
+                res.set_rd(self.operands[0].as_ref());
+                res.set_frs1(self.operands[1].as_ref());
+                res.set(20, 24, BinaryInstruction::bits_array(0b01000, 5));
+            }
+            "fmin" | "fmax" => {
+                let funct3 = if &self.opcode[..index] == "fmin" {
+                    0b000
+                } else {
+                    0b001
+                };
+                res.set_opcode(0b1010011);
+                res.set_funct3(funct3);
+                res.set_funct5(0b00101);
+                res.set_fmt(fmt);
+                res.set_fp_operands(&self.operands);
+            }
+            _ => return None,
+        }
+        Some(res)
+    }
+    // fcvt.<dst>.<src>, each side is either a floating-point format or an
+    // integer type: w, wu, l, lu
+    fn convert_fcvt(&self, types: &str) -> Option<BinaryInstruction> {
+        let index = types.find('.')?;
+        let dst = &types[..index];
+        let src = &types[index + 1..];
+
+        // conversions which can't lose precision default to rne
+        let (operands, rm) = self.split_rm(2, RM_DYN);
+        assert_eq!(operands.len(), 2);
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1010011);
+        let (rs2, exact) = if let (Some(dst_fmt), Some(src_fmt)) = (fp_fmt(dst), fp_fmt(src)) {
+            res.set_funct5(0b01000);
+            res.set_fmt(dst_fmt);
+            res.set_frd(operands[0].as_ref());
+            res.set_frs1(operands[1].as_ref());
+            (src_fmt, fp_precision(src_fmt) <= fp_precision(dst_fmt))
+        } else if let (Some(fmt), Some(int)) = (fp_fmt(dst), int_type(src)) {
+            res.set_funct5(0b11010);
+            res.set_fmt(fmt);
+            res.set_frd(operands[0].as_ref());
+            res.set_rs1(operands[1].as_ref());
+            (int, int_width(int) <= fp_precision(fmt))
+        } else if let (Some(int), Some(fmt)) = (int_type(dst), fp_fmt(src)) {
+            res.set_funct5(0b11000);
+            res.set_fmt(fmt);
+            res.set_rd(operands[0].as_ref());
+            res.set_frs1(operands[1].as_ref());
+            (int, false)
+        } else {
+            return None;
+        };
+        let bits = BinaryInstruction::bits_array(rs2.into(), 5);
+        res.set(20, 24, bits);
+        if exact && operands.len() == self.operands.len() {
+            res.set_funct3(0b000);
+        } else {
+            res.set_funct3(rm);
+        }
+        Some(res)
+    }
+    // I-type arithmetic, loads and stores with a 12-bit signed immediate,
+    // sign-extended by the hardware. Only numeric immediates, %lo(sym) and
+    // the like are left to the real assembler.
+    fn convert_imm12(&self) -> Option<BinaryInstruction> {
+        let ops = &self.operands;
+        let name = self.opcode.as_str();
+        let (format, opcode, funct3) = match name {
+            "addi" => ("i", "op_imm", "0"),
+            "slti" => ("i", "op_imm", "2"),
+            "sltiu" => ("i", "op_imm", "3"),
+            "xori" => ("i", "op_imm", "4"),
+            "ori" => ("i", "op_imm", "6"),
+            "andi" => ("i", "op_imm", "7"),
+            "addiw" => ("i", "op_imm_32", "0"),
+            "lb" => ("i", "load", "0"),
+            "lh" => ("i", "load", "1"),
+            "lw" => ("i", "load", "2"),
+            "ld" => ("i", "load", "3"),
+            "lbu" => ("i", "load", "4"),
+            "lhu" => ("i", "load", "5"),
+            "lwu" => ("i", "load", "6"),
+            "sb" => ("s", "store", "0"),
+            "sh" => ("s", "store", "1"),
+            "sw" => ("s", "store", "2"),
+            _ => ("s", "store", "3"),
+        };
+        let imm = if opcode.starts_with("op_imm") {
+            assert_eq!(ops.len(), 3);
+            ops[2].as_str()
+        } else {
+            assert_eq!(ops.len(), 2);
+            split_address(&ops[1]).0
+        };
+        if !imm.is_empty() && !is_number(imm) {
+            return None;
+        }
+        if !imm.is_empty() && !(-2048..=2047).contains(&parse_imm(imm)) {
+            panic!("immediate {} out of range [-2048, 2047]", imm);
+        }
+        let mut insn_ops = vec![opcode, funct3];
+        insn_ops.extend(ops.iter().map(String::as_str));
+        let insn_ops: Vec<String> = insn_ops.into_iter().map(String::from).collect();
+        Some(insn::insn_format(format, &insn_ops))
+    }
+    // beq/bne/blt/bge/bltu/bgeu, the zero-compare pseudos, which use zero as
+    // the other register, and bgt/ble/bgtu/bleu, which swap the registers of
+    // blt/bge/bltu/bgeu. Only numeric offsets, symbols are left to the
+    // real assembler.
+    fn convert_branch(&self) -> Option<BinaryInstruction> {
+        let ops = &self.operands;
+        let zero = String::from("zero");
+        let zero_compare = self.opcode.ends_with('z');
+        assert_eq!(ops.len(), if zero_compare { 2 } else { 3 });
+        let (name, rs1, rs2, offset) = match self.opcode.as_str() {
+            "beqz" | "bnez" | "bgez" | "bltz" => {
+                let name = self.opcode.trim_end_matches('z');
+                (name, &ops[0], &zero, &ops[1])
+            }
+            "blez" => ("bge", &zero, &ops[0], &ops[1]),
+            "bgtz" => ("blt", &zero, &ops[0], &ops[1]),
+            "bgt" => ("blt", &ops[1], &ops[0], &ops[2]),
+            "ble" => ("bge", &ops[1], &ops[0], &ops[2]),
+            "bgtu" => ("bltu", &ops[1], &ops[0], &ops[2]),
+            "bleu" => ("bgeu", &ops[1], &ops[0], &ops[2]),
+            name => (name, &ops[0], &ops[1], &ops[2]),
+        };
+        if !is_number(offset) {
+            return None;
         }
+        if let Some(e) = labels::offset_error(self) {
+            panic!("{}", e);
+        }
+        let funct3 = match name {
+            "beq" => "0",
+            "bne" => "1",
+            "blt" => "4",
+            "bge" => "5",
+            "bltu" => "6",
+            _ => "7",
+        };
+        let ops: Vec<String> = vec!["branch", funct3, rs1, rs2, offset]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        Some(insn::insn_format("b", &ops))
+    }
+    // .insn [length,] value: raw encoding of an instruction of 2, 4, 6 or 8
+    // bytes. The length must agree with the one encoded in the low bits.
+    fn convert_insn(&self) -> Option<BinaryInstruction> {
+        if let Some((space, first)) = self.operands.first().and_then(|o| o.split_once(' ')) {
+            let mut operands = self.operands.clone();
+            operands[0] = String::from(first.trim());
+            if insn::is_format(space) {
+                return Some(insn::insn_format(space, &operands));
+            }
+            return Some(BinaryInstruction::custom(space, &operands));
+        }
+        let (length, value) = match self.operands.len() {
+            1 => (None, parse_unsigned(&self.operands[0])),
+            2 => (
+                Some(parse_imm(&self.operands[0]) as usize),
+                parse_unsigned(&self.operands[1]),
+            ),
+            _ => panic!(".insn expects [length,] value"),
+        };
+        let encoded = instruction_length((value & 0xffff) as u16);
+        if encoded.is_none() || encoded.unwrap() > 8 {
+            panic!(
+                ".insn 0x{:x}: instructions longer than 64 bits are not supported",
+                value
+            );
+        }
+        let encoded = encoded.unwrap();
+        match length {
+            Some(length) if length != encoded => panic!(
+                ".insn 0x{:x}: length {} doesn't match the {} bytes encoded in the low bits",
+                value, length, encoded
+            ),
+            _ => {}
+        }
+        if encoded < 8 && value >> (encoded * 8) != 0 {
+            panic!(".insn 0x{:x}: value is wider than {} bytes", value, encoded);
+        }
+
+        let mut res = BinaryInstruction::with_len(encoded);
+        for (index, byte) in res.data.iter_mut().enumerate() {
+            *byte = (value >> (index * 8)) as u8;
+        }
+        Some(res)
+    }
+    // fmv.x.w/fmv.w.x: move bit patterns between integer and floating-point
+    // registers. The older names fmv.x.s/fmv.s.x are accepted as well.
+    fn convert_fmv(&self, types: &str) -> Option<BinaryInstruction> {
+        let (to_int, fmt) = match types {
+            "x.w" | "x.s" => (true, 0b00),
+            "w.x" | "s.x" => (false, 0b00),
+            "x.d" => (true, 0b01),
+            "d.x" => (false, 0b01),
+            _ => return None,
+        };
+
+        assert_eq!(self.operands.len(), 2);
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1010011);
+        res.set_funct3(0b000);
+        res.set_fmt(fmt);
+        if to_int {
+            res.set_funct5(0b11100);
+            res.set_rd(self.operands[0].as_ref());
+            res.set_frs1(self.operands[1].as_ref());
+        } else {
+            res.set_funct5(0b11110);
+            res.set_frd(self.operands[0].as_ref());
+            res.set_rs1(self.operands[1].as_ref());
+        }
+        res.set(20, 24, BinaryInstruction::bits_array(0, 5));
+        Some(res)
     }
+    // Operands without the optional trailing rounding mode, and the rounding
+    // mode itself (default when it's omitted).
+    fn split_rm(&self, count: usize, default: u8) -> (&[String], u8) {
+        if self.operands.len() == count + 1 {
+            (&self.operands[..count], parse_rm(&self.operands[count]))
+        } else {
+            (&self.operands[..], default)
+        }
+    }
+    // Zimop: mop.r.N rd, rs1 (N in 0..31) and mop.rr.N rd, rs1, rs2 (N in
+    // 0..7). The bits of N are scattered over funct7 and the rs2 field.
+    fn convert_mop(&self) -> Option<BinaryInstruction> {
+        let (n, rr) = if let Some(n) = self.opcode.strip_prefix("mop.r.") {
+            (n, false)
+        } else {
+            (self.opcode.strip_prefix("mop.rr.")?, true)
+        };
+        let n = n.parse::<u32>().ok()?;
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1110011);
+        res.set_funct3(0b100);
+        res.set(31, 31, vec![1]);
+        if rr {
+            if n > 7 {
+                return None;
+            }
+            assert_eq!(self.operands.len(), 3);
+            res.set_operands(&self.operands);
+            res.set(30, 30, vec![(n >> 2) as u8]);
+            res.set(26, 27, BinaryInstruction::bits_array(n & 0b11, 2));
+            res.set(25, 25, vec![1]);
+        } else {
+            if n > 31 {
+                return None;
+            }
+            res.set_2operands(&self.operands, 0);
+            res.set(30, 30, vec![(n >> 4) as u8]);
+            res.set(26, 27, BinaryInstruction::bits_array((n >> 2) & 0b11, 2));
+            res.set(22, 25, BinaryInstruction::bits_array(0b0111, 4));
+            res.set(20, 21, BinaryInstruction::bits_array(n & 0b11, 2));
+        }
+        Some(res)
+    }
+    // Zacas: amocas.q (amocas.d on RV32) works on the register pairs rd/rd+1
+    // and rs2/rs2+1
+    fn check_even_pairs(&self) {
+        assert_eq!(self.operands.len(), 3);
+        for reg in &self.operands[..2] {
+            if !reg_name2value(reg).is_multiple_of(2) {
+                panic!("{} needs an even register, got {}", self.opcode, reg);
+            }
+        }
+    }
+    // A-extension, the mnemonic can carry an ordering suffix: .aq, .rl or .aqrl
+    fn convert_atomic(&self) -> Option<BinaryInstruction> {
+        let mut name = self.opcode.as_str();
+        let mut aq = 0;
+        let mut rl = 0;
+        for (suffix, a, r) in &[(".aqrl", 1, 1), (".aq", 1, 0), (".rl", 0, 1)] {
+            if let Some(n) = name.strip_suffix(suffix) {
+                name = n;
+                aq = *a;
+                rl = *r;
+                break;
+            }
+        }
+        let index = name.rfind('.')?;
+        let funct3 = match &name[index + 1..] {
+            // Zabha
+            "b" => 0b000,
+            "h" => 0b001,
+            "w" => 0b010,
+            "d" => 0b011,
+            "q" => 0b100,
+            _ => return None,
+        };
+        let funct5 = match &name[..index] {
+            "lr" => 0b00010,
+            "sc" => 0b00011,
+            "amoswap" => 0b00001,
+            "amoadd" => 0b00000,
+            "amoxor" => 0b00100,
+            "amoand" => 0b01100,
+            "amoor" => 0b01000,
+            "amomin" => 0b10000,
+            "amomax" => 0b10100,
+            "amominu" => 0b11000,
+            "amomaxu" => 0b11100,
+            "amocas" => 0b00101,
+            _ => return None,
+        };
+        // there are no byte or halfword lr/sc
+        if funct3 < 0b010 && (funct5 == 0b00010 || funct5 == 0b00011) {
+            return None;
+        }
+        if funct3 == 0b100 {
+            if funct5 != 0b00101 {
+                return None;
+            }
+            self.check_even_pairs();
+        }
+
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b0101111);
+        res.set_funct3(funct3);
+        res.set_funct5(funct5);
+        res.set_aq_rl(aq, rl);
+        if funct5 == 0b00010 {
+            res.set_lr_operands(&self.operands);
+        } else {
+            res.set_atomic_operands(&self.operands);
+        }
+        Some(res)
+    }
+}
+
+// instructions that can be written without operands, besides the c.* ones
+const NO_OPERANDS: [&str; 20] = [
+    "fence",
+    "fence.i",
+    "fence.tso",
+    "sfence.vma",
+    "sinval.vma",
+    "sfence.w.inval",
+    "sfence.inval.ir",
+    "hfence.vvma",
+    "hfence.gvma",
+    "hinval.vvma",
+    "hinval.gvma",
+    "wrs.nto",
+    "wrs.sto",
+    "pause",
+    "ntl.p1",
+    "ntl.pall",
+    "ntl.s1",
+    "ntl.all",
+    "nop",
+    "ret",
+];
+
+fn parse_line(line: &str) -> TextInstruction {
+    let tokens = lexer::tokenize(line);
+    // the mnemonic, or a label, up to the first whitespace
+    let split = tokens
+        .iter()
+        .skip(1)
+        .position(|t| t.space)
+        .map_or(tokens.len(), |pos| pos + 1);
+    let opcode = lexer::join(&tokens[..split]);
+    if split < tokens.len() {
+        let operands = tokens[split..]
+            .split(|t| t.kind == lexer::Kind::Punct && t.text == ",")
+            .map(lexer::join)
+            .filter(|r| !r.is_empty())
+            .collect();
+        TextInstruction {
+            opcode,
+            operands,
+            raw: None,
+        }
+    } else if opcode.starts_with("c.")
+        || NO_OPERANDS.contains(&opcode.as_str())
+        || opcodes::is_user_opcode(&opcode)
+    {
+        // instruction without operands: c.nop, c.ebreak, fence, fence.i
+        TextInstruction {
+            opcode,
+            operands: vec![],
+            raw: None,
+        }
+    } else {
+        let mut r = TextInstruction::new();
+        r.raw = Some(String::from(line.trim()));
+        r
+    }
+}
+
+// x86 register names as written after % in AT&T syntax
+fn is_x86_register(name: &str) -> bool {
+    const X86_REGISTERS: [&str; 40] = [
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "rip", "eax", "ebx", "ecx", "edx",
+        "esi", "edi", "ebp", "esp", "eip", "ax", "bx", "cx", "dx", "si", "di", "bp", "sp", "al",
+        "bl", "cl", "dl", "ah", "bh", "ch", "dh", "cs", "ds", "es", "fs", "gs", "ss",
+    ];
+    // r8 to r15 with their d, w and b parts, xmm0 to zmm31
+    let numbered = |prefix: &str, range: std::ops::RangeInclusive<u32>, suffixes: &[&str]| {
+        name.strip_prefix(prefix).is_some_and(|rest| {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest[..len].parse().is_ok_and(|n| range.contains(&n))
+                && suffixes.contains(&&rest[len..])
+        })
+    };
+    X86_REGISTERS.contains(&name)
+        || numbered("r", 8..=15, &["", "d", "w", "b"])
+        || ["xmm", "ymm", "zmm"]
+            .iter()
+            .any(|prefix| numbered(prefix, 0..=31, &[""]))
+}
+
+// how many lines are inspected by detect_foreign_arch
+const ARCH_CHECK_LINES: usize = 64;
+
+// Look for obviously non-RISC-V assembly (x86, ARM) in the first lines.
+// Returns the line index and the guessed architecture.
+fn detect_foreign_arch(lines: &[String]) -> Option<(usize, &'static str)> {
+    const X86_MNEMONICS: [&str; 12] = [
+        "movq", "movl", "movb", "pushq", "popq", "pushl", "popl", "leaq", "retq", "cmpq", "jmp",
+        "syscall",
+    ];
+    const ARM_MNEMONICS: [&str; 10] = [
+        "ldr", "str", "ldp", "stp", "adrp", "bl", "bx", "cbz", "cbnz", "svc",
+    ];
+    const X86_DIRECTIVES: [&str; 5] = [
+        ".intel_syntax",
+        ".att_syntax",
+        ".code16",
+        ".code32",
+        ".code64",
+    ];
+    const ARM_DIRECTIVES: [&str; 4] = [".arm", ".thumb", ".thumb_func", ".syntax"];
+
+    for (index, line) in lines.iter().take(ARCH_CHECK_LINES).enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        let word = line.split_whitespace().next().unwrap();
+        let lowercase = word.to_lowercase();
+        let mnemonic = lowercase.as_str();
+        if X86_DIRECTIVES.contains(&mnemonic) {
+            return Some((index, "x86"));
+        }
+        if ARM_DIRECTIVES.contains(&mnemonic) {
+            return Some((index, "ARM"));
+        }
+        if mnemonic.starts_with('.') || mnemonic.ends_with(':') {
+            continue;
+        }
+        if X86_MNEMONICS.contains(&mnemonic) {
+            return Some((index, "x86"));
+        }
+        if ARM_MNEMONICS.contains(&mnemonic) {
+            return Some((index, "ARM"));
+        }
+        let operands = &line[word.len()..];
+        // AT&T register operand like %rax, but not %hi(sym) or 10 % 3
+        let mut rest = operands;
+        while let Some(pos) = rest.find('%') {
+            rest = &rest[pos + 1..];
+            let name_len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            if is_x86_register(&rest[..name_len].to_lowercase()) {
+                return Some((index, "x86"));
+            }
+        }
+        // RISC-V never uses brackets in memory operands
+        if operands.contains('[') {
+            return Some((index, "ARM or x86"));
+        }
+    }
+    None
+}
+
+// explain a mismatch of two ".byte" lines field by field
+#[cfg(test)]
+fn explain_mismatch(source: &str, expected: &str, actual: &str) -> String {
+    let mut msg = format!(
+        "{}\n  expected: {}\n  actual:   {}",
+        source, expected, actual
+    );
+    if let (Some(e), Some(a)) = (
+        BinaryInstruction::from_byte_line(expected),
+        BinaryInstruction::from_byte_line(actual),
+    ) {
+        for diff in a.diff_fields(&e) {
+            msg += &format!("\n  {}", diff);
+        }
+    }
+    msg
+}
+
+#[cfg(test)]
+fn test(line: &str, bytes: &str) {
+    let inst = parse_line(line);
+    let inst2 = inst.convert();
+    if let Some(i) = inst2 {
+        let res = i.to_string();
+        // println!("{}\n{}", line, i.to_bits_string());
+        assert!(bytes == res, "{}", explain_mismatch(line, bytes, &res));
+        #[cfg(feature = "riscv-opcodes")]
+        riscv_opcodes::verify(&inst.opcode, &i, false);
+    } else {
+        assert_eq!(format!("{}", inst), bytes);
+    }
+}
+
+// tests/input.txt must assemble to tests/output.txt
+#[test]
+fn test_golden() {
+    let input = std::fs::read_to_string("tests/input.txt").unwrap();
+    let expected = std::fs::read_to_string("tests/output.txt").unwrap();
+    let lines: Vec<String> = input.split('\n').map(|l| l.trim().to_lowercase()).collect();
+    let actual = assemble("tests/input.txt", lines, &Options::default());
+    let expected: Vec<&str> = expected.lines().collect();
+    let mut errors = vec![];
+    for (index, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        if e != a {
+            let source = format!("tests/output.txt:{}", index + 1);
+            errors.push(explain_mismatch(&source, e, a));
+        }
+    }
+    if expected.len() != actual.len() {
+        errors.push(format!(
+            "expected {} lines, got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+    assert!(errors.is_empty(), "\n{}", errors.join("\n"));
+}
+
+#[test]
+fn test_line_comments() {
+    let lines = vec![String::from(".text"), String::from("andn zero, tp, s6")];
+    let options = Options {
+        line_comments: true,
+        ..Default::default()
+    };
+    let output = assemble("a.s", lines, &options);
+    assert_eq!(
+        output,
+        vec![
+            ".text",
+            "# andn zero,tp,s6",
+            ".byte 0x33,0x70,0x62,0x41 # a.s:2"
+        ]
+    );
+}
+
+#[test]
+fn test_compress_option() {
+    let lines = vec![
+        String::from("fld fa0, 8(sp)"),
+        String::from("fld fa0, 8(a0)"),
+        String::from("fld fa0, 8(t0)"),
+    ];
+    let options = Options {
+        compress: true,
+        ..Default::default()
+    };
+    let output = assemble("a.s", lines, &options);
+    assert_eq!(
+        output,
+        vec![
+            "# fld fa0,8(sp)",
+            ".byte 0x22,0x25",
+            "# fld fa0,8(a0)",
+            ".byte 0x08,0x25",
+            "# fld fa0,8(t0)",
+            ".byte 0x07,0xb5,0x82,0x00"
+        ]
+    );
+}
+
+#[test]
+fn test_reserved_reg_write() {
+    let reserved = parse_warnings(&["reserved-reg"]);
+    let check = |line: &str| reserved_reg_write(&parse_line(line), &reserved);
+    assert_eq!(check("addi gp, gp, 8"), Some("gp"));
+    assert_eq!(check("andn tp, a0, a1"), Some("tp"));
+    assert_eq!(check("c.mv gp, a0"), Some("gp"));
+    assert_eq!(check("la gp, __global_pointer$"), None);
+    assert_eq!(check("sd gp, 0(sp)"), None);
+    assert_eq!(check("andn a0, gp, tp"), None);
+    assert_eq!(check(".word gp"), None);
+    // gp is only read
+    assert_eq!(check("jalr gp"), None);
+    assert_eq!(check("c.jalr gp"), None);
+    assert_eq!(check("sfence.vma gp"), None);
+    assert_eq!(check("sinval.vma gp, a0"), None);
+    assert_eq!(check("hfence.gvma gp"), None);
+    assert_eq!(check("hinval.vvma a0, gp"), None);
+    assert_eq!(check("hsv.w gp, (a0)"), None);
+    assert_eq!(check("jalr gp, 0(a0)"), Some("gp"));
+
+    let reserved = parse_warnings(&["reserved-reg=ra"]);
+    let check = |line: &str| reserved_reg_write(&parse_line(line), &reserved);
+    assert_eq!(check("jalr gp"), Some("ra"));
+    assert_eq!(check("c.jalr gp"), Some("ra"));
+
+    let reserved = parse_warnings(&["reserved-reg=gp"]);
+    assert_eq!(
+        reserved_reg_write(&parse_line("mv tp, a0"), &reserved),
+        None
+    );
+    assert!(parse_warnings(&["reserved-reg", "no-reserved-reg"]).is_empty());
+}
+
+#[test]
+fn test_explain_mismatch() {
+    let msg = explain_mismatch(
+        "sh3add.uw a3,s5,gp",
+        ".byte 0xbb,0xe6,0x3a,0x20",
+        ".byte 0xbb,0xc6,0x3a,0x20",
+    );
+    assert!(msg.contains("funct3: expected 110, got 100"));
+    assert!(!msg.contains("opcode:"));
+}
+
+#[test]
+fn test_adduw() {
+    test("add.uw a2, s11, s5", ".byte 0x3b,0x86,0x5d,0x09");
+}
+
+#[test]
+fn test_andn() {
+    test("andn zero, tp, s6", ".byte 0x33,0x70,0x62,0x41");
+}
+
+#[test]
+fn test_zbkb() {
+    test("pack a0, a1, a2", ".byte 0x33,0xc5,0xc5,0x08");
+    test("packh a0, a1, a2", ".byte 0x33,0xf5,0xc5,0x08");
+    test("packw a0, a1, a2", ".byte 0x3b,0xc5,0xc5,0x08");
+    test("brev8 a0, a1", ".byte 0x13,0xd5,0x75,0x68");
+    rv32::test_rv32("zip a0, a1", ".byte 0x13,0x95,0xf5,0x08");
+    rv32::test_rv32("unzip a0, a1", ".byte 0x13,0xd5,0xf5,0x08");
+}
+
+#[test]
+fn test_zbkx() {
+    test("xperm4 a0, a1, a2", ".byte 0x33,0xa5,0xc5,0x28");
+    test("xperm8 a0, a1, a2", ".byte 0x33,0xc5,0xc5,0x28");
+}
+
+#[test]
+fn test_mv_not_neg() {
+    test("mv a0, a1", ".byte 0x13,0x85,0x05,0x00");
+    test("mv s0, sp", ".byte 0x13,0x04,0x01,0x00");
+    test("not a0, a1", ".byte 0x13,0xc5,0xf5,0xff");
+    test("neg a0, a1", ".byte 0x33,0x05,0xb0,0x40");
+    test("negw a0, a1", ".byte 0x3b,0x05,0xb0,0x40");
+}
+
+#[test]
+fn test_set_condition() {
+    test("seqz a0, a1", ".byte 0x13,0xb5,0x15,0x00");
+    test("snez a0, a1", ".byte 0x33,0x35,0xb0,0x00");
+    test("sltz a0, a1", ".byte 0x33,0xa5,0x05,0x00");
+    test("sgtz a0, a1", ".byte 0x33,0x25,0xb0,0x00");
+}
+
+#[test]
+fn test_nop_ret() {
+    test("nop", ".byte 0x13,0x00,0x00,0x00");
+    test("ret", ".byte 0x67,0x80,0x00,0x00");
+}
+
+#[test]
+fn test_jump_shorthands() {
+    test("j 2046", ".byte 0x6f,0x00,0xe0,0x7f");
+    test("j -8", ".byte 0x6f,0xf0,0x9f,0xff");
+    test("jal 16", ".byte 0xef,0x00,0x00,0x01");
+    test("jal t0, 16", ".byte 0xef,0x02,0x00,0x01");
+    test("jr a0", ".byte 0x67,0x00,0x05,0x00");
+    test("jalr a0", ".byte 0xe7,0x00,0x05,0x00");
+    test("jalr t0, a0", ".byte 0xe7,0x02,0x05,0x00");
+    test("jalr t0, -4(a0)", ".byte 0xe7,0x02,0xc5,0xff");
+    test("jalr t0, a0, 8", ".byte 0xe7,0x02,0x85,0x00");
+    // symbols are passed through
+    assert!(parse_line("j .l1").convert().is_none());
+    assert!(parse_line("jalr ra, %pcrel_lo(.l2)(ra)")
+        .convert()
+        .is_none());
+}
+
+#[test]
+fn test_branch() {
+    test("beq a0, a1, 16", ".byte 0x63,0x08,0xb5,0x00");
+    test("bgeu a0, a1, -4096", ".byte 0x63,0x70,0xb5,0x80");
+    test("beqz a0, 8", ".byte 0x63,0x04,0x05,0x00");
+    test("bnez a0, -8", ".byte 0xe3,0x1c,0x05,0xfe");
+    test("blez a0, 16", ".byte 0x63,0x58,0xa0,0x00");
+    test("bgez a0, 16", ".byte 0x63,0x58,0x05,0x00");
+    test("bltz a0, 16", ".byte 0x63,0x48,0x05,0x00");
+    test("bgtz a0, 16", ".byte 0x63,0x48,0xa0,0x00");
+    assert!(parse_line("bnez a0, .l1").convert().is_none());
+}
+
+#[test]
+fn test_branch_swapped() {
+    test("bgt a0, a1, 16", ".byte 0x63,0xc8,0xa5,0x00");
+    test("ble a0, a1, 16", ".byte 0x63,0xd8,0xa5,0x00");
+    test("bgtu a0, a1, -16", ".byte 0xe3,0xe8,0xa5,0xfe");
+    test("bleu a0, a1, 16", ".byte 0x63,0xf8,0xa5,0x00");
+}
+
+#[test]
+#[should_panic(expected = "a.s:2: beq offset 3 out of range [-4096, 4094] or not a multiple of 2")]
+fn test_branch_range() {
+    let lines = vec![String::from("nop"), String::from("beq a0, a1, 3")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+#[should_panic(expected = "a.s:1: jal offset 1048576 out of range [-1048576, 1048574]")]
+fn test_jump_range() {
+    assemble(
+        "a.s",
+        vec![String::from("jal 1048576")],
+        &Options::default(),
+    );
+}
+
+#[test]
+fn test_numeric_reg_names() {
+    test("andn x10, x11, x12", ".byte 0x33,0xf5,0xc5,0x40");
+    test("andn x0, x4, x22", ".byte 0x33,0x70,0x62,0x41");
+    test("mv x31, x1", ".byte 0x93,0x8f,0x00,0x00");
+}
+
+#[test]
+fn test_imm12() {
+    test("addi a0, a1, -8", ".byte 0x13,0x85,0x85,0xff");
+    test("addi a0, a1, -2048", ".byte 0x13,0x85,0x05,0x80");
+    test("addiw a0, a1, 2047", ".byte 0x1b,0x85,0xf5,0x7f");
+    test("slti a0, a1, -1", ".byte 0x13,0xa5,0xf5,0xff");
+    test("sltiu a0, a1, 5", ".byte 0x13,0xb5,0x55,0x00");
+    test("xori a0, a1, -1", ".byte 0x13,0xc5,0xf5,0xff");
+    test("ori a0, a1, 0x7f", ".byte 0x13,0xe5,0xf5,0x07");
+    test("andi a0, a1, -16", ".byte 0x13,0xf5,0x05,0xff");
+    test("addi a0, a0, %lo(sym)", "addi a0,a0,%lo(sym)");
+}
+
+#[test]
+fn test_load_store_imm12() {
+    test("lb a0, -8(a1)", ".byte 0x03,0x85,0x85,0xff");
+    test("lh a0, 2(a1)", ".byte 0x03,0x95,0x25,0x00");
+    test("lw a0, -2048(sp)", ".byte 0x03,0x25,0x01,0x80");
+    test("ld a0, (a1)", ".byte 0x03,0xb5,0x05,0x00");
+    test("lbu a0, 1(a1)", ".byte 0x03,0xc5,0x15,0x00");
+    test("lhu a0, -2(a1)", ".byte 0x03,0xd5,0xe5,0xff");
+    test("lwu a0, 4(a1)", ".byte 0x03,0xe5,0x45,0x00");
+    test("sb a0, -1(a1)", ".byte 0xa3,0x8f,0xa5,0xfe");
+    test("sh a0, 2(a1)", ".byte 0x23,0x91,0xa5,0x00");
+    test("sw a0, -4(sp)", ".byte 0x23,0x2e,0xa1,0xfe");
+    test("sd a0, 2047(a1)", ".byte 0xa3,0xbf,0xa5,0x7e");
+    test("lw a0, %lo(sym)(a1)", "lw a0,%lo(sym)(a1)");
+}
+
+#[test]
+#[should_panic(expected = "immediate -2049 out of range [-2048, 2047]")]
+fn test_imm12_range() {
+    test("addi a0, a1, -2049", "");
+}
+
+#[test]
+fn test_strip_comments() {
+    let content = "add a0, a1, a2 # sum\n\
+                   // whole line\n\
+                   li a0, ';' # semicolon\n\
+                   sub a0, /* inline */ a0, a1\n\
+                   /* block\n\
+                   still the block */ ret\n\
+                   .ascii \"# not a comment\" // but this";
+    assert_eq!(
+        prepare_lines(content),
+        vec![
+            "add a0, a1, a2",
+            "",
+            "li a0, ';'",
+            "sub a0,   a0, a1",
+            "",
+            "ret",
+            ".ascii \"# not a comment\"",
+        ]
+    );
+}
+
+#[test]
+fn test_statements() {
+    assert_eq!(
+        split_statements("addi a0,a0,1; addi a1,a1,1"),
+        vec!["addi a0,a0,1", "addi a1,a1,1"]
+    );
+    assert_eq!(
+        split_statements("li a0, ';' ;; nop;"),
+        vec!["li a0, ';'", "nop"]
+    );
+    assert_eq!(split_statements(".ascii \"a;b\""), vec![".ascii \"a;b\""]);
+    assert_eq!(split_statements(""), vec![""]);
+    let options = Options {
+        line_comments: true,
+        ..Default::default()
+    };
+    let lines = vec![String::from("addi a0, a0, 1; addi a1, a1, 1")];
+    assert_eq!(
+        assemble("a.s", lines, &options),
+        vec![
+            "# addi a0,a0,1",
+            ".byte 0x13,0x05,0x15,0x00 # a.s:1",
+            "# addi a1,a1,1",
+            ".byte 0x93,0x85,0x15,0x00 # a.s:1",
+        ]
+    );
+}
+
+#[test]
+fn test_keep_case() {
+    assert_eq!(prepare_lines("  LI A0, 'A'  "), vec!["LI A0, 'A'"]);
+    let case = |line: &str| parse_line(line).to_string();
+    assert_eq!(case("LI A0, 'A'"), "li a0,'A'");
+    assert_eq!(case("LW A0, %LO(MySym)(SP)"), "lw a0,%lo(MySym)(sp)");
+    assert_eq!(case(".ASCIZ \"Hello, World\""), ".asciz \"Hello, World\"");
+    assert_eq!(case("CSRR A0, MSTATUS"), "csrr a0,mstatus");
+    assert_eq!(case("FADD.S FA0, FA1, FA2, RTZ"), "fadd.s fa0,fa1,fa2,rtz");
+    assert_eq!(case("BNEZ A0, Loop"), "bnez a0,Loop");
+    assert_eq!(case("ADDI A0, A0, 0X1F"), "addi a0,a0,0x1f");
+    assert_eq!(case("FENCE RW, W"), "fence rw,w");
+    assert_eq!(case("BUF_SIZE = 256"), "BUF_SIZE = 256");
+    assert_eq!(case("Msg: .asciz \"Hi\""), "Msg: .asciz \"Hi\"");
+    assert_eq!(parse_line("MyLabel:").raw.unwrap(), "MyLabel:");
+    assert_eq!(split_operands("a0, ','"), vec!["a0", " ','"]);
+}
+
+#[test]
+fn test_option() {
+    let lines = vec![
+        ".option push",
+        ".option rvc",
+        "addi a0, a0, 1",
+        ".option pop",
+        "addi a0, a0, 1",
+        ".option pic",
+        "la a0, sym",
+        ".option arch, +xtheadba",
+        "th.addsl a0, a1, a2, 1",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            ".option push",
+            ".option rvc",
+            "# addi a0,a0,1",
+            ".byte 0x05,0x05",
+            ".option pop",
+            "# addi a0,a0,1",
+            ".byte 0x13,0x05,0x15,0x00",
+            ".option pic",
+            "# la a0,sym",
+            ".Lpcrel_la0:",
+            "auipc a0,%got_pcrel_hi(sym)",
+            "ld a0,%pcrel_lo(.Lpcrel_la0)(a0)",
+            ".option arch,+xtheadba",
+            "# th.addsl a0,a1,a2,1",
+            ".byte 0x0b,0x95,0xc5,0x02",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = ".option pop without .option push")]
+fn test_option_pop() {
+    assemble(
+        "a.s",
+        vec![String::from(".option pop")],
+        &Options::default(),
+    );
+}
+
+#[test]
+fn test_radix_literals() {
+    test("rori a0, a1, 0x1f", ".byte 0x13,0xd5,0xf5,0x61");
+    test("bseti a0, a1, 0b101", ".byte 0x13,0x95,0x55,0x28");
+    test("roriw a0, a1, 0o17", ".byte 0x1b,0xd5,0xf5,0x60");
+    test(
+        ".insn i 0x13, 0, a0, a1, -0b1010",
+        ".byte 0x13,0x85,0x65,0xff",
+    );
+}
+
+#[test]
+#[should_panic(expected = "shift amount 0x40 out of range [0, 63]")]
+fn test_shamt_range() {
+    test("rori a0, a1, 0x40", "");
+}
+
+#[test]
+fn test_sext_w() {
+    test("sext.w a0, a1", ".byte 0x1b,0x85,0x05,0x00");
+}
+
+#[test]
+fn test_zext_b_w() {
+    test("zext.b a0, a1", ".byte 0x13,0xf5,0xf5,0x0f");
+    test("zext.w a0, a1", ".byte 0x3b,0x85,0x05,0x08");
+}
+
+#[test]
+fn test_zicond() {
+    test("czero.eqz a0, a1, a2", ".byte 0x33,0xd5,0xc5,0x0e");
+    test("czero.nez a0, a1, a2", ".byte 0x33,0xf5,0xc5,0x0e");
+}
+
+#[test]
+fn test_zawrs() {
+    test("wrs.nto", ".byte 0x73,0x00,0xd0,0x00");
+    test("wrs.sto", ".byte 0x73,0x00,0xd0,0x01");
+}
+
+#[test]
+fn test_cbo() {
+    test("cbo.clean (a0)", ".byte 0x0f,0x20,0x15,0x00");
+    test("cbo.flush 0(a0)", ".byte 0x0f,0x20,0x25,0x00");
+    test("cbo.inval (a0)", ".byte 0x0f,0x20,0x05,0x00");
+    test("cbo.zero (a0)", ".byte 0x0f,0x20,0x45,0x00");
+}
+
+#[test]
+fn test_hints() {
+    test("pause", ".byte 0x0f,0x00,0x00,0x01");
+    test("ntl.p1", ".byte 0x33,0x00,0x20,0x00");
+    test("ntl.pall", ".byte 0x33,0x00,0x30,0x00");
+    test("ntl.s1", ".byte 0x33,0x00,0x40,0x00");
+    test("ntl.all", ".byte 0x33,0x00,0x50,0x00");
+}
+
+#[test]
+fn test_zimop() {
+    test("mop.r.0 a0, a1", ".byte 0x73,0xc5,0xc5,0x81");
+    test("mop.r.31 a0, a1", ".byte 0x73,0xc5,0xf5,0xcd");
+    test("mop.r.13 t0, t1", ".byte 0xf3,0x42,0xd3,0x8d");
+    test("mop.rr.0 a0, a1, a2", ".byte 0x73,0xc5,0xc5,0x82");
+    test("mop.rr.7 a0, a1, a2", ".byte 0x73,0xc5,0xc5,0xce");
+}
+
+#[test]
+fn test_bclr() {
+    test("bclr s10, a4, a5", ".byte 0x33,0x1d,0xf7,0x48");
+}
+
+#[test]
+fn test_lr_sc() {
+    test("lr.w a0, (a1)", ".byte 0x2f,0xa5,0x05,0x10");
+    test("lr.d t0, 0(sp)", ".byte 0xaf,0x32,0x01,0x10");
+    test("sc.w a0, a2, (a1)", ".byte 0x2f,0xa5,0xc5,0x18");
+    test("sc.d s1, a3, (t1)", ".byte 0xaf,0x34,0xd3,0x18");
+}
+
+#[test]
+fn test_amo() {
+    test("amoswap.w a0, a1, (a2)", ".byte 0x2f,0x25,0xb6,0x08");
+    test("amoadd.d.aq t0, t1, (sp)", ".byte 0xaf,0x32,0x61,0x04");
+    test("amomaxu.w.rl s0, s1, (a0)", ".byte 0x2f,0x24,0x95,0xe2");
+    test("amoor.d.aqrl a5, a4, 0(a3)", ".byte 0xaf,0xb7,0xe6,0x46");
+    test("lr.w.aq a0, (a1)", ".byte 0x2f,0xa5,0x05,0x14");
+    test("sc.d.aqrl a0, a2, (a1)", ".byte 0x2f,0xb5,0xc5,0x1e");
+}
+
+#[test]
+fn test_amocas() {
+    test("amocas.w a0, a1, (a2)", ".byte 0x2f,0x25,0xb6,0x28");
+    test("amocas.d.aqrl a0, a2, (a3)", ".byte 0x2f,0xb5,0xc6,0x2e");
+    test("amocas.q a0, a2, (a3)", ".byte 0x2f,0xc5,0xc6,0x28");
+    test("amocas.q.aq zero, zero, (a3)", ".byte 0x2f,0xc0,0x06,0x2c");
+}
+
+#[test]
+fn test_zabha() {
+    test("amoadd.b a0, a1, (a2)", ".byte 0x2f,0x05,0xb6,0x00");
+    test("amoswap.h.aq a0, a1, (a2)", ".byte 0x2f,0x15,0xb6,0x0c");
+    test("amomaxu.b.aqrl a0, a1, (a2)", ".byte 0x2f,0x05,0xb6,0xe6");
+    test("amocas.b a0, a1, (a2)", ".byte 0x2f,0x05,0xb6,0x28");
+    test("amocas.h.rl a0, a1, (a2)", ".byte 0x2f,0x15,0xb6,0x2a");
+    assert!(parse_line("lr.b a0, (a1)").convert().is_none());
+}
+
+#[test]
+#[should_panic(expected = "amocas.q needs an even register, got a1")]
+fn test_amocas_odd_pair() {
+    test("amocas.q a0, a1, (a2)", "");
+}
+
+#[test]
+fn test_float() {
+    test("fadd.s ft0, ft1, ft2", ".byte 0x53,0xf0,0x20,0x00");
+    test("fsub.s fa0, fa1, fs2", ".byte 0x53,0xf5,0x25,0x09");
+    test("fdiv.s f31, f30, f29", ".byte 0xd3,0x7f,0xdf,0x19");
+    test("fsqrt.s fs11, ft11", ".byte 0xd3,0xfd,0x0f,0x58");
+    test("fsgnjx.s fa0, fa1, fa2", ".byte 0x53,0xa5,0xc5,0x20");
+    test("fmax.s ft3, ft4, ft5", ".byte 0xd3,0x11,0x52,0x28");
+    test("fmadd.s fa0, fa1, fa2, fa3", ".byte 0x43,0xf5,0xc5,0x68");
+    test("fnmadd.s ft0, ft1, ft2, ft3", ".byte 0x4f,0xf0,0x20,0x18");
+    test("flw ft0, -4(a0)", ".byte 0x07,0x20,0xc5,0xff");
+    test("fsw fs1, 2044(sp)", ".byte 0x27,0x2e,0x91,0x7e");
+}
+
+#[test]
+#[should_panic(expected = "a1 is an integer register, expected a floating-point register")]
+fn test_float_int_reg() {
+    test("fadd.s fa0, a1, fa2", "");
+}
+
+#[test]
+#[should_panic(expected = "fa0 is a floating-point register, expected an integer register")]
+fn test_float_fp_reg_as_int() {
+    test("flw ft0, 0(fa0)", "");
+}
+
+#[test]
+fn test_float_sign_injection_pseudos() {
+    test("fmv.s fa0, fa1", ".byte 0x53,0x85,0xb5,0x20");
+    test("fabs.s fa0, fa1", ".byte 0x53,0xa5,0xb5,0x20");
+    test("fneg.s fa0, fa1", ".byte 0x53,0x95,0xb5,0x20");
+    test("fmv.d ft0, fs1", ".byte 0x53,0x80,0x94,0x22");
+    test("fabs.d fa0, fa1", ".byte 0x53,0xa5,0xb5,0x22");
+    test("fneg.d fa0, fa1", ".byte 0x53,0x95,0xb5,0x22");
+    test("fmv.h fa0, fa1", ".byte 0x53,0x85,0xb5,0x24");
+    test("fabs.h fa0, fa1", ".byte 0x53,0xa5,0xb5,0x24");
+    test("fneg.h fa0, fa1", ".byte 0x53,0x95,0xb5,0x24");
+    test("fcvt.s.h fa0, fa1", ".byte 0x53,0x85,0x25,0x40");
+}
+
+#[test]
+fn test_double() {
+    test("fadd.d fa0, fa1, fa2", ".byte 0x53,0xf5,0xc5,0x02");
+    test("fmadd.d fa0, fa1, fa2, fa3", ".byte 0x43,0xf5,0xc5,0x6a");
+    test("fsgnj.d fa0, fa1, fa2", ".byte 0x53,0x85,0xc5,0x22");
+    test("fsqrt.d ft0, ft1", ".byte 0x53,0xf0,0x00,0x5a");
+    test("fld fa0, 8(sp)", ".byte 0x07,0x35,0x81,0x00");
+    test("fsd fa0, -8(sp)", ".byte 0x27,0x3c,0xa1,0xfe");
+    test("fcvt.d.s fa0, fa1", ".byte 0x53,0x85,0x05,0x42");
+    test("fcvt.s.d fa0, fa1", ".byte 0x53,0xf5,0x15,0x40");
+    test("fcvt.d.w fa0, a0", ".byte 0x53,0x05,0x05,0xd2");
+    test("fcvt.d.lu fa0, a0", ".byte 0x53,0x75,0x35,0xd2");
+    test("fcvt.w.d a0, fa0", ".byte 0x53,0x75,0x05,0xc2");
+}
+
+#[test]
+fn test_quad() {
+    test("fadd.q fa0, fa1, fa2", ".byte 0x53,0xf5,0xc5,0x06");
+    test("fmadd.q fa0, fa1, fa2, fa3", ".byte 0x43,0xf5,0xc5,0x6e");
+    test("flq fa0, 8(sp)", ".byte 0x07,0x45,0x81,0x00");
+    test("fsq fa0, -8(sp)", ".byte 0x27,0x4c,0xa1,0xfe");
+    test("fcvt.q.d fa0, fa1", ".byte 0x53,0x85,0x15,0x46");
+    test("fcvt.d.q fa0, fa1", ".byte 0x53,0xf5,0x35,0x42");
+    test("fcvt.q.l fa0, a1", ".byte 0x53,0x85,0x25,0xd6");
+}
+
+#[test]
+fn test_float_compare_convert() {
+    test("feq.s a0, fa0, fa1", ".byte 0x53,0x25,0xb5,0xa0");
+    test("flt.d a0, fa0, fa1", ".byte 0x53,0x15,0xb5,0xa2");
+    test("fle.s t0, ft0, ft1", ".byte 0xd3,0x02,0x10,0xa0");
+    test("fclass.d a0, fa0", ".byte 0x53,0x15,0x05,0xe2");
+    test("fmv.x.w a0, fa0", ".byte 0x53,0x05,0x05,0xe0");
+    test("fmv.w.x fa0, a0", ".byte 0x53,0x05,0x05,0xf0");
+    test("fmv.x.d a0, fa0", ".byte 0x53,0x05,0x05,0xe2");
+    test("fmv.d.x fa0, a0", ".byte 0x53,0x05,0x05,0xf2");
+    test("fcvt.s.w fa0, a0", ".byte 0x53,0x75,0x05,0xd0");
+    test("fcvt.s.wu fa0, a0", ".byte 0x53,0x75,0x15,0xd0");
+    test("fcvt.l.s a0, fa0", ".byte 0x53,0x75,0x25,0xc0");
+    test("fcvt.lu.d a0, fa0", ".byte 0x53,0x75,0x35,0xc2");
+    test("fcvt.wu.d a0, fa0", ".byte 0x53,0x75,0x15,0xc2");
+    test("fcvt.d.l fa0, a0", ".byte 0x53,0x75,0x25,0xd2");
+    test("fcvt.d.wu fa0, a0", ".byte 0x53,0x05,0x15,0xd2");
+}
+
+#[test]
+fn test_zfa() {
+    test("fli.s ft0, 1.0", ".byte 0x53,0x00,0x18,0xf0");
+    test("fli.d fa0, min", ".byte 0x53,0x85,0x10,0xf2");
+    test("fli.d fa0, inf", ".byte 0x53,0x05,0x1f,0xf2");
+    test("fli.s fa0, nan", ".byte 0x53,0x85,0x1f,0xf0");
+    test("fli.q fa0, -1.0", ".byte 0x53,0x05,0x10,0xf6");
+    test("fli.s fa0, 1.52587890625e-05", ".byte 0x53,0x05,0x11,0xf0");
+    test("fli.d fa0, 65536.0", ".byte 0x53,0x85,0x1e,0xf2");
+    test("fminm.s fa0, fa1, fa2", ".byte 0x53,0xa5,0xc5,0x28");
+    test("fmaxm.d fa0, fa1, fa2", ".byte 0x53,0xb5,0xc5,0x2a");
+    test("fround.s fa0, fa1", ".byte 0x53,0xf5,0x45,0x40");
+    test("fround.d fa0, fa1, rtz", ".byte 0x53,0x95,0x45,0x42");
+    test("froundnx.s fa0, fa1, rne", ".byte 0x53,0x85,0x55,0x40");
+    test("fleq.s a0, fa1, fa2", ".byte 0x53,0xc5,0xc5,0xa0");
+    test("fltq.d a0, fa1, fa2", ".byte 0x53,0xd5,0xc5,0xa2");
+    test("fcvtmod.w.d a0, fa1, rtz", ".byte 0x53,0x95,0x85,0xc2");
+}
+
+#[test]
+#[should_panic(expected = "0.1 is not a valid fli constant")]
+fn test_fli_invalid() {
+    test("fli.s fa0, 0.1", "");
+}
+
+#[test]
+fn test_rounding_mode() {
+    test("fadd.s ft0, ft1, ft2, rne", ".byte 0x53,0x80,0x20,0x00");
+    test("fmul.d fa0, fa1, fa2, rtz", ".byte 0x53,0x95,0xc5,0x12");
+    test("fsqrt.s fs11, ft11, rdn", ".byte 0xd3,0xad,0x0f,0x58");
+    test(
+        "fmadd.s fa0, fa1, fa2, fa3, rup",
+        ".byte 0x43,0xb5,0xc5,0x68",
+    );
+    test("fcvt.w.d a0, fa0, rmm", ".byte 0x53,0x45,0x05,0xc2");
+    test("fcvt.d.w fa0, a0, dyn", ".byte 0x53,0x75,0x05,0xd2");
+    test("fdiv.s f31, f30, f29, dyn", ".byte 0xd3,0x7f,0xdf,0x19");
 }
 
-fn parse_line(line: &str) -> TextInstruction {
-    let fields: Vec<&str> = line.split(" ").collect();
-    if fields.len() >= 2 {
-        let opcode = String::from(fields[0]);
-        let index = line.find(" ").unwrap();
-        let operands: Vec<String> = line[index + 1..]
-            .split(",")
-            .map(|r| String::from(r.trim()))
-            .filter(|r| r.len() > 0)
-            .collect();
-        TextInstruction {
-            opcode,
-            operands,
-            raw: None,
-        }
-    } else {
-        let mut r = TextInstruction::new();
-        r.raw = Some(String::from(line));
-        r
-    }
+#[test]
+#[should_panic(expected = "invalid rounding mode")]
+fn test_rounding_mode_invalid() {
+    test("fadd.s ft0, ft1, ft2, rxx", "");
 }
 
-fn test(line: &str, bytes: &str) {
-    let inst = parse_line(line);
-    let inst2 = inst.convert();
-    if let Some(i) = inst2 {
-        let res = i.to_string();
-        // println!("{}\n{}", line, i.to_bits_string());
-        assert_eq!(bytes, res);
-    } else {
-        assert_eq!(format!("{}", inst), bytes);
-    }
+#[test]
+fn test_fence_i() {
+    test("fence.i", ".byte 0x0f,0x10,0x00,0x00");
 }
 
 #[test]
-fn test_adduw() {
-    test("add.uw a2, s11, s5", ".byte 0x3b,0x86,0x5d,0x09");
+fn test_fence() {
+    test("fence", ".byte 0x0f,0x00,0xf0,0x0f");
+    test("fence iorw, iorw", ".byte 0x0f,0x00,0xf0,0x0f");
+    test("fence r, w", ".byte 0x0f,0x00,0x10,0x02");
+    test("fence io, rw", ".byte 0x0f,0x00,0x30,0x0c");
+    test("fence w,o", ".byte 0x0f,0x00,0x40,0x01");
+    test("fence.tso", ".byte 0x0f,0x00,0x30,0x83");
 }
 
 #[test]
-fn test_andn() {
-    test("andn zero, tp, s6", ".byte 0x33,0x70,0x62,0x41");
+#[should_panic(expected = "invalid fence operand")]
+fn test_fence_order() {
+    test("fence wr, w", "");
 }
 
 #[test]
-fn test_bclr() {
-    test("bclr s10, a4, a5", ".byte 0x33,0x1d,0xf7,0x48");
+fn test_instruction_length() {
+    assert_eq!(instruction_length(0x0001), Some(2));
+    assert_eq!(instruction_length(0x0013), Some(4));
+    assert_eq!(instruction_length(0x001f), Some(6));
+    assert_eq!(instruction_length(0x003f), Some(8));
+    assert_eq!(instruction_length(0x007f), Some(10));
+    assert_eq!(instruction_length(0x607f), Some(22));
+    assert_eq!(instruction_length(0x707f), None);
+}
+
+#[test]
+fn test_insn() {
+    test(".insn 0x0001", ".byte 0x01,0x00");
+    test(".insn 4, 0x0000a5b3", ".byte 0xb3,0xa5,0x00,0x00");
+    test(
+        ".insn 6, 0x123456789a9f",
+        ".byte 0x9f,0x9a,0x78,0x56,0x34,0x12",
+    );
+    test(
+        ".insn 0x0123456789abcdbf",
+        ".byte 0xbf,0xcd,0xab,0x89,0x67,0x45,0x23,0x01",
+    );
+}
+
+#[test]
+fn test_insn_custom() {
+    test(
+        ".insn custom0 0, 0, a0, a1, a2",
+        ".byte 0x0b,0x85,0xc5,0x00",
+    );
+    test(
+        ".insn custom1 7, 127, t0, t1, t2",
+        ".byte 0xab,0x72,0x73,0xfe",
+    );
+    test(".insn custom-2 2, a0, sp, -1", ".byte 0x5b,0x25,0xf1,0xff");
+    test(
+        ".insn custom_3 1, 64, s0, s1, s2",
+        ".byte 0x7b,0x94,0x24,0x81",
+    );
+}
+
+#[test]
+#[should_panic(expected = "doesn't fit into 3 bits")]
+fn test_insn_custom_funct3() {
+    test(".insn custom0 8, 0, a0, a1, a2", "");
+}
+
+#[test]
+#[should_panic(expected = "doesn't match")]
+fn test_insn_length_mismatch() {
+    test(".insn 6, 0x13", "");
 }
 
 #[test]
@@ -610,12 +2842,767 @@ fn test_others() {
     test("sh3add.uw a3,s5,gp", ".byte 0xbb,0xe6,0x3a,0x20");
 }
 
+#[test]
+fn test_foreign_arch() {
+    let lines = |text: &str| -> Vec<String> { text.lines().map(String::from).collect() };
+    let riscv = lines(".text\nmain:\n  lui a0, %hi(msg)\n  addi a0, a0, %lo(msg)\n  ret");
+    assert_eq!(detect_foreign_arch(&riscv), None);
+    let x86 = lines("main:\n  pushq %rbp\n  movq %rsp, %rbp");
+    assert_eq!(detect_foreign_arch(&x86), Some((1, "x86")));
+    let att = lines("  addl $1, %eax");
+    assert_eq!(detect_foreign_arch(&att), Some((0, "x86")));
+    let sse = lines("  movaps %XMM15, %r10d");
+    assert_eq!(detect_foreign_arch(&sse), Some((0, "x86")));
+    let modulo = lines("  addi a0, a0, 10%3\n  li a1, BASE % 8\n  auipc a2, %pcrel_hi(x)");
+    assert_eq!(detect_foreign_arch(&modulo), None);
+    let source = "addi a0, a0, 10%3";
+    let output = assemble_source("a.s", source, &Options::default(), true);
+    assert_eq!(
+        output.unwrap(),
+        "# addi a0,a0,1\n.byte 0x13,0x05,0x15,0x00\n"
+    );
+    let arm = lines(".text\n  stp x29, x30, [sp, #-16]!");
+    assert_eq!(detect_foreign_arch(&arm), Some((1, "ARM")));
+}
+
 #[test]
 fn test_add() {
     test("add t6, t6, s0", "add t6,t6,s0");
     test("xor t6, t6, s6", "xor t6,t6,s6");
 }
 
+#[test]
+fn test_balign_pattern() {
+    // 2- and 4-byte fill patterns, after zeros up to a multiple of their size
+    let lines = vec![
+        ".data",
+        ".byte 1",
+        ".balignw 4, 0x1234",
+        ".byte 2",
+        ".balignl 16, 0x00000013",
+        ".p2alignw 1, -1",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            ".data",
+            "# .byte 1",
+            ".byte 0x01",
+            "# .balignw 4,0x1234",
+            ".byte 0x00,0x34,0x12",
+            "# .byte 2",
+            ".byte 0x02",
+            "# .balignl 16,0x00000013",
+            ".byte 0x00,0x00,0x00,0x13,0x00,0x00,0x00,0x13,0x00,0x00,0x00",
+            "# .p2alignw 1,-1",
+        ]
+    );
+}
+
+// The register an instruction writes, as written: the first operand, except
+// for stores, branches, jumps through a register, fences and the like, whose
+// operands are only read, and jalr rs and c.jalr, which write ra.
+fn destination(inst: &TextInstruction) -> Option<&str> {
+    const NO_DEST: [&str; 43] = [
+        "sb",
+        "sh",
+        "sw",
+        "sd",
+        "fsh",
+        "fsw",
+        "fsd",
+        "fsq",
+        "beq",
+        "bne",
+        "blt",
+        "bge",
+        "bltu",
+        "bgeu",
+        "beqz",
+        "bnez",
+        "blez",
+        "bgez",
+        "bltz",
+        "bgtz",
+        "jr",
+        "c.sw",
+        "c.sd",
+        "c.fsw",
+        "c.fsd",
+        "c.swsp",
+        "c.sdsp",
+        "c.fswsp",
+        "c.fsdsp",
+        "c.jr",
+        "sfence.vma",
+        "sinval.vma",
+        "hfence.vvma",
+        "hfence.gvma",
+        "hinval.vvma",
+        "hinval.gvma",
+        "hsv.b",
+        "hsv.h",
+        "hsv.w",
+        "hsv.d",
+        "c.beqz",
+        "c.bnez",
+        "fence",
+    ];
+    let opcode = inst.opcode.as_str();
+    let first = inst.operands.first()?;
+    match opcode {
+        "c.jalr" => Some("ra"),
+        "jalr" if inst.operands.len() == 1 => Some("ra"),
+        _ if opcode.starts_with('.') || NO_DEST.contains(&opcode) => None,
+        _ => Some(first),
+    }
+}
+
+// The recognized ways of setting up a reserved register, e.g.
+// "la gp, __global_pointer$" in the startup code.
+fn is_reserved_reg_init(reg: &str, inst: &TextInstruction) -> bool {
+    reg == "gp"
+        && inst
+            .operands
+            .iter()
+            .any(|o| o.contains("__global_pointer$"))
+}
+
+// Returns the reserved register written by the instruction, if any.
+fn reserved_reg_write<'a>(inst: &TextInstruction, reserved: &'a [String]) -> Option<&'a str> {
+    if inst.raw.is_some() {
+        return None;
+    }
+    let dest = REG_MAP.get(destination(inst)?)?;
+    reserved
+        .iter()
+        .find(|r| REG_MAP.get(r.as_str()) == Some(dest) && !is_reserved_reg_init(r, inst))
+        .map(|r| r.as_str())
+}
+
+// -W options: "reserved-reg" warns on writes to gp and tp, "reserved-reg=gp"
+// only on the listed registers, "no-reserved-reg" turns it off again.
+fn parse_warnings(warnings: &[&str]) -> Vec<String> {
+    let mut reserved = vec![];
+    for w in warnings {
+        if *w == "reserved-reg" {
+            reserved = vec![String::from("gp"), String::from("tp")];
+        } else if let Some(regs) = w.strip_prefix("reserved-reg=") {
+            reserved = regs.split(',').map(|r| String::from(r.trim())).collect();
+            for r in &reserved {
+                reg_name2value(r);
+            }
+        } else if *w == "no-reserved-reg" {
+            reserved = vec![];
+        } else {
+            panic!("unknown warning option -W{}", w);
+        }
+    }
+    reserved
+}
+
+// split the input into trimmed, lowercased lines
+fn prepare_lines(content: &str) -> Vec<String> {
+    let mut in_block = false;
+    content
+        .split('\n')
+        .map(|l| String::from(strip_comments(l, &mut in_block).trim()))
+        .collect()
+}
+
+// Remove # and // line comments and /* */ block comments, which may span
+// lines: in_block tells whether the line starts inside one. The lines stay,
+// so that line numbers don't change.
+fn strip_comments(line: &str, in_block: &mut bool) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if *in_block {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                *in_block = false;
+            }
+            continue;
+        }
+        match quote {
+            Some(q) => {
+                if !escaped && c == q {
+                    quote = None;
+                }
+                escaped = !escaped && c == '\\';
+            }
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == '#' => break,
+            None if c == '/' && chars.peek() == Some(&'/') => break,
+            None if c == '/' && chars.peek() == Some(&'*') => {
+                chars.next();
+                *in_block = true;
+                // keep the tokens around the comment apart
+                res.push(' ');
+                continue;
+            }
+            None => {}
+        }
+        res.push(c);
+    }
+    res
+}
+
+// Registers are case-insensitive, and so are CSR names, rounding modes,
+// vtype fields and fence sets where the instruction takes them, i.e. in
+// operand `operand` from 0. Symbols and labels aren't.
+fn is_keyword(opcode: &str, operand: usize, word: &str) -> bool {
+    const ROUNDING_MODES: [&str; 6] = ["rne", "rtz", "rdn", "rup", "rmm", "dyn"];
+    const VTYPE: [&str; 15] = [
+        "e8", "e16", "e32", "e64", "m1", "m2", "m4", "m8", "mf8", "mf4", "mf2", "ta", "tu", "ma",
+        "mu",
+    ];
+    REG_MAP.contains_key(word)
+        || FP_REG_MAP.contains_key(word)
+        || vector::is_vreg(word)
+        || (csr::csr_operand(opcode) == Some(operand) && csr::is_csr_name(word))
+        || (opcode.starts_with('f') && ROUNDING_MODES.contains(&word))
+        || ((opcode.ends_with("vsetvli") || opcode == "vsetivli") && VTYPE.contains(&word))
+        || (opcode.starts_with("fence") && word.chars().all(|c| "iorw".contains(c)))
+}
+
+// split at the commas which aren't part of a character literal or string
+fn split_operands(operands: &str) -> Vec<&str> {
+    split_unquoted(operands, ',')
+}
+
+// Split a line into its statements, like GNU as does at ';', and the labels
+// in front of a statement, as in `loop: addi a0, a0, -1`, into statements of
+// their own. A line without either stays as it is, even if it's empty.
+fn split_statements(line: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    for mut statement in split_unquoted(line, ';') {
+        while let Some(pos) = statement.find(':') {
+            let label = statement[..=pos].trim();
+            if labels::label_name(label).is_none() {
+                break;
+            }
+            statements.push(label);
+            statement = &statement[pos + 1..];
+        }
+        statements.push(statement);
+    }
+    if statements.len() == 1 {
+        return statements;
+    }
+    statements
+        .into_iter()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// split at the separators which aren't part of a character literal or string
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut res = vec![];
+    let mut quote = None;
+    let mut escaped = false;
+    let mut begin = 0;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if !escaped && c == q {
+                    quote = None;
+                }
+                escaped = !escaped && c == '\\';
+            }
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == separator => {
+                res.push(&text[begin..i]);
+                begin = i + c.len_utf8();
+            }
+            None => {}
+        }
+    }
+    res.push(&text[begin..]);
+    res
+}
+
+// error message for input that isn't RISC-V assembly, see detect_foreign_arch
+fn check_arch(lines: &[String]) -> Result<(), String> {
+    match detect_foreign_arch(lines) {
+        Some((index, arch)) => Err(format!(
+            "line {}: \"{}\" looks like {} assembly, not RISC-V.",
+            index + 1,
+            lines[index],
+            arch
+        )),
+        None => Ok(()),
+    }
+}
+
+#[derive(Clone, Default)]
+struct Options {
+    // print more information: encoding
+    debug: bool,
+    // annotate .byte lines with the source location: file:line
+    line_comments: bool,
+    // rewrite instructions into their compressed form where possible
+    compress: bool,
+    // local rewrites before encoding, see peephole.rs
+    peephole: bool,
+    // warn on writes to these registers: -Wreserved-reg
+    reserved_regs: Vec<String>,
+    // draft extensions enabled by --experimental, only "p" so far
+    experimental: Vec<String>,
+    // --xlen 32, RV64 otherwise
+    rv32: bool,
+    // vendor extensions from --march: xtheadba, ...
+    vendor: Vec<String>,
+    // Zba in --march, for the shNadd of --peephole
+    zba: bool,
+    // long li constants from a .rodata pool, see litpool.rs
+    litpool: bool,
+    // constants from --defsym NAME=VALUE
+    defsyms: Vec<(String, i64)>,
+    // la through the GOT, from .option pic
+    pic: bool,
+    // list the constants on stderr
+    dump_consts: bool,
+    // from --max-macro-depth, macros::MAX_DEPTH otherwise
+    max_macro_depth: Option<usize>,
+    // from --max-expansions, source::MAX_EXPANSIONS otherwise
+    max_expansions: Option<usize>,
+}
+
+// .option: rvc and norvc turn compression on and off like --compress, pic and
+// nopic switch la to the GOT and back, arch adds and removes vendor
+// extensions and C, or sets them like --march. push and pop save and restore
+// all of them. The line is passed on to the real assembler too.
+fn apply_option(state: &mut Options, stack: &mut Vec<Options>, inst: &TextInstruction) {
+    match inst.operands.first().map(|o| o.as_str()) {
+        Some("rvc") => state.compress = true,
+        Some("norvc") => state.compress = false,
+        Some("pic") => state.pic = true,
+        Some("nopic") => state.pic = false,
+        Some("push") => stack.push(state.clone()),
+        Some("pop") => match stack.pop() {
+            Some(saved) => *state = saved,
+            None => panic!(".option pop without .option push"),
+        },
+        Some("arch") => {
+            for arch in &inst.operands[1..] {
+                if let Some(ext) = arch.strip_prefix('+') {
+                    if ext == "c" {
+                        state.compress = true;
+                    } else if ext == "zba" {
+                        state.zba = true;
+                    } else if ext.starts_with('x') {
+                        state
+                            .vendor
+                            .extend(thead::parse_march(&format!("rv64_{}", ext)));
+                    }
+                } else if let Some(ext) = arch.strip_prefix('-') {
+                    if ext == "c" {
+                        state.compress = false;
+                    } else if ext == "zba" {
+                        state.zba = false;
+                    }
+                    state.vendor.retain(|v| v != ext);
+                } else {
+                    if arch.starts_with("rv32") != state.rv32 {
+                        panic!(".option arch {} can't change the xlen", arch);
+                    }
+                    let base = arch.split('_').next().unwrap();
+                    state.compress = base[4..].contains('c');
+                    state.zba = has_zba(arch);
+                    state.vendor = thead::parse_march(arch);
+                }
+            }
+        }
+        // relax, norelax, ...
+        _ => {}
+    }
+}
+
+// whether an --march string has Zba, on its own or as part of B
+fn has_zba(march: &str) -> bool {
+    let mut parts = march.split('_');
+    let base = parts.next().unwrap_or("");
+    base.get(4..).is_some_and(|e| e.contains('b')) || parts.any(|e| e == "zba")
+}
+
+// encoding of one instruction with the extensions and xlen of the options
+fn convert_with_options(inst: &TextInstruction, options: &Options) -> Option<BinaryInstruction> {
+    if packed::is_packed(&inst.opcode) {
+        if !options.experimental.iter().any(|e| e == "p") {
+            panic!(
+                "{} is a draft P-extension instruction, enable it with --experimental p",
+                inst.opcode
+            );
+        }
+        inst.convert_packed()
+    } else if let Some(ext) = thead::extension_of(&inst.opcode) {
+        if !options.vendor.iter().any(|v| v == ext) {
+            panic!("{} needs {} in --march", inst.opcode, ext);
+        }
+        inst.convert_thead()
+    } else if options.rv32 {
+        inst.convert_rv32()
+    } else {
+        inst.convert_rv64()
+    }
+}
+
+// li from the literal pool with --litpool
+fn pool_load(
+    pool: &mut litpool::LitPool,
+    inst: &TextInstruction,
+    index: usize,
+    options: &Options,
+) -> Option<Vec<String>> {
+    if !options.litpool || options.rv32 {
+        return None;
+    }
+    pool.load(inst, index)
+}
+
+// .byte lines of strings and .incbin are split after this many bytes
+const BYTES_PER_LINE: usize = 16;
+
+// a line after the first pass of assemble
+enum Item {
+    // lines which are emitted as they are
+    Text(Vec<String>),
+    Binary(TextInstruction, Vec<BinaryInstruction>),
+    // data directive, its bytes may depend on labels
+    Data(TextInstruction),
+    // alignment and its fill byte, the padding is resolved by labels.rs
+    Align(TextInstruction, Option<Vec<u8>>),
+    // instruction which refers to a label, the operand and the text which is
+    // replaced by the value of labels::resolve
+    Symbolic(TextInstruction, usize, String),
+    // .option, which isn't moved with the output of its section
+    Option(String),
+}
+
+fn emit_binary(
+    output: &mut Vec<String>,
+    location: &str,
+    inst: &TextInstruction,
+    bin_insts: &[BinaryInstruction],
+    options: &Options,
+) {
+    output.push(format!("# {}", inst));
+    for bin_inst in bin_insts {
+        if options.debug {
+            output.push(format!("# Encoding {}", bin_inst.to_bits_string()));
+        }
+        if options.line_comments {
+            output.push(format!("{} # {}", bin_inst, location));
+        } else {
+            output.push(format!("{}", bin_inst));
+        }
+    }
+}
+
+fn assemble(name: &str, all_lines: Vec<String>, options: &Options) -> Vec<String> {
+    assemble_with_symbols(name, all_lines, options).0
+}
+
+// the assembled lines and the symbol table of symbols.rs
+fn assemble_with_symbols(
+    name: &str,
+    all_lines: Vec<String>,
+    options: &Options,
+) -> (Vec<String>, serde_json::Value) {
+    let mut output = vec![];
+    let mut compressed_count = 0;
+    // labels of the auipc in la/call/... expansions
+    let mut pcrel_count = 0;
+    let mut pool = litpool::LitPool::default();
+    // the --defsym constants are passed on to the real assembler too
+    let max_macro_depth = options.max_macro_depth.unwrap_or(macros::MAX_DEPTH);
+    let max_expansions = options.max_expansions.unwrap_or(source::MAX_EXPANSIONS);
+    let constants = options.defsyms.iter().cloned().collect();
+    let mut source = source::Source::new(constants, max_macro_depth, max_expansions);
+    for (sym, val) in &options.defsyms {
+        output.push(format!(".set {},{}", sym, val));
+    }
+    source.read(name, all_lines, 0);
+    let mut all_text_inst = source.finish();
+    if options.dump_consts {
+        for (sym, val) in &options.defsyms {
+            eprintln!("--defsym: {} = {}", sym, val);
+        }
+        for line in source.dump_constants() {
+            eprintln!("{}", line);
+        }
+    }
+    if options.peephole {
+        let count = all_text_inst.len();
+        all_text_inst = peephole::peephole(all_text_inst, options);
+        eprintln!(
+            "{}: peephole removed {} instructions",
+            name,
+            count - all_text_inst.len()
+        );
+    }
+    // first pass: encode what can be encoded, and the slots of labels.rs
+    let mut items = vec![];
+    let mut slots = vec![];
+    let mut sections = sections::Sections::new();
+    let mut symbols = symbols::Symbols::default();
+    // the options with the .option lines so far, and the ones of .option push
+    let mut state = options.clone();
+    let mut option_stack = vec![];
+    // lines to do, and whether they come from an la/call/... expansion
+    let mut queue: VecDeque<(usize, TextInstruction, bool)> = all_text_inst
+        .into_iter()
+        .map(|(index, inst)| (index, inst, false))
+        .collect();
+    while let Some((index, inst, expanded)) = queue.pop_front() {
+        // the expansions were checked as a whole
+        let reserved = if expanded {
+            None
+        } else {
+            reserved_reg_write(&inst, &options.reserved_regs)
+        };
+        if let Some(reg) = reserved {
+            eprintln!(
+                "{}: warning: \"{}\" writes reserved register {}{}",
+                source.location(index),
+                inst,
+                reg,
+                source.calls(index)
+            );
+        }
+        // the directives are replaced by the headers of the sections
+        if let Some(section) = sections.switch(&inst) {
+            slots.push(labels::Slot::Section(section));
+            items.push((index, section, Item::Text(vec![])));
+            continue;
+        }
+        let section = sections.current();
+        symbols.directive(&inst, slots.len());
+        if inst.opcode == ".option" {
+            apply_option(&mut state, &mut option_stack, &inst);
+            slots.push(labels::Slot::Size(Some(0)));
+            items.push((index, section, Item::Option(format!("{}", inst))));
+            continue;
+        }
+        if let Some(raw) = inst.raw {
+            let mut lines = vec![];
+            if options.litpool && !expanded && (raw == ".ltorg" || raw.ends_with(':')) {
+                lines.extend(pool.flush());
+            }
+            // unknown instruction, normally it's directive or label.
+            if !(options.litpool && raw == ".ltorg") {
+                lines.push(raw.clone());
+            }
+            let slot = match labels::label_name(&raw) {
+                Some(label) => {
+                    symbols.label(label);
+                    labels::Slot::Label(String::from(label))
+                }
+                None if raw.is_empty() => labels::Slot::Size(Some(0)),
+                None => labels::Slot::Size(None),
+            };
+            slots.push(slot);
+            items.push((index, section, Item::Text(lines)));
+        } else if let Some(lines) = pool_load(&mut pool, &inst, pcrel_count, &state) {
+            pcrel_count += 1;
+            let mut text = vec![format!("# {}", inst)];
+            text.extend(lines);
+            slots.push(labels::Slot::Size(None));
+            items.push((index, section, Item::Text(text)));
+        } else if pseudo::is_pcrel(&inst) {
+            let label = format!(".Lpcrel_{}{}", inst.opcode, pcrel_count);
+            pcrel_count += 1;
+            let lines = if state.pic && inst.opcode == "la" {
+                inst.expand_got(&label, state.rv32)
+            } else {
+                inst.expand_pcrel(&label)
+            };
+            for line in lines.iter().rev() {
+                queue.push_front((index, parse_line(line), true));
+            }
+            slots.push(labels::Slot::Size(Some(0)));
+            items.push((index, section, Item::Text(vec![format!("# {}", inst)])));
+        } else if let Some(bytes) = data::bytes(&inst) {
+            slots.push(labels::Slot::Size(Some(bytes.len())));
+            if bytes.is_empty() {
+                items.push((index, section, Item::Text(vec![format!("{}", inst)])));
+            } else {
+                let bin_insts = bytes
+                    .chunks(BYTES_PER_LINE)
+                    .map(|b| BinaryInstruction::from_bytes(b.to_vec()))
+                    .collect();
+                items.push((index, section, Item::Binary(inst, bin_insts)));
+            }
+        } else if let Some((align, fill, max)) = data::alignment(&inst) {
+            slots.push(labels::Slot::Align(align, max));
+            // nops in code, zeros in data
+            let fill = fill.or(if sections.is_code(section) {
+                None
+            } else {
+                Some(vec![0])
+            });
+            items.push((index, section, Item::Align(inst, fill)));
+        } else if let Some(width) = data::width(&inst.opcode) {
+            slots.push(labels::Slot::Size(Some(width * inst.operands.len())));
+            items.push((index, section, Item::Data(inst)));
+        } else if let Some((pos, pattern, reloc)) = labels::label_operand(&inst, state.compress) {
+            slots.push(labels::Slot::Ref(reloc));
+            items.push((index, section, Item::Symbolic(inst, pos, pattern)));
+        } else {
+            if let Some(e) = labels::offset_error(&inst) {
+                panic!("{}: {}{}", source.location(index), e, source.calls(index));
+            }
+            // pseudo-instructions like li may expand to several instructions
+            let mut bin_insts = match inst.convert_pseudo(state.rv32) {
+                Some(seq) => seq,
+                None => convert_with_options(&inst, &state).into_iter().collect(),
+            };
+            if !bin_insts.is_empty() {
+                // raw .insn values are emitted as written
+                if state.compress && inst.opcode != ".insn" {
+                    for bin_inst in bin_insts.iter_mut() {
+                        if let Some((_, compressed)) = compressed::compress(bin_inst) {
+                            *bin_inst = compressed;
+                            compressed_count += 1;
+                        }
+                    }
+                }
+                let size = bin_insts.iter().map(|b| b.data.len()).sum();
+                slots.push(labels::Slot::Size(Some(size)));
+                items.push((index, section, Item::Binary(inst, bin_insts)));
+            } else {
+                // instruction, but not B-Extension
+                let size = if labels::is_zero_sized(&inst) {
+                    Some(0)
+                } else {
+                    data::space_size(&inst)
+                };
+                slots.push(labels::Slot::Size(size));
+                items.push((index, section, Item::Text(vec![format!("{}", inst)])));
+            }
+        }
+    }
+    // second pass: the references to labels which could be resolved
+    let layout = labels::resolve(&slots);
+    let values = &layout.values;
+    let label_values = data::label_values(&layout.labels);
+    let mut groups = vec![vec![]; sections.len()];
+    for (slot, (index, section, item)) in items.into_iter().enumerate() {
+        // the lines so far go first, in any section
+        if let Item::Option(line) = item {
+            sections.flush(&mut output, &mut groups, false);
+            output.push(line);
+            continue;
+        }
+        let output = &mut groups[section];
+        match item {
+            Item::Option(_) => unreachable!(),
+            Item::Text(lines) => output.extend(lines),
+            Item::Binary(inst, bin_insts) => {
+                emit_binary(output, &source.location(index), &inst, &bin_insts, options)
+            }
+            Item::Data(inst) => match data::encode(&inst.opcode, &inst.operands, &label_values) {
+                Some(bytes) if !bytes.is_empty() => {
+                    let bin_inst = BinaryInstruction::from_bytes(bytes);
+                    emit_binary(output, &source.location(index), &inst, &[bin_inst], options);
+                }
+                _ => output.push(format!("{}", inst)),
+            },
+            Item::Align(inst, fill) => match values.get(&slot) {
+                Some(padding) => {
+                    let bin_insts: Vec<_> = data::padding(*padding as usize, fill.as_deref())
+                        .into_iter()
+                        .map(BinaryInstruction::from_bytes)
+                        .collect();
+                    emit_binary(output, &source.location(index), &inst, &bin_insts, options);
+                }
+                None => output.push(format!("{}", inst)),
+            },
+            // not compressed, the first pass counted 4 bytes
+            Item::Symbolic(inst, pos, pattern) => match values.get(&slot) {
+                Some(value) => {
+                    if let labels::Slot::Ref(labels::Reloc::Offset(label, _, align)) = &slots[slot]
+                    {
+                        if value % align != 0 {
+                            panic!(
+                                "{}: branch target {} is not aligned to {} bytes{}",
+                                source.location(index),
+                                label,
+                                align,
+                                source.calls(index)
+                            );
+                        }
+                    }
+                    let mut resolved = inst.clone();
+                    let value = value.to_string();
+                    resolved.operands[pos] = inst.operands[pos].replacen(&pattern, &value, 1);
+                    let bin_inst = convert_with_options(&resolved, options).unwrap();
+                    emit_binary(output, &source.location(index), &inst, &[bin_inst], options);
+                }
+                None => output.push(format!("{}", inst)),
+            },
+        }
+    }
+    sections.flush(&mut output, &mut groups, true);
+    output.extend(pool.flush());
+    if options.compress || compressed_count > 0 {
+        eprintln!(
+            "{}: compressed {} instructions, {} bytes saved",
+            name,
+            compressed_count,
+            compressed_count * 2
+        );
+    }
+    let table = symbols.table(&layout, &sections);
+    (output, table)
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        String::from(*msg)
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown error")
+    }
+}
+
+// Assembled text of one source, or the error message. Used by archive and
+// batch mode, which report errors per file instead of aborting.
+fn assemble_source(
+    name: &str,
+    content: &str,
+    options: &Options,
+    arch_check: bool,
+) -> Result<String, String> {
+    let lines = prepare_lines(content);
+    if arch_check {
+        check_arch(&lines)?;
+    }
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut output = String::new();
+        for line in assemble(name, lines, options) {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        output
+    }))
+    .map_err(|e| panic_message(e.as_ref()))
+}
+
+// status of one file in the index of archive and batch mode
+fn file_status<T>(file: &str, result: &Result<T, String>) -> serde_json::Value {
+    match result {
+        Ok(_) => serde_json::json!({ "file": file, "status": "ok" }),
+        Err(msg) => serde_json::json!({ "file": file, "status": "error", "message": msg }),
+    }
+}
+
 fn main() {
     let matches = App::new("rna")
         .version("1.0")
@@ -628,6 +3615,22 @@ fn main() {
                 .takes_value(true)
                 .help("input file, default stdin"),
         )
+        .arg(
+            Arg::with_name("input-archive")
+                .required(false)
+                .long("input-archive")
+                .takes_value(true)
+                .requires("output-archive")
+                .conflicts_with("input")
+                .help("assemble every .s file of a .tar, .tar.gz or .zip archive"),
+        )
+        .arg(
+            Arg::with_name("output-archive")
+                .required(false)
+                .long("output-archive")
+                .takes_value(true)
+                .help("archive for the results of --input-archive, with an index.json"),
+        )
         .arg(
             Arg::with_name("debug")
                 .required(false)
@@ -635,41 +3638,231 @@ fn main() {
                 .long("debug")
                 .help("debug flags, print more information: encoding"),
         )
+        .arg(
+            Arg::with_name("line-comments")
+                .required(false)
+                .long("line-comments")
+                .help("annotate each .byte line with its source location: # file:line"),
+        )
+        .arg(
+            Arg::with_name("litpool")
+                .required(false)
+                .long("litpool")
+                .help("load li constants which need more than 4 instructions from a .rodata pool"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .required(false)
+                .long("compress")
+                .help("use compressed instructions where possible, like .option rvc"),
+        )
+        .arg(
+            Arg::with_name("peephole")
+                .required(false)
+                .long("peephole")
+                .help("remove redundant instructions, merge lui+addi into li and slli+add into shNadd"),
+        )
+        .arg(
+            Arg::with_name("skip-arch-check")
+                .required(false)
+                .long("skip-arch-check")
+                .help("don't abort on input that looks like x86 or ARM assembly"),
+        )
+        .arg(
+            Arg::with_name("csr-defs")
+                .required(false)
+                .long("csr-defs")
+                .takes_value(true)
+                .help("TOML file with vendor CSR names: name = 0x7c0"),
+        )
+        .arg(
+            Arg::with_name("opcodes")
+                .required(false)
+                .long("opcodes")
+                .takes_value(true)
+                .help("TOML or JSON file with custom instructions: format, opcode, funct fields and operands"),
+        )
+        .arg(
+            Arg::with_name("defsym")
+                .required(false)
+                .long("defsym")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("define a constant for the expressions in operands: NAME=VALUE"),
+        )
+        .arg(
+            Arg::with_name("dump-consts")
+                .required(false)
+                .long("dump-consts")
+                .help("list every constant with its value and where it's defined, on stderr"),
+        )
+        .arg(
+            Arg::with_name("max-macro-depth")
+                .required(false)
+                .long("max-macro-depth")
+                .takes_value(true)
+                .help("how deep macros may call macros, default 100"),
+        )
+        .arg(
+            Arg::with_name("max-expansions")
+                .required(false)
+                .long("max-expansions")
+                .takes_value(true)
+                .help("how many lines macros and .rept may expand to in all, default 1000000"),
+        )
+        .arg(
+            Arg::with_name("map")
+                .required(false)
+                .long("map")
+                .takes_value(true)
+                .help("write the symbol table to this file, as JSON"),
+        )
+        .arg(
+            Arg::with_name("warning")
+                .required(false)
+                .short("W")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("warnings: reserved-reg[=gp,tp] warns on writes to reserved registers"),
+        )
+        .arg(
+            Arg::with_name("xlen")
+                .required(false)
+                .long("xlen")
+                .takes_value(true)
+                .possible_values(&["32", "64"])
+                .default_value("64")
+                .help("register width, 32 selects the RV32 encodings and rejects RV64-only instructions"),
+        )
+        .arg(
+            Arg::with_name("march")
+                .required(false)
+                .long("march")
+                .takes_value(true)
+                .help("target ISA, e.g. rv64gc_xtheadba_xtheadbb enables vendor extensions, rv32* implies --xlen 32"),
+        )
+        .arg(
+            Arg::with_name("experimental")
+                .required(false)
+                .long("experimental")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["p"])
+                .help("enable a draft extension: p (packed SIMD)"),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("assemble every .s file below a directory in parallel")
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("directory with the sources"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("output directory, mirrors the input directory, with a summary.json"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .required(false)
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .help("number of threads, default the number of CPUs"),
+                ),
+        )
         .get_matches();
     let mut content = String::new();
-    let is_debug = matches.is_present("debug");
+    let warnings: Vec<&str> = matches
+        .values_of("warning")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    let march = matches.value_of("march").unwrap_or("rv64gc");
+    let options = Options {
+        debug: matches.is_present("debug"),
+        line_comments: matches.is_present("line-comments"),
+        compress: matches.is_present("compress"),
+        peephole: matches.is_present("peephole"),
+        reserved_regs: parse_warnings(&warnings),
+        experimental: matches
+            .values_of("experimental")
+            .map_or(vec![], |v| v.map(String::from).collect()),
+        rv32: matches.value_of("xlen") == Some("32") || march.starts_with("rv32"),
+        vendor: thead::parse_march(march),
+        zba: has_zba(march),
+        litpool: matches.is_present("litpool"),
+        defsyms: matches
+            .values_of("defsym")
+            .map_or(vec![], |v| v.map(expr::parse_defsym).collect()),
+        // only from .option pic
+        pic: false,
+        dump_consts: matches.is_present("dump-consts"),
+        max_macro_depth: matches
+            .value_of("max-macro-depth")
+            .map(|depth| parse_unsigned(depth) as usize),
+        max_expansions: matches
+            .value_of("max-expansions")
+            .map(|count| parse_unsigned(count) as usize),
+    };
+    let name = matches.value_of("input").unwrap_or("<stdin>");
+    if let Some(path) = matches.value_of("csr-defs") {
+        let mut defs = String::new();
+        File::open(path).unwrap().read_to_string(&mut defs).unwrap();
+        csr::load_csr_defs(&defs);
+    }
+    if let Some(path) = matches.value_of("opcodes") {
+        let mut defs = String::new();
+        File::open(path).unwrap().read_to_string(&mut defs).unwrap();
+        opcodes::load_opcodes(path, &defs);
+    }
+    let arch_check = !matches.is_present("skip-arch-check");
+    if let Some(batch) = matches.subcommand_matches("batch") {
+        let jobs = match batch.value_of("jobs") {
+            Some(jobs) => parse_unsigned(jobs) as usize,
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        batch::assemble_dir(
+            batch.value_of("dir").unwrap(),
+            batch.value_of("output").unwrap(),
+            jobs,
+            &options,
+            arch_check,
+        );
+        return;
+    }
+    if let Some(input) = matches.value_of("input-archive") {
+        let output = matches.value_of("output-archive").unwrap();
+        archive::assemble_archive(input, output, &options, arch_check);
+        return;
+    }
 
     if matches.is_present("input") {
-        let name = matches.value_of("input").unwrap();
         let mut input = File::open(name).unwrap();
         input.read_to_string(&mut content).unwrap();
     } else {
         let mut stdin = io::stdin();
         stdin.read_to_string(&mut content).unwrap();
     }
-    let all_lines = content.split("\n");
-    let all_lines: Vec<String> = all_lines
-        .into_iter()
-        .map(|l| l.trim())
-        .map(|l| l.to_lowercase())
-        .collect();
-    let all_text_inst: Vec<TextInstruction> =
-        all_lines.into_iter().map(|l| parse_line(&l)).collect();
-    for inst in all_text_inst {
-        if let Some(raw) = inst.raw {
-            // unknown instruction, normally it's directive or label.
-            println!("{}", raw);
-        } else {
-            if let Some(bin_inst) = inst.convert() {
-                if is_debug {
-                    println!("# Encoding {}", bin_inst.to_bits_string());
-                }
-                println!("# {}", inst);
-                println!("{}", bin_inst);
-            } else {
-                // instruction, but not B-Extension
-                println!("{}", inst);
-            }
+    let all_lines = prepare_lines(&content);
+    if arch_check {
+        if let Err(msg) = check_arch(&all_lines) {
+            eprintln!("error: {}", msg);
+            eprintln!("Check the input file, or use --skip-arch-check to assemble it anyway.");
+            std::process::exit(1);
         }
     }
+    let (output, symbols) = assemble_with_symbols(name, all_lines, &options);
+    for line in output {
+        println!("{}", line);
+    }
+    if let Some(path) = matches.value_of("map") {
+        std::fs::write(path, serde_json::to_string_pretty(&symbols).unwrap()).unwrap();
+    }
 }
@@ -0,0 +1,244 @@
+// Custom instructions from --opcodes, e.g. for accelerators in the custom-0/1/2/3
+// opcode spaces. They are encoded with the .insn formats of insn.rs:
+//   ["myacc.mac"]
+//   format = "r"
+//   opcode = "custom0"
+//   funct3 = 1
+//   funct7 = 0x20
+//   operands = ["rd", "rs1", "rs2"]
+// Fields and operands which are left out are 0 and zero.
+use super::{insn, split_address, BinaryInstruction, TextInstruction};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+struct OpcodeDef {
+    format: String,
+    opcode: String,
+    // funct2/funct3/funct4/funct7 by name
+    functs: HashMap<String, u32>,
+    operands: Vec<String>,
+}
+
+// the .insn operands of each format, after the opcode
+fn format_fields(format: &str) -> &'static [&'static str] {
+    match format {
+        "r" => &["funct3", "funct7", "rd", "rs1", "rs2"],
+        "r4" => &["funct3", "funct2", "rd", "rs1", "rs2", "rs3"],
+        "i" => &["funct3", "rd", "rs1", "imm"],
+        "s" => &["funct3", "rs2", "imm(rs1)"],
+        "b" => &["funct3", "rs1", "rs2", "imm"],
+        "u" | "j" => &["rd", "imm"],
+        "cr" => &["funct4", "rd", "rs2"],
+        "ci" => &["funct3", "rd", "imm"],
+        _ => panic!("unknown instruction format {}", format),
+    }
+}
+
+// the operands which can be written: registers, imm and imm(rs1) for i and s
+fn operand_kinds(fields: &[&'static str]) -> Vec<&'static str> {
+    let mut kinds: Vec<&str> = fields
+        .iter()
+        .copied()
+        .filter(|f| !f.starts_with("funct"))
+        .collect();
+    if kinds.contains(&"rs1") && kinds.contains(&"imm") {
+        kinds.push("imm(rs1)");
+    }
+    kinds
+}
+
+lazy_static! {
+    // custom instructions from --opcodes, by mnemonic
+    static ref USER_OPCODES: RwLock<HashMap<String, OpcodeDef>> = RwLock::new(HashMap::new());
+}
+
+pub(super) fn is_user_opcode(opcode: &str) -> bool {
+    USER_OPCODES.read().unwrap().contains_key(opcode)
+}
+
+fn parse_def(name: &str, value: &toml::Value) -> OpcodeDef {
+    let table = match value.as_table() {
+        Some(t) => t,
+        None => panic!("opcode {} must be a table", name),
+    };
+    let format = match table.get("format").and_then(|f| f.as_str()) {
+        Some(f) => f.to_lowercase(),
+        None => panic!("opcode {} needs a format", name),
+    };
+    let fields = format_fields(&format);
+    let opcode = match table.get("opcode") {
+        Some(toml::Value::Integer(i)) => i.to_string(),
+        Some(toml::Value::String(s)) => s.to_lowercase(),
+        _ => panic!("opcode {} needs an opcode number or name", name),
+    };
+    let mut functs = HashMap::new();
+    let mut operands = vec![];
+    for (key, value) in table {
+        match key.as_str() {
+            "format" | "opcode" => {}
+            "operands" => {
+                let list = match value.as_array() {
+                    Some(l) => l,
+                    None => panic!("opcode {}: operands must be a list", name),
+                };
+                for operand in list {
+                    match operand.as_str() {
+                        Some(o) if operand_kinds(fields).contains(&o) => {
+                            operands.push(String::from(o))
+                        }
+                        _ => panic!(
+                            "opcode {}: operands of format {} are {:?}",
+                            name,
+                            format,
+                            operand_kinds(fields)
+                        ),
+                    }
+                }
+            }
+            funct if fields.contains(&funct) && funct.starts_with("funct") => {
+                match value.as_integer() {
+                    Some(v) if v >= 0 => functs.insert(String::from(funct), v as u32),
+                    _ => panic!("opcode {}: {} must be a number, got {}", name, funct, value),
+                };
+            }
+            _ => panic!(
+                "opcode {}: unknown field {} for format {}",
+                name, key, format
+            ),
+        }
+    }
+    OpcodeDef {
+        format,
+        opcode,
+        functs,
+        operands,
+    }
+}
+
+// Load custom instructions from a TOML file, or JSON if the name ends with
+// .json. Every definition is encoded once here, so that a bad opcode or funct
+// field is reported when loading.
+pub(super) fn load_opcodes(path: &str, content: &str) {
+    let value = if path.ends_with(".json") {
+        serde_json::from_str::<toml::Value>(content).map_err(|e| e.to_string())
+    } else {
+        content.parse::<toml::Value>().map_err(|e| e.to_string())
+    };
+    let table = match value {
+        Ok(toml::Value::Table(t)) => t,
+        Ok(_) => panic!("opcode definitions must be a table"),
+        Err(e) => panic!("invalid opcode definitions: {}", e),
+    };
+    let mut map = HashMap::new();
+    for (name, value) in table {
+        let def = parse_def(&name, &value);
+        encode(&def, &[]);
+        map.insert(name.to_lowercase(), def);
+    }
+    USER_OPCODES.write().unwrap().extend(map);
+}
+
+// assemble .insn operands from the definition and the written operands
+fn encode(def: &OpcodeDef, operands: &[String]) -> BinaryInstruction {
+    let mut values: HashMap<&str, String> = HashMap::new();
+    for (kind, operand) in def.operands.iter().zip(operands) {
+        if kind == "imm(rs1)" {
+            let (offset, rs1) = split_address(operand);
+            let offset = if offset.is_empty() { "0" } else { offset };
+            values.insert("imm", String::from(offset));
+            values.insert("rs1", String::from(rs1));
+        } else {
+            values.insert(kind, operand.clone());
+        }
+    }
+    let mut ops = vec![def.opcode.clone()];
+    for field in format_fields(&def.format) {
+        let value = match *field {
+            "imm(rs1)" => format!(
+                "{}({})",
+                values.get("imm").map_or("0", |v| v),
+                values.get("rs1").map_or("zero", |v| v)
+            ),
+            "imm" => values
+                .get("imm")
+                .cloned()
+                .unwrap_or_else(|| String::from("0")),
+            funct if funct.starts_with("funct") => {
+                def.functs.get(funct).copied().unwrap_or(0).to_string()
+            }
+            reg => values
+                .get(reg)
+                .cloned()
+                .unwrap_or_else(|| String::from("zero")),
+        };
+        ops.push(value);
+    }
+    insn::insn_format(&def.format, &ops)
+}
+
+impl TextInstruction {
+    pub(super) fn convert_user(&self) -> Option<BinaryInstruction> {
+        let opcodes = USER_OPCODES.read().unwrap();
+        let def = opcodes.get(&self.opcode)?;
+        if self.operands.len() != def.operands.len() {
+            panic!(
+                "{} expects {} operands: {}",
+                self.opcode,
+                def.operands.len(),
+                def.operands.join(", ")
+            );
+        }
+        Some(encode(def, &self.operands))
+    }
+}
+
+#[cfg(test)]
+use super::test;
+
+#[test]
+fn test_user_opcodes() {
+    load_opcodes(
+        "ops.toml",
+        r#"
+        ["acc.mac"]
+        format = "r"
+        opcode = "custom0"
+        funct3 = 1
+        funct7 = 0x20
+        operands = ["rd", "rs1", "rs2"]
+
+        ["acc.ld"]
+        format = "i"
+        opcode = 0x2b
+        funct3 = 2
+        operands = ["rd", "imm(rs1)"]
+
+        ["acc.reset"]
+        format = "i"
+        opcode = "custom1"
+        "#,
+    );
+    test("acc.mac a0, a1, a2", ".byte 0x0b,0x95,0xc5,0x40");
+    test("acc.ld a0, -8(a1)", ".byte 0x2b,0xa5,0x85,0xff");
+    test("acc.reset", ".byte 0x2b,0x00,0x00,0x00");
+}
+
+#[test]
+fn test_user_opcodes_json() {
+    load_opcodes(
+        "ops.json",
+        r#"{"acc.st": {"format": "s", "opcode": "custom2", "funct3": 3,
+            "operands": ["rs2", "imm(rs1)"]}}"#,
+    );
+    test("acc.st a0, 16(sp)", ".byte 0x5b,0x38,0xa1,0x00");
+}
+
+#[test]
+#[should_panic(expected = "opcode bad: unknown field funct7 for format i")]
+fn test_user_opcodes_bad_field() {
+    load_opcodes(
+        "ops.toml",
+        "[bad]\nformat = \"i\"\nopcode = \"custom0\"\nfunct7 = 1\n",
+    );
+}
@@ -0,0 +1,141 @@
+// Draft P-extension (packed SIMD, v0.9), only with --experimental p. The
+// encodings share the OP-P major opcode with vector crypto and may change
+// before ratification.
+use super::{BinaryInstruction, TextInstruction};
+
+// mnemonic, funct7, funct3. All are R-type: rd, rs1, rs2.
+const PACKED_OPS: [(&str, u8, u8); 64] = [
+    // 16-bit add/subtract, halving (r) and saturating (k) forms
+    ("add16", 0b0100000, 0b000),
+    ("radd16", 0b0000000, 0b000),
+    ("uradd16", 0b0010000, 0b000),
+    ("kadd16", 0b0001000, 0b000),
+    ("ukadd16", 0b0011000, 0b000),
+    ("sub16", 0b0100001, 0b000),
+    ("rsub16", 0b0000001, 0b000),
+    ("ursub16", 0b0010001, 0b000),
+    ("ksub16", 0b0001001, 0b000),
+    ("uksub16", 0b0011001, 0b000),
+    ("cras16", 0b0100010, 0b000),
+    ("rcras16", 0b0000010, 0b000),
+    ("urcras16", 0b0010010, 0b000),
+    ("kcras16", 0b0001010, 0b000),
+    ("ukcras16", 0b0011010, 0b000),
+    ("crsa16", 0b0100011, 0b000),
+    ("rcrsa16", 0b0000011, 0b000),
+    ("urcrsa16", 0b0010011, 0b000),
+    ("kcrsa16", 0b0001011, 0b000),
+    ("ukcrsa16", 0b0011011, 0b000),
+    // 8-bit add/subtract
+    ("add8", 0b0100100, 0b000),
+    ("radd8", 0b0000100, 0b000),
+    ("uradd8", 0b0010100, 0b000),
+    ("kadd8", 0b0001100, 0b000),
+    ("ukadd8", 0b0011100, 0b000),
+    ("sub8", 0b0100101, 0b000),
+    ("rsub8", 0b0000101, 0b000),
+    ("ursub8", 0b0010101, 0b000),
+    ("ksub8", 0b0001101, 0b000),
+    ("uksub8", 0b0011101, 0b000),
+    // shifts
+    ("sra16", 0b0101000, 0b000),
+    ("srl16", 0b0101001, 0b000),
+    ("sll16", 0b0101010, 0b000),
+    ("sra8", 0b0101100, 0b000),
+    ("srl8", 0b0101101, 0b000),
+    ("sll8", 0b0101110, 0b000),
+    // compares
+    ("cmpeq16", 0b0100110, 0b000),
+    ("scmplt16", 0b0000110, 0b000),
+    ("scmple16", 0b0001110, 0b000),
+    ("ucmplt16", 0b0010110, 0b000),
+    ("ucmple16", 0b0011110, 0b000),
+    ("cmpeq8", 0b0100111, 0b000),
+    ("scmplt8", 0b0000111, 0b000),
+    ("scmple8", 0b0001111, 0b000),
+    ("ucmplt8", 0b0010111, 0b000),
+    ("ucmple8", 0b0011111, 0b000),
+    // min/max
+    ("smin16", 0b1000000, 0b000),
+    ("smax16", 0b1000001, 0b000),
+    ("umin16", 0b1001000, 0b000),
+    ("umax16", 0b1001001, 0b000),
+    ("smin8", 0b1000100, 0b000),
+    ("smax8", 0b1000101, 0b000),
+    ("umin8", 0b1001100, 0b000),
+    ("umax8", 0b1001101, 0b000),
+    // widening multiplies, the result is twice as wide as the elements
+    ("smul16", 0b1010000, 0b000),
+    ("smulx16", 0b1010001, 0b000),
+    ("umul16", 0b1011000, 0b000),
+    ("umulx16", 0b1011001, 0b000),
+    ("smul8", 0b1010100, 0b000),
+    ("smulx8", 0b1010101, 0b000),
+    ("umul8", 0b1011100, 0b000),
+    ("umulx8", 0b1011101, 0b000),
+    // 32-bit saturating add/subtract
+    ("kaddw", 0b0000000, 0b001),
+    ("ksubw", 0b0000001, 0b001),
+];
+
+pub(super) fn is_packed(opcode: &str) -> bool {
+    PACKED_OPS.iter().any(|p| p.0 == opcode)
+}
+
+impl TextInstruction {
+    pub(super) fn convert_packed(&self) -> Option<BinaryInstruction> {
+        let (_, funct7, funct3) = PACKED_OPS.iter().find(|p| p.0 == self.opcode)?;
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1110111);
+        res.set_funct3(*funct3);
+        res.set_funct7(*funct7);
+        res.set_operands(&self.operands);
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, parse_line, Options};
+
+#[cfg(test)]
+fn test_packed(line: &str, bytes: &str) {
+    let bin = parse_line(line).convert_packed().unwrap();
+    assert_eq!(format!("{}", bin), bytes);
+}
+
+#[test]
+fn test_packed_simd() {
+    test_packed("add16 a0, a1, a2", ".byte 0x77,0x85,0xc5,0x40");
+    test_packed("kadd16 a0, a1, a2", ".byte 0x77,0x85,0xc5,0x10");
+    test_packed("ukadd8 a0, a1, a2", ".byte 0x77,0x85,0xc5,0x38");
+    test_packed("sub8 a0, a1, a2", ".byte 0x77,0x85,0xc5,0x4a");
+    test_packed("smul8 a0, a1, a2", ".byte 0x77,0x85,0xc5,0xa8");
+    test_packed("umulx16 a0, a1, a2", ".byte 0x77,0x85,0xc5,0xb2");
+    test_packed("sll16 a0, a1, a2", ".byte 0x77,0x85,0xc5,0x54");
+    test_packed("ucmple8 a0, a1, a2", ".byte 0x77,0x85,0xc5,0x3e");
+    test_packed("kaddw a0, a1, a2", ".byte 0x77,0x95,0xc5,0x00");
+    test_packed("ksubw a0, a1, a2", ".byte 0x77,0x95,0xc5,0x02");
+}
+
+#[test]
+fn test_packed_gate() {
+    let options = Options {
+        experimental: vec![String::from("p")],
+        ..Default::default()
+    };
+    let output = assemble("a.s", vec![String::from("add16 a0, a1, a2")], &options);
+    assert_eq!(
+        output,
+        vec!["# add16 a0,a1,a2", ".byte 0x77,0x85,0xc5,0x40"]
+    );
+}
+
+#[test]
+#[should_panic(expected = "add16 is a draft P-extension instruction")]
+fn test_packed_not_enabled() {
+    assemble(
+        "a.s",
+        vec![String::from("add16 a0, a1, a2")],
+        &Options::default(),
+    );
+}
@@ -0,0 +1,245 @@
+// --peephole: safe rewrites of single instructions and adjacent pairs, done
+// on the parsed text before encoding. Directives and labels in between stop
+// any pair rewrite. The rewrites only use what the target has: its xlen and
+// Zba, from the options and the .option lines so far.
+use super::{apply_option, pseudo, Options, TextInstruction, REG_MAP};
+
+fn text_inst(opcode: &str, operands: Vec<String>) -> TextInstruction {
+    TextInstruction {
+        opcode: String::from(opcode),
+        operands,
+        raw: None,
+    }
+}
+
+fn same_reg(a: &str, b: &str) -> bool {
+    match (REG_MAP.get(a), REG_MAP.get(b)) {
+        (Some(x), Some(y)) => x == y,
+        _ => false,
+    }
+}
+
+fn is_zero_reg(r: &str) -> bool {
+    REG_MAP.get(r) == Some(&0)
+}
+
+// addi rd, rd, 0. The canonical nop (addi zero, zero, 0) is kept, it's
+// usually there on purpose.
+fn is_redundant(inst: &TextInstruction) -> bool {
+    inst.opcode == "addi"
+        && inst.operands.len() == 3
+        && same_reg(&inst.operands[0], &inst.operands[1])
+        && !is_zero_reg(&inst.operands[0])
+        && inst.operands[2] == "0"
+}
+
+// lui rd, hi; addi(w) rd, rd, lo => li rd, value, if li doesn't take more
+// instructions
+fn merge_lui_addi(a: &TextInstruction, b: &TextInstruction, rv32: bool) -> Option<TextInstruction> {
+    if a.opcode != "lui" || (b.opcode != "addi" && b.opcode != "addiw") {
+        return None;
+    }
+    if rv32 && b.opcode == "addiw" {
+        return None;
+    }
+    if a.operands.len() != 2 || b.operands.len() != 3 {
+        return None;
+    }
+    let rd = &a.operands[0];
+    if !same_reg(rd, &b.operands[0]) || !same_reg(rd, &b.operands[1]) || is_zero_reg(rd) {
+        return None;
+    }
+    // only plain numbers, not %hi(sym) and the like
+    let hi = a.operands[1].parse::<i64>().ok()?;
+    let lo = b.operands[2].parse::<i64>().ok()?;
+    if !(0..=0xfffff).contains(&hi) || !(-2048..=2047).contains(&lo) {
+        return None;
+    }
+    // lui sign-extends bit 31, addiw and RV32 registers wrap the sum to 32
+    // bits
+    let upper = (hi << 12) as u32 as i32 as i64;
+    let value = if b.opcode == "addiw" || rv32 {
+        (upper + lo) as i32 as i64
+    } else {
+        upper + lo
+    };
+    if pseudo::li_sequence(value, rv32).len() > 2 {
+        return None;
+    }
+    Some(text_inst("li", vec![rd.clone(), value.to_string()]))
+}
+
+// slli rd, rs1, n; add rd, rd, rs2 => shNadd rd, rs1, rs2 (Zba), n in 1..3
+fn merge_shift_add(a: &TextInstruction, b: &TextInstruction) -> Option<TextInstruction> {
+    if a.opcode != "slli" || b.opcode != "add" {
+        return None;
+    }
+    if a.operands.len() != 3 || b.operands.len() != 3 {
+        return None;
+    }
+    let opcode = match a.operands[2].as_str() {
+        "1" => "sh1add",
+        "2" => "sh2add",
+        "3" => "sh3add",
+        _ => return None,
+    };
+    let rd = &a.operands[0];
+    if !same_reg(rd, &b.operands[0]) || is_zero_reg(rd) {
+        return None;
+    }
+    // add is commutative, the shifted value can be either source
+    let rs2 = if same_reg(rd, &b.operands[1]) {
+        &b.operands[2]
+    } else if same_reg(rd, &b.operands[2]) {
+        &b.operands[1]
+    } else {
+        return None;
+    };
+    // add rd, rd, rd doubles the shifted value, shNadd would read the old rd
+    if same_reg(rd, rs2) {
+        return None;
+    }
+    Some(text_inst(
+        opcode,
+        vec![rd.clone(), a.operands[1].clone(), rs2.clone()],
+    ))
+}
+
+// Instructions are paired with their source line index, a merged pair
+// keeps the line of its first instruction.
+pub(super) fn peephole(
+    insts: Vec<(usize, TextInstruction)>,
+    options: &Options,
+) -> Vec<(usize, TextInstruction)> {
+    let mut res: Vec<(usize, TextInstruction)> = vec![];
+    // the target with the .option lines so far, like the first pass
+    let mut state = options.clone();
+    let mut option_stack = vec![];
+    let mut iter = insts.into_iter().peekable();
+    while let Some((index, inst)) = iter.next() {
+        if inst.opcode == ".option" {
+            apply_option(&mut state, &mut option_stack, &inst);
+        }
+        if inst.raw.is_none() {
+            if let Some((_, next)) = iter.peek() {
+                if next.raw.is_none() {
+                    let merged = merge_lui_addi(&inst, next, state.rv32)
+                        .or_else(|| merge_shift_add(&inst, next).filter(|_| state.zba));
+                    if let Some(merged) = merged {
+                        iter.next();
+                        res.push((index, merged));
+                        continue;
+                    }
+                }
+            }
+            if is_redundant(&inst) {
+                continue;
+            }
+        }
+        res.push((index, inst));
+    }
+    res
+}
+
+#[cfg(test)]
+use super::parse_line;
+
+#[cfg(test)]
+fn run_with(lines: &[&str], options: &Options) -> Vec<String> {
+    let insts = lines.iter().map(|l| parse_line(l)).enumerate().collect();
+    peephole(insts, options)
+        .into_iter()
+        .map(|(i, inst)| format!("{}: {}", i + 1, inst))
+        .collect()
+}
+
+// RV64 with Zba
+#[cfg(test)]
+fn run(lines: &[&str]) -> Vec<String> {
+    let options = Options {
+        zba: true,
+        ..Default::default()
+    };
+    run_with(lines, &options)
+}
+
+#[test]
+fn test_peephole_redundant() {
+    assert_eq!(
+        run(&["addi a0, a0, 0", "addi zero, zero, 0", "addi a0, a1, 0"]),
+        vec!["2: addi zero,zero,0", "3: addi a0,a1,0"]
+    );
+}
+
+#[test]
+fn test_peephole_li() {
+    assert_eq!(
+        run(&["lui a0, 74565", "addi a0, a0, 1656"]),
+        vec!["1: li a0,305419896"]
+    );
+    assert_eq!(
+        run(&["lui a0, 524288", "addiw a0, a0, -1"]),
+        vec!["1: li a0,2147483647"]
+    );
+    // li would take 5 instructions
+    assert_eq!(run(&["lui a0, 524288", "addi a0, a0, -1"]).len(), 2);
+    // different register, symbol, label in between
+    assert_eq!(run(&["lui a0, 1", "addi a1, a0, 1"]).len(), 2);
+    assert_eq!(run(&["lui a0, %hi(x)", "addi a0, a0, %lo(x)"]).len(), 2);
+    assert_eq!(run(&["lui a0, 1", "1:", "addi a0, a0, 1"]).len(), 3);
+}
+
+#[test]
+fn test_peephole_shadd() {
+    assert_eq!(
+        run(&["slli a0, a1, 3", "add a0, a0, a2"]),
+        vec!["1: sh3add a0,a1,a2"]
+    );
+    assert_eq!(
+        run(&["slli a0, a1, 1", "add a0, a2, a0"]),
+        vec!["1: sh1add a0,a1,a2"]
+    );
+    assert_eq!(run(&["slli a0, a1, 4", "add a0, a0, a2"]).len(), 2);
+    assert_eq!(run(&["slli a0, a1, 2", "add a0, a0, a0"]).len(), 2);
+    assert_eq!(run(&["slli t0, a1, 2", "add a0, t0, a2"]).len(), 2);
+}
+
+#[test]
+fn test_peephole_li_rv32() {
+    let options = Options {
+        rv32: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        run_with(&["lui a0, 524288", "addi a0, a0, -1"], &options),
+        vec!["1: li a0,2147483647"]
+    );
+    assert_eq!(
+        run_with(&["lui a0, 524288", "addiw a0, a0, -1"], &options).len(),
+        2
+    );
+}
+
+#[test]
+fn test_peephole_no_zba() {
+    // rv64g
+    let lines = ["slli a0, a1, 3", "add a0, a0, a2"];
+    assert_eq!(run_with(&lines, &Options::default()).len(), 2);
+    let lines = [
+        ".option arch, +zba",
+        "slli a0, a1, 3",
+        "add a0, a0, a2",
+        ".option arch, -zba",
+        "slli a0, a1, 3",
+        "add a0, a0, a2",
+    ];
+    assert_eq!(
+        run_with(&lines, &Options::default())[1..],
+        [
+            "2: sh3add a0,a1,a2",
+            "4: .option arch,-zba",
+            "5: slli a0,a1,3",
+            "6: add a0,a0,a2"
+        ]
+    );
+}
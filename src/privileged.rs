@@ -0,0 +1,143 @@
+// Supervisor-level instructions: sfence.vma and Svinval, and the
+// H-extension virtual-machine loads/stores and fences.
+use super::{address_reg_name, BinaryInstruction, TextInstruction};
+
+impl BinaryInstruction {
+    // fence operands: rs1 (address), rs2 (ASID), both default to zero
+    fn set_fence_vma_operands(&mut self, operands: &[String]) {
+        assert!(operands.len() <= 2);
+        self.set_rs1(operands.first().map_or("zero", |r| r.as_ref()));
+        self.set_rs2(operands.get(1).map_or("zero", |r| r.as_ref()));
+    }
+    // hlv.w rd, (rs1): the rs2 field selects unsigned (1) or execute (3)
+    fn set_hlv_operands(&mut self, operands: &[String], variant: u32) {
+        assert_eq!(operands.len(), 2);
+        self.set_rd(operands[0].as_ref());
+        self.set_rs1(address_reg_name(operands[1].as_ref()));
+        self.set(20, 24, BinaryInstruction::bits_array(variant, 5));
+    }
+    // hsv.w rs2, (rs1)
+    fn set_hsv_operands(&mut self, operands: &[String]) {
+        assert_eq!(operands.len(), 2);
+        self.set_rs2(operands[0].as_ref());
+        self.set_rs1(address_reg_name(operands[1].as_ref()));
+    }
+}
+
+impl TextInstruction {
+    pub(super) fn convert_privileged(&self) -> Option<BinaryInstruction> {
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1110011);
+        res.set_funct3(0);
+        res.set_rd("zero");
+        match self.opcode.as_str() {
+            "sfence.vma" => {
+                res.set_funct7(0b0001001);
+                res.set_fence_vma_operands(&self.operands);
+            }
+            "sinval.vma" => {
+                res.set_funct7(0b0001011);
+                res.set_fence_vma_operands(&self.operands);
+            }
+            "sfence.w.inval" | "sfence.inval.ir" => {
+                assert!(self.operands.is_empty());
+                res.set_funct7(0b0001100);
+                // rs2 field selects the variant
+                let variant = if self.opcode == "sfence.w.inval" {
+                    0
+                } else {
+                    1
+                };
+                res.set_rs1("zero");
+                res.set(20, 24, BinaryInstruction::bits_array(variant, 5));
+            }
+            "hfence.vvma" | "hfence.gvma" | "hinval.vvma" | "hinval.gvma" => {
+                res.set_funct7(match self.opcode.as_str() {
+                    "hfence.vvma" => 0b0010001,
+                    "hfence.gvma" => 0b0110001,
+                    "hinval.vvma" => 0b0010011,
+                    _ => 0b0110011,
+                });
+                res.set_fence_vma_operands(&self.operands);
+            }
+            _ => return self.convert_hypervisor_mem(),
+        }
+        Some(res)
+    }
+    // hlv.*, hlvx.* and hsv.*, funct7 is 0b0110 followed by log2(size) and
+    // the store bit
+    fn convert_hypervisor_mem(&self) -> Option<BinaryInstruction> {
+        let (funct7, variant, store) = match self.opcode.as_str() {
+            "hlv.b" => (0b0110000, 0, false),
+            "hlv.bu" => (0b0110000, 1, false),
+            "hlv.h" => (0b0110010, 0, false),
+            "hlv.hu" => (0b0110010, 1, false),
+            "hlvx.hu" => (0b0110010, 3, false),
+            "hlv.w" => (0b0110100, 0, false),
+            "hlv.wu" => (0b0110100, 1, false),
+            "hlvx.wu" => (0b0110100, 3, false),
+            "hlv.d" => (0b0110110, 0, false),
+            "hsv.b" => (0b0110001, 0, true),
+            "hsv.h" => (0b0110011, 0, true),
+            "hsv.w" => (0b0110101, 0, true),
+            "hsv.d" => (0b0110111, 0, true),
+            _ => return None,
+        };
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1110011);
+        res.set_funct3(0b100);
+        res.set_funct7(funct7);
+        if store {
+            res.set_rd("zero");
+            res.set_hsv_operands(&self.operands);
+        } else {
+            res.set_hlv_operands(&self.operands, variant);
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::test;
+
+#[test]
+fn test_sfence_vma() {
+    test("sfence.vma", ".byte 0x73,0x00,0x00,0x12");
+    test("sfence.vma a0", ".byte 0x73,0x00,0x05,0x12");
+    test("sfence.vma a0, a1", ".byte 0x73,0x00,0xb5,0x12");
+}
+
+#[test]
+fn test_svinval() {
+    test("sinval.vma a0, a1", ".byte 0x73,0x00,0xb5,0x16");
+    test("sinval.vma", ".byte 0x73,0x00,0x00,0x16");
+    test("sfence.w.inval", ".byte 0x73,0x00,0x00,0x18");
+    test("sfence.inval.ir", ".byte 0x73,0x00,0x10,0x18");
+}
+
+#[test]
+fn test_hypervisor_load_store() {
+    test("hlv.b a0, (a1)", ".byte 0x73,0xc5,0x05,0x60");
+    test("hlv.bu a0, (a1)", ".byte 0x73,0xc5,0x15,0x60");
+    test("hlv.h a0, 0(a1)", ".byte 0x73,0xc5,0x05,0x64");
+    test("hlv.hu a0, (a1)", ".byte 0x73,0xc5,0x15,0x64");
+    test("hlvx.hu a0, (a1)", ".byte 0x73,0xc5,0x35,0x64");
+    test("hlv.w a0, (a1)", ".byte 0x73,0xc5,0x05,0x68");
+    test("hlv.wu a0, (a1)", ".byte 0x73,0xc5,0x15,0x68");
+    test("hlvx.wu a0, (a1)", ".byte 0x73,0xc5,0x35,0x68");
+    test("hlv.d a0, (a1)", ".byte 0x73,0xc5,0x05,0x6c");
+    test("hsv.b a0, (a1)", ".byte 0x73,0xc0,0xa5,0x62");
+    test("hsv.h a0, (a1)", ".byte 0x73,0xc0,0xa5,0x66");
+    test("hsv.w a0, (a1)", ".byte 0x73,0xc0,0xa5,0x6a");
+    test("hsv.d a0, (a1)", ".byte 0x73,0xc0,0xa5,0x6e");
+}
+
+#[test]
+fn test_hypervisor_fence() {
+    test("hfence.vvma a0, a1", ".byte 0x73,0x00,0xb5,0x22");
+    test("hfence.vvma", ".byte 0x73,0x00,0x00,0x22");
+    test("hfence.gvma a0, a1", ".byte 0x73,0x00,0xb5,0x62");
+    test("hfence.gvma", ".byte 0x73,0x00,0x00,0x62");
+    test("hinval.vvma a0, a1", ".byte 0x73,0x00,0xb5,0x26");
+    test("hinval.gvma a0, a1", ".byte 0x73,0x00,0xb5,0x66");
+}
@@ -0,0 +1,327 @@
+// Pseudo-instructions which expand to more than one instruction. Every
+// instruction of the li expansion gets its own .byte line, la/call/tail/...
+// expand to auipc pairs with %pcrel_hi/%pcrel_lo.
+use super::{insn, parse_imm, BinaryInstruction, TextInstruction};
+
+// lui/addi(w)/slli/srli steps which build an immediate in rd, like
+// RISCVMatInt in LLVM
+fn li_steps(val: i64, rv32: bool, steps: &mut Vec<(&'static str, i64)>) {
+    let lo12 = (val << 52) >> 52;
+    if rv32 || val as i32 as i64 == val {
+        let hi20 = ((val + 0x800) >> 12) & 0xfffff;
+        if hi20 != 0 {
+            steps.push(("lui", hi20));
+        }
+        if lo12 != 0 || hi20 == 0 {
+            // addiw wraps lui 0x80000 + a negative lo12 back to 32 bits
+            let addi = if hi20 != 0 && !rv32 { "addiw" } else { "addi" };
+            steps.push((addi, lo12));
+        }
+        return;
+    }
+    // build the upper bits, shift them into place and add the lower 12 bits
+    let hi52 = ((val as u64).wrapping_add(0x800) >> 12) as i64;
+    let shift = 12 + hi52.trailing_zeros();
+    li_steps(hi52 >> (shift - 12), rv32, steps);
+    steps.push(("slli", shift.into()));
+    if lo12 != 0 {
+        steps.push(("addi", lo12));
+    }
+}
+
+// the shortest steps: positive values with leading zeros may be cheaper as
+// a value with leading ones (or zeros) shifted right, e.g. 0xffffffff is -1
+// shifted right by 32
+pub(super) fn li_sequence(val: i64, rv32: bool) -> Vec<(&'static str, i64)> {
+    let mut best = vec![];
+    li_steps(val, rv32, &mut best);
+    let zeros = val.leading_zeros();
+    if rv32 || best.len() <= 2 || val <= 0 || zeros == 0 {
+        return best;
+    }
+    let shifted = val << zeros;
+    for candidate in [shifted | ((1i64 << zeros) - 1), shifted].iter() {
+        let mut steps = vec![];
+        li_steps(*candidate, rv32, &mut steps);
+        steps.push(("srli", zeros.into()));
+        if steps.len() < best.len() {
+            best = steps;
+        }
+    }
+    best
+}
+
+// auipc + addi/jalr pairs with a symbol, see expand_pcrel
+const PCREL: [&str; 5] = ["la", "lla", "call", "tail", "jump"];
+
+// loads and stores which take a symbol instead of offset(rs1)
+pub(super) const LOADS: [&str; 9] = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", "flw", "fld"];
+pub(super) const STORES: [&str; 6] = ["sb", "sh", "sw", "sd", "fsw", "fsd"];
+
+pub(super) fn is_pcrel(inst: &TextInstruction) -> bool {
+    let opcode = inst.opcode.as_str();
+    let symbol = inst.operands.get(1).is_some_and(|o| !o.contains('('));
+    PCREL.contains(&opcode) || (symbol && (LOADS.contains(&opcode) || STORES.contains(&opcode)))
+}
+
+// li rd, imm: any 32-bit value on RV32, any 64-bit value on RV64
+fn expand_li(rd: &str, imm: &str, rv32: bool) -> Vec<BinaryInstruction> {
+    let val = parse_imm(imm);
+    let val = if rv32 {
+        if !(i32::MIN as i64..=u32::MAX as i64).contains(&val) {
+            panic!("immediate {} out of range for RV32", imm);
+        }
+        val as i32 as i64
+    } else {
+        val
+    };
+    let mut src = "zero";
+    let mut res = vec![];
+    for (op, imm) in li_sequence(val, rv32) {
+        let imm = imm.to_string();
+        let (format, ops) = match op {
+            "lui" => ("u", vec!["lui", rd, &imm]),
+            "addi" => ("i", vec!["op_imm", "0", rd, src, &imm]),
+            "addiw" => ("i", vec!["op_imm_32", "0", rd, src, &imm]),
+            "slli" => ("i", vec!["op_imm", "1", rd, rd, &imm]),
+            _ => ("i", vec!["op_imm", "5", rd, rd, &imm]),
+        };
+        let ops: Vec<String> = ops.into_iter().map(String::from).collect();
+        res.push(insn::insn_format(format, &ops));
+        src = rd;
+    }
+    res
+}
+
+impl TextInstruction {
+    // auipc with %pcrel_hi(symbol) and addi/jalr with %pcrel_lo, which refers
+    // to the label of the auipc. The lines go through assemble again: a label
+    // close by is resolved by labels.rs, anything else by the real assembler.
+    // la is the non-PIC form, there's no GOT load.
+    //   la/lla rd, symbol
+    //   call [rd,] symbol: rd is ra by default
+    //   tail symbol: through t1
+    //   jump symbol, rt: through rt
+    //   lw rd, symbol: through rd, flw/fld fd, symbol, rt through rt
+    //   sw rs, symbol, rt: through rt
+    pub(super) fn expand_pcrel(&self, label: &str) -> Vec<String> {
+        let ops = &self.operands;
+        let name = self.opcode.as_str();
+        if LOADS.contains(&name) || STORES.contains(&name) {
+            let rt = match ops.len() {
+                2 if !name.starts_with('f') && LOADS.contains(&name) => ops[0].as_str(),
+                3 => ops[2].as_str(),
+                _ => panic!("{} with a symbol needs a temporary register", name),
+            };
+            return vec![
+                format!("{}:", label),
+                format!("auipc {},%pcrel_hi({})", rt, ops[1]),
+                format!("{} {},%pcrel_lo({})({})", name, ops[0], label, rt),
+            ];
+        }
+        let (rd, symbol, link) = match (self.opcode.as_str(), ops.len()) {
+            ("la", 2) | ("lla", 2) => (ops[0].as_str(), ops[1].as_str(), None),
+            ("call", 1) => ("ra", ops[0].as_str(), Some("ra")),
+            ("call", 2) => (ops[0].as_str(), ops[1].as_str(), Some(ops[0].as_str())),
+            ("tail", 1) => ("t1", ops[0].as_str(), Some("zero")),
+            ("jump", 2) => (ops[1].as_str(), ops[0].as_str(), Some("zero")),
+            _ => panic!("wrong number of operands for {}", self.opcode),
+        };
+        let lo = match link {
+            Some(link) => format!("jalr {},%pcrel_lo({})({})", link, label, rd),
+            None => format!("addi {},{},%pcrel_lo({})", rd, rd, label),
+        };
+        vec![
+            format!("{}:", label),
+            format!("auipc {},%pcrel_hi({})", rd, symbol),
+            lo,
+        ]
+    }
+    // la rd, symbol with .option pic: the address is loaded from the GOT
+    pub(super) fn expand_got(&self, label: &str, rv32: bool) -> Vec<String> {
+        let ops = &self.operands;
+        if ops.len() != 2 {
+            panic!("wrong number of operands for {}", self.opcode);
+        }
+        let load = if rv32 { "lw" } else { "ld" };
+        vec![
+            format!("{}:", label),
+            format!("auipc {},%got_pcrel_hi({})", ops[0], ops[1]),
+            format!("{} {},%pcrel_lo({})({})", load, ops[0], label, ops[0]),
+        ]
+    }
+    pub(super) fn convert_pseudo(&self, rv32: bool) -> Option<Vec<BinaryInstruction>> {
+        match self.opcode.as_str() {
+            "li" => {
+                assert_eq!(self.operands.len(), 2);
+                Some(expand_li(&self.operands[0], &self.operands[1], rv32))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, parse_line, Options};
+
+#[cfg(test)]
+fn test_pseudo(line: &str, rv32: bool, bytes: &[&str]) {
+    let bin = parse_line(line).convert_pseudo(rv32).unwrap();
+    let res: Vec<String> = bin.iter().map(|b| b.to_string()).collect();
+    assert_eq!(res, bytes, "{}", line);
+}
+
+#[test]
+fn test_li() {
+    test_pseudo("li a0, 0", false, &[".byte 0x13,0x05,0x00,0x00"]);
+    test_pseudo("li a0, -1", false, &[".byte 0x13,0x05,0xf0,0xff"]);
+    test_pseudo("li a0, 2047", false, &[".byte 0x13,0x05,0xf0,0x7f"]);
+    test_pseudo("li a0, 0x1_0000", false, &[".byte 0x37,0x05,0x01,0x00"]);
+    test_pseudo("li a0, 0x1000", false, &[".byte 0x37,0x15,0x00,0x00"]);
+    test_pseudo(
+        "li a0, 0x12345678",
+        false,
+        &[".byte 0x37,0x55,0x34,0x12", ".byte 0x1b,0x05,0x85,0x67"],
+    );
+    test_pseudo(
+        "li a0, 0x7ffff800",
+        false,
+        &[".byte 0x37,0x05,0x00,0x80", ".byte 0x1b,0x05,0x05,0x80"],
+    );
+    test_pseudo("li a0, -0x80000000", false, &[".byte 0x37,0x05,0x00,0x80"]);
+}
+
+#[test]
+fn test_li_rv64() {
+    test_pseudo(
+        "li a0, 0x80000000",
+        false,
+        &[".byte 0x13,0x05,0x10,0x00", ".byte 0x13,0x15,0xf5,0x01"],
+    );
+    test_pseudo(
+        "li a0, 0xffffffff",
+        false,
+        &[".byte 0x13,0x05,0xf0,0xff", ".byte 0x13,0x55,0x05,0x02"],
+    );
+    test_pseudo(
+        "li a0, 0x123456789abcdef0",
+        false,
+        &[
+            ".byte 0x37,0x75,0x24,0x00",
+            ".byte 0x1b,0x05,0xd5,0x8a",
+            ".byte 0x13,0x15,0xe5,0x00",
+            ".byte 0x13,0x05,0xd5,0xc4",
+            ".byte 0x13,0x15,0xc5,0x00",
+            ".byte 0x13,0x05,0x75,0x5e",
+            ".byte 0x13,0x15,0xd5,0x00",
+            ".byte 0x13,0x05,0x05,0xef",
+        ],
+    );
+}
+
+#[test]
+fn test_li_rv32() {
+    test_pseudo(
+        "li a0, 0x12345678",
+        true,
+        &[".byte 0x37,0x55,0x34,0x12", ".byte 0x13,0x05,0x85,0x67"],
+    );
+    test_pseudo("li a0, 0xffffffff", true, &[".byte 0x13,0x05,0xf0,0xff"]);
+}
+
+#[test]
+#[should_panic(expected = "immediate 0x100000000 out of range for RV32")]
+fn test_li_rv32_range() {
+    test_pseudo("li a0, 0x100000000", true, &[]);
+}
+
+#[test]
+fn test_la() {
+    let lines = vec![String::from("la a0, msg"), String::from("lla t1, table+8")];
+    let output = assemble("a.s", lines, &Options::default());
+    assert_eq!(
+        output,
+        vec![
+            "# la a0,msg",
+            ".Lpcrel_la0:",
+            "auipc a0,%pcrel_hi(msg)",
+            "addi a0,a0,%pcrel_lo(.Lpcrel_la0)",
+            "# lla t1,table+8",
+            ".Lpcrel_lla1:",
+            "auipc t1,%pcrel_hi(table+8)",
+            "addi t1,t1,%pcrel_lo(.Lpcrel_lla1)",
+        ]
+    );
+}
+
+#[test]
+fn test_call_tail_jump() {
+    let lines = vec![
+        String::from("call memcpy"),
+        String::from("call t0, helper"),
+        String::from("tail exit"),
+        String::from("jump done, t2"),
+    ];
+    let output = assemble("a.s", lines, &Options::default());
+    assert_eq!(
+        output,
+        vec![
+            "# call memcpy",
+            ".Lpcrel_call0:",
+            "auipc ra,%pcrel_hi(memcpy)",
+            "jalr ra,%pcrel_lo(.Lpcrel_call0)(ra)",
+            "# call t0,helper",
+            ".Lpcrel_call1:",
+            "auipc t0,%pcrel_hi(helper)",
+            "jalr t0,%pcrel_lo(.Lpcrel_call1)(t0)",
+            "# tail exit",
+            ".Lpcrel_tail2:",
+            "auipc t1,%pcrel_hi(exit)",
+            "jalr zero,%pcrel_lo(.Lpcrel_tail2)(t1)",
+            "# jump done,t2",
+            ".Lpcrel_jump3:",
+            "auipc t2,%pcrel_hi(done)",
+            "jalr zero,%pcrel_lo(.Lpcrel_jump3)(t2)",
+        ]
+    );
+}
+
+#[test]
+fn test_load_store_symbol() {
+    let lines = vec![
+        String::from("lw a0, counter"),
+        String::from("sd a1, total, t0"),
+        String::from("fld fa0, pi, t1"),
+        String::from("ld a2, 8(sp)"),
+    ];
+    let output = assemble("a.s", lines, &Options::default());
+    assert_eq!(
+        output,
+        vec![
+            "# lw a0,counter",
+            ".Lpcrel_lw0:",
+            "auipc a0,%pcrel_hi(counter)",
+            "lw a0,%pcrel_lo(.Lpcrel_lw0)(a0)",
+            "# sd a1,total,t0",
+            ".Lpcrel_sd1:",
+            "auipc t0,%pcrel_hi(total)",
+            "sd a1,%pcrel_lo(.Lpcrel_sd1)(t0)",
+            "# fld fa0,pi,t1",
+            ".Lpcrel_fld2:",
+            "auipc t1,%pcrel_hi(pi)",
+            "fld fa0,%pcrel_lo(.Lpcrel_fld2)(t1)",
+            "# ld a2,8(sp)",
+            ".byte 0x03,0x36,0x81,0x00",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "sw with a symbol needs a temporary register")]
+fn test_store_symbol_no_temporary() {
+    assemble(
+        "a.s",
+        vec![String::from("sw a0, counter")],
+        &Options::default(),
+    );
+}
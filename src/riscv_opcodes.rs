@@ -0,0 +1,155 @@
+// Cross-check the hand-written encodings against the official instruction
+// tables from https://github.com/riscv/riscv-opcodes. Every instruction
+// encoded by the test() and test_rv32() helpers is compared with the fixed
+// bits (opcode, funct fields) of the same instruction in riscv-opcodes.
+//
+// RISCV_OPCODES_DIR=/path/to/riscv-opcodes cargo test --features riscv-opcodes
+//
+// Without RISCV_OPCODES_DIR the cross-check is skipped.
+use super::BinaryInstruction;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    // mnemonic -> (mask, match), for RV64 and RV32
+    static ref OPCODES: Option<(Opcodes, Opcodes)> = match std::env::var("RISCV_OPCODES_DIR") {
+        Ok(dir) => Some((load(Path::new(&dir), false), load(Path::new(&dir), true))),
+        Err(_) => {
+            eprintln!("RISCV_OPCODES_DIR is not set, skipping the riscv-opcodes cross-check");
+            None
+        }
+    };
+}
+
+type Opcodes = HashMap<String, (u32, u32)>;
+
+fn parse_value(val: &str) -> u32 {
+    if let Some(hex) = val.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).unwrap()
+    } else {
+        val.parse::<u32>().unwrap()
+    }
+}
+
+// one line of an opcode file, e.g.
+// add.uw rd rs1 rs2 31..25=4 14..12=0 6..2=0x0E 1..0=3
+fn parse_line(line: &str) -> Option<(String, u32, u32)> {
+    let line = line.split('#').next().unwrap().trim();
+    let mut fields = line.split_whitespace();
+    let name = fields.next()?;
+    // $import, $pseudo_op: not a real instruction definition
+    if name.starts_with('$') {
+        return None;
+    }
+    let mut mask = 0u32;
+    let mut matches = 0u32;
+    for field in fields {
+        let (range, val) = match field.split_once('=') {
+            Some(r) => r,
+            None => continue, // operand field like rd, rs1
+        };
+        let (hi, lo) = match range.split_once("..") {
+            Some((hi, lo)) => (hi.parse::<u32>().unwrap(), lo.parse::<u32>().unwrap()),
+            None => {
+                let bit = range.parse::<u32>().unwrap();
+                (bit, bit)
+            }
+        };
+        let width = hi - lo + 1;
+        let field_mask = if width == 32 {
+            u32::MAX
+        } else {
+            ((1u32 << width) - 1) << lo
+        };
+        mask |= field_mask;
+        matches |= (parse_value(val) << lo) & field_mask;
+    }
+    Some((String::from(name), mask, matches))
+}
+
+// Opcode files live in the top directory of older checkouts and in
+// extensions/ of newer ones. rv32_* files are for RV32 only and rv64_* and
+// rv128_* ones for RV64 only, some instructions like rev8 are in both.
+fn load(dir: &Path, rv32: bool) -> Opcodes {
+    let mut dirs: Vec<PathBuf> = vec![dir.to_path_buf()];
+    for sub in ["extensions", "extensions/unratified"] {
+        let path = dir.join(sub);
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    let mut map = HashMap::new();
+    for d in dirs {
+        for entry in fs::read_dir(&d).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let other_xlen = if rv32 {
+                name.starts_with("rv64_") || name.starts_with("rv128_")
+            } else {
+                name.starts_with("rv32_")
+            };
+            if !path.is_file() || !name.starts_with("rv") || other_xlen {
+                continue;
+            }
+            let content = fs::read_to_string(&path).unwrap();
+            for line in content.lines() {
+                if let Some((name, mask, matches)) = parse_line(line) {
+                    map.insert(name, (mask, matches));
+                }
+            }
+        }
+    }
+    assert!(
+        !map.is_empty(),
+        "no riscv-opcodes files found in {}",
+        dir.display()
+    );
+    map
+}
+
+// Instructions unknown to riscv-opcodes (e.g. pseudo instructions) are ignored.
+pub fn verify(mnemonic: &str, bin: &BinaryInstruction, rv32: bool) {
+    let opcodes = match &*OPCODES {
+        Some((_, rv32_opcodes)) if rv32 => rv32_opcodes,
+        Some((opcodes, _)) => opcodes,
+        None => return,
+    };
+    let encoding = bin
+        .data
+        .iter()
+        .rev()
+        .fold(0, |acc, b| (acc << 8) | u32::from(*b));
+    let mut name = mnemonic;
+    if !opcodes.contains_key(name) {
+        for suffix in [".aqrl", ".aq", ".rl"] {
+            if let Some(n) = name.strip_suffix(suffix) {
+                name = n;
+                break;
+            }
+        }
+    }
+    if let Some((mask, matches)) = opcodes.get(name) {
+        assert_eq!(
+            encoding & mask,
+            *matches,
+            "{} disagrees with riscv-opcodes: mask 0x{:08x}, expected 0x{:08x}, got 0x{:08x}",
+            mnemonic,
+            mask,
+            matches,
+            encoding & mask
+        );
+    }
+}
+
+#[test]
+fn test_parse_line() {
+    let (name, mask, matches) =
+        parse_line("add.uw rd rs1 rs2 31..25=4 14..12=0 6..2=0x0E 1..0=3").unwrap();
+    assert_eq!(name, "add.uw");
+    assert_eq!(mask, 0xfe00707f);
+    assert_eq!(matches, 0x0800003b);
+    assert_eq!(parse_line("$import rv_zbb::rol"), None);
+    assert_eq!(parse_line("# comment"), None);
+}
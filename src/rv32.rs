@@ -0,0 +1,240 @@
+// --xlen 32: RV32 encodings of the instructions which differ from RV64, and
+// rejection of the RV64-only ones. Everything else is shared with RV64, which
+// rejects the RV32-only ones in turn.
+use super::{parse_unsigned, BinaryInstruction, TextInstruction};
+
+const RV64_ONLY: [&str; 43] = [
+    "addiw",
+    "ld",
+    "lwu",
+    "sd",
+    "add.uw",
+    "sh1add.uw",
+    "sh2add.uw",
+    "sh3add.uw",
+    "slli.uw",
+    "zext.w",
+    "sext.w",
+    "clzw",
+    "ctzw",
+    "cpopw",
+    "rolw",
+    "rorw",
+    "roriw",
+    "packw",
+    "negw",
+    "aes64es",
+    "aes64esm",
+    "aes64ds",
+    "aes64dsm",
+    "aes64im",
+    "aes64ks1i",
+    "aes64ks2",
+    "sha512sig0",
+    "sha512sig1",
+    "sha512sum0",
+    "sha512sum1",
+    "fmv.x.d",
+    "fmv.d.x",
+    "c.addiw",
+    "c.addw",
+    "c.subw",
+    "c.ld",
+    "c.sd",
+    "c.ldsp",
+    "c.sdsp",
+    "c.zext.w",
+    "lr.d",
+    "sc.d",
+    "amocas.q",
+];
+
+const RV32_ONLY: [&str; 20] = [
+    "aes32esi",
+    "aes32esmi",
+    "aes32dsi",
+    "aes32dsmi",
+    "sha512sum0r",
+    "sha512sum1r",
+    "sha512sig0l",
+    "sha512sig0h",
+    "sha512sig1l",
+    "sha512sig1h",
+    "zip",
+    "unzip",
+    "rdcycleh",
+    "rdtimeh",
+    "rdinstreth",
+    "c.jal",
+    "c.flw",
+    "c.fsw",
+    "c.flwsp",
+    "c.fswsp",
+];
+
+// shift immediates are 5 bits wide on RV32
+const SHIFT_IMM: [&str; 5] = ["rori", "bclri", "bexti", "binvi", "bseti"];
+
+fn is_rv64_only(opcode: &str) -> bool {
+    let mut name = opcode;
+    for suffix in [".aqrl", ".aq", ".rl"].iter() {
+        if let Some(n) = name.strip_suffix(suffix) {
+            name = n;
+            break;
+        }
+    }
+    // 64-bit atomics, except amocas.d which works on register pairs
+    if name.starts_with("amo") && name.ends_with(".d") && name != "amocas.d" {
+        return true;
+    }
+    // conversions from/to 64-bit integers
+    if let Some(types) = name.strip_prefix("fcvt.") {
+        return types.split('.').any(|t| t == "l" || t == "lu");
+    }
+    RV64_ONLY.contains(&name)
+}
+
+impl TextInstruction {
+    pub(super) fn convert_rv64(&self) -> Option<BinaryInstruction> {
+        if RV32_ONLY.contains(&self.opcode.as_str()) {
+            panic!("{} is only available on RV32", self.opcode);
+        }
+        self.convert()
+    }
+    pub(super) fn convert_rv32(&self) -> Option<BinaryInstruction> {
+        if is_rv64_only(&self.opcode) {
+            panic!("{} is only available on RV64", self.opcode);
+        }
+        let mut res = BinaryInstruction::new();
+        match self.opcode.as_str() {
+            "rev8" => {
+                res.set_opcode(0b0010011);
+                res.set_funct3(0b101);
+                res.set_funct7(0b0110100);
+                res.set_2operands(&self.operands, 0b11000);
+                Some(res)
+            }
+            // pack rd, rs1, zero
+            "zext.h" => {
+                res.set_opcode(0b0110011);
+                res.set_funct3(0b100);
+                res.set_funct7(0b0000100);
+                res.set_2operands(&self.operands, 0b00000);
+                Some(res)
+            }
+            // c.j with funct3 001, taken by c.addiw on RV64
+            "c.jal" => {
+                let j = TextInstruction {
+                    opcode: String::from("c.j"),
+                    operands: self.operands.clone(),
+                    raw: None,
+                };
+                let mut res = j.convert_compressed()?;
+                res.set(13, 15, vec![1, 0, 0]);
+                Some(res)
+            }
+            "amocas.d" | "amocas.d.aq" | "amocas.d.rl" | "amocas.d.aqrl" => {
+                // rd/rd+1 and rs2/rs2+1 hold the 64-bit values
+                self.check_even_pairs();
+                self.convert()
+            }
+            opcode if SHIFT_IMM.contains(&opcode) => {
+                let shamt = parse_unsigned(self.operands.get(2)?);
+                if shamt > 31 {
+                    panic!("shift amount {} out of range [0, 31] on RV32", shamt);
+                }
+                self.convert()
+            }
+            _ => self.convert(),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, parse_line, Options};
+
+#[cfg(test)]
+pub(super) fn test_rv32(line: &str, bytes: &str) {
+    let inst = parse_line(line);
+    let bin = inst.convert_rv32().unwrap();
+    assert_eq!(format!("{}", bin), bytes);
+    #[cfg(feature = "riscv-opcodes")]
+    super::riscv_opcodes::verify(&inst.opcode, &bin, true);
+}
+
+#[test]
+fn test_rv32_zbb() {
+    test_rv32("rev8 a0, a1", ".byte 0x13,0xd5,0x85,0x69");
+    test_rv32("zext.h a0, a1", ".byte 0x33,0xc5,0x05,0x08");
+    test_rv32("rori a0, a1, 31", ".byte 0x13,0xd5,0xf5,0x61");
+    test_rv32("andn zero, tp, s6", ".byte 0x33,0x70,0x62,0x41");
+}
+
+#[test]
+fn test_rv32_c_jal() {
+    test_rv32("c.jal -2048", ".byte 0x01,0x30");
+    test_rv32("c.jal 2046", ".byte 0xfd,0x2f");
+}
+
+#[test]
+fn test_rv32_c_flw() {
+    test_rv32("c.flw fa0, 4(a1)", ".byte 0xc8,0x61");
+    test_rv32("c.flw fs1, 124(a5)", ".byte 0xe4,0x7f");
+    test_rv32("c.fsw fs0, 16(a0)", ".byte 0x00,0xe9");
+    test_rv32("c.fsw fa5, 124(s0)", ".byte 0x7c,0xfc");
+    test_rv32("c.flwsp ft0, 0(sp)", ".byte 0x02,0x60");
+    test_rv32("c.flwsp fa0, 252(sp)", ".byte 0x7e,0x75");
+    test_rv32("c.fswsp ft11, 252(sp)", ".byte 0xfe,0xff");
+    test_rv32("c.fswsp fa0, 4(sp)", ".byte 0x2a,0xe2");
+}
+
+#[test]
+fn test_rv32_amocas_d() {
+    test_rv32("amocas.d a0, a2, (a3)", ".byte 0x2f,0xb5,0xc6,0x28");
+}
+
+#[test]
+#[should_panic(expected = "add.uw is only available on RV64")]
+fn test_rv32_rv64_only() {
+    test_rv32("add.uw a2, s11, s5", "");
+}
+
+#[test]
+#[should_panic(expected = "amoadd.d.aq is only available on RV64")]
+fn test_rv32_atomic_d() {
+    test_rv32("amoadd.d.aq t0, t1, (sp)", "");
+}
+
+#[test]
+#[should_panic(expected = "c.flwsp is only available on RV32")]
+fn test_rv64_c_flwsp() {
+    parse_line("c.flwsp fa0, 4(sp)").convert_rv64();
+}
+
+#[test]
+#[should_panic(expected = "aes32esi is only available on RV32")]
+fn test_rv64_aes32() {
+    parse_line("aes32esi a0, a1, a2, 3").convert_rv64();
+}
+
+#[test]
+#[should_panic(expected = "sha512sig0h is only available on RV32")]
+fn test_rv64_sha512_rv32() {
+    parse_line("sha512sig0h a0, a1, a2").convert_rv64();
+}
+
+#[test]
+#[should_panic(expected = "rdcycleh is only available on RV32")]
+fn test_rv64_counter_high() {
+    assemble(
+        "a.s",
+        vec![String::from("rdcycleh a0")],
+        &Options::default(),
+    );
+}
+
+#[test]
+#[should_panic(expected = "shift amount 32 out of range")]
+fn test_rv32_shift_imm() {
+    test_rv32("bseti a0, a1, 32", "");
+}
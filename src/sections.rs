@@ -0,0 +1,224 @@
+// Sections: the section of each line, for the location counters of labels.rs
+// and the output, which is grouped per section. A file starts in .text.
+// .pushsection/.popsection and .previous are followed like in GNU as. Lines
+// like .option, which apply to what follows in any section, split the
+// grouping, see flush.
+use super::TextInstruction;
+
+// directives which switch to a section, without operands
+const SECTIONS: [&str; 4] = [".text", ".data", ".bss", ".rodata"];
+
+struct Section {
+    name: String,
+    // the directive of the first switch to it, which starts its output
+    header: Option<String>,
+    code: bool,
+    // whether some of its output was flushed
+    started: bool,
+}
+
+pub(super) struct Sections {
+    sections: Vec<Section>,
+    current: usize,
+    previous: usize,
+    // current and previous section of each .pushsection
+    stack: Vec<(usize, usize)>,
+    // the section of the output flushed so far
+    output: usize,
+}
+
+impl Sections {
+    pub(super) fn new() -> Sections {
+        Sections {
+            sections: vec![Section {
+                name: String::from(".text"),
+                header: None,
+                code: true,
+                started: false,
+            }],
+            current: 0,
+            previous: 0,
+            stack: vec![],
+            output: 0,
+        }
+    }
+    pub(super) fn current(&self) -> usize {
+        self.current
+    }
+    pub(super) fn len(&self) -> usize {
+        self.sections.len()
+    }
+    pub(super) fn name(&self, section: usize) -> &str {
+        &self.sections[section].name
+    }
+    // .text and .text.*, or "x" in the flags: alignments are filled with nops
+    pub(super) fn is_code(&self, section: usize) -> bool {
+        self.sections[section].code
+    }
+    // Move the output of each section so far to `output`, after a switch to
+    // the section: the directive of its first switch the first time, the
+    // same directive again later. With `last`, the directives of sections
+    // without output are emitted too.
+    pub(super) fn flush(
+        &mut self,
+        output: &mut Vec<String>,
+        groups: &mut [Vec<String>],
+        last: bool,
+    ) {
+        for (i, lines) in groups.iter_mut().enumerate() {
+            let section = &mut self.sections[i];
+            if lines.is_empty() && (section.started || !last || section.header.is_none()) {
+                continue;
+            }
+            let directive = match &section.header {
+                Some(header) if !section.started || i != self.output => Some(header.clone()),
+                None if i != self.output => Some(String::from(".text")),
+                _ => None,
+            };
+            output.extend(directive);
+            output.append(lines);
+            section.started = true;
+            self.output = i;
+        }
+    }
+    fn enter(&mut self, name: &str, flags: Option<&str>, header: String) {
+        let section = match self.sections.iter().position(|s| s.name == name) {
+            Some(section) => section,
+            None => {
+                let code = name == ".text"
+                    || name.starts_with(".text.")
+                    || flags.is_some_and(|f| f.trim_matches('"').contains('x'));
+                self.sections.push(Section {
+                    name: String::from(name),
+                    header: None,
+                    code,
+                    started: false,
+                });
+                self.sections.len() - 1
+            }
+        };
+        if self.sections[section].header.is_none() {
+            self.sections[section].header = Some(header);
+        }
+        self.previous = self.current;
+        self.current = section;
+    }
+    // Switch to the section of a section directive, None for other lines.
+    pub(super) fn switch(&mut self, inst: &TextInstruction) -> Option<usize> {
+        let ops = &inst.operands;
+        match (inst.raw.as_deref(), inst.opcode.as_str()) {
+            (Some(".previous"), _) => {
+                std::mem::swap(&mut self.current, &mut self.previous);
+            }
+            (Some(".popsection"), _) => match self.stack.pop() {
+                Some((current, previous)) => {
+                    self.current = current;
+                    self.previous = previous;
+                }
+                None => panic!(".popsection without .pushsection"),
+            },
+            (Some(raw), _) if SECTIONS.contains(&raw) => {
+                self.enter(raw, None, String::from(raw));
+            }
+            (Some(_), _) => return None,
+            // subsections like .text 1 aren't kept apart
+            (None, name) if SECTIONS.contains(&name) => {
+                self.enter(name, None, String::from(name));
+            }
+            (None, ".section") | (None, ".pushsection") if !ops.is_empty() => {
+                if inst.opcode == ".pushsection" {
+                    self.stack.push((self.current, self.previous));
+                }
+                let header = format!(".section {}", ops.join(","));
+                self.enter(&ops[0], ops.get(1).map(|f| f.as_str()), header);
+            }
+            _ => return None,
+        }
+        Some(self.current)
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_sections() {
+    let lines = vec![
+        "nop",
+        ".data",
+        "msg:",
+        ".byte 1",
+        ".text",
+        "j 1f",
+        ".pushsection .rodata",
+        ".word 2",
+        ".popsection",
+        "1:",
+        ".previous",
+        ".balign 4",
+        "end:",
+        ".word end - msg",
+        ".section .note.GNU-stack,\"\",@progbits",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            ".text",
+            "# nop",
+            ".byte 0x13,0x00,0x00,0x00",
+            "# j 1f",
+            ".byte 0x6f,0x00,0x40,0x00",
+            "1:",
+            ".data",
+            "msg:",
+            "# .byte 1",
+            ".byte 0x01",
+            "# .balign 4",
+            ".byte 0x00,0x00,0x00",
+            "end:",
+            "# .word end - msg",
+            ".byte 0x04,0x00,0x00,0x00",
+            ".section .rodata",
+            "# .word 2",
+            ".byte 0x02,0x00,0x00,0x00",
+            ".section .note.GNU-stack,\"\",@progbits",
+        ]
+    );
+}
+
+#[test]
+fn test_sections_option() {
+    let lines = vec![
+        ".text",
+        "add.uw a0, a1, a2",
+        ".data",
+        ".option norvc",
+        ".word 1",
+        ".text",
+        "add.uw a0, a0, a2",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            ".text",
+            "# add.uw a0,a1,a2",
+            ".byte 0x3b,0x85,0xc5,0x08",
+            // before the second add.uw, as in the source
+            ".option norvc",
+            "# add.uw a0,a0,a2",
+            ".byte 0x3b,0x05,0xc5,0x08",
+            ".data",
+            "# .word 1",
+            ".byte 0x01,0x00,0x00,0x00",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = ".popsection without .pushsection")]
+fn test_popsection() {
+    let lines = vec![String::from(".popsection")];
+    assemble("a.s", lines, &Options::default());
+}
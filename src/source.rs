@@ -0,0 +1,589 @@
+// The lines of a source as the encoder sees them: .include files are read in
+// place, split at ';', macros expanded, .if blocks kept or dropped, .rept
+// blocks repeated and constants folded. Each line keeps the file and line
+// number it comes from, for warnings and --line-comments, and the macro calls
+// it comes from, for errors.
+use super::{
+    expr, macros, macros::Macros, parse_line, prepare_lines, split_statements, TextInstruction,
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+// .include within .include, beyond this it's probably a file including itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// Lines expanded by macros and .rept in all, beyond this it's probably a
+// count gone wrong. The default of --max-expansions.
+pub(super) const MAX_EXPANSIONS: usize = 1_000_000;
+
+pub(super) struct Source {
+    macros: Macros,
+    // --defsym and .equ/.set constants, for the expressions in operands
+    constants: HashMap<String, i64>,
+    // file and line number of each line read, and for the lines of a macro
+    // call the calls it comes from, see calls()
+    origins: Vec<(Rc<str>, usize, Option<Rc<str>>)>,
+    // the macro calls being expanded: the name with the arguments and where
+    // it's defined, and the index of the call
+    calls: Vec<(String, usize)>,
+    // lines expanded by macros and .rept so far, and the limit
+    expanded: usize,
+    max_expansions: usize,
+    // the index in origins of each instruction
+    lines: Vec<(usize, TextInstruction)>,
+    // the open .if blocks: the index of the .if, whether the lines are
+    // assembled, and whether a branch was taken
+    conditions: Vec<(usize, bool, bool)>,
+    // the .rept block being read
+    repeat: Option<Repeat>,
+    // each .equ/.set/= definition: its index, the name, the value as written
+    // and as evaluated, for --dump-consts
+    definitions: Vec<(usize, String, String, Option<i64>)>,
+}
+
+struct Repeat {
+    // of the .rept
+    index: usize,
+    count: usize,
+    // the name of `.rept count, name`, \name in the body is 0, 1, ...
+    counter: Option<String>,
+    // with the index of each line and where it's written, see statement
+    body: Vec<(usize, usize, String)>,
+    // the .rept lines nested in the body
+    nested: usize,
+}
+
+// the directive of a line, in lower case, and its operands
+fn split_directive(line: &str) -> (String, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(pos) => (line[..pos].to_lowercase(), line[pos..].trim()),
+        None => (line.to_lowercase(), ""),
+    }
+}
+
+// the file of .include "file"
+fn include_file(line: &str) -> Option<&str> {
+    let (directive, file) = line.split_at(line.find(char::is_whitespace)?);
+    if !directive.eq_ignore_ascii_case(".include") {
+        return None;
+    }
+    let file = file.trim();
+    Some(
+        file.strip_prefix('"')
+            .and_then(|f| f.strip_suffix('"'))
+            .unwrap_or(file),
+    )
+}
+
+impl Source {
+    pub(super) fn new(
+        constants: HashMap<String, i64>,
+        max_macro_depth: usize,
+        max_expansions: usize,
+    ) -> Source {
+        Source {
+            macros: Macros::new(max_macro_depth),
+            constants,
+            origins: vec![],
+            calls: vec![],
+            expanded: 0,
+            max_expansions,
+            lines: vec![],
+            conditions: vec![],
+            repeat: None,
+            definitions: vec![],
+        }
+    }
+    // file:line of an instruction
+    pub(super) fn location(&self, index: usize) -> String {
+        let (file, line, _) = &self.origins[index];
+        format!("{}:{}", file, line)
+    }
+    // The macro calls an instruction comes from, innermost first, for the end
+    // of an error message. Empty if it isn't from a macro.
+    pub(super) fn calls(&self, index: usize) -> &str {
+        self.origins[index].2.as_deref().unwrap_or("")
+    }
+    // the macro calls being expanded, like calls()
+    fn backtrace(&self) -> String {
+        self.calls
+            .iter()
+            .rev()
+            .map(|(call, at)| format!("\n  in macro {}, called at {}", call, self.location(*at)))
+            .collect()
+    }
+    // count a line of a macro or .rept expansion, for --max-expansions
+    fn expand(&mut self, index: usize) {
+        self.expanded += 1;
+        if self.expanded > self.max_expansions {
+            panic!(
+                "{}: more than {} lines expanded by macros and .rept{}",
+                self.location(index),
+                self.max_expansions,
+                self.backtrace()
+            );
+        }
+    }
+    // The lines of a file, `depth` .include deep. Included files are relative
+    // to the directory of the file.
+    pub(super) fn read(&mut self, file: &str, lines: Vec<String>, depth: usize) {
+        let name: Rc<str> = Rc::from(file);
+        for (number, line) in lines.into_iter().enumerate() {
+            self.origins.push((name.clone(), number + 1, None));
+            let index = self.origins.len() - 1;
+            for statement in split_statements(&line) {
+                self.statement(file, statement, index, index, depth);
+            }
+        }
+    }
+    // A line of a file, a macro body or a .rept block, at `index` for the
+    // location of the instruction, i.e. the call for the lines of a macro, and
+    // written on the line at `at`. True for .exitm, which ends the body.
+    fn statement(&mut self, file: &str, line: &str, index: usize, at: usize, depth: usize) -> bool {
+        if self.repeat.is_some() {
+            return self.record(file, line, index, at, depth);
+        }
+        if !self.macros.is_defining() && self.conditional(line, index) {
+            return false;
+        }
+        if self.macros.define(line, at) {
+            return false;
+        }
+        let (directive, operands) = split_directive(line);
+        match directive.as_str() {
+            ".rept" => {
+                let (count, counter) = match operands.find(',') {
+                    Some(pos) => (&operands[..pos], Some(operands[pos + 1..].trim())),
+                    None => (operands, None),
+                };
+                // like GNU as, a negative count repeats nothing
+                let count = self.constant(count, index).max(0) as usize;
+                self.repeat = Some(Repeat {
+                    index,
+                    count,
+                    counter: counter.map(String::from),
+                    body: vec![],
+                    nested: 0,
+                });
+                return false;
+            }
+            ".endr" => panic!(
+                "{}: .endr without .rept{}",
+                self.location(index),
+                self.calls(index)
+            ),
+            _ => {}
+        }
+        if self.macros.is_call(line) {
+            let call = match self.macros.call(line, self.calls.len()) {
+                Ok(call) => call,
+                Err(e) => panic!("{}: {}{}", self.location(at), e, self.backtrace()),
+            };
+            let frame = format!(
+                "{}({}), defined at {}",
+                call.name,
+                call.args,
+                self.location(call.defined)
+            );
+            self.calls.push((frame, at));
+            // the lines of a macro call have the location of the call
+            let (file_name, number, _) = self.origins[index].clone();
+            let calls = Rc::from(self.backtrace());
+            self.origins.push((file_name, number, Some(calls)));
+            let index = self.origins.len() - 1;
+            // an .if left open by .exitm
+            let level = self.conditions.len();
+            for (at, line) in call.body {
+                self.expand(index);
+                if self.statement(file, &line, index, at, depth) {
+                    break;
+                }
+            }
+            self.conditions.truncate(level);
+            self.calls.pop();
+            return false;
+        }
+        if line.eq_ignore_ascii_case(".exitm") {
+            return true;
+        }
+        if let Some(include) = include_file(line) {
+            if depth >= MAX_INCLUDE_DEPTH {
+                panic!(
+                    "{}: .include nested too deeply{}",
+                    self.location(index),
+                    self.calls(index)
+                );
+            }
+            let path = Path::new(file)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(include);
+            let content = match std::fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(e) => panic!(
+                    "{}: can't read {}: {}{}",
+                    self.location(index),
+                    path.display(),
+                    e,
+                    self.calls(index)
+                ),
+            };
+            self.read(&path.to_string_lossy(), prepare_lines(&content), depth + 1);
+            return false;
+        }
+        let mut inst = parse_line(line);
+        let definition =
+            expr::definition(&inst).map(|(name, value)| (String::from(name), String::from(value)));
+        expr::fold_constants(&mut inst, &self.constants);
+        expr::define_constant(&inst, &mut self.constants);
+        if let Some((name, value)) = definition {
+            let val = self.constants.get(&name).copied();
+            self.definitions.push((index, name, value, val));
+        }
+        self.lines.push((index, inst));
+        false
+    }
+    // A line of the .rept block being read. At its .endr, the block is
+    // assembled `count` times, true if it ends with .exitm.
+    fn record(&mut self, file: &str, line: &str, index: usize, at: usize, depth: usize) -> bool {
+        let repeat = self.repeat.as_mut().unwrap();
+        match split_directive(line).0.as_str() {
+            ".endr" if repeat.nested == 0 => {}
+            ".endr" => {
+                repeat.nested -= 1;
+                repeat.body.push((index, at, String::from(line)));
+                return false;
+            }
+            ".rept" => {
+                repeat.nested += 1;
+                repeat.body.push((index, at, String::from(line)));
+                return false;
+            }
+            _ => {
+                repeat.body.push((index, at, String::from(line)));
+                return false;
+            }
+        }
+        let repeat = self.repeat.take().unwrap();
+        for i in 0..repeat.count {
+            for (index, at, line) in &repeat.body {
+                self.expand(repeat.index);
+                let line = match &repeat.counter {
+                    Some(counter) => macros::substitute_counter(line, counter, i),
+                    None => line.clone(),
+                };
+                if self.statement(file, &line, *index, *at, depth) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    // the value of the expression of .if or .rept
+    fn constant(&self, expr: &str, index: usize) -> i64 {
+        match expr::eval(expr, &self.constants) {
+            Some(val) => val,
+            None => panic!(
+                "{}: {} is not a constant{}",
+                self.location(index),
+                expr,
+                self.calls(index)
+            ),
+        }
+    }
+    // .if and the like, true if the line is one of them or is dropped
+    fn conditional(&mut self, line: &str, index: usize) -> bool {
+        let (directive, expr) = split_directive(line);
+        let active = self.conditions.last().is_none_or(|c| c.1);
+        // the block of the .if, .elseif or .else
+        let outer = match self.conditions.len() {
+            0 | 1 => true,
+            len => self.conditions[len - 2].1,
+        };
+        match directive.as_str() {
+            ".if" | ".ifne" | ".ifeq" | ".ifgt" | ".ifge" | ".iflt" | ".ifle" | ".ifdef"
+            | ".ifndef" | ".ifnotdef" => {
+                // the expression of a dropped .if may not be known
+                let taken = active
+                    && match directive.as_str() {
+                        ".ifdef" => self.constants.contains_key(expr),
+                        ".ifndef" | ".ifnotdef" => !self.constants.contains_key(expr),
+                        ".ifeq" => self.constant(expr, index) == 0,
+                        ".ifgt" => self.constant(expr, index) > 0,
+                        ".ifge" => self.constant(expr, index) >= 0,
+                        ".iflt" => self.constant(expr, index) < 0,
+                        ".ifle" => self.constant(expr, index) <= 0,
+                        _ => self.constant(expr, index) != 0,
+                    };
+                self.conditions.push((index, taken, taken));
+            }
+            ".elseif" | ".else" => {
+                let taken = match self.conditions.last() {
+                    Some(&(_, _, taken)) => taken,
+                    None => panic!(
+                        "{}: {} without .if{}",
+                        self.location(index),
+                        directive,
+                        self.calls(index)
+                    ),
+                };
+                let branch =
+                    !taken && outer && (directive == ".else" || self.constant(expr, index) != 0);
+                let last = self.conditions.last_mut().unwrap();
+                last.1 = branch;
+                last.2 = taken || branch;
+            }
+            ".endif" => {
+                if self.conditions.pop().is_none() {
+                    panic!(
+                        "{}: .endif without .if{}",
+                        self.location(index),
+                        self.calls(index)
+                    );
+                }
+            }
+            _ => return !active,
+        }
+        true
+    }
+    // The constant definitions in the order they are read, with where they
+    // are, for --dump-consts. A value which isn't constant is left to the
+    // real assembler.
+    pub(super) fn dump_constants(&self) -> Vec<String> {
+        self.definitions
+            .iter()
+            .map(|(index, name, value, val)| {
+                let location = self.location(*index);
+                match val {
+                    Some(val) if *value == val.to_string() => {
+                        format!("{}: {} = {}", location, name, val)
+                    }
+                    Some(val) => format!("{}: {} = {} = {}", location, name, value, val),
+                    None => format!("{}: {} = {} is not a constant", location, name, value),
+                }
+            })
+            .collect()
+    }
+    // the instructions of everything read, with their index for location()
+    pub(super) fn finish(&mut self) -> Vec<(usize, TextInstruction)> {
+        self.macros.finish();
+        if let Some(repeat) = &self.repeat {
+            panic!("{}: .rept without .endr", self.location(repeat.index));
+        }
+        if let Some(&(index, _, _)) = self.conditions.last() {
+            panic!("{}: .if without .endif", self.location(index));
+        }
+        std::mem::take(&mut self.lines)
+    }
+}
+
+#[cfg(test)]
+use super::{assemble, Options};
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("rna-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(dir.join("inc/b.s"), ".include \"c.s\"\naddi a1, a1, 1").unwrap();
+    std::fs::write(dir.join("inc/c.s"), ".equ STEP, 2").unwrap();
+    let options = Options {
+        line_comments: true,
+        ..Default::default()
+    };
+    let file = dir.join("a.s");
+    let file = file.to_str().unwrap();
+    let lines = vec![".include \"inc/b.s\"", "addi a0, a0, STEP"];
+    let lines = lines.into_iter().map(String::from).collect();
+    let output = assemble(file, lines, &options);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let inc = dir.join("inc/b.s");
+    assert_eq!(
+        output,
+        vec![
+            String::from(".equ STEP,2"),
+            String::from("# addi a1,a1,1"),
+            format!(".byte 0x93,0x85,0x15,0x00 # {}:2", inc.display()),
+            String::from("# addi a0,a0,2"),
+            format!(".byte 0x13,0x05,0x25,0x00 # {}:2", file),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = ".include nested too deeply")]
+fn test_include_depth() {
+    let dir = std::env::temp_dir().join(format!("rna-include-depth-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.s"), ".include \"a.s\"\n").unwrap();
+    let file = dir.join("a.s");
+    let lines = vec![String::from(".include \"a.s\"")];
+    assemble(file.to_str().unwrap(), lines, &Options::default());
+}
+
+#[test]
+fn test_conditionals() {
+    let lines = vec![
+        ".if XLEN == 64",
+        "ld a0, 0(sp)",
+        ".elseif XLEN == 32",
+        "lw a0, 0(sp)",
+        ".else",
+        ".error \"unknown XLEN\"",
+        ".endif",
+        ".ifdef EXPERIMENTAL",
+        ".equ STEP, 2",
+        ".if 0",
+        ".endif",
+        ".else",
+        ".equ STEP, 1",
+        ".endif",
+        ".ifndef STEP",
+        "nop",
+        ".endif",
+        "addi a0, a0, STEP",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let options = Options {
+        defsyms: vec![(String::from("XLEN"), 32)],
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble("a.s", lines, &options),
+        vec![
+            ".set XLEN,32",
+            "# lw a0,0(sp)",
+            ".byte 0x03,0x25,0x01,0x00",
+            ".equ STEP,1",
+            "# addi a0,a0,1",
+            ".byte 0x13,0x05,0x15,0x00",
+        ]
+    );
+}
+
+#[test]
+fn test_conditional_macro() {
+    let lines = vec![
+        ".macro countdown n",
+        ".byte \\n",
+        ".if \\n > 1",
+        "countdown \\n-1",
+        ".endif",
+        ".endm",
+        "countdown 3",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    assert_eq!(
+        assemble("a.s", lines, &Options::default()),
+        vec![
+            "# .byte 3",
+            ".byte 0x03",
+            "# .byte 2",
+            ".byte 0x02",
+            "# .byte 1",
+            ".byte 0x01",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "a.s:1: .if without .endif")]
+fn test_conditional_unterminated() {
+    let lines = vec![String::from(".if 1"), String::from("nop")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+fn test_rept() {
+    let lines = vec![
+        ".rept 2",
+        "nop",
+        ".endr",
+        ".rept 3, i",
+        ".rept 2, j",
+        ".byte \\i * 2 + \\j",
+        ".endr",
+        ".endr",
+        ".if 0",
+        ".rept 2",
+        "nop",
+        ".endr",
+        ".endif",
+        ".rept -1",
+        "nop",
+        ".endr",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let mut expected = vec![];
+    for _ in 0..2 {
+        expected.push(String::from("# nop"));
+        expected.push(String::from(".byte 0x13,0x00,0x00,0x00"));
+    }
+    for i in 0..6 {
+        expected.push(format!("# .byte {}", i));
+        expected.push(format!(".byte 0x{:02x}", i));
+    }
+    assert_eq!(assemble("a.s", lines, &Options::default()), expected);
+}
+
+#[test]
+#[should_panic(
+    expected = "a.s:7: more than 10 lines expanded by macros and .rept\n  \
+                in macro fill(n=1000000000), defined at a.s:1, called at a.s:7"
+)]
+fn test_max_expansions() {
+    let lines = vec![
+        ".macro fill n",
+        "nop",
+        ".rept \\n",
+        "nop",
+        ".endr",
+        ".endm",
+        "fill 1000000000",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let options = Options {
+        max_expansions: Some(10),
+        ..Default::default()
+    };
+    assemble("a.s", lines, &options);
+}
+
+#[test]
+#[should_panic(expected = "a.s:1: .rept without .endr")]
+fn test_rept_unterminated() {
+    let lines = vec![String::from(".rept 2"), String::from("nop")];
+    assemble("a.s", lines, &Options::default());
+}
+
+#[test]
+fn test_dump_constants() {
+    let dir = std::env::temp_dir().join(format!("rna-dump-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("sizes.s"), ".equ BUF_SIZE, 2 * STEP").unwrap();
+    let file = dir.join("a.s");
+    let file = file.to_str().unwrap();
+    let lines = vec![
+        ".set STEP, 64",
+        ".include \"sizes.s\"",
+        "END = start + 4",
+        "STEP = 128",
+    ];
+    let mut source = Source::new(HashMap::new(), macros::MAX_DEPTH, MAX_EXPANSIONS);
+    source.read(file, lines.into_iter().map(String::from).collect(), 0);
+    source.finish();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        source.dump_constants(),
+        vec![
+            format!("{}:1: STEP = 64", file),
+            format!(
+                "{}: BUF_SIZE = 2 * STEP = 128",
+                dir.join("sizes.s:1").display()
+            ),
+            format!("{}:3: END = start + 4 is not a constant", file),
+            format!("{}:4: STEP = 128", file),
+        ]
+    );
+}
@@ -0,0 +1,146 @@
+// The symbol table for --map: the labels of the file with their section and
+// offset, and the binding, type and size of .globl, .local, .weak, .type and
+// .size. The directives are passed on to the real assembler as well.
+use super::{data, labels, sections::Sections, TextInstruction};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(Default)]
+struct Attributes {
+    binding: Option<&'static str>,
+    kind: Option<String>,
+    // expression of .size and its slot, for the value of .
+    size: Option<(String, usize)>,
+}
+
+#[derive(Default)]
+pub(super) struct Symbols {
+    // in the order they are seen, by label or directive
+    names: Vec<String>,
+    attributes: HashMap<String, Attributes>,
+}
+
+// .type sym, @function: STT_FUNC and %function are the same
+fn symbol_type(kind: &str) -> String {
+    let kind = kind.trim_start_matches(['@', '%']).to_lowercase();
+    match kind.as_str() {
+        "stt_func" => String::from("function"),
+        "stt_object" => String::from("object"),
+        "stt_tls" => String::from("tls_object"),
+        "stt_notype" => String::from("notype"),
+        _ => kind,
+    }
+}
+
+impl Symbols {
+    fn attributes(&mut self, name: &str) -> &mut Attributes {
+        if !self.attributes.contains_key(name) {
+            self.names.push(String::from(name));
+        }
+        self.attributes.entry(String::from(name)).or_default()
+    }
+    // a label definition, local labels aren't in the table
+    pub(super) fn label(&mut self, name: &str) {
+        if !name.starts_with(".L") && !name.chars().all(|c| c.is_ascii_digit()) {
+            self.attributes(name);
+        }
+    }
+    // the attributes of a symbol directive at slot `slot`
+    pub(super) fn directive(&mut self, inst: &TextInstruction, slot: usize) {
+        let ops = &inst.operands;
+        let binding = match inst.opcode.as_str() {
+            ".globl" | ".global" => "global",
+            ".local" => "local",
+            ".weak" => "weak",
+            ".type" if ops.len() == 2 => {
+                self.attributes(&ops[0]).kind = Some(symbol_type(&ops[1]));
+                return;
+            }
+            ".size" if ops.len() == 2 => {
+                self.attributes(&ops[0]).size = Some((ops[1].clone(), slot));
+                return;
+            }
+            _ => return,
+        };
+        for name in ops {
+            self.attributes(name).binding = Some(binding);
+        }
+    }
+    // The table, one object per symbol. The offset is only known from the
+    // start of a section, the size only if it's a number or a label
+    // difference.
+    pub(super) fn table(&self, layout: &labels::Layout, sections: &Sections) -> Value {
+        let mut label_values = data::label_values(&layout.labels);
+        let mut table = vec![];
+        for name in &self.names {
+            let attributes = &self.attributes[name];
+            let location = layout.labels.get(name);
+            let size = attributes.size.as_ref().and_then(|(size, slot)| {
+                data::insert_label(&mut label_values, ".", &layout.locations[*slot]);
+                data::value(size, &label_values)
+            });
+            table.push(json!({
+                "name": name,
+                "section": location.map(|l| sections.name(l.section)),
+                "offset": location.filter(|l| l.from_start).map(|l| l.offset),
+                "size": size,
+                "binding": attributes.binding.unwrap_or("local"),
+                "type": attributes.kind.as_deref().unwrap_or("notype"),
+            }));
+        }
+        Value::Array(table)
+    }
+}
+
+#[cfg(test)]
+use super::{assemble_with_symbols, Options};
+
+#[test]
+fn test_symbols() {
+    let lines = vec![
+        ".globl main",
+        ".type main, @function",
+        "main:",
+        "nop",
+        "ret",
+        ".size main, .-main",
+        ".weak helper",
+        ".data",
+        ".type table, STT_OBJECT",
+        "table:",
+        ".word 1, 2",
+        ".size table, 8",
+        ".Lend:",
+    ];
+    let lines = lines.into_iter().map(String::from).collect();
+    let (_, symbols) = assemble_with_symbols("a.s", lines, &Options::default());
+    assert_eq!(
+        symbols,
+        json!([
+            {
+                "name": "main",
+                "section": ".text",
+                "offset": 0,
+                "size": 8,
+                "binding": "global",
+                "type": "function",
+            },
+            {
+                "name": "helper",
+                "section": null,
+                "offset": null,
+                "size": null,
+                "binding": "weak",
+                "type": "notype",
+            },
+            {
+                "name": "table",
+                "section": ".data",
+                "offset": 0,
+                "size": 8,
+                "binding": "local",
+                "type": "object",
+            },
+        ])
+    );
+}
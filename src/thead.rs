@@ -0,0 +1,204 @@
+// T-Head vendor extensions in the custom-0 opcode space, enabled by --march:
+// XTheadBa, XTheadBb, XTheadBs and XTheadMemPair.
+use super::{address_reg_name, parse_unsigned, reg_name2value, BinaryInstruction, TextInstruction};
+
+const EXTENSIONS: [&str; 4] = ["xtheadba", "xtheadbb", "xtheadbs", "xtheadmempair"];
+
+// mnemonic, extension
+const THEAD_OPS: [(&str, &str); 16] = [
+    ("th.addsl", "xtheadba"),
+    ("th.srri", "xtheadbb"),
+    ("th.srriw", "xtheadbb"),
+    ("th.ext", "xtheadbb"),
+    ("th.extu", "xtheadbb"),
+    ("th.ff0", "xtheadbb"),
+    ("th.ff1", "xtheadbb"),
+    ("th.rev", "xtheadbb"),
+    ("th.revw", "xtheadbb"),
+    ("th.tstnbz", "xtheadbb"),
+    ("th.tst", "xtheadbs"),
+    ("th.lwd", "xtheadmempair"),
+    ("th.lwud", "xtheadmempair"),
+    ("th.ldd", "xtheadmempair"),
+    ("th.swd", "xtheadmempair"),
+    ("th.sdd", "xtheadmempair"),
+];
+
+// vendor extensions of --march rv64gc_xtheadba_xtheadbb
+pub(super) fn parse_march(march: &str) -> Vec<String> {
+    march
+        .split('_')
+        .skip(1)
+        .filter(|e| e.starts_with('x'))
+        .map(|e| {
+            if !EXTENSIONS.contains(&e) {
+                panic!("unknown vendor extension {} in --march {}", e, march);
+            }
+            String::from(e)
+        })
+        .collect()
+}
+
+// the vendor extension of a mnemonic
+pub(super) fn extension_of(opcode: &str) -> Option<&'static str> {
+    THEAD_OPS.iter().find(|t| t.0 == opcode).map(|t| t.1)
+}
+
+fn check_uimm(imm: &str, bits: u32) -> u32 {
+    let val = parse_unsigned(imm);
+    if val >> bits != 0 {
+        panic!("immediate {} out of range [0, {}]", imm, (1 << bits) - 1);
+    }
+    val as u32
+}
+
+impl TextInstruction {
+    pub(super) fn convert_thead(&self) -> Option<BinaryInstruction> {
+        let ops = &self.operands;
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b0001011);
+        res.set_funct3(0b001);
+        match self.opcode.as_str() {
+            // th.addsl rd, rs1, rs2, imm2: rd = rs1 + (rs2 << imm2)
+            "th.addsl" => {
+                assert_eq!(ops.len(), 4);
+                res.set_operands(&ops[..3]);
+                res.set_funct7(0);
+                let imm = check_uimm(&ops[3], 2);
+                res.set(25, 26, BinaryInstruction::bits_array(imm, 2));
+            }
+            // th.srri rd, rs1, imm6 and th.tst rd, rs1, imm6
+            "th.srri" | "th.tst" => {
+                assert_eq!(ops.len(), 3);
+                let funct6 = if self.opcode == "th.srri" {
+                    0b000100
+                } else {
+                    0b100010
+                };
+                res.set_rd(&ops[0]);
+                res.set_rs1(&ops[1]);
+                let imm = check_uimm(&ops[2], 6);
+                res.set(26, 31, BinaryInstruction::bits_array(funct6, 6));
+                res.set(20, 25, BinaryInstruction::bits_array(imm, 6));
+            }
+            "th.srriw" => {
+                assert_eq!(ops.len(), 3);
+                res.set_rd(&ops[0]);
+                res.set_rs1(&ops[1]);
+                res.set_funct7(0b0001010);
+                let imm = check_uimm(&ops[2], 5);
+                res.set(20, 24, BinaryInstruction::bits_array(imm, 5));
+            }
+            // th.ext rd, rs1, msb, lsb
+            "th.ext" | "th.extu" => {
+                assert_eq!(ops.len(), 4);
+                let funct3 = if self.opcode == "th.ext" {
+                    0b010
+                } else {
+                    0b011
+                };
+                let msb = check_uimm(&ops[2], 6);
+                let lsb = check_uimm(&ops[3], 6);
+                res.set_funct3(funct3);
+                res.set_rd(&ops[0]);
+                res.set_rs1(&ops[1]);
+                res.set(26, 31, BinaryInstruction::bits_array(msb, 6));
+                res.set(20, 25, BinaryInstruction::bits_array(lsb, 6));
+            }
+            "th.ff0" | "th.ff1" | "th.rev" | "th.revw" | "th.tstnbz" => {
+                res.set_funct7(match self.opcode.as_str() {
+                    "th.tstnbz" => 0b1000000,
+                    "th.rev" => 0b1000001,
+                    "th.ff0" => 0b1000010,
+                    "th.ff1" => 0b1000011,
+                    _ => 0b1001000,
+                });
+                res.set_2operands(ops, 0);
+            }
+            // th.ldd rd1, rd2, (rs1), imm2, 4: the offset is imm2 << 4 (imm2 << 3
+            // for words), the shift is part of the syntax
+            "th.lwd" | "th.lwud" | "th.ldd" | "th.swd" | "th.sdd" => {
+                assert_eq!(ops.len(), 5);
+                let (funct5, shift) = match self.opcode.as_str() {
+                    "th.lwd" | "th.swd" => (0b11100, "3"),
+                    "th.lwud" => (0b11110, "3"),
+                    _ => (0b11111, "4"),
+                };
+                if ops[4] != shift {
+                    panic!("{} needs the shift {}, got {}", self.opcode, shift, ops[4]);
+                }
+                let rs1 = address_reg_name(&ops[2]);
+                let store = self.opcode.starts_with("th.s");
+                if !store {
+                    let rd1 = reg_name2value(&ops[0]);
+                    let rd2 = reg_name2value(&ops[1]);
+                    let base = reg_name2value(rs1);
+                    if rd1 == rd2 || rd1 == base || rd2 == base {
+                        panic!("{}: rd1, rd2 and rs1 must be different", self.opcode);
+                    }
+                }
+                res.set_funct3(if store { 0b101 } else { 0b100 });
+                res.set_rd(&ops[0]);
+                res.set_rs2(&ops[1]);
+                res.set_rs1(rs1);
+                let imm = check_uimm(&ops[3], 2);
+                res.set(27, 31, BinaryInstruction::bits_array(funct5, 5));
+                res.set(25, 26, BinaryInstruction::bits_array(imm, 2));
+            }
+            _ => return None,
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::parse_line;
+
+#[cfg(test)]
+fn test_thead(line: &str, bytes: &str) {
+    let bin = parse_line(line).convert_thead().unwrap();
+    assert_eq!(format!("{}", bin), bytes);
+}
+
+#[test]
+fn test_xtheadba_bs() {
+    test_thead("th.addsl a0, a1, a2, 3", ".byte 0x0b,0x95,0xc5,0x06");
+    test_thead("th.tst a0, a1, 63", ".byte 0x0b,0x95,0xf5,0x8b");
+}
+
+#[test]
+fn test_xtheadbb() {
+    test_thead("th.srri a0, a1, 5", ".byte 0x0b,0x95,0x55,0x10");
+    test_thead("th.srriw a0, a1, 31", ".byte 0x0b,0x95,0xf5,0x15");
+    test_thead("th.ext a0, a1, 15, 8", ".byte 0x0b,0xa5,0x85,0x3c");
+    test_thead("th.extu a0, a1, 63, 0", ".byte 0x0b,0xb5,0x05,0xfc");
+    test_thead("th.ff0 a0, a1", ".byte 0x0b,0x95,0x05,0x84");
+    test_thead("th.ff1 a0, a1", ".byte 0x0b,0x95,0x05,0x86");
+    test_thead("th.rev a0, a1", ".byte 0x0b,0x95,0x05,0x82");
+    test_thead("th.revw a0, a1", ".byte 0x0b,0x95,0x05,0x90");
+    test_thead("th.tstnbz a0, a1", ".byte 0x0b,0x95,0x05,0x80");
+}
+
+#[test]
+fn test_xtheadmempair() {
+    test_thead("th.ldd a0, a1, (a2), 1, 4", ".byte 0x0b,0x45,0xb6,0xfa");
+    test_thead("th.lwd a0, a1, (a2), 3, 3", ".byte 0x0b,0x45,0xb6,0xe6");
+    test_thead("th.lwud a0, a1, (a2), 0, 3", ".byte 0x0b,0x45,0xb6,0xf0");
+    test_thead("th.sdd a0, a1, (a2), 2, 4", ".byte 0x0b,0x55,0xb6,0xfc");
+    test_thead("th.swd a0, a1, (a2), 0, 3", ".byte 0x0b,0x55,0xb6,0xe0");
+}
+
+#[test]
+#[should_panic(expected = "th.ldd: rd1, rd2 and rs1 must be different")]
+fn test_xtheadmempair_overlap() {
+    test_thead("th.ldd a0, a1, (a0), 0, 4", "");
+}
+
+#[test]
+fn test_march() {
+    assert_eq!(
+        parse_march("rv64gc_xtheadba_xtheadbb"),
+        vec!["xtheadba", "xtheadbb"]
+    );
+    assert_eq!(parse_march("rv64gc_zba_zbb").len(), 0);
+}
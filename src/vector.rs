@@ -0,0 +1,811 @@
+// V-extension: configuration instructions, integer arithmetic, reductions,
+// permutations, loads/stores and the vector register table. Zvbb/Zvkb
+// vector bit-manipulation, Zvkned/Zvknh vector crypto.
+use super::{address_reg_name, parse_imm, parse_unsigned, BinaryInstruction, TextInstruction};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+lazy_static! {
+    pub static ref VREG_MAP: HashMap<String, u8> = {
+        let mut map = HashMap::new();
+        for i in 0..32 {
+            map.insert(format!("v{}", i), i);
+        }
+        map
+    };
+}
+
+// v0-v31 and the mask operand v0.t
+pub(super) fn is_vreg(name: &str) -> bool {
+    VREG_MAP.contains_key(name) || name == "v0.t"
+}
+
+fn vreg_name2value(name: &str) -> u8 {
+    let res = VREG_MAP.get(name);
+    if res.is_none() {
+        panic!("can't find vector register name {}", name);
+    }
+    *res.unwrap()
+}
+
+// funct3 of the operand categories
+const OPIVV: u8 = 0b000;
+const OPFVV: u8 = 0b001;
+const OPMVV: u8 = 0b010;
+const OPIVI: u8 = 0b011;
+const OPIVX: u8 = 0b100;
+const OPFVF: u8 = 0b101;
+const OPMVX: u8 = 0b110;
+
+// Integer arithmetic, reductions and permutations: mnemonic without suffix,
+// funct6, OPI or OPM and the allowed suffixes. "vi" with an unsigned
+// immediate is written "vu". "vs" (reductions) and "vm" (vcompress) take a
+// vector register like "vv".
+const VECTOR_ARITH: [(&str, u8, bool, &str); 50] = [
+    ("vadd", 0b000000, true, "vv,vx,vi"),
+    ("vsub", 0b000010, true, "vv,vx"),
+    ("vrsub", 0b000011, true, "vx,vi"),
+    ("vminu", 0b000100, true, "vv,vx"),
+    ("vmin", 0b000101, true, "vv,vx"),
+    ("vmaxu", 0b000110, true, "vv,vx"),
+    ("vmax", 0b000111, true, "vv,vx"),
+    ("vand", 0b001001, true, "vv,vx,vi"),
+    ("vor", 0b001010, true, "vv,vx,vi"),
+    ("vxor", 0b001011, true, "vv,vx,vi"),
+    ("vmseq", 0b011000, true, "vv,vx,vi"),
+    ("vmsne", 0b011001, true, "vv,vx,vi"),
+    ("vmsltu", 0b011010, true, "vv,vx"),
+    ("vmslt", 0b011011, true, "vv,vx"),
+    ("vmsleu", 0b011100, true, "vv,vx,vi"),
+    ("vmsle", 0b011101, true, "vv,vx,vi"),
+    ("vmsgtu", 0b011110, true, "vx,vi"),
+    ("vmsgt", 0b011111, true, "vx,vi"),
+    ("vsll", 0b100101, true, "vv,vx,vu"),
+    ("vsrl", 0b101000, true, "vv,vx,vu"),
+    ("vsra", 0b101001, true, "vv,vx,vu"),
+    ("vdivu", 0b100000, false, "vv,vx"),
+    ("vdiv", 0b100001, false, "vv,vx"),
+    ("vremu", 0b100010, false, "vv,vx"),
+    ("vrem", 0b100011, false, "vv,vx"),
+    ("vmulhu", 0b100100, false, "vv,vx"),
+    ("vmul", 0b100101, false, "vv,vx"),
+    ("vmulhsu", 0b100110, false, "vv,vx"),
+    ("vmulh", 0b100111, false, "vv,vx"),
+    ("vredsum", 0b000000, false, "vs"),
+    ("vredand", 0b000001, false, "vs"),
+    ("vredor", 0b000010, false, "vs"),
+    ("vredxor", 0b000011, false, "vs"),
+    ("vredminu", 0b000100, false, "vs"),
+    ("vredmin", 0b000101, false, "vs"),
+    ("vredmaxu", 0b000110, false, "vs"),
+    ("vredmax", 0b000111, false, "vs"),
+    ("vwredsumu", 0b110000, true, "vs"),
+    ("vwredsum", 0b110001, true, "vs"),
+    ("vrgather", 0b001100, true, "vv,vx,vu"),
+    ("vrgatherei16", 0b001110, true, "vv"),
+    ("vslideup", 0b001110, true, "vx,vu"),
+    ("vslidedown", 0b001111, true, "vx,vu"),
+    ("vslide1up", 0b001110, false, "vx"),
+    ("vslide1down", 0b001111, false, "vx"),
+    ("vcompress", 0b010111, false, "vm"),
+    // Zvbb/Zvkb, vror.vi has a 6-bit immediate, see convert_vector_bitmanip
+    ("vandn", 0b000001, true, "vv,vx"),
+    ("vrol", 0b010101, true, "vv,vx"),
+    ("vror", 0b010100, true, "vv,vx"),
+    ("vwsll", 0b110101, true, "vv,vx,vu"),
+];
+
+// Zvbb/Zvkb unary instructions: funct6 010010 (OPMVV) with the operation in
+// the vs1 field
+const VECTOR_UNARY: [(&str, u32); 6] = [
+    ("vbrev8.v", 0b01000),
+    ("vrev8.v", 0b01001),
+    ("vbrev.v", 0b01010),
+    ("vclz.v", 0b01100),
+    ("vctz.v", 0b01101),
+    ("vcpop.v", 0b01110),
+];
+
+// Zvkned: funct6 101000 (.vv) or 101001 (.vs), vs1 field selects the round
+const VECTOR_AES: [(&str, u32); 5] = [
+    ("vaesdm", 0b00000),
+    ("vaesdf", 0b00001),
+    ("vaesem", 0b00010),
+    ("vaesef", 0b00011),
+    ("vaesz", 0b00111),
+];
+
+// fields of a vector load/store taken from the mnemonic
+struct VectorMem {
+    store: bool,
+    // 00 unit-stride, 01 indexed-unordered, 10 strided, 11 indexed-ordered
+    mop: u32,
+    // number of fields minus one: segment or whole register count
+    nf: u32,
+    width: u32,
+    // lumop/sumop of unit-stride: 01000 whole register, 01011 mask,
+    // 10000 fault-only-first
+    umop: u32,
+}
+
+fn vector_mem_width(width: &str) -> Option<u32> {
+    match width {
+        "8" => Some(0b000),
+        "16" => Some(0b101),
+        "32" => Some(0b110),
+        "64" => Some(0b111),
+        _ => None,
+    }
+}
+
+// [s|ux|ox][seg<nf>]e[i]<width>[ff] after "vl"/"vs", for example "e32",
+// "sseg4e64", "uxseg2ei32", "e16ff"
+fn parse_vector_mem_body(body: &str, kind: &str, mop: u32) -> Option<(u32, u32, bool)> {
+    let mut rest = body.strip_prefix(kind)?;
+    let mut nf = 0;
+    if let Some(r) = rest.strip_prefix("seg") {
+        let n = r
+            .get(..1)?
+            .parse::<u32>()
+            .ok()
+            .filter(|n| (2..=8).contains(n))?;
+        nf = n - 1;
+        rest = &r[1..];
+    }
+    rest = rest.strip_prefix(if mop & 1 == 1 { "ei" } else { "e" })?;
+    let (rest, ff) = match rest.strip_suffix("ff") {
+        Some(r) if mop == 0 => (r, true),
+        _ => (rest, false),
+    };
+    Some((nf, vector_mem_width(rest)?, ff))
+}
+
+fn parse_vector_mem(opcode: &str) -> Option<VectorMem> {
+    let body = opcode.strip_suffix(".v")?;
+    let (store, rest) = match body.strip_prefix("vl") {
+        Some(r) => (false, r),
+        None => (true, body.strip_prefix("vs")?),
+    };
+    // vlm.v/vsm.v: mask, like e8
+    if rest == "m" {
+        return Some(VectorMem {
+            store,
+            mop: 0,
+            nf: 0,
+            width: 0,
+            umop: 0b01011,
+        });
+    }
+    // vl<n>re<width>.v/vs<n>r.v: whole registers
+    if let Some(n) = rest.get(..1).and_then(|n| n.parse::<u32>().ok()) {
+        if ![1, 2, 4, 8].contains(&n) {
+            return None;
+        }
+        let width = if store {
+            if &rest[1..] != "r" {
+                return None;
+            }
+            0
+        } else {
+            vector_mem_width(rest[1..].strip_prefix("re")?)?
+        };
+        return Some(VectorMem {
+            store,
+            mop: 0,
+            nf: n - 1,
+            width,
+            umop: 0b01000,
+        });
+    }
+    // "s" must come after "seg" was tried with no kind
+    for (kind, mop) in [("ux", 0b01), ("ox", 0b11), ("", 0b00), ("s", 0b10)].iter() {
+        if let Some((nf, width, ff)) = parse_vector_mem_body(rest, kind, *mop) {
+            if ff && store {
+                return None;
+            }
+            let umop = if ff { 0b10000 } else { 0 };
+            return Some(VectorMem {
+                store,
+                mop: *mop,
+                nf,
+                width,
+                umop,
+            });
+        }
+    }
+    None
+}
+
+// vtype immediate from "e32, m4, ta, ma" or a number. Missing fields default
+// to m1, tu, mu like GNU as.
+fn parse_vtype(fields: &[String]) -> u32 {
+    if fields.len() == 1 && fields[0].starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_unsigned(&fields[0]) as u32;
+    }
+    let mut sew = None;
+    let mut lmul = 0;
+    let mut ta = 0;
+    let mut ma = 0;
+    for field in fields {
+        match field.as_str() {
+            "e8" => sew = Some(0),
+            "e16" => sew = Some(1),
+            "e32" => sew = Some(2),
+            "e64" => sew = Some(3),
+            "m1" => lmul = 0,
+            "m2" => lmul = 1,
+            "m4" => lmul = 2,
+            "m8" => lmul = 3,
+            "mf8" => lmul = 5,
+            "mf4" => lmul = 6,
+            "mf2" => lmul = 7,
+            "ta" => ta = 1,
+            "tu" => ta = 0,
+            "ma" => ma = 1,
+            "mu" => ma = 0,
+            _ => panic!("invalid vtype field {}", field),
+        }
+    }
+    let sew = sew.unwrap_or_else(|| panic!("missing element width in vtype {}", fields.join(",")));
+    (ma << 7) | (ta << 6) | (sew << 3) | lmul
+}
+
+impl BinaryInstruction {
+    // OP-V with funct6 and the operand category, unmasked
+    fn new_vector(funct6: u8, funct3: u8) -> BinaryInstruction {
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1010111);
+        res.set_funct3(funct3);
+        res.set(25, 25, vec![1]);
+        res.set(26, 31, BinaryInstruction::bits_array(funct6.into(), 6));
+        res
+    }
+    fn set_vd(&mut self, vd: &str) {
+        let bits = BinaryInstruction::bits_array(vreg_name2value(vd).into(), 5);
+        self.set(7, 11, bits);
+    }
+    fn set_vs1(&mut self, vs1: &str) {
+        let bits = BinaryInstruction::bits_array(vreg_name2value(vs1).into(), 5);
+        self.set(15, 19, bits);
+    }
+    fn set_vs2(&mut self, vs2: &str) {
+        let bits = BinaryInstruction::bits_array(vreg_name2value(vs2).into(), 5);
+        self.set(20, 24, bits);
+    }
+    // 5-bit immediate in the vs1 field
+    fn set_vimm(&mut self, imm: &str, signed: bool) {
+        let val = parse_imm(imm);
+        let range = if signed { -16..=15 } else { 0..=31 };
+        if !range.contains(&val) {
+            panic!(
+                "immediate {} out of range [{}, {}]",
+                imm,
+                range.start(),
+                range.end()
+            );
+        }
+        self.set(15, 19, BinaryInstruction::bits_array(val as u32 & 0x1f, 5));
+    }
+    // vm is 0 with a trailing "v0.t", 1 (unmasked) otherwise. Returns the
+    // operands without the mask.
+    fn set_vm<'a>(&mut self, operands: &'a [String]) -> &'a [String] {
+        let masked = operands.last().is_some_and(|o| o == "v0.t");
+        self.set(25, 25, vec![if masked { 0 } else { 1 }]);
+        if masked {
+            &operands[..operands.len() - 1]
+        } else {
+            operands
+        }
+    }
+}
+
+impl TextInstruction {
+    // vle32.v vd, (rs1)[, v0.t]
+    // vlse32.v vd, (rs1), rs2[, v0.t]
+    // vluxei32.v vd, (rs1), vs2[, v0.t]
+    // stores take vs3 in place of vd
+    fn convert_vector_mem(&self) -> Option<BinaryInstruction> {
+        let mem = parse_vector_mem(&self.opcode)?;
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(if mem.store { 0b0100111 } else { 0b0000111 });
+        res.set_funct3(mem.width as u8);
+        res.set(29, 31, BinaryInstruction::bits_array(mem.nf, 3));
+        res.set(28, 28, vec![0]);
+        res.set(26, 27, BinaryInstruction::bits_array(mem.mop, 2));
+        let operands = res.set_vm(&self.operands);
+        res.set_vd(operands[0].as_ref());
+        res.set_rs1(address_reg_name(operands[1].as_ref()));
+        match mem.mop {
+            0b00 => {
+                assert_eq!(operands.len(), 2);
+                res.set(20, 24, BinaryInstruction::bits_array(mem.umop, 5));
+            }
+            0b10 => {
+                assert_eq!(operands.len(), 3);
+                res.set_rs2(operands[2].as_ref());
+            }
+            _ => {
+                assert_eq!(operands.len(), 3);
+                res.set_vs2(operands[2].as_ref());
+            }
+        }
+        Some(res)
+    }
+    // vadd.vv vd, vs2, vs1[, v0.t]
+    // vadd.vx vd, vs2, rs1[, v0.t]
+    // vadd.vi vd, vs2, imm[, v0.t]
+    fn convert_vector_arith(&self) -> Option<BinaryInstruction> {
+        let (name, suffix) = self.opcode.split_at(self.opcode.rfind('.')?);
+        let suffix = &suffix[1..];
+        let (_, funct6, opi, suffixes) = VECTOR_ARITH.iter().find(|a| a.0 == name)?;
+        let kind = suffixes
+            .split(',')
+            .find(|s| *s == suffix || (suffix == "vi" && *s == "vu"))?;
+        let funct3 = match (kind, opi) {
+            ("vv", true) | ("vs", true) => OPIVV,
+            ("vv", false) | ("vs", false) | ("vm", false) => OPMVV,
+            ("vx", true) => OPIVX,
+            ("vx", false) => OPMVX,
+            _ => OPIVI,
+        };
+
+        let mut res = BinaryInstruction::new_vector(*funct6, funct3);
+        let operands = res.set_vm(&self.operands);
+        assert_eq!(operands.len(), 3);
+        res.set_vd(operands[0].as_ref());
+        res.set_vs2(operands[1].as_ref());
+        match kind {
+            "vv" | "vs" | "vm" => res.set_vs1(operands[2].as_ref()),
+            "vx" => res.set_rs1(operands[2].as_ref()),
+            _ => res.set_vimm(operands[2].as_ref(), kind == "vi"),
+        }
+        Some(res)
+    }
+
+    // Moves between vector, scalar and floating-point registers, whole
+    // register moves and vmerge. The unused vector fields are zero.
+    fn convert_vector_move(&self) -> Option<BinaryInstruction> {
+        let ops = &self.operands;
+        let res = match self.opcode.as_str() {
+            // vmv.x.s rd, vs2
+            "vmv.x.s" => {
+                let mut res = BinaryInstruction::new_vector(0b010000, OPMVV);
+                res.set_rd(ops[0].as_ref());
+                res.set_vs2(ops[1].as_ref());
+                res
+            }
+            // vmv.s.x vd, rs1
+            "vmv.s.x" => {
+                let mut res = BinaryInstruction::new_vector(0b010000, OPMVX);
+                res.set_vd(ops[0].as_ref());
+                res.set_rs1(ops[1].as_ref());
+                res
+            }
+            // vfmv.f.s rd, vs2
+            "vfmv.f.s" => {
+                let mut res = BinaryInstruction::new_vector(0b010000, OPFVV);
+                res.set_frd(ops[0].as_ref());
+                res.set_vs2(ops[1].as_ref());
+                res
+            }
+            // vfmv.s.f vd, rs1
+            "vfmv.s.f" => {
+                let mut res = BinaryInstruction::new_vector(0b010000, OPFVF);
+                res.set_vd(ops[0].as_ref());
+                res.set_frs1(ops[1].as_ref());
+                res
+            }
+            // vmv.v.v vd, vs1; vmv.v.x vd, rs1; vmv.v.i vd, imm
+            "vmv.v.v" | "vmv.v.x" | "vmv.v.i" => {
+                let funct3 = match self.opcode.as_str() {
+                    "vmv.v.v" => OPIVV,
+                    "vmv.v.x" => OPIVX,
+                    _ => OPIVI,
+                };
+                let mut res = BinaryInstruction::new_vector(0b010111, funct3);
+                res.set_vd(ops[0].as_ref());
+                match funct3 {
+                    OPIVV => res.set_vs1(ops[1].as_ref()),
+                    OPIVX => res.set_rs1(ops[1].as_ref()),
+                    _ => res.set_vimm(ops[1].as_ref(), true),
+                }
+                res
+            }
+            // vmv<nr>r.v vd, vs2, the immediate is nr - 1
+            "vmv1r.v" | "vmv2r.v" | "vmv4r.v" | "vmv8r.v" => {
+                let nr = u32::from(self.opcode.as_bytes()[3] - b'0');
+                let mut res = BinaryInstruction::new_vector(0b100111, OPIVI);
+                res.set_vd(ops[0].as_ref());
+                res.set_vs2(ops[1].as_ref());
+                res.set(15, 19, BinaryInstruction::bits_array(nr - 1, 5));
+                res
+            }
+            // vmerge.vvm vd, vs2, vs1, v0
+            "vmerge.vvm" | "vmerge.vxm" | "vmerge.vim" => {
+                assert_eq!(ops.len(), 4);
+                if ops[3] != "v0" {
+                    panic!("the mask operand of {} must be v0", self.opcode);
+                }
+                let funct3 = match self.opcode.as_str() {
+                    "vmerge.vvm" => OPIVV,
+                    "vmerge.vxm" => OPIVX,
+                    _ => OPIVI,
+                };
+                let mut res = BinaryInstruction::new_vector(0b010111, funct3);
+                res.set(25, 25, vec![0]);
+                res.set_vd(ops[0].as_ref());
+                res.set_vs2(ops[1].as_ref());
+                match funct3 {
+                    OPIVV => res.set_vs1(ops[2].as_ref()),
+                    OPIVX => res.set_rs1(ops[2].as_ref()),
+                    _ => res.set_vimm(ops[2].as_ref(), true),
+                }
+                res
+            }
+            _ => return None,
+        };
+        Some(res)
+    }
+
+    // vbrev8.v vd, vs2[, v0.t]
+    // vror.vi vd, vs2, uimm6[, v0.t]
+    fn convert_vector_bitmanip(&self) -> Option<BinaryInstruction> {
+        if self.opcode == "vror.vi" {
+            let mut res = BinaryInstruction::new_vector(0b010100, OPIVI);
+            let operands = res.set_vm(&self.operands);
+            assert_eq!(operands.len(), 3);
+            res.set_vd(operands[0].as_ref());
+            res.set_vs2(operands[1].as_ref());
+            let imm = parse_unsigned(operands[2].as_ref());
+            if imm > 63 {
+                panic!("immediate {} out of range [0, 63]", operands[2]);
+            }
+            res.set(15, 19, BinaryInstruction::bits_array(imm as u32 & 0x1f, 5));
+            res.set(26, 26, vec![(imm >> 5) as u8]);
+            return Some(res);
+        }
+        let (_, op) = VECTOR_UNARY.iter().find(|u| u.0 == self.opcode)?;
+        let mut res = BinaryInstruction::new_vector(0b010010, OPMVV);
+        let operands = res.set_vm(&self.operands);
+        assert_eq!(operands.len(), 2);
+        res.set_vd(operands[0].as_ref());
+        res.set_vs2(operands[1].as_ref());
+        res.set(15, 19, BinaryInstruction::bits_array(*op, 5));
+        Some(res)
+    }
+
+    // Zvkned/Zvknh, all unmasked with opcode OP-VE
+    // vaesef.vv vd, vs2
+    // vaeskf1.vi vd, vs2, uimm
+    // vsha2ms.vv vd, vs2, vs1
+    fn convert_vector_crypto(&self) -> Option<BinaryInstruction> {
+        let ops = &self.operands;
+        let (funct6, form, round) = match self.opcode.as_str() {
+            "vaeskf1.vi" => (0b100010, "vi", 0),
+            "vaeskf2.vi" => (0b101010, "vi", 0),
+            "vsha2ms.vv" => (0b101101, "vvv", 0),
+            "vsha2ch.vv" => (0b101110, "vvv", 0),
+            "vsha2cl.vv" => (0b101111, "vvv", 0),
+            opcode => {
+                let (name, kind) = opcode.split_once('.')?;
+                let funct6 = match kind {
+                    "vv" if name != "vaesz" => 0b101000,
+                    "vs" => 0b101001,
+                    _ => return None,
+                };
+                let (_, round) = VECTOR_AES.iter().find(|a| a.0 == name)?;
+                (funct6, kind, *round)
+            }
+        };
+        if ops.last().is_some_and(|o| o == "v0.t") {
+            panic!("{} can't be masked", self.opcode);
+        }
+        let mut res = BinaryInstruction::new_vector(funct6, OPMVV);
+        res.set_opcode(0b1110111);
+        res.set_vd(ops[0].as_ref());
+        res.set_vs2(ops[1].as_ref());
+        let vd = vreg_name2value(ops[0].as_ref());
+        let vs2 = vreg_name2value(ops[1].as_ref());
+        match form {
+            "vi" => {
+                assert_eq!(ops.len(), 3);
+                res.set_vimm(ops[2].as_ref(), false);
+            }
+            "vvv" => {
+                assert_eq!(ops.len(), 3);
+                res.set_vs1(ops[2].as_ref());
+                let vs1 = vreg_name2value(ops[2].as_ref());
+                if vd == vs1 || vd == vs2 || vs1 == vs2 {
+                    panic!("{}: vd, vs1 and vs2 must not overlap", self.opcode);
+                }
+            }
+            _ => {
+                assert_eq!(ops.len(), 2);
+                res.set(15, 19, BinaryInstruction::bits_array(round, 5));
+                // the scalar element group of vs2 is read for every group of vd
+                if form == "vs" && vd == vs2 {
+                    panic!("{}: vd and vs2 must not overlap", self.opcode);
+                }
+            }
+        }
+        Some(res)
+    }
+
+    pub(super) fn convert_vector(&self) -> Option<BinaryInstruction> {
+        let mut res = BinaryInstruction::new();
+        res.set_opcode(0b1010111);
+        match self.opcode.as_str() {
+            // vsetvli rd, rs1, vtype
+            "vsetvli" => {
+                assert!(self.operands.len() >= 3);
+                let vtype = parse_vtype(&self.operands[2..]);
+                if vtype > 0x7ff {
+                    panic!("vtype {:#x} out of range", vtype);
+                }
+                res.set_funct3(0b111);
+                res.set_rd(self.operands[0].as_ref());
+                res.set_rs1(self.operands[1].as_ref());
+                res.set(20, 31, BinaryInstruction::bits_array(vtype, 12));
+            }
+            // vsetivli rd, uimm, vtype
+            "vsetivli" => {
+                assert!(self.operands.len() >= 3);
+                let vtype = parse_vtype(&self.operands[2..]);
+                if vtype > 0x3ff {
+                    panic!("vtype {:#x} out of range", vtype);
+                }
+                let avl = parse_unsigned(&self.operands[1]);
+                if avl > 31 {
+                    panic!("immediate {} out of range [0, 31]", self.operands[1]);
+                }
+                res.set_funct3(0b111);
+                res.set_rd(self.operands[0].as_ref());
+                res.set(15, 19, BinaryInstruction::bits_array(avl as u32, 5));
+                res.set(20, 29, BinaryInstruction::bits_array(vtype, 10));
+                res.set(30, 31, BinaryInstruction::bits_array(0b11, 2));
+            }
+            // vsetvl rd, rs1, rs2
+            "vsetvl" => {
+                res.set_funct3(0b111);
+                res.set_funct7(0b1000000);
+                res.set_operands(&self.operands);
+            }
+            _ => {
+                return self
+                    .convert_vector_arith()
+                    .or_else(|| self.convert_vector_mem())
+                    .or_else(|| self.convert_vector_move())
+                    .or_else(|| self.convert_vector_bitmanip())
+                    .or_else(|| self.convert_vector_crypto())
+            }
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+use super::test;
+
+#[test]
+fn test_vset() {
+    test(
+        "vsetvli a0, a1, e32, m4, ta, ma",
+        ".byte 0x57,0xf5,0x25,0x0d",
+    );
+    test(
+        "vsetvli t0, zero, e16, m2, ta, mu",
+        ".byte 0xd7,0x72,0x90,0x04",
+    );
+    test(
+        "vsetvli a0, a1, e8, mf8, tu, mu",
+        ".byte 0x57,0xf5,0x55,0x00",
+    );
+    test("vsetvli a0, a1, e64", ".byte 0x57,0xf5,0x85,0x01");
+    test("vsetvli a0, a1, 0x55", ".byte 0x57,0xf5,0x55,0x05");
+    test(
+        "vsetivli a0, 31, e32, mf2, tu, ma",
+        ".byte 0x57,0xf5,0x7f,0xc9",
+    );
+    test("vsetvl a0, a1, a2", ".byte 0x57,0xf5,0xc5,0x80");
+}
+
+#[test]
+#[should_panic(expected = "missing element width")]
+fn test_vtype_no_sew() {
+    test("vsetvli a0, a1, m4, ta", "");
+}
+
+#[test]
+fn test_vector_arith() {
+    test("vadd.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x02");
+    test("vadd.vx v1, v2, a0, v0.t", ".byte 0xd7,0x40,0x25,0x00");
+    test("vadd.vi v1, v2, -16", ".byte 0xd7,0x30,0x28,0x02");
+    test("vadd.vi v1, v2, 15, v0.t", ".byte 0xd7,0xb0,0x27,0x00");
+    test("vrsub.vi v4, v5, 3", ".byte 0x57,0xb2,0x51,0x0e");
+    test("vminu.vx v1, v2, a0", ".byte 0xd7,0x40,0x25,0x12");
+    test("vmaxu.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x1a");
+    test("vmseq.vi v0, v2, 5", ".byte 0x57,0xb0,0x22,0x62");
+    test("vmsgtu.vi v0, v2, -3", ".byte 0x57,0xb0,0x2e,0x7a");
+}
+
+#[test]
+fn test_vector_logical_shift() {
+    test("vand.vv v8, v9, v10", ".byte 0x57,0x04,0x95,0x26");
+    test("vor.vx v8, v9, t0", ".byte 0x57,0xc4,0x92,0x2a");
+    test("vxor.vi v8, v9, -1", ".byte 0x57,0xb4,0x9f,0x2e");
+    test("vsll.vi v1, v2, 31", ".byte 0xd7,0xb0,0x2f,0x96");
+    test("vsrl.vx v1, v2, a1, v0.t", ".byte 0xd7,0xc0,0x25,0xa0");
+    test("vsra.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0xa6");
+}
+
+#[test]
+fn test_vector_mul_div() {
+    test("vmul.vv v1, v2, v3", ".byte 0xd7,0xa0,0x21,0x96");
+    test("vmulhsu.vv v1, v2, v3", ".byte 0xd7,0xa0,0x21,0x9a");
+    test("vdivu.vx v1, v2, a0", ".byte 0xd7,0x60,0x25,0x82");
+    test("vrem.vx v1, v2, a0", ".byte 0xd7,0x60,0x25,0x8e");
+}
+
+#[test]
+#[should_panic(expected = "out of range [0, 31]")]
+fn test_vector_shift_imm() {
+    test("vsll.vi v1, v2, -1", "");
+}
+
+#[test]
+fn test_vector_load_store() {
+    test("vle8.v v1, (a0)", ".byte 0x87,0x00,0x05,0x02");
+    test("vle16.v v1, (a0), v0.t", ".byte 0x87,0x50,0x05,0x00");
+    test("vle32.v v8, (a1)", ".byte 0x07,0xe4,0x05,0x02");
+    test("vle64.v v8, 0(a1)", ".byte 0x07,0xf4,0x05,0x02");
+    test("vse32.v v8, (a1)", ".byte 0x27,0xe4,0x05,0x02");
+    test("vse64.v v8, (a1), v0.t", ".byte 0x27,0xf4,0x05,0x00");
+    test("vle32ff.v v8, (a1)", ".byte 0x07,0xe4,0x05,0x03");
+    test("vlm.v v0, (a0)", ".byte 0x07,0x00,0xb5,0x02");
+    test("vsm.v v0, (a0)", ".byte 0x27,0x00,0xb5,0x02");
+    test("vl1re8.v v1, (a0)", ".byte 0x87,0x00,0x85,0x02");
+    test("vl2re32.v v2, (a0)", ".byte 0x07,0x61,0x85,0x22");
+    test("vl8re64.v v8, (a0)", ".byte 0x07,0x74,0x85,0xe2");
+    test("vs1r.v v1, (a0)", ".byte 0xa7,0x00,0x85,0x02");
+    test("vs4r.v v4, (a0)", ".byte 0x27,0x02,0x85,0x62");
+}
+
+#[test]
+fn test_vector_strided_indexed() {
+    test("vlse32.v v8, (a1), a2", ".byte 0x07,0xe4,0xc5,0x0a");
+    test("vsse64.v v8, (a1), a2, v0.t", ".byte 0x27,0xf4,0xc5,0x08");
+    test("vluxei32.v v8, (a1), v4", ".byte 0x07,0xe4,0x45,0x06");
+    test("vloxei8.v v8, (a1), v4, v0.t", ".byte 0x07,0x84,0x45,0x0c");
+    test("vsuxei16.v v8, (a1), v4", ".byte 0x27,0xd4,0x45,0x06");
+    test("vsoxei64.v v8, (a1), v4", ".byte 0x27,0xf4,0x45,0x0e");
+}
+
+#[test]
+fn test_vector_segment() {
+    test("vlseg2e32.v v8, (a1)", ".byte 0x07,0xe4,0x05,0x22");
+    test("vlseg8e8.v v8, (a1), v0.t", ".byte 0x07,0x84,0x05,0xe0");
+    test("vsseg3e16.v v8, (a1)", ".byte 0x27,0xd4,0x05,0x42");
+    test("vlsseg4e64.v v8, (a1), a2", ".byte 0x07,0xf4,0xc5,0x6a");
+    test("vssseg2e32.v v8, (a1), a2", ".byte 0x27,0xe4,0xc5,0x2a");
+    test("vluxseg2ei32.v v8, (a1), v4", ".byte 0x07,0xe4,0x45,0x26");
+    test("vloxseg3ei8.v v8, (a1), v4", ".byte 0x07,0x84,0x45,0x4e");
+    test("vsuxseg4ei16.v v8, (a1), v4", ".byte 0x27,0xd4,0x45,0x66");
+    test("vsoxseg5ei64.v v8, (a1), v4", ".byte 0x27,0xf4,0x45,0x8e");
+    test("vlseg2e16ff.v v8, (a1)", ".byte 0x07,0xd4,0x05,0x23");
+}
+
+#[test]
+fn test_vector_reduction() {
+    test("vredsum.vs v1, v2, v3", ".byte 0xd7,0xa0,0x21,0x02");
+    test("vredmaxu.vs v1, v2, v3, v0.t", ".byte 0xd7,0xa0,0x21,0x18");
+    test("vredxor.vs v1, v2, v3", ".byte 0xd7,0xa0,0x21,0x0e");
+    test("vwredsumu.vs v1, v2, v3", ".byte 0xd7,0x80,0x21,0xc2");
+    test("vwredsum.vs v1, v2, v3", ".byte 0xd7,0x80,0x21,0xc6");
+}
+
+#[test]
+fn test_vector_permutation() {
+    test("vrgather.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x32");
+    test("vrgather.vx v1, v2, a0", ".byte 0xd7,0x40,0x25,0x32");
+    test("vrgather.vi v1, v2, 31", ".byte 0xd7,0xb0,0x2f,0x32");
+    test("vrgatherei16.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x3a");
+    test("vslideup.vx v1, v2, a0", ".byte 0xd7,0x40,0x25,0x3a");
+    test("vslideup.vi v1, v2, 3, v0.t", ".byte 0xd7,0xb0,0x21,0x38");
+    test("vslidedown.vx v1, v2, a0", ".byte 0xd7,0x40,0x25,0x3e");
+    test("vslidedown.vi v1, v2, 31", ".byte 0xd7,0xb0,0x2f,0x3e");
+    test("vslide1up.vx v1, v2, a0", ".byte 0xd7,0x60,0x25,0x3a");
+    test(
+        "vslide1down.vx v1, v2, a0, v0.t",
+        ".byte 0xd7,0x60,0x25,0x3c",
+    );
+    test("vcompress.vm v1, v2, v3", ".byte 0xd7,0xa0,0x21,0x5e");
+}
+
+#[test]
+fn test_vector_move() {
+    test("vmv.x.s a0, v2", ".byte 0x57,0x25,0x20,0x42");
+    test("vmv.s.x v1, a0", ".byte 0xd7,0x60,0x05,0x42");
+    test("vmv.v.v v1, v3", ".byte 0xd7,0x80,0x01,0x5e");
+    test("vmv.v.x v1, a0", ".byte 0xd7,0x40,0x05,0x5e");
+    test("vmv.v.i v1, -16", ".byte 0xd7,0x30,0x08,0x5e");
+    test("vmv1r.v v1, v2", ".byte 0xd7,0x30,0x20,0x9e");
+    test("vmv2r.v v2, v4", ".byte 0x57,0xb1,0x40,0x9e");
+    test("vmv4r.v v4, v8", ".byte 0x57,0xb2,0x81,0x9e");
+    test("vmv8r.v v8, v16", ".byte 0x57,0xb4,0x03,0x9f");
+    test("vfmv.f.s fa0, v2", ".byte 0x57,0x15,0x20,0x42");
+    test("vfmv.s.f v1, fa0", ".byte 0xd7,0x50,0x05,0x42");
+    test("vmerge.vvm v1, v2, v3, v0", ".byte 0xd7,0x80,0x21,0x5c");
+    test("vmerge.vxm v1, v2, a0, v0", ".byte 0xd7,0x40,0x25,0x5c");
+    test("vmerge.vim v1, v2, -1, v0", ".byte 0xd7,0xb0,0x2f,0x5c");
+}
+
+#[test]
+fn test_vector_bitmanip() {
+    test("vandn.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x06");
+    test("vandn.vx v1, v2, a0, v0.t", ".byte 0xd7,0x40,0x25,0x04");
+    test("vbrev.v v1, v2", ".byte 0xd7,0x20,0x25,0x4a");
+    test("vbrev8.v v1, v2, v0.t", ".byte 0xd7,0x20,0x24,0x48");
+    test("vrev8.v v1, v2", ".byte 0xd7,0xa0,0x24,0x4a");
+    test("vclz.v v1, v2", ".byte 0xd7,0x20,0x26,0x4a");
+    test("vctz.v v1, v2", ".byte 0xd7,0xa0,0x26,0x4a");
+    test("vcpop.v v1, v2", ".byte 0xd7,0x20,0x27,0x4a");
+}
+
+#[test]
+fn test_vector_rotate_shift() {
+    test("vrol.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x56");
+    test("vrol.vx v1, v2, a0", ".byte 0xd7,0x40,0x25,0x56");
+    test("vror.vv v1, v2, v3", ".byte 0xd7,0x80,0x21,0x52");
+    test("vror.vx v1, v2, a0", ".byte 0xd7,0x40,0x25,0x52");
+    test("vror.vi v1, v2, 3", ".byte 0xd7,0xb0,0x21,0x52");
+    test("vror.vi v1, v2, 63, v0.t", ".byte 0xd7,0xb0,0x2f,0x54");
+    test("vwsll.vv v2, v4, v6", ".byte 0x57,0x01,0x43,0xd6");
+    test("vwsll.vx v2, v4, a0", ".byte 0x57,0x41,0x45,0xd6");
+    test("vwsll.vi v2, v4, 31", ".byte 0x57,0xb1,0x4f,0xd6");
+}
+
+#[test]
+fn test_vector_aes() {
+    test("vaesdf.vv v4, v8", ".byte 0x77,0xa2,0x80,0xa2");
+    test("vaesdf.vs v4, v8", ".byte 0x77,0xa2,0x80,0xa6");
+    test("vaesdm.vv v4, v8", ".byte 0x77,0x22,0x80,0xa2");
+    test("vaesdm.vs v4, v8", ".byte 0x77,0x22,0x80,0xa6");
+    test("vaesef.vv v4, v8", ".byte 0x77,0xa2,0x81,0xa2");
+    test("vaesef.vs v4, v8", ".byte 0x77,0xa2,0x81,0xa6");
+    test("vaesem.vv v4, v8", ".byte 0x77,0x22,0x81,0xa2");
+    test("vaesem.vs v4, v8", ".byte 0x77,0x22,0x81,0xa6");
+    test("vaesz.vs v4, v8", ".byte 0x77,0xa2,0x83,0xa6");
+    test("vaeskf1.vi v4, v8, 10", ".byte 0x77,0x22,0x85,0x8a");
+    test("vaeskf2.vi v4, v8, 31", ".byte 0x77,0xa2,0x8f,0xaa");
+}
+
+#[test]
+fn test_vector_sha2() {
+    test("vsha2ms.vv v4, v8, v12", ".byte 0x77,0x22,0x86,0xb6");
+    test("vsha2ch.vv v4, v8, v12", ".byte 0x77,0x22,0x86,0xba");
+    test("vsha2cl.vv v4, v8, v12", ".byte 0x77,0x22,0x86,0xbe");
+}
+
+#[test]
+#[should_panic(expected = "vd and vs2 must not overlap")]
+fn test_vector_aes_overlap() {
+    test("vaesem.vs v4, v4", "");
+}
+
+#[test]
+#[should_panic(expected = "vd, vs1 and vs2 must not overlap")]
+fn test_vector_sha2_overlap() {
+    test("vsha2ch.vv v4, v8, v4", "");
+}
+
+#[test]
+#[should_panic(expected = "vaesef.vv can't be masked")]
+fn test_vector_aes_masked() {
+    test("vaesef.vv v1, v2, v0.t", "");
+}
+
+#[test]
+#[should_panic(expected = "vsha2ms.vv can't be masked")]
+fn test_vector_sha2_masked() {
+    test("vsha2ms.vv v4, v8, v12, v0.t", "");
+}